@@ -0,0 +1,108 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use oxzmq::Error;
+use oxzmq_zmtp::{ConnectionError, InprocError, SocketError, WsError};
+use std::{cell::Cell, io};
+
+// The error numbers libzmq uses on Linux. The ones that aren't POSIX are
+// offset by libzmq's "Hausnumero" so they don't clash with the platform's.
+pub(crate) const ENOENT: i32 = 2;
+pub(crate) const EAGAIN: i32 = 11;
+pub(crate) const EFAULT: i32 = 14;
+pub(crate) const EINVAL: i32 = 22;
+pub(crate) const ENOTSOCK: i32 = 88;
+pub(crate) const EPROTONOSUPPORT: i32 = 93;
+pub(crate) const ENOTSUP: i32 = 95;
+pub(crate) const EADDRINUSE: i32 = 98;
+pub(crate) const ECONNREFUSED: i32 = 111;
+pub(crate) const EHOSTUNREACH: i32 = 113;
+
+const ZMQ_HAUSNUMERO: i32 = 156_384_712;
+pub(crate) const EFSM: i32 = ZMQ_HAUSNUMERO + 51;
+pub(crate) const ENOCOMPATPROTO: i32 = ZMQ_HAUSNUMERO + 52;
+pub(crate) const ETERM: i32 = ZMQ_HAUSNUMERO + 53;
+
+thread_local! {
+    static ERRNO: Cell<i32> = const { Cell::new(0) };
+}
+
+// The error of the last call on this thread that failed.
+pub(crate) fn errno() -> i32 {
+    ERRNO.with(Cell::get)
+}
+
+// Records `errno` for `zmq_errno`, and returns -1 for the caller to return,
+// as every failing call in libzmq does.
+pub(crate) fn fail(errno: i32) -> i32 {
+    ERRNO.with(|cell| cell.set(errno));
+    -1
+}
+
+// The error number libzmq would have failed with in place of `err`.
+pub(crate) fn from_error(err: &Error) -> i32 {
+    match err {
+        Error::Endpoint(_) => EINVAL,
+        Error::Socket(err) => from_socket_error(err),
+        Error::Connection(ConnectionError::Io(err)) | Error::Io(err) => from_io_error(err),
+        Error::Connection(ConnectionError::InvalidSocketCombination(..)) => ENOCOMPATPROTO,
+        Error::Connection(_) => EPROTONOSUPPORT,
+        Error::Ws(WsError::Io(err)) => from_io_error(err),
+        Error::Ws(_) => EPROTONOSUPPORT,
+        Error::Inproc(InprocError::AddressInUse(_)) => EADDRINUSE,
+        Error::Inproc(InprocError::ConnectionRefused(_)) => ECONNREFUSED,
+        Error::Inproc(InprocError::InvalidSocketCombination(..)) => ENOCOMPATPROTO,
+        Error::Inproc(InprocError::NotInproc(_)) => EINVAL,
+        Error::NotBound(_) => ENOENT,
+    }
+}
+
+fn from_socket_error(err: &SocketError) -> i32 {
+    match err {
+        SocketError::NoPeers
+        | SocketError::HighWaterMark
+        | SocketError::PeerBusy(_)
+        | SocketError::Timeout => EAGAIN,
+        SocketError::InvalidState => EFSM,
+        SocketError::Terminated => ETERM,
+        SocketError::Peer(_) | SocketError::MissingRoutingId | SocketError::HostUnreachable(_) => {
+            EHOSTUNREACH
+        }
+        SocketError::InvalidSocketCombination(..) => ENOCOMPATPROTO,
+        SocketError::AlreadyConnected
+        | SocketError::DuplicateRoutingId(_)
+        | SocketError::MultipartNotAllowed
+        | SocketError::InvalidGroup => EINVAL,
+    }
+}
+
+fn from_io_error(err: &io::Error) -> i32 {
+    match err.kind() {
+        io::ErrorKind::AddrInUse => EADDRINUSE,
+        io::ErrorKind::ConnectionRefused => ECONNREFUSED,
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::InvalidInput => EINVAL,
+        _ => err.raw_os_error().unwrap_or(EINVAL),
+    }
+}
+
+// What `zmq_strerror` says about `errno`.
+pub(crate) fn describe(errno: i32) -> &'static str {
+    match errno {
+        ENOENT => "No such file or directory\0",
+        EAGAIN => "Resource temporarily unavailable\0",
+        EFAULT => "Bad address\0",
+        EINVAL => "Invalid argument\0",
+        ENOTSOCK => "Socket operation on non-socket\0",
+        EPROTONOSUPPORT => "Protocol not supported\0",
+        ENOTSUP => "Operation not supported\0",
+        EADDRINUSE => "Address already in use\0",
+        ECONNREFUSED => "Connection refused\0",
+        EHOSTUNREACH => "Host unreachable\0",
+        EFSM => "Operation cannot be accomplished in current state\0",
+        ENOCOMPATPROTO => "The protocol is not compatible with the socket type\0",
+        ETERM => "Context was terminated\0",
+        _ => "Unknown error\0",
+    }
+}
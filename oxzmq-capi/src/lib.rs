@@ -0,0 +1,576 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The core of libzmq's C API, so that programs written against `zmq.h` can
+//! link against OxZMQ instead. The library is built as `libzmq`, both shared
+//! and static, and the functions, constants and error numbers are libzmq's,
+//! as of version 4.3.
+//!
+//! Contexts and sockets are handed out as opaque pointers, as in libzmq.
+//! Every call blocks the calling thread until it's done, and peers are
+//! accepted in the background.
+//!
+//! # Safety
+//!
+//! Like libzmq, the functions trust their callers: context and socket
+//! pointers have to be ones they handed out and haven't been closed since,
+//! buffers have to be valid for the lengths passed with them, and strings have
+//! to end in a NUL byte. Null pointers are caught and fail with `EFAULT`,
+//! `ENOTSOCK` or `EINVAL`, as libzmq's do; anything else is undefined
+//! behavior. A socket can't be used from two threads at once.
+
+#![allow(clippy::missing_safety_doc)]
+
+use crate::errno::{fail, from_error, EAGAIN, EFAULT, EINVAL, ENOTSOCK, ENOTSUP};
+use futures::{executor::block_on, FutureExt};
+use oxzmq::{
+    Context, DealerSocket, Error, Message, PairSocket, PubSocket, PullSocket, PushSocket,
+    RepSocket, ReqSocket, RouterSocket, SocketOption, SocketOptionName, SubSocket,
+};
+use std::{
+    collections::VecDeque,
+    ffi::CStr,
+    os::raw::{c_char, c_int, c_void},
+    ptr, slice,
+    time::Duration,
+};
+
+mod errno;
+
+pub const ZMQ_VERSION_MAJOR: c_int = 4;
+pub const ZMQ_VERSION_MINOR: c_int = 3;
+pub const ZMQ_VERSION_PATCH: c_int = 5;
+
+pub const ZMQ_PAIR: c_int = 0;
+pub const ZMQ_PUB: c_int = 1;
+pub const ZMQ_SUB: c_int = 2;
+pub const ZMQ_REQ: c_int = 3;
+pub const ZMQ_REP: c_int = 4;
+pub const ZMQ_DEALER: c_int = 5;
+pub const ZMQ_ROUTER: c_int = 6;
+pub const ZMQ_PULL: c_int = 7;
+pub const ZMQ_PUSH: c_int = 8;
+
+pub const ZMQ_SUBSCRIBE: c_int = 6;
+pub const ZMQ_UNSUBSCRIBE: c_int = 7;
+pub const ZMQ_RCVMORE: c_int = 13;
+pub const ZMQ_TYPE: c_int = 16;
+pub const ZMQ_LINGER: c_int = 17;
+
+pub const ZMQ_DONTWAIT: c_int = 1;
+pub const ZMQ_SNDMORE: c_int = 2;
+
+// The socket behind a `zmq_socket` pointer, and the message parts that are
+// on their way in or out.
+struct Socket {
+    socket_type: c_int,
+    inner: AnySocket,
+
+    // Parts sent with `ZMQ_SNDMORE`, waiting for the last one.
+    outgoing: Message,
+
+    // The rest of the message the last part came from.
+    incoming: VecDeque<Vec<u8>>,
+}
+
+enum AnySocket {
+    Pair(PairSocket),
+    Pub(PubSocket),
+    Sub(SubSocket),
+    Req(ReqSocket),
+    Rep(RepSocket),
+    Dealer(DealerSocket),
+    Router(RouterSocket),
+    Pull(PullSocket),
+    Push(PushSocket),
+}
+
+// Calls the same method on whichever socket this is, for methods that every
+// socket type has.
+macro_rules! on_socket {
+    ($socket:expr, $s:ident => $call:expr) => {
+        match $socket {
+            AnySocket::Pair($s) => $call,
+            AnySocket::Pub($s) => $call,
+            AnySocket::Sub($s) => $call,
+            AnySocket::Req($s) => $call,
+            AnySocket::Rep($s) => $call,
+            AnySocket::Dealer($s) => $call,
+            AnySocket::Router($s) => $call,
+            AnySocket::Pull($s) => $call,
+            AnySocket::Push($s) => $call,
+        }
+    };
+}
+
+impl AnySocket {
+    fn new(context: &Context, socket_type: c_int) -> Option<AnySocket> {
+        Some(match socket_type {
+            ZMQ_PAIR => AnySocket::Pair(PairSocket::with_context(context)),
+            ZMQ_PUB => AnySocket::Pub(PubSocket::with_context(context)),
+            ZMQ_SUB => AnySocket::Sub(SubSocket::with_context(context)),
+            ZMQ_REQ => AnySocket::Req(ReqSocket::with_context(context)),
+            ZMQ_REP => AnySocket::Rep(RepSocket::with_context(context)),
+            ZMQ_DEALER => AnySocket::Dealer(DealerSocket::with_context(context)),
+            ZMQ_ROUTER => AnySocket::Router(RouterSocket::with_context(context)),
+            ZMQ_PULL => AnySocket::Pull(PullSocket::with_context(context)),
+            ZMQ_PUSH => AnySocket::Push(PushSocket::with_context(context)),
+            _ => return None,
+        })
+    }
+
+    // Sends a message, or returns `None` if the socket type can't send.
+    async fn send(&mut self, msg: Message) -> Option<Result<(), Error>> {
+        Some(match self {
+            AnySocket::Pair(s) => s.send(msg).await,
+            AnySocket::Pub(s) => s.send(msg).await,
+            AnySocket::Req(s) => s.send(msg).await,
+            AnySocket::Rep(s) => s.send(msg).await,
+            AnySocket::Dealer(s) => s.send(msg).await,
+            AnySocket::Router(s) => s.send(msg).await,
+            AnySocket::Push(s) => s.send(msg).await,
+            AnySocket::Sub(_) | AnySocket::Pull(_) => return None,
+        })
+    }
+
+    // Receives a message, or returns `None` if the socket type can't receive.
+    async fn recv(&mut self) -> Option<Result<Message, Error>> {
+        Some(match self {
+            AnySocket::Pair(s) => s.recv().await,
+            AnySocket::Sub(s) => s.recv().await,
+            AnySocket::Req(s) => s.recv().await,
+            AnySocket::Rep(s) => s.recv().await,
+            AnySocket::Dealer(s) => s.recv().await,
+            AnySocket::Router(s) => s.recv().await,
+            AnySocket::Pull(s) => s.recv().await,
+            AnySocket::Pub(_) | AnySocket::Push(_) => return None,
+        })
+    }
+}
+
+// Turns the result of a call into what libzmq returns for it: 0, or -1 with
+// the error number set.
+fn status(result: Result<(), Error>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(err) => fail(from_error(&err)),
+    }
+}
+
+unsafe fn socket_mut<'a>(socket: *mut c_void) -> Option<&'a mut Socket> {
+    (socket as *mut Socket).as_mut()
+}
+
+unsafe fn endpoint<'a>(addr: *const c_char) -> Option<&'a str> {
+    if addr.is_null() {
+        return None;
+    }
+    CStr::from_ptr(addr).to_str().ok()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn zmq_version(major: *mut c_int, minor: *mut c_int, patch: *mut c_int) {
+    for &(out, value) in &[
+        (major, ZMQ_VERSION_MAJOR),
+        (minor, ZMQ_VERSION_MINOR),
+        (patch, ZMQ_VERSION_PATCH),
+    ] {
+        if let Some(out) = out.as_mut() {
+            *out = value;
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_errno() -> c_int {
+    errno::errno()
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_strerror(errnum: c_int) -> *const c_char {
+    errno::describe(errnum).as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_ctx_new() -> *mut c_void {
+    Box::into_raw(Box::new(Context::new())) as *mut c_void
+}
+
+/// Terminates the context, which makes its sockets fail with `ETERM`, and
+/// waits for all of them to be closed before freeing it.
+#[no_mangle]
+pub unsafe extern "C" fn zmq_ctx_term(context: *mut c_void) -> c_int {
+    if context.is_null() {
+        return fail(EFAULT);
+    }
+    let context = Box::from_raw(context as *mut Context);
+    block_on(context.terminate());
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn zmq_ctx_destroy(context: *mut c_void) -> c_int {
+    zmq_ctx_term(context)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn zmq_socket(context: *mut c_void, socket_type: c_int) -> *mut c_void {
+    let context = match (context as *const Context).as_ref() {
+        Some(context) => context,
+        None => {
+            fail(EFAULT);
+            return ptr::null_mut();
+        }
+    };
+    let inner = match AnySocket::new(context, socket_type) {
+        Some(inner) => inner,
+        None => {
+            fail(EINVAL);
+            return ptr::null_mut();
+        }
+    };
+    let socket = Socket {
+        socket_type,
+        inner,
+        outgoing: Message::new(),
+        incoming: VecDeque::new(),
+    };
+    Box::into_raw(Box::new(socket)) as *mut c_void
+}
+
+/// Closes the socket, waiting up to its `ZMQ_LINGER` period for messages
+/// that haven't been written out yet. Unlike in libzmq, where the waiting is
+/// left to `zmq_ctx_term`, it's done before returning.
+#[no_mangle]
+pub unsafe extern "C" fn zmq_close(socket: *mut c_void) -> c_int {
+    if socket.is_null() {
+        return fail(ENOTSOCK);
+    }
+    let socket = Box::from_raw(socket as *mut Socket);
+    on_socket!(socket.inner, s => block_on(s.close()));
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn zmq_bind(socket: *mut c_void, addr: *const c_char) -> c_int {
+    let socket = match socket_mut(socket) {
+        Some(socket) => socket,
+        None => return fail(ENOTSOCK),
+    };
+    let addr = match endpoint(addr) {
+        Some(addr) => addr,
+        None => return fail(EINVAL),
+    };
+    status(on_socket!(&mut socket.inner, s => block_on(s.bind(addr)).map(drop)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn zmq_unbind(socket: *mut c_void, addr: *const c_char) -> c_int {
+    let socket = match socket_mut(socket) {
+        Some(socket) => socket,
+        None => return fail(ENOTSOCK),
+    };
+    let addr = match endpoint(addr) {
+        Some(addr) => addr,
+        None => return fail(EINVAL),
+    };
+    status(on_socket!(&mut socket.inner, s => s.unbind(addr)))
+}
+
+/// Connects and performs the handshake before returning, so unlike with
+/// libzmq, the peer has to be there already.
+#[no_mangle]
+pub unsafe extern "C" fn zmq_connect(socket: *mut c_void, addr: *const c_char) -> c_int {
+    let socket = match socket_mut(socket) {
+        Some(socket) => socket,
+        None => return fail(ENOTSOCK),
+    };
+    let addr = match endpoint(addr) {
+        Some(addr) => addr,
+        None => return fail(EINVAL),
+    };
+    status(on_socket!(&mut socket.inner, s => block_on(s.connect(addr))))
+}
+
+/// Queues a part of a message, and sends the message once its last part,
+/// without `ZMQ_SNDMORE`, is queued. With `ZMQ_DONTWAIT`, sending fails with
+/// `EAGAIN` instead of waiting, and the last part has to be sent again.
+#[no_mangle]
+pub unsafe extern "C" fn zmq_send(
+    socket: *mut c_void,
+    buf: *const c_void,
+    len: usize,
+    flags: c_int,
+) -> c_int {
+    let socket = match socket_mut(socket) {
+        Some(socket) => socket,
+        None => return fail(ENOTSOCK),
+    };
+    let part = match len {
+        0 => Vec::new(),
+        _ if buf.is_null() => return fail(EFAULT),
+        _ => slice::from_raw_parts(buf as *const u8, len).to_vec(),
+    };
+    socket.outgoing.push_back(part);
+    if flags & ZMQ_SNDMORE != 0 {
+        return len as c_int;
+    }
+
+    let result = if flags & ZMQ_DONTWAIT != 0 {
+        match socket.inner.send(socket.outgoing.clone()).now_or_never() {
+            Some(result) => result,
+            None => {
+                socket.outgoing.pop_back();
+                return fail(EAGAIN);
+            }
+        }
+    } else {
+        block_on(socket.inner.send(socket.outgoing.clone()))
+    };
+    socket.outgoing = Message::new();
+    match result {
+        Some(Ok(())) => len as c_int,
+        Some(Err(err)) => fail(from_error(&err)),
+        None => fail(ENOTSUP),
+    }
+}
+
+/// Receives the next part of a message into `buf`, truncating it if it's
+/// longer than `len`, and returns its full length. `ZMQ_RCVMORE` tells
+/// whether more parts follow.
+#[no_mangle]
+pub unsafe extern "C" fn zmq_recv(
+    socket: *mut c_void,
+    buf: *mut c_void,
+    len: usize,
+    flags: c_int,
+) -> c_int {
+    let socket = match socket_mut(socket) {
+        Some(socket) => socket,
+        None => return fail(ENOTSOCK),
+    };
+    if len > 0 && buf.is_null() {
+        return fail(EFAULT);
+    }
+
+    if socket.incoming.is_empty() {
+        let recv = socket.inner.recv();
+        let result = if flags & ZMQ_DONTWAIT != 0 {
+            match recv.now_or_never() {
+                Some(result) => result,
+                None => return fail(EAGAIN),
+            }
+        } else {
+            block_on(recv)
+        };
+        match result {
+            Some(Ok(msg)) => socket.incoming = msg.into_parts().into(),
+            Some(Err(err)) => return fail(from_error(&err)),
+            None => return fail(ENOTSUP),
+        }
+    }
+
+    let part = socket.incoming.pop_front().unwrap_or_default();
+    let copied = part.len().min(len);
+    if copied > 0 {
+        ptr::copy_nonoverlapping(part.as_ptr(), buf as *mut u8, copied);
+    }
+    part.len() as c_int
+}
+
+/// Sets `ZMQ_SUBSCRIBE` and `ZMQ_UNSUBSCRIBE` on SUB sockets, and
+/// `ZMQ_LINGER`, an `int` of milliseconds, or -1 to wait for as long as it
+/// takes, on any socket.
+#[no_mangle]
+pub unsafe extern "C" fn zmq_setsockopt(
+    socket: *mut c_void,
+    option: c_int,
+    optval: *const c_void,
+    optvallen: usize,
+) -> c_int {
+    let socket = match socket_mut(socket) {
+        Some(socket) => socket,
+        None => return fail(ENOTSOCK),
+    };
+    let value = match optvallen {
+        0 => &[][..],
+        _ if optval.is_null() => return fail(EINVAL),
+        _ => slice::from_raw_parts(optval as *const u8, optvallen),
+    };
+    match (&mut socket.inner, option) {
+        (AnySocket::Sub(sub), ZMQ_SUBSCRIBE) => status(block_on(sub.subscribe(value))),
+        (AnySocket::Sub(sub), ZMQ_UNSUBSCRIBE) => status(block_on(sub.unsubscribe(value))),
+        (inner, ZMQ_LINGER) if optvallen == std::mem::size_of::<c_int>() => {
+            let linger = match (optval as *const c_int).read_unaligned() {
+                -1 => None,
+                millis if millis >= 0 => Some(Duration::from_millis(millis as u64)),
+                _ => return fail(EINVAL),
+            };
+            on_socket!(inner, s => s.set_option(SocketOption::Linger(linger)));
+            0
+        }
+        _ => fail(EINVAL),
+    }
+}
+
+/// Gets `ZMQ_RCVMORE`, `ZMQ_TYPE` or `ZMQ_LINGER`, all of which are `int`s.
+#[no_mangle]
+pub unsafe extern "C" fn zmq_getsockopt(
+    socket: *mut c_void,
+    option: c_int,
+    optval: *mut c_void,
+    optvallen: *mut usize,
+) -> c_int {
+    let socket = match socket_mut(socket) {
+        Some(socket) => socket,
+        None => return fail(ENOTSOCK),
+    };
+    let value = match option {
+        ZMQ_RCVMORE => !socket.incoming.is_empty() as c_int,
+        ZMQ_TYPE => socket.socket_type,
+        ZMQ_LINGER => {
+            match on_socket!(&socket.inner, s => s.get_option(SocketOptionName::Linger)) {
+                SocketOption::Linger(Some(linger)) => linger.as_millis() as c_int,
+                _ => -1,
+            }
+        }
+        _ => return fail(EINVAL),
+    };
+    match optvallen.as_mut() {
+        Some(len) if *len >= std::mem::size_of::<c_int>() && !optval.is_null() => {
+            *(optval as *mut c_int) = value;
+            *len = std::mem::size_of::<c_int>();
+            0
+        }
+        _ => fail(EINVAL),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errno::{EFSM, ENOTSOCK};
+
+    unsafe fn send(socket: *mut c_void, part: &[u8], flags: c_int) {
+        let sent = zmq_send(socket, part.as_ptr() as *const c_void, part.len(), flags);
+        assert_eq!(sent, part.len() as c_int);
+    }
+
+    unsafe fn recv(socket: *mut c_void) -> (Vec<u8>, bool) {
+        let mut buf = [0_u8; 64];
+        let len = zmq_recv(socket, buf.as_mut_ptr() as *mut c_void, buf.len(), 0);
+        assert!(len >= 0, "{:?}", CStr::from_ptr(zmq_strerror(zmq_errno())));
+        let mut more: c_int = 0;
+        let mut more_len = std::mem::size_of::<c_int>();
+        let more_ptr = &mut more as *mut c_int as *mut c_void;
+        assert_eq!(
+            zmq_getsockopt(socket, ZMQ_RCVMORE, more_ptr, &mut more_len),
+            0
+        );
+        (buf[..len as usize].to_vec(), more == 1)
+    }
+
+    #[test]
+    fn test_req_rep() {
+        unsafe {
+            let context = zmq_ctx_new();
+            let rep = zmq_socket(context, ZMQ_REP);
+            let req = zmq_socket(context, ZMQ_REQ);
+            let addr = b"inproc://echo\0".as_ptr() as *const c_char;
+            assert_eq!(zmq_bind(rep, addr), 0);
+            assert_eq!(zmq_connect(req, addr), 0);
+
+            // REQ sockets have to wait for a reply before sending again.
+            send(req, b"hello", ZMQ_SNDMORE);
+            send(req, b"world", 0);
+            assert_eq!(zmq_send(req, ptr::null(), 0, 0), -1);
+            assert_eq!(zmq_errno(), EFSM);
+
+            assert_eq!(recv(rep), (b"hello".to_vec(), true));
+            assert_eq!(recv(rep), (b"world".to_vec(), false));
+            send(rep, b"hi", 0);
+            assert_eq!(recv(req), (b"hi".to_vec(), false));
+
+            let mut nothing = [0_u8; 1];
+            let buf = nothing.as_mut_ptr() as *mut c_void;
+            assert_eq!(zmq_recv(rep, buf, 1, ZMQ_DONTWAIT), -1);
+            assert_eq!(zmq_errno(), EAGAIN);
+
+            assert_eq!(zmq_close(req), 0);
+            assert_eq!(zmq_close(rep), 0);
+            assert_eq!(zmq_close(ptr::null_mut()), -1);
+            assert_eq!(zmq_errno(), ENOTSOCK);
+            assert_eq!(zmq_ctx_term(context), 0);
+        }
+    }
+
+    #[test]
+    fn test_truncated_recv() {
+        unsafe {
+            let context = zmq_ctx_new();
+            let pull = zmq_socket(context, ZMQ_PULL);
+            let push = zmq_socket(context, ZMQ_PUSH);
+            let addr = b"inproc://work\0".as_ptr() as *const c_char;
+            assert_eq!(zmq_bind(pull, addr), 0);
+            assert_eq!(zmq_connect(push, addr), 0);
+
+            let mut buf = [0_u8; 4];
+            let buf_ptr = buf.as_mut_ptr() as *mut c_void;
+            assert_eq!(zmq_recv(pull, buf_ptr, buf.len(), ZMQ_DONTWAIT), -1);
+            assert_eq!(zmq_errno(), EAGAIN);
+
+            send(push, b"too long", 0);
+            assert_eq!(zmq_recv(pull, buf_ptr, buf.len(), 0), 8);
+            assert_eq!(&buf, b"too ");
+
+            assert_eq!(zmq_recv(push, buf_ptr, buf.len(), 0), -1);
+            assert_eq!(zmq_errno(), ENOTSUP);
+
+            zmq_close(push);
+            zmq_close(pull);
+            zmq_ctx_term(context);
+        }
+    }
+
+    #[test]
+    fn test_dontwait_send_and_linger() {
+        unsafe {
+            let context = zmq_ctx_new();
+            let push = zmq_socket(context, ZMQ_PUSH);
+            let addr = b"inproc://work\0".as_ptr() as *const c_char;
+            assert_eq!(zmq_bind(push, addr), 0);
+
+            // Without a peer, the message can't be sent without waiting, and
+            // its last part is left to be sent again.
+            send(push, b"first", ZMQ_SNDMORE);
+            let part = b"second";
+            let part_ptr = part.as_ptr() as *const c_void;
+            assert_eq!(zmq_send(push, part_ptr, part.len(), ZMQ_DONTWAIT), -1);
+            assert_eq!(zmq_errno(), EAGAIN);
+
+            let linger: c_int = 100;
+            let linger_ptr = &linger as *const c_int as *const c_void;
+            let int_len = std::mem::size_of::<c_int>();
+            assert_eq!(zmq_setsockopt(push, ZMQ_LINGER, linger_ptr, int_len), 0);
+            let mut value: c_int = 0;
+            let mut value_len = int_len;
+            let value_ptr = &mut value as *mut c_int as *mut c_void;
+            assert_eq!(
+                zmq_getsockopt(push, ZMQ_LINGER, value_ptr, &mut value_len),
+                0
+            );
+            assert_eq!(value, 100);
+
+            let pull = zmq_socket(context, ZMQ_PULL);
+            assert_eq!(zmq_connect(pull, addr), 0);
+            send(push, b"second", 0);
+            assert_eq!(zmq_close(push), 0);
+            assert_eq!(recv(pull), (b"first".to_vec(), true));
+            assert_eq!(recv(pull), (b"second".to_vec(), false));
+
+            zmq_close(pull);
+            zmq_ctx_term(context);
+        }
+    }
+}
@@ -0,0 +1,18 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// Runs requests from a few clients through a broker to two workers.
+//
+//     cargo run -p oxzmq-examples --bin broker
+
+use futures::executor::block_on;
+
+fn main() {
+    let replies = block_on(oxzmq_examples::broker::run(3, 2)).expect("broker failed");
+    for (client, replies) in replies.iter().enumerate() {
+        for reply in replies {
+            println!("client {} got: {}", client, reply);
+        }
+    }
+}
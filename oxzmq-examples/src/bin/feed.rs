@@ -0,0 +1,19 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// Publishes a few updates to subscribers of different topics.
+//
+//     cargo run -p oxzmq-examples --bin feed
+
+use futures::executor::block_on;
+
+fn main() {
+    let topics = ["weather", "sports"];
+    let received = block_on(oxzmq_examples::feed::run(&topics)).expect("feed failed");
+    for (topic, updates) in topics.iter().zip(received) {
+        for update in updates {
+            println!("{} subscriber got: {}", topic, update);
+        }
+    }
+}
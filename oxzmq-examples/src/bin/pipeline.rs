@@ -0,0 +1,19 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// Spreads tasks over a few workers and collects the results.
+//
+//     cargo run -p oxzmq-examples --bin pipeline
+
+use futures::executor::block_on;
+
+fn main() {
+    let numbers: Vec<u32> = (1..=100).collect();
+    let (sum, done) =
+        block_on(oxzmq_examples::pipeline::run(&numbers, 4)).expect("pipeline failed");
+    println!("sum of squares: {}", sum);
+    for (worker, done) in done.iter().enumerate() {
+        println!("worker {} did {} tasks", worker, done);
+    }
+}
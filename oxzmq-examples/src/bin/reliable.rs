@@ -0,0 +1,15 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// Retries a request until a server survives long enough to answer it.
+//
+//     cargo run -p oxzmq-examples --bin reliable
+
+use futures::executor::block_on;
+
+fn main() {
+    let (reply, attempts) =
+        block_on(oxzmq_examples::reliable::run("hello", 1)).expect("no server replied");
+    println!("got {:?} after {} attempts", reply, attempts);
+}
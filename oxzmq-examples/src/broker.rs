@@ -0,0 +1,101 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! A broker between REQ clients and REP workers: a ROUTER takes the requests
+//! and a DEALER hands them out to the workers, and the replies find their way
+//! back along the routing IDs that the ROUTER put in front of them.
+
+use crate::LocalPeer;
+use futures::{
+    future::{self, Either},
+    join,
+};
+use oxzmq_zmtp::{Dealer, Message, Rep, Req, Router, SocketError, SocketType};
+
+/// Has each of `clients` send `requests` requests through the broker to two
+/// workers, which reply with the request in upper case. Returns the replies
+/// each client got.
+pub async fn run(clients: usize, requests: usize) -> Result<Vec<Vec<String>>, SocketError> {
+    let mut frontend = Router::new();
+    let mut backend = Dealer::new();
+
+    let mut reqs = Vec::new();
+    for _ in 0..clients {
+        let (client_end, broker_end) = LocalPeer::pair(SocketType::Req, SocketType::Router);
+        let mut req = Req::new();
+        req.attach(client_end)?;
+        frontend.attach(broker_end)?;
+        reqs.push(req);
+    }
+
+    let mut reps = Vec::new();
+    for _ in 0..2 {
+        let (broker_end, worker_end) = LocalPeer::pair(SocketType::Dealer, SocketType::Rep);
+        let mut rep = Rep::new();
+        rep.attach(worker_end)?;
+        backend.attach(broker_end)?;
+        reps.push(rep);
+    }
+
+    let total = clients * requests;
+    let broker = proxy(frontend, backend, 2 * total);
+    let clients = future::try_join_all(
+        reqs.iter_mut()
+            .enumerate()
+            .map(|(id, req)| client(req, id, requests)),
+    );
+    let workers = future::try_join_all(reps.iter_mut().map(worker));
+    let (broker, clients, _) = join!(broker, clients, workers);
+    broker?;
+    clients
+}
+
+// Passes `count` messages between the two sides, whichever has one ready.
+// The broker then shuts down, which tells the workers to stop.
+async fn proxy(
+    mut frontend: Router<LocalPeer>,
+    mut backend: Dealer<LocalPeer>,
+    count: usize,
+) -> Result<(), SocketError> {
+    for _ in 0..count {
+        // The side that didn't have a message is dropped before it has read
+        // any of one, so nothing is lost.
+        let next = match future::select(Box::pin(frontend.recv()), Box::pin(backend.recv())).await {
+            Either::Left((request, _)) => Either::Left(request?),
+            Either::Right((reply, _)) => Either::Right(reply?),
+        };
+        match next {
+            Either::Left(request) => backend.send(request).await?,
+            Either::Right(reply) => frontend.send(reply).await?,
+        }
+    }
+    Ok(())
+}
+
+async fn client(
+    req: &mut Req<LocalPeer>,
+    id: usize,
+    requests: usize,
+) -> Result<Vec<String>, SocketError> {
+    let mut replies = Vec::new();
+    for n in 0..requests {
+        let request = format!("client {} request {}", id, n);
+        req.send(Message::from(request.into_bytes())).await?;
+        let reply = req.recv().await?;
+        replies.push(String::from_utf8_lossy(&reply.parts()[0]).into_owned());
+    }
+    Ok(replies)
+}
+
+// Workers reply until the broker goes away.
+async fn worker(rep: &mut Rep<LocalPeer>) -> Result<(), SocketError> {
+    loop {
+        let request = match rep.recv().await {
+            Ok(request) => request,
+            Err(_) => return Ok(()),
+        };
+        let reply = String::from_utf8_lossy(&request.parts()[0]).to_uppercase();
+        rep.send(Message::from(reply.into_bytes())).await?;
+    }
+}
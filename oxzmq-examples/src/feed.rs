@@ -0,0 +1,56 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! A PUB socket publishing updates on several topics, and SUB sockets that
+//! each only get the topics they subscribed to.
+
+use crate::LocalPeer;
+use oxzmq_zmtp::{Message, Pub, SocketError, SocketType, Sub};
+
+const UPDATES: [(&str, &str); 5] = [
+    ("weather", "sunny"),
+    ("sports", "2-1"),
+    ("weather", "rain later"),
+    ("traffic", "jam on the bridge"),
+    ("sports", "3-1"),
+];
+
+/// Publishes a few updates to one subscriber per topic in `topics`. Returns
+/// the updates each subscriber got, as `topic: update`.
+pub async fn run(topics: &[&str]) -> Result<Vec<Vec<String>>, SocketError> {
+    let mut publisher = Pub::new();
+    let mut subscribers = Vec::new();
+    for topic in topics {
+        let (pub_end, sub_end) = LocalPeer::pair(SocketType::Pub, SocketType::Sub);
+        let mut sub = Sub::new();
+        sub.attach(sub_end).await?;
+        sub.subscribe(topic.as_bytes()).await?;
+        publisher.attach(pub_end)?;
+        subscribers.push(sub);
+    }
+
+    // The topic goes in the first part, which is what subscriptions match.
+    for (topic, update) in UPDATES.iter() {
+        let msg = Message::from(vec![topic.as_bytes().to_vec(), update.as_bytes().to_vec()]);
+        publisher.send(msg).await?;
+    }
+
+    // Hanging up ends each subscriber's feed once it has read everything.
+    drop(publisher);
+
+    let mut received = Vec::new();
+    for sub in subscribers.iter_mut() {
+        let mut updates = Vec::new();
+        while let Ok(msg) = sub.recv().await {
+            let parts = msg.parts();
+            updates.push(format!(
+                "{}: {}",
+                String::from_utf8_lossy(&parts[0]),
+                String::from_utf8_lossy(&parts[1])
+            ));
+        }
+        received.push(updates);
+    }
+    Ok(received)
+}
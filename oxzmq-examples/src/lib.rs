@@ -0,0 +1,18 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Runnable examples of the usual messaging patterns, built only on the
+//! public API of `oxzmq-zmtp`. Each example is a function that the binaries
+//! in `src/bin` run and the tests in `tests` check, so the patterns are known
+//! to work with every release.
+//!
+//! The peers are connected in memory, so the examples run anywhere.
+
+pub use crate::local::LocalPeer;
+
+pub mod broker;
+pub mod feed;
+mod local;
+pub mod pipeline;
+pub mod reliable;
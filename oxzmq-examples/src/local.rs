@@ -0,0 +1,57 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use futures::{channel::mpsc, SinkExt, StreamExt};
+use oxzmq_zmtp::{Message, Peer, PeerError, SocketType};
+use std::task::{Context, Poll};
+
+// How many messages each direction holds before the sender has to wait.
+const CAPACITY: usize = 100;
+
+/// A peer connected to another one in the same process by a pair of
+/// channels, which is all the examples need.
+#[derive(Debug)]
+pub struct LocalPeer {
+    remote_socket_type: SocketType,
+    tx: mpsc::Sender<Message>,
+    rx: mpsc::Receiver<Message>,
+}
+
+impl LocalPeer {
+    /// Creates two connected ends, for sockets of type `a` and `b`
+    /// respectively.
+    pub fn pair(a: SocketType, b: SocketType) -> (LocalPeer, LocalPeer) {
+        let (a_tx, b_rx) = mpsc::channel(CAPACITY);
+        let (b_tx, a_rx) = mpsc::channel(CAPACITY);
+        let a_end = LocalPeer {
+            remote_socket_type: b,
+            tx: a_tx,
+            rx: a_rx,
+        };
+        let b_end = LocalPeer {
+            remote_socket_type: a,
+            tx: b_tx,
+            rx: b_rx,
+        };
+        (a_end, b_end)
+    }
+}
+
+impl Peer for LocalPeer {
+    fn remote_socket_type(&self) -> SocketType {
+        self.remote_socket_type
+    }
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), PeerError>> {
+        self.tx.poll_ready(cx).map_err(|_| PeerError::Disconnected)
+    }
+
+    async fn send_message(&mut self, msg: Message) -> Result<(), PeerError> {
+        self.tx.send(msg).await.map_err(|_| PeerError::Disconnected)
+    }
+
+    async fn recv_message(&mut self) -> Result<Message, PeerError> {
+        self.rx.next().await.ok_or(PeerError::Disconnected)
+    }
+}
@@ -0,0 +1,74 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! A ventilator PUSHes tasks to workers, which PULL them, do them, and PUSH
+//! the results on to a sink that PULLs them all in.
+
+use crate::LocalPeer;
+use futures::{future, join};
+use oxzmq_zmtp::{Message, Pull, Push, SocketError, SocketType};
+use std::convert::TryFrom;
+
+/// Squares each of `numbers` on one of `workers` workers, and returns the sum
+/// of the squares along with how many tasks each worker did.
+pub async fn run(numbers: &[u32], workers: usize) -> Result<(u64, Vec<usize>), SocketError> {
+    let mut ventilator = Push::new();
+    let mut sink = Pull::new();
+    let mut pipes = Vec::new();
+    for _ in 0..workers {
+        let (vent_end, task_end) = LocalPeer::pair(SocketType::Push, SocketType::Pull);
+        let (result_end, sink_end) = LocalPeer::pair(SocketType::Push, SocketType::Pull);
+        let mut tasks = Pull::new();
+        let mut results = Push::new();
+        ventilator.attach(vent_end)?;
+        tasks.attach(task_end)?;
+        results.attach(result_end)?;
+        sink.attach(sink_end)?;
+        pipes.push((tasks, results));
+    }
+
+    let ventilate = async move {
+        for n in numbers {
+            ventilator
+                .send(Message::from(n.to_be_bytes().to_vec()))
+                .await?;
+        }
+        // Workers stop once the ventilator hangs up.
+        Ok::<_, SocketError>(())
+    };
+    let work = future::try_join_all(
+        pipes
+            .into_iter()
+            .map(|(tasks, results)| worker(tasks, results)),
+    );
+    let collect = async {
+        let mut sum = 0;
+        for _ in numbers {
+            let result = sink.recv().await?;
+            sum += u64::from_be_bytes(<[u8; 8]>::try_from(&result.parts()[0][..]).unwrap());
+        }
+        Ok::<_, SocketError>(sum)
+    };
+
+    let (ventilated, done, sum) = join!(ventilate, work, collect);
+    ventilated?;
+    Ok((sum?, done?))
+}
+
+// Returns how many tasks the worker did.
+async fn worker(
+    mut tasks: Pull<LocalPeer>,
+    mut results: Push<LocalPeer>,
+) -> Result<usize, SocketError> {
+    let mut done = 0;
+    while let Ok(task) = tasks.recv().await {
+        let n = u32::from_be_bytes(<[u8; 4]>::try_from(&task.parts()[0][..]).unwrap());
+        let square = u64::from(n) * u64::from(n);
+        results
+            .send(Message::from(square.to_be_bytes().to_vec()))
+            .await?;
+        done += 1;
+    }
+    Ok(done)
+}
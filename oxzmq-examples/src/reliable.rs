@@ -0,0 +1,69 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Reliable request-reply, in the style of the guide's "Lazy Pirate": when a
+//! server dies before replying, the client sends the request again, and it
+//! goes to the next server.
+
+use crate::LocalPeer;
+use futures::join;
+use oxzmq_zmtp::{Message, Rep, Req, SocketError, SocketType};
+
+// How many times a request is sent before giving up.
+const ATTEMPTS: usize = 3;
+
+/// Sends `request` to a pool of servers in which the first `crashing` die
+/// as soon as they get a request. Returns the reply and the number of
+/// attempts it took.
+pub async fn run(request: &str, crashing: usize) -> Result<(String, usize), SocketError> {
+    let mut client = Req::new();
+    let mut servers = Vec::new();
+    for _ in 0..=crashing {
+        let (client_end, server_end) = LocalPeer::pair(SocketType::Req, SocketType::Rep);
+        client.attach(client_end)?;
+        let mut rep = Rep::new();
+        rep.attach(server_end)?;
+        servers.push(rep);
+    }
+
+    let healthy = servers.pop().unwrap();
+    let crash = async move {
+        for mut server in servers {
+            // Dropping the server with the request unanswered looks just like
+            // a crash to the client. Servers the client never gets to see the
+            // client hang up instead.
+            let _ = server.recv().await;
+        }
+    };
+    let serve = async move {
+        let mut healthy = healthy;
+        let request = healthy.recv().await?;
+        healthy.send(request).await?;
+        // Keep the connection open until the client is done with it.
+        let _ = healthy.recv().await;
+        Ok::<_, SocketError>(())
+    };
+    let request = async move {
+        let mut result = Err(SocketError::NoPeers);
+        for attempt in 1..=ATTEMPTS {
+            client
+                .send(Message::from(request.as_bytes().to_vec()))
+                .await?;
+            match client.recv().await {
+                Ok(reply) => {
+                    let reply = String::from_utf8_lossy(&reply.parts()[0]).into_owned();
+                    result = Ok((reply, attempt));
+                    break;
+                }
+                // The server went away, so try again with the next one.
+                Err(err) => result = Err(err),
+            }
+        }
+        result
+    };
+
+    let (_, served, result) = join!(crash, serve, request);
+    served?;
+    result
+}
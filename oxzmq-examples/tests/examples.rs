@@ -0,0 +1,46 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use futures::executor::block_on;
+use oxzmq_examples::{broker, feed, pipeline, reliable};
+
+#[test]
+fn test_broker() {
+    let replies = block_on(broker::run(3, 2)).unwrap();
+    assert_eq!(replies.len(), 3);
+    for (client, replies) in replies.iter().enumerate() {
+        let expected: Vec<_> = (0..2)
+            .map(|n| format!("CLIENT {} REQUEST {}", client, n))
+            .collect();
+        assert_eq!(replies, &expected);
+    }
+}
+
+#[test]
+fn test_feed() {
+    let received = block_on(feed::run(&["weather", "traffic"])).unwrap();
+    assert_eq!(
+        received,
+        vec![
+            vec!["weather: sunny", "weather: rain later"],
+            vec!["traffic: jam on the bridge"],
+        ]
+    );
+}
+
+#[test]
+fn test_pipeline() {
+    let numbers: Vec<u32> = (1..=10).collect();
+    let (sum, done) = block_on(pipeline::run(&numbers, 3)).unwrap();
+    assert_eq!(sum, 385);
+    assert_eq!(done.iter().sum::<usize>(), 10);
+    assert!(done.iter().all(|&n| n > 0));
+}
+
+#[test]
+fn test_reliable() {
+    let (reply, attempts) = block_on(reliable::run("hello", 1)).unwrap();
+    assert_eq!(reply, "hello");
+    assert_eq!(attempts, 2);
+}
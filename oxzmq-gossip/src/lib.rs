@@ -0,0 +1,104 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The gossip discovery protocol from CZMQ (zgossip): nodes connect to a few
+//! seed nodes and share a store of key/value tuples with everyone they're
+//! connected to, so that a tuple published anywhere reaches the whole mesh.
+//! Nodes usually publish the endpoints they're bound to, and connect to the
+//! endpoints they learn about.
+//!
+//! More info: https://github.com/zeromq/czmq/blob/master/src/zgossip_msg.xml
+//!
+//! Everything runs over the ROUTER and DEALER sockets of `oxzmq-zmtp`, so it
+//! works over any transport they do.
+
+use oxzmq_zmtp::SocketError;
+
+pub use crate::node::Node;
+
+mod node;
+mod protocol;
+
+#[derive(thiserror::Error, Debug)]
+pub enum GossipError {
+    #[error("{0}")]
+    Socket(#[from] SocketError),
+
+    #[error("protocol error: {0}")]
+    Protocol(&'static str),
+
+    #[error("keys can't be longer than 255 bytes")]
+    KeyTooLong,
+
+    #[error("values can't be longer than 2^32 - 1 bytes")]
+    ValueTooLong,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use oxzmq_zmtp::{ConnectionOptions, InprocContext, InprocPeer, SocketType};
+
+    // Connects `node` to `seed`.
+    async fn connect(
+        seed: &mut Node<InprocPeer>,
+        node: &mut Node<InprocPeer>,
+        context: &InprocContext,
+        name: &str,
+    ) {
+        let endpoint = format!("inproc://{}", name).parse().unwrap();
+        let mut listener = context.bind(&endpoint, SocketType::Router).unwrap();
+        let peer = context
+            .connect(&endpoint, SocketType::Dealer, &ConnectionOptions::new())
+            .unwrap();
+        seed.attach(listener.accept().await.unwrap()).unwrap();
+        node.connect(peer).await.unwrap();
+    }
+
+    #[test]
+    fn test_discovery() {
+        block_on(async {
+            let context = InprocContext::new();
+            let mut seed = Node::new();
+            let mut a = Node::new();
+            let mut b = Node::new();
+            connect(&mut seed, &mut a, &context, "a").await;
+            connect(&mut seed, &mut b, &context, "b").await;
+
+            // Both HELLOs.
+            assert_eq!(seed.step().await.unwrap(), None);
+            assert_eq!(seed.step().await.unwrap(), None);
+
+            // A publishes through the seed to B.
+            a.publish("a", "tcp://10.0.0.1:5670").await.unwrap();
+            let learned = Some(("a".to_string(), "tcp://10.0.0.1:5670".to_string()));
+            assert_eq!(seed.step().await.unwrap(), learned);
+            assert_eq!(b.step().await.unwrap(), learned);
+            assert_eq!(b.get("a"), Some("tcp://10.0.0.1:5670"));
+
+            // Nodes that join later are told everything the seed knows.
+            let mut c = Node::new();
+            connect(&mut seed, &mut c, &context, "c").await;
+            assert_eq!(seed.step().await.unwrap(), None);
+            assert_eq!(c.step().await.unwrap(), learned);
+
+            // Changes spread the same way.
+            a.publish("a", "tcp://10.0.0.1:5671").await.unwrap();
+            let changed = Some(("a".to_string(), "tcp://10.0.0.1:5671".to_string()));
+            assert_eq!(seed.step().await.unwrap(), changed);
+            assert_eq!(b.step().await.unwrap(), changed);
+            assert_eq!(c.step().await.unwrap(), changed);
+
+            // Tuples with a TTL expire.
+            let mut d: Node<InprocPeer> = Node::new();
+            d.set_ttl(std::time::Duration::from_secs(1));
+            d.publish("d", "tcp://10.0.0.4:5670").await.unwrap();
+            assert!(d.expire().is_empty());
+            std::thread::sleep(std::time::Duration::from_millis(1100));
+            assert_eq!(d.expire(), vec!["d".to_string()]);
+            assert_eq!(d.get("d"), None);
+        });
+    }
+}
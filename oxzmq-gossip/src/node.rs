@@ -0,0 +1,326 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{protocol::GossipMessage, GossipError};
+use futures::future::{self, Either};
+use oxzmq_zmtp::{Dealer, Message, Peer, Router, SocketError};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    time::{Duration, Instant},
+};
+
+/// One member of a gossip mesh. Other nodes connect to it as clients, and it
+/// connects to other nodes (usually a few well-known seeds) as remotes.
+/// Tuples that are new or have changed are passed on to every client and
+/// remote except the one they came from, so they spread through the whole
+/// mesh without looping.
+///
+/// The node only does something when the application calls `step`, which
+/// handles one message. Tuples with a TTL are only dropped when the
+/// application calls `expire`.
+#[derive(Debug)]
+pub struct Node<P> {
+    server: Router<P>,
+    // The routing IDs of the clients that have said HELLO.
+    clients: HashSet<Vec<u8>>,
+    remotes: Vec<Dealer<P>>,
+    tuples: HashMap<String, Tuple>,
+    // The TTL of our own tuples, in seconds. Zero means they never expire.
+    ttl: u32,
+}
+
+#[derive(Debug)]
+struct Tuple {
+    value: String,
+    ttl: u32,
+    expires: Option<Instant>,
+}
+
+impl Tuple {
+    fn new(value: String, ttl: u32) -> Tuple {
+        let expires = match ttl {
+            0 => None,
+            ttl => Some(Instant::now() + Duration::from_secs(ttl.into())),
+        };
+        Tuple {
+            value,
+            ttl,
+            expires,
+        }
+    }
+}
+
+// Where a message came from, so it isn't sent back there.
+#[derive(Debug)]
+enum Source {
+    Local,
+    Client(Vec<u8>),
+    Remote(usize),
+}
+
+impl<P: Peer> Node<P> {
+    pub fn new() -> Node<P> {
+        Node {
+            server: Router::new(),
+            clients: HashSet::new(),
+            remotes: Vec::new(),
+            tuples: HashMap::new(),
+            ttl: 0,
+        }
+    }
+
+    /// Sets how long the tuples we publish live on other nodes if we stop
+    /// republishing them. Rounded down to whole seconds; zero, the default,
+    /// means they never expire.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = u32::try_from(ttl.as_secs()).unwrap_or(u32::MAX);
+    }
+
+    /// Attaches a node that connected to us.
+    pub fn attach(&mut self, peer: P) -> Result<Vec<u8>, GossipError> {
+        Ok(self.server.attach(peer)?)
+    }
+
+    /// Attaches a connection to another node, says HELLO, and sends it every
+    /// tuple we know.
+    pub async fn connect(&mut self, peer: P) -> Result<(), GossipError> {
+        let mut remote = Dealer::new();
+        remote.attach(peer)?;
+        self.remotes.push(remote);
+        self.hello(self.remotes.len() - 1).await
+    }
+
+    /// The value of `key`, if any node has published it.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.tuples.get(key).map(|tuple| tuple.value.as_str())
+    }
+
+    /// Every tuple we know, in no particular order.
+    pub fn tuples(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.tuples
+            .iter()
+            .map(|(key, tuple)| (key.as_str(), tuple.value.as_str()))
+    }
+
+    /// Stores a tuple and sends it to every node we're connected to.
+    pub async fn publish(&mut self, key: &str, value: &str) -> Result<(), GossipError> {
+        let (key, value, ttl) = (key.to_string(), value.to_string(), self.ttl);
+        self.accept(key, value, ttl, Source::Local).await?;
+        Ok(())
+    }
+
+    /// Waits for one message from a client or remote and handles it. Returns
+    /// the tuple it carried if it was new or changed.
+    pub async fn step(&mut self) -> Result<Option<(String, String)>, GossipError> {
+        let (source, msg) = self.recv().await?;
+        let frame = match &source {
+            // The routing ID comes first.
+            Source::Client(_) => msg.get(1),
+            _ => msg.get(0),
+        };
+        let parsed = frame.map_or(
+            Err(GossipError::Protocol("empty message")),
+            GossipMessage::parse,
+        );
+
+        match source {
+            Source::Client(id) => match parsed {
+                Ok(GossipMessage::Hello) => {
+                    self.clients.insert(id.clone());
+                    let tuples = self.publications();
+                    for publish in tuples {
+                        self.send_to_client(&id, &publish).await?;
+                    }
+                    Ok(None)
+                }
+                // Clients have to say HELLO before anything else.
+                Ok(_) if !self.clients.contains(&id) => {
+                    self.send_to_client(&id, &GossipMessage::Invalid).await?;
+                    Ok(None)
+                }
+                Ok(GossipMessage::Publish { key, value, ttl }) => {
+                    self.accept(key, value, ttl, Source::Client(id)).await
+                }
+                Ok(GossipMessage::Ping) => {
+                    self.send_to_client(&id, &GossipMessage::Pong).await?;
+                    Ok(None)
+                }
+                Ok(_) => Ok(None),
+                Err(_) => {
+                    self.clients.remove(&id);
+                    self.send_to_client(&id, &GossipMessage::Invalid).await?;
+                    Ok(None)
+                }
+            },
+            Source::Remote(idx) => match parsed {
+                Ok(GossipMessage::Publish { key, value, ttl }) => {
+                    self.accept(key, value, ttl, Source::Remote(idx)).await
+                }
+                Ok(GossipMessage::Ping) => {
+                    self.send_to_remote(idx, &GossipMessage::Pong).await?;
+                    Ok(None)
+                }
+                // The remote has forgotten us, so start over.
+                Ok(GossipMessage::Invalid) => {
+                    self.hello(idx).await?;
+                    Ok(None)
+                }
+                // Remotes that send us garbage are ignored rather than
+                // dropped, since we'd only connect to them again.
+                _ => Ok(None),
+            },
+            Source::Local => unreachable!(),
+        }
+    }
+
+    /// Sends a PING to every remote, so they see we're still here.
+    pub async fn ping(&mut self) -> Result<(), GossipError> {
+        for idx in 0..self.remotes.len() {
+            self.send_to_remote(idx, &GossipMessage::Ping).await?;
+        }
+        Ok(())
+    }
+
+    /// Drops the tuples whose TTL has run out, and returns their keys.
+    pub fn expire(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .tuples
+            .iter()
+            .filter(|(_, tuple)| tuple.expires.is_some_and(|expires| expires <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.tuples.remove(key);
+        }
+        expired
+    }
+
+    // Waits on the clients and the remotes at once. A socket with no peers
+    // fails straight away, so only the ones that have some are waited on.
+    async fn recv(&mut self) -> Result<(Source, Message), GossipError> {
+        let has_clients = self.server.routing_ids().next().is_some();
+        if self.remotes.is_empty() {
+            return self.recv_client().await;
+        } else if !has_clients {
+            return self.recv_remote().await;
+        }
+
+        let inbound = Box::pin(self.server.recv());
+        let outbound = future::select_all(
+            self.remotes
+                .iter_mut()
+                .map(|remote| Box::pin(remote.recv())),
+        );
+        match future::select(inbound, outbound).await {
+            Either::Left((msg, _)) => {
+                let msg = msg?;
+                Ok((Self::client_source(&msg), msg))
+            }
+            Either::Right(((msg, idx, _), _)) => Ok((Source::Remote(idx), msg?)),
+        }
+    }
+
+    async fn recv_client(&mut self) -> Result<(Source, Message), GossipError> {
+        let msg = self.server.recv().await?;
+        Ok((Self::client_source(&msg), msg))
+    }
+
+    async fn recv_remote(&mut self) -> Result<(Source, Message), GossipError> {
+        let recvs = self
+            .remotes
+            .iter_mut()
+            .map(|remote| Box::pin(remote.recv()));
+        let (msg, idx, _) = future::select_all(recvs).await;
+        Ok((Source::Remote(idx), msg?))
+    }
+
+    fn client_source(msg: &Message) -> Source {
+        Source::Client(msg.get(0).unwrap_or_default().to_vec())
+    }
+
+    // Stores a tuple, and passes it on if it's new or has changed.
+    async fn accept(
+        &mut self,
+        key: String,
+        value: String,
+        ttl: u32,
+        source: Source,
+    ) -> Result<Option<(String, String)>, GossipError> {
+        let changed = self
+            .tuples
+            .get(&key)
+            .is_none_or(|tuple| tuple.value != value);
+        self.tuples
+            .insert(key.clone(), Tuple::new(value.clone(), ttl));
+        if !changed {
+            return Ok(None);
+        }
+
+        let publish = GossipMessage::Publish {
+            key: key.clone(),
+            value: value.clone(),
+            ttl,
+        };
+        for idx in 0..self.remotes.len() {
+            if !matches!(source, Source::Remote(from) if from == idx) {
+                self.send_to_remote(idx, &publish).await?;
+            }
+        }
+        let clients: Vec<_> = self.clients.iter().cloned().collect();
+        for id in clients {
+            if !matches!(&source, Source::Client(from) if *from == id) {
+                self.send_to_client(&id, &publish).await?;
+            }
+        }
+
+        match source {
+            Source::Local => Ok(None),
+            _ => Ok(Some((key, value))),
+        }
+    }
+
+    async fn hello(&mut self, idx: usize) -> Result<(), GossipError> {
+        self.send_to_remote(idx, &GossipMessage::Hello).await?;
+        for publish in self.publications() {
+            self.send_to_remote(idx, &publish).await?;
+        }
+        Ok(())
+    }
+
+    fn publications(&self) -> Vec<GossipMessage> {
+        self.tuples
+            .iter()
+            .map(|(key, tuple)| GossipMessage::Publish {
+                key: key.clone(),
+                value: tuple.value.clone(),
+                ttl: tuple.ttl,
+            })
+            .collect()
+    }
+
+    async fn send_to_remote(&mut self, idx: usize, msg: &GossipMessage) -> Result<(), GossipError> {
+        let frame = msg.to_frame()?;
+        Ok(self.remotes[idx].send(Message::from(frame)).await?)
+    }
+
+    // Clients that have gone away are forgotten.
+    async fn send_to_client(&mut self, id: &[u8], msg: &GossipMessage) -> Result<(), GossipError> {
+        let msg = Message::from(vec![id.to_vec(), msg.to_frame()?]);
+        match self.server.send(msg).await {
+            Err(SocketError::HostUnreachable(_)) => {
+                self.clients.remove(id);
+                Ok(())
+            }
+            result => Ok(result?),
+        }
+    }
+}
+
+impl<P: Peer> Default for Node<P> {
+    fn default() -> Node<P> {
+        Node::new()
+    }
+}
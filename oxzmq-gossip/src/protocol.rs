@@ -0,0 +1,160 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::GossipError;
+use std::convert::TryFrom;
+
+// More info: https://github.com/zeromq/czmq/blob/master/src/zgossip_msg.xml
+//
+// Every message is a single frame: a two-byte signature, the command, the
+// protocol version, and then the command's fields. Numbers are big-endian.
+const SIGNATURE: [u8; 2] = [0xAA, 0xA0];
+const VERSION: u8 = 1;
+
+const HELLO: u8 = 1;
+const PUBLISH: u8 = 3;
+const PING: u8 = 4;
+const PONG: u8 = 5;
+const INVALID: u8 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum GossipMessage {
+    Hello,
+    Publish {
+        key: String,
+        value: String,
+        ttl: u32,
+    },
+    Ping,
+    Pong,
+    Invalid,
+}
+
+impl GossipMessage {
+    pub(crate) fn parse(frame: &[u8]) -> Result<GossipMessage, GossipError> {
+        let mut reader = Reader(frame);
+        if reader.take(2)? != SIGNATURE {
+            return Err(GossipError::Protocol("wrong signature"));
+        }
+        let command = reader.take(1)?[0];
+        if reader.take(1)?[0] != VERSION {
+            return Err(GossipError::Protocol("unsupported version"));
+        }
+
+        let msg = match command {
+            HELLO => GossipMessage::Hello,
+            PUBLISH => {
+                let key_len = reader.take(1)?[0] as usize;
+                let key = reader.string(key_len)?;
+                let value_len = reader.number()? as usize;
+                let value = reader.string(value_len)?;
+                let ttl = reader.number()?;
+                GossipMessage::Publish { key, value, ttl }
+            }
+            PING => GossipMessage::Ping,
+            PONG => GossipMessage::Pong,
+            INVALID => GossipMessage::Invalid,
+            _ => return Err(GossipError::Protocol("unknown command")),
+        };
+        if !reader.0.is_empty() {
+            return Err(GossipError::Protocol("trailing bytes"));
+        }
+        Ok(msg)
+    }
+
+    pub(crate) fn to_frame(&self) -> Result<Vec<u8>, GossipError> {
+        let command = match self {
+            GossipMessage::Hello => HELLO,
+            GossipMessage::Publish { .. } => PUBLISH,
+            GossipMessage::Ping => PING,
+            GossipMessage::Pong => PONG,
+            GossipMessage::Invalid => INVALID,
+        };
+        let mut frame = SIGNATURE.to_vec();
+        frame.push(command);
+        frame.push(VERSION);
+
+        if let GossipMessage::Publish { key, value, ttl } = self {
+            let key_len = u8::try_from(key.len()).map_err(|_| GossipError::KeyTooLong)?;
+            let value_len = u32::try_from(value.len()).map_err(|_| GossipError::ValueTooLong)?;
+            frame.push(key_len);
+            frame.extend_from_slice(key.as_bytes());
+            frame.extend_from_slice(&value_len.to_be_bytes());
+            frame.extend_from_slice(value.as_bytes());
+            frame.extend_from_slice(&ttl.to_be_bytes());
+        }
+        Ok(frame)
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], GossipError> {
+        if self.0.len() < len {
+            return Err(GossipError::Protocol("message too short"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn number(&mut self) -> Result<u32, GossipError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, GossipError> {
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| GossipError::Protocol("string isn't UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let msgs = vec![
+            GossipMessage::Hello,
+            GossipMessage::Publish {
+                key: "node-a".to_string(),
+                value: "tcp://127.0.0.1:5670".to_string(),
+                ttl: 30,
+            },
+            GossipMessage::Ping,
+            GossipMessage::Pong,
+            GossipMessage::Invalid,
+        ];
+        for msg in msgs {
+            let frame = msg.to_frame().unwrap();
+            assert_eq!(GossipMessage::parse(&frame).unwrap(), msg);
+        }
+
+        assert_eq!(
+            GossipMessage::Hello.to_frame().unwrap(),
+            vec![0xAA, 0xA0, HELLO, VERSION]
+        );
+
+        // Truncated messages are refused rather than read past the end.
+        let frame = GossipMessage::Publish {
+            key: "k".to_string(),
+            value: "v".to_string(),
+            ttl: 0,
+        }
+        .to_frame()
+        .unwrap();
+        assert!(GossipMessage::parse(&frame[..frame.len() - 1]).is_err());
+
+        let key = "k".repeat(256);
+        let publish = GossipMessage::Publish {
+            key,
+            value: String::new(),
+            ttl: 0,
+        };
+        assert!(matches!(publish.to_frame(), Err(GossipError::KeyTooLong)));
+    }
+}
@@ -0,0 +1,276 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    protocol::{self, WorkerCommand, CLIENT_HEADER, WORKER_HEADER},
+    MdpError,
+};
+use oxzmq_zmtp::{Message, Peer, Router, SocketError};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+// Services whose names start with this are answered by the broker itself.
+// More info: https://rfc.zeromq.org/spec/8/
+const MMI_PREFIX: &[u8] = b"mmi.";
+const MMI_SERVICE: &[u8] = b"mmi.service";
+
+/// Passes requests from clients to workers that offer the service they're
+/// for, and the replies back. Requests for a service with no idle worker wait
+/// until one is ready. Clients and workers can share the broker's socket or
+/// be attached separately; the header on each message says which one sent
+/// it.
+///
+/// The broker only does something when the application calls `step`, which
+/// handles one message, and `heartbeat`, which should be called regularly to
+/// check on the workers.
+#[derive(Debug)]
+pub struct Broker<P> {
+    socket: Router<P>,
+    services: HashMap<Vec<u8>, Service>,
+    workers: HashMap<Vec<u8>, WorkerState>,
+}
+
+#[derive(Debug, Default)]
+struct Service {
+    // Requests waiting for a worker, along with the clients that sent them.
+    requests: VecDeque<(Vec<u8>, Message)>,
+    // Workers waiting for a request, longest waiting first.
+    idle: VecDeque<Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct WorkerState {
+    service: Vec<u8>,
+    last_heard: Instant,
+}
+
+impl<P: Peer> Broker<P> {
+    pub fn new() -> Broker<P> {
+        Broker {
+            socket: Router::new(),
+            services: HashMap::new(),
+            workers: HashMap::new(),
+        }
+    }
+
+    /// Attaches a client or worker, and returns its routing ID.
+    pub fn attach(&mut self, peer: P) -> Result<Vec<u8>, MdpError> {
+        Ok(self.socket.attach(peer)?)
+    }
+
+    /// How many workers offer `service`, busy or not.
+    pub fn workers(&self, service: &[u8]) -> usize {
+        self.workers
+            .values()
+            .filter(|worker| worker.service == service)
+            .count()
+    }
+
+    /// Handles forever.
+    pub async fn run(&mut self) -> Result<(), MdpError> {
+        loop {
+            self.step().await?;
+        }
+    }
+
+    /// Waits for one message from a client or worker and handles it.
+    /// Clients and workers that break the protocol are ignored, or in the
+    /// case of workers told to disconnect, so only socket errors are
+    /// returned.
+    pub async fn step(&mut self) -> Result<(), MdpError> {
+        let mut msg = self.socket.recv().await?;
+        let sender = match msg.pop_front() {
+            Some(sender) => sender,
+            None => return Ok(()),
+        };
+
+        match msg.get(1) {
+            Some(header) if header == CLIENT_HEADER => {
+                if let Ok((service, body)) = protocol::parse_client(msg) {
+                    self.client_request(sender, service, body).await?;
+                }
+            }
+            Some(header) if header == WORKER_HEADER => match WorkerCommand::parse(msg) {
+                Ok(command) => self.worker_command(sender, command).await?,
+                Err(_) => self.disconnect(&sender).await?,
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Sends a heartbeat to every idle worker, and forgets the workers that
+    /// haven't been heard from within `expiry`.
+    pub async fn heartbeat(&mut self, expiry: Duration) -> Result<(), MdpError> {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .workers
+            .iter()
+            .filter(|(_, worker)| now.duration_since(worker.last_heard) > expiry)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            self.forget(&id);
+        }
+
+        let idle: Vec<_> = self
+            .services
+            .values()
+            .flat_map(|service| service.idle.iter().cloned())
+            .collect();
+        for id in idle {
+            self.send_to_worker(&id, WorkerCommand::Heartbeat).await?;
+        }
+        Ok(())
+    }
+
+    async fn client_request(
+        &mut self,
+        client: Vec<u8>,
+        service: Vec<u8>,
+        body: Message,
+    ) -> Result<(), MdpError> {
+        if service.starts_with(MMI_PREFIX) {
+            return self.mmi(client, service, body).await;
+        }
+
+        self.services
+            .entry(service.clone())
+            .or_default()
+            .requests
+            .push_back((client, body));
+        self.dispatch(&service).await
+    }
+
+    // Answers a request to one of the broker's own services. Only
+    // `mmi.service` is implemented, which says whether a service has any
+    // workers.
+    async fn mmi(
+        &mut self,
+        client: Vec<u8>,
+        service: Vec<u8>,
+        body: Message,
+    ) -> Result<(), MdpError> {
+        let code: &[u8] = if service != MMI_SERVICE {
+            b"501"
+        } else if body.get(0).map_or(0, |asked| self.workers(asked)) > 0 {
+            b"200"
+        } else {
+            b"404"
+        };
+        self.send_to_client(client, &service, Message::from(code))
+            .await
+    }
+
+    async fn worker_command(
+        &mut self,
+        id: Vec<u8>,
+        command: WorkerCommand,
+    ) -> Result<(), MdpError> {
+        let known = match self.workers.get_mut(&id) {
+            Some(worker) => {
+                worker.last_heard = Instant::now();
+                true
+            }
+            None => false,
+        };
+
+        match command {
+            WorkerCommand::Ready { service } if !known && !service.starts_with(MMI_PREFIX) => {
+                self.workers.insert(
+                    id.clone(),
+                    WorkerState {
+                        service: service.clone(),
+                        last_heard: Instant::now(),
+                    },
+                );
+                self.idle(id, &service).await
+            }
+            WorkerCommand::Reply { client, body } if known => {
+                let service = self.workers[&id].service.clone();
+                self.send_to_client(client, &service, body).await?;
+                self.idle(id, &service).await
+            }
+            WorkerCommand::Heartbeat if known => Ok(()),
+            WorkerCommand::Disconnect => {
+                self.forget(&id);
+                Ok(())
+            }
+            // Anything else means the worker and the broker don't agree on
+            // what state it's in, so it has to start over.
+            _ => self.disconnect(&id).await,
+        }
+    }
+
+    async fn idle(&mut self, id: Vec<u8>, service: &[u8]) -> Result<(), MdpError> {
+        self.services
+            .entry(service.to_vec())
+            .or_default()
+            .idle
+            .push_back(id);
+        self.dispatch(service).await
+    }
+
+    // Hands waiting requests for the service to idle workers, for as long as
+    // there are both.
+    async fn dispatch(&mut self, service: &[u8]) -> Result<(), MdpError> {
+        loop {
+            let entry = match self.services.get_mut(service) {
+                Some(entry) if !entry.requests.is_empty() && !entry.idle.is_empty() => entry,
+                _ => return Ok(()),
+            };
+            let worker = entry.idle.pop_front().unwrap();
+            let (client, body) = entry.requests.pop_front().unwrap();
+            self.send_to_worker(&worker, WorkerCommand::Request { client, body })
+                .await?;
+        }
+    }
+
+    async fn disconnect(&mut self, id: &[u8]) -> Result<(), MdpError> {
+        self.forget(id);
+        self.send_to_worker(id, WorkerCommand::Disconnect).await
+    }
+
+    fn forget(&mut self, id: &[u8]) {
+        if let Some(worker) = self.workers.remove(id) {
+            if let Some(service) = self.services.get_mut(&worker.service) {
+                service.idle.retain(|idle| idle != id);
+            }
+        }
+    }
+
+    async fn send_to_worker(&mut self, id: &[u8], command: WorkerCommand) -> Result<(), MdpError> {
+        let mut msg = command.to_message();
+        msg.push_front(id.to_vec());
+        self.send(msg).await
+    }
+
+    async fn send_to_client(
+        &mut self,
+        client: Vec<u8>,
+        service: &[u8],
+        body: Message,
+    ) -> Result<(), MdpError> {
+        let mut msg = protocol::client_message(service, body);
+        msg.push_front(client);
+        self.send(msg).await
+    }
+
+    // Messages for clients and workers that have gone away are dropped. A
+    // request lost that way has to be retried by its client.
+    async fn send(&mut self, msg: Message) -> Result<(), MdpError> {
+        match self.socket.send(msg).await {
+            Err(SocketError::HostUnreachable(_)) => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
+
+impl<P: Peer> Default for Broker<P> {
+    fn default() -> Broker<P> {
+        Broker::new()
+    }
+}
@@ -0,0 +1,48 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{protocol, MdpError};
+use oxzmq_zmtp::{Dealer, Message, Peer};
+
+/// Sends requests to services through a broker, one at a time, and waits for
+/// the replies.
+///
+/// Requests aren't retried. A client that wants to give up on a request after
+/// a while can wrap it in `Runtime::timeout`, but should then reconnect, since
+/// the reply may still arrive and would be taken for the next one's.
+#[derive(Debug)]
+pub struct Client<P> {
+    socket: Dealer<P>,
+}
+
+impl<P: Peer> Client<P> {
+    pub fn new() -> Client<P> {
+        Client {
+            socket: Dealer::new(),
+        }
+    }
+
+    /// Attaches the connection to the broker.
+    pub fn attach(&mut self, peer: P) -> Result<(), MdpError> {
+        Ok(self.socket.attach(peer)?)
+    }
+
+    /// Sends `body` to `service` and returns the reply.
+    pub async fn request(&mut self, service: &[u8], body: Message) -> Result<Message, MdpError> {
+        self.socket
+            .send(protocol::client_message(service, body))
+            .await?;
+        let (replied_for, reply) = protocol::parse_client(self.socket.recv().await?)?;
+        if replied_for != service {
+            return Err(MdpError::Protocol("reply from the wrong service"));
+        }
+        Ok(reply)
+    }
+}
+
+impl<P: Peer> Default for Client<P> {
+    fn default() -> Client<P> {
+        Client::new()
+    }
+}
@@ -0,0 +1,112 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The Majordomo Protocol (MDP/0.1): a broker that hands requests for named
+//! services to the workers that offer them, with the clients and workers to
+//! go with it. Everything runs over the ROUTER and DEALER sockets of
+//! `oxzmq-zmtp`, so it works over any transport they do.
+//!
+//! More info: https://rfc.zeromq.org/spec/7/
+//!
+//! MDP/0.2 (RFC 18), which lets workers stream partial replies, isn't
+//! supported.
+
+use oxzmq_zmtp::SocketError;
+
+pub use crate::{
+    broker::Broker,
+    client::Client,
+    worker::{Request, Worker},
+};
+
+mod broker;
+mod client;
+mod protocol;
+mod worker;
+
+#[derive(thiserror::Error, Debug)]
+pub enum MdpError {
+    #[error("{0}")]
+    Socket(#[from] SocketError),
+
+    #[error("protocol error: {0}")]
+    Protocol(&'static str),
+
+    #[error("the broker told us to disconnect")]
+    Disconnected,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, join};
+    use oxzmq_zmtp::{ConnectionOptions, InprocContext, InprocPeer, Message, SocketType};
+    use std::time::Duration;
+
+    // Connects a client or worker to the broker.
+    async fn connect(
+        broker: &mut Broker<InprocPeer>,
+        context: &InprocContext,
+        name: &str,
+    ) -> InprocPeer {
+        let endpoint = format!("inproc://{}", name).parse().unwrap();
+        let mut listener = context.bind(&endpoint, SocketType::Router).unwrap();
+        let peer = context
+            .connect(&endpoint, SocketType::Dealer, &ConnectionOptions::new())
+            .unwrap();
+        broker.attach(listener.accept().await.unwrap()).unwrap();
+        peer
+    }
+
+    #[test]
+    fn test_request_reply() {
+        block_on(async {
+            let context = InprocContext::new();
+            let mut broker = Broker::new();
+            let mut client = Client::new();
+            client
+                .attach(connect(&mut broker, &context, "client").await)
+                .unwrap();
+            let mut worker = Worker::new(b"echo");
+            worker
+                .attach(connect(&mut broker, &context, "worker").await)
+                .await
+                .unwrap();
+
+            // READY, the request, and the reply.
+            let brokered = async {
+                for _ in 0..3 {
+                    broker.step().await.unwrap();
+                }
+            };
+            let requested = client.request(b"echo", Message::from(&b"hello"[..]));
+            let served = async {
+                let request = worker.recv().await.unwrap();
+                let body = request.body().clone();
+                worker.reply(request, body).await.unwrap();
+            };
+            let ((), reply, ()) = join!(brokered, requested, served);
+            assert_eq!(reply.unwrap(), Message::from(&b"hello"[..]));
+
+            // The broker knows which services have workers.
+            let (_, found) = join!(
+                broker.step(),
+                client.request(b"mmi.service", Message::from(&b"echo"[..]))
+            );
+            assert_eq!(found.unwrap(), Message::from(&b"200"[..]));
+            let (_, missing) = join!(
+                broker.step(),
+                client.request(b"mmi.service", Message::from(&b"nope"[..]))
+            );
+            assert_eq!(missing.unwrap(), Message::from(&b"404"[..]));
+
+            // Workers that go quiet are forgotten.
+            assert_eq!(broker.workers(b"echo"), 1);
+            broker.heartbeat(Duration::from_secs(60)).await.unwrap();
+            assert_eq!(broker.workers(b"echo"), 1);
+            broker.heartbeat(Duration::ZERO).await.unwrap();
+            assert_eq!(broker.workers(b"echo"), 0);
+        });
+    }
+}
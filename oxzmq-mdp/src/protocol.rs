@@ -0,0 +1,153 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::MdpError;
+use oxzmq_zmtp::Message;
+
+// More info: https://rfc.zeromq.org/spec/7/
+//
+// Every MDP message starts with an empty delimiter and a header saying which
+// half of the protocol it belongs to. REQ clients get the delimiter for free,
+// but everything here talks over DEALER and ROUTER sockets, so it's added and
+// removed by hand.
+pub(crate) const CLIENT_HEADER: &[u8] = b"MDPC01";
+pub(crate) const WORKER_HEADER: &[u8] = b"MDPW01";
+
+const READY: u8 = 0x01;
+const REQUEST: u8 = 0x02;
+const REPLY: u8 = 0x03;
+const HEARTBEAT: u8 = 0x04;
+const DISCONNECT: u8 = 0x05;
+
+// What workers and the broker say to each other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum WorkerCommand {
+    Ready { service: Vec<u8> },
+    Request { client: Vec<u8>, body: Message },
+    Reply { client: Vec<u8>, body: Message },
+    Heartbeat,
+    Disconnect,
+}
+
+impl WorkerCommand {
+    // Parses a message from the broker or a worker, with the delimiter at the
+    // front.
+    pub(crate) fn parse(mut msg: Message) -> Result<WorkerCommand, MdpError> {
+        expect_header(&mut msg, WORKER_HEADER)?;
+        let command = match msg.pop_front().as_deref() {
+            Some(&[command]) => command,
+            _ => return Err(MdpError::Protocol("missing worker command")),
+        };
+
+        match command {
+            READY => {
+                let service = msg
+                    .pop_front()
+                    .ok_or(MdpError::Protocol("READY without a service"))?;
+                Ok(WorkerCommand::Ready { service })
+            }
+            REQUEST | REPLY => {
+                let client = msg
+                    .pop_front()
+                    .ok_or(MdpError::Protocol("missing client address"))?;
+                if msg.pop_front().is_none_or(|part| !part.is_empty()) {
+                    return Err(MdpError::Protocol("missing empty frame after address"));
+                }
+                Ok(if command == REQUEST {
+                    WorkerCommand::Request { client, body: msg }
+                } else {
+                    WorkerCommand::Reply { client, body: msg }
+                })
+            }
+            HEARTBEAT => Ok(WorkerCommand::Heartbeat),
+            DISCONNECT => Ok(WorkerCommand::Disconnect),
+            _ => Err(MdpError::Protocol("unknown worker command")),
+        }
+    }
+
+    // The message to send, with the delimiter at the front.
+    pub(crate) fn to_message(&self) -> Message {
+        let mut msg = Message::new();
+        let (command, rest) = match self {
+            WorkerCommand::Ready { service } => (READY, vec![service.clone()]),
+            WorkerCommand::Request { client, .. } => (REQUEST, vec![client.clone(), Vec::new()]),
+            WorkerCommand::Reply { client, .. } => (REPLY, vec![client.clone(), Vec::new()]),
+            WorkerCommand::Heartbeat => (HEARTBEAT, Vec::new()),
+            WorkerCommand::Disconnect => (DISCONNECT, Vec::new()),
+        };
+        msg.push_back(Vec::new());
+        msg.push_back(WORKER_HEADER.to_vec());
+        msg.push_back(vec![command]);
+        msg.extend(rest);
+        if let WorkerCommand::Request { body, .. } | WorkerCommand::Reply { body, .. } = self {
+            msg.extend(body.iter().map(<[u8]>::to_vec));
+        }
+        msg
+    }
+}
+
+// Requests from clients and the replies to them have the same layout: the
+// service, then the body.
+pub(crate) fn parse_client(mut msg: Message) -> Result<(Vec<u8>, Message), MdpError> {
+    expect_header(&mut msg, CLIENT_HEADER)?;
+    let service = msg
+        .pop_front()
+        .ok_or(MdpError::Protocol("missing service name"))?;
+    Ok((service, msg))
+}
+
+pub(crate) fn client_message(service: &[u8], body: Message) -> Message {
+    let mut msg = Message::new();
+    msg.push_back(Vec::new());
+    msg.push_back(CLIENT_HEADER.to_vec());
+    msg.push_back(service.to_vec());
+    msg.extend(body);
+    msg
+}
+
+fn expect_header(msg: &mut Message, header: &[u8]) -> Result<(), MdpError> {
+    if msg.pop_front().is_none_or(|part| !part.is_empty()) {
+        return Err(MdpError::Protocol("missing empty delimiter"));
+    }
+    match msg.pop_front() {
+        Some(part) if part == header => Ok(()),
+        _ => Err(MdpError::Protocol("wrong protocol header")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let commands = vec![
+            WorkerCommand::Ready {
+                service: b"echo".to_vec(),
+            },
+            WorkerCommand::Request {
+                client: b"client".to_vec(),
+                body: Message::from(vec![b"a".to_vec(), b"b".to_vec()]),
+            },
+            WorkerCommand::Reply {
+                client: b"client".to_vec(),
+                body: Message::new(),
+            },
+            WorkerCommand::Heartbeat,
+            WorkerCommand::Disconnect,
+        ];
+        for command in commands {
+            assert_eq!(WorkerCommand::parse(command.to_message()).unwrap(), command);
+        }
+
+        let msg = client_message(b"echo", Message::from(&b"hi"[..]));
+        let (service, body) = parse_client(msg).unwrap();
+        assert_eq!(service, b"echo");
+        assert_eq!(body, Message::from(&b"hi"[..]));
+
+        // A client header on the worker side is refused.
+        let msg = client_message(b"echo", Message::new());
+        assert!(WorkerCommand::parse(msg).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{protocol::WorkerCommand, MdpError};
+use oxzmq_zmtp::{Dealer, Message, Peer};
+use std::time::Instant;
+
+/// A request that a worker received, to be answered with `Worker::reply`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    client: Vec<u8>,
+    body: Message,
+}
+
+impl Request {
+    pub fn body(&self) -> &Message {
+        &self.body
+    }
+
+    pub fn into_body(self) -> Message {
+        self.body
+    }
+}
+
+/// Takes requests for a service from a broker and replies to them.
+///
+/// Heartbeats are sent whenever the application calls `heartbeat`, which it
+/// should do more often than the broker's expiry. The broker's own
+/// heartbeats only update `last_heard`; a worker that hasn't heard from the
+/// broker for too long should reconnect.
+#[derive(Debug)]
+pub struct Worker<P> {
+    socket: Dealer<P>,
+    service: Vec<u8>,
+    last_heard: Instant,
+}
+
+impl<P: Peer> Worker<P> {
+    pub fn new(service: &[u8]) -> Worker<P> {
+        Worker {
+            socket: Dealer::new(),
+            service: service.to_vec(),
+            last_heard: Instant::now(),
+        }
+    }
+
+    /// Attaches the connection to the broker and tells the broker which
+    /// service we offer.
+    pub async fn attach(&mut self, peer: P) -> Result<(), MdpError> {
+        self.socket.attach(peer)?;
+        let ready = WorkerCommand::Ready {
+            service: self.service.clone(),
+        };
+        self.socket.send(ready.to_message()).await?;
+        self.last_heard = Instant::now();
+        Ok(())
+    }
+
+    /// Waits for the next request. Fails with `MdpError::Disconnected` if the
+    /// broker tells us to go away.
+    pub async fn recv(&mut self) -> Result<Request, MdpError> {
+        loop {
+            let msg = self.socket.recv().await?;
+            self.last_heard = Instant::now();
+            match WorkerCommand::parse(msg)? {
+                WorkerCommand::Request { client, body } => return Ok(Request { client, body }),
+                WorkerCommand::Heartbeat => continue,
+                WorkerCommand::Disconnect => return Err(MdpError::Disconnected),
+                _ => return Err(MdpError::Protocol("unexpected command from broker")),
+            }
+        }
+    }
+
+    /// Sends the reply to `request` back to the client that made it.
+    pub async fn reply(&mut self, request: Request, body: Message) -> Result<(), MdpError> {
+        let reply = WorkerCommand::Reply {
+            client: request.client,
+            body,
+        };
+        Ok(self.socket.send(reply.to_message()).await?)
+    }
+
+    /// Tells the broker we're still here.
+    pub async fn heartbeat(&mut self) -> Result<(), MdpError> {
+        let heartbeat = WorkerCommand::Heartbeat.to_message();
+        Ok(self.socket.send(heartbeat).await?)
+    }
+
+    /// When the broker last sent us anything.
+    pub fn last_heard(&self) -> Instant {
+        self.last_heard
+    }
+
+    /// Tells the broker we're going away.
+    pub async fn disconnect(mut self) -> Result<(), MdpError> {
+        let disconnect = WorkerCommand::Disconnect.to_message();
+        self.socket.send(disconnect).await?;
+        self.socket.close().await;
+        Ok(())
+    }
+}
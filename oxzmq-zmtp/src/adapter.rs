@@ -0,0 +1,188 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::Peer,
+    sockets::{
+        Client, Dealer, Dish, Gather, Pair, Pub, Pull, Push, Radio, Router, Scatter, Server,
+        SocketError, Stream, Sub, XPub, XSub,
+    },
+};
+use futures::{sink, stream, Sink, SinkExt, StreamExt};
+use std::future::Future;
+
+/// A socket that messages can be received from, which can pump them into an
+/// async channel so that the rest of an application never deals with sockets.
+///
+/// REQ and REP sockets can't be used this way, since they have to alternate
+/// between sending and receiving.
+pub trait RecvSocket {
+    fn recv(&mut self) -> impl Future<Output = Result<Message, SocketError>>;
+
+    /// Receives messages and sends them into `sink` until either the socket
+    /// fails or the sink is closed (every receiver of a channel was dropped,
+    /// say), which ends forwarding without an error.
+    fn forward_to<S>(&mut self, sink: S) -> impl Future<Output = Result<(), SocketError>>
+    where
+        S: Sink<Message> + Unpin,
+    {
+        async move {
+            let mut sink = sink;
+            loop {
+                let msg = self.recv().await?;
+                if sink.send(msg).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// The socket's messages as a stream, for use with `StreamExt` and
+    /// `select!`. The stream never ends; errors are yielded as items and the
+    /// socket can still be received from after them. Like most futures
+    /// combinators, the stream needs to be pinned (with `pin_mut!`, say) to be
+    /// polled.
+    fn incoming(&mut self) -> impl stream::Stream<Item = Result<Message, SocketError>> + '_
+    where
+        Self: Sized,
+    {
+        stream::unfold(self, |socket| async move {
+            let msg = socket.recv().await;
+            Some((msg, socket))
+        })
+    }
+}
+
+/// A socket that messages can be sent on, which can take them from an async
+/// channel.
+pub trait SendSocket {
+    fn send(&mut self, msg: Message) -> impl Future<Output = Result<(), SocketError>>;
+
+    /// Sends every message that `stream` yields until it ends (every sender of
+    /// a channel was dropped, say) or the socket fails.
+    fn drain_from<S>(&mut self, stream: S) -> impl Future<Output = Result<(), SocketError>>
+    where
+        S: stream::Stream<Item = Message> + Unpin,
+    {
+        async move {
+            let mut stream = stream;
+            while let Some(msg) = stream.next().await {
+                self.send(msg).await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// A sink that sends every message it's given on the socket, for use with
+    /// `SinkExt` and `StreamExt::forward`. Like `incoming`, it needs to be
+    /// pinned, and it can't be used again after it returns an error.
+    fn outgoing(&mut self) -> impl Sink<Message, Error = SocketError> + '_
+    where
+        Self: Sized,
+    {
+        sink::unfold(self, |socket, msg| async move {
+            socket.send(msg).await?;
+            Ok(socket)
+        })
+    }
+}
+
+macro_rules! impl_recv_socket {
+    ($($socket:ident),*) => {
+        $(
+            impl<P: Peer> RecvSocket for $socket<P> {
+                async fn recv(&mut self) -> Result<Message, SocketError> {
+                    $socket::recv(self).await
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_send_socket {
+    ($($socket:ident),*) => {
+        $(
+            impl<P: Peer> SendSocket for $socket<P> {
+                async fn send(&mut self, msg: Message) -> Result<(), SocketError> {
+                    $socket::send(self, msg).await
+                }
+            }
+        )*
+    };
+}
+
+impl_recv_socket!(
+    Client, Dealer, Dish, Gather, Pair, Pull, Router, Server, Stream, Sub, XPub, XSub
+);
+impl_send_socket!(
+    Client, Dealer, Pair, Pub, Push, Radio, Router, Scatter, Server, Stream, XPub, XSub
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{peer::ChannelPeer, socket::SocketType};
+    use futures::{channel::mpsc, executor::block_on, join, pin_mut};
+
+    #[test]
+    fn test_channel_round_trip() {
+        block_on(async {
+            let mut push = Push::new();
+            let mut pull = Pull::new();
+            let (push_end, pull_end) = ChannelPeer::pair(SocketType::Push, SocketType::Pull);
+            push.attach(push_end).unwrap();
+            pull.attach(pull_end).unwrap();
+
+            let (mut outgoing, outgoing_rx) = mpsc::unbounded();
+            for i in 0..3_u8 {
+                outgoing.send(Message::from(vec![i])).await.unwrap();
+            }
+            drop(outgoing);
+            push.drain_from(outgoing_rx).await.unwrap();
+
+            // Forwarding stops at the first message after the receiving end
+            // of the channel goes away.
+            let (incoming_tx, incoming) = mpsc::unbounded();
+            let (forwarded, received) = join!(pull.forward_to(incoming_tx), async {
+                let received: Vec<_> = incoming.take(3).collect().await;
+                push.send(Message::from(vec![3])).await.unwrap();
+                received
+            });
+            forwarded.unwrap();
+            assert_eq!(
+                received,
+                (0..3_u8)
+                    .map(|i| Message::from(vec![i]))
+                    .collect::<Vec<_>>()
+            );
+        });
+    }
+
+    #[test]
+    fn test_stream_and_sink() {
+        block_on(async {
+            let mut push = Push::new();
+            let mut pull = Pull::new();
+            let (push_end, pull_end) = ChannelPeer::pair(SocketType::Push, SocketType::Pull);
+            push.attach(push_end).unwrap();
+            pull.attach(pull_end).unwrap();
+
+            let outgoing = push.outgoing();
+            pin_mut!(outgoing);
+            let mut sent = stream::iter((0..3_u8).map(|i| Ok(Message::from(vec![i]))));
+            outgoing.send_all(&mut sent).await.unwrap();
+
+            let incoming = pull.incoming();
+            pin_mut!(incoming);
+            let received: Vec<_> = incoming.take(3).map(Result::unwrap).collect().await;
+            assert_eq!(
+                received,
+                (0..3_u8)
+                    .map(|i| Message::from(vec![i]))
+                    .collect::<Vec<_>>()
+            );
+        });
+    }
+}
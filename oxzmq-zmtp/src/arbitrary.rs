@@ -0,0 +1,55 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// Random inputs for round-trip tests. A fixed seed keeps failures
+// reproducible; a failing case can be found again by its number.
+
+// How many cases each round-trip test tries.
+pub(crate) const CASES: u64 = 1000;
+
+// An xorshift generator, which is plenty for picking test inputs.
+#[derive(Debug, Clone)]
+pub(crate) struct Gen(u64);
+
+impl Gen {
+    pub(crate) fn new(seed: u64) -> Gen {
+        // Xorshift gets stuck at zero.
+        Gen(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub(crate) fn u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub(crate) fn u8(&mut self) -> u8 {
+        self.u64() as u8
+    }
+
+    pub(crate) fn bool(&mut self) -> bool {
+        self.u64() & 1 == 1
+    }
+
+    // A number in `0..=max`.
+    pub(crate) fn up_to(&mut self, max: usize) -> usize {
+        (self.u64() % (max as u64 + 1)) as usize
+    }
+
+    // Up to `max_len` random bytes. Short lengths are as likely as long
+    // ones, so edge cases like empty and one-byte buffers come up often.
+    pub(crate) fn bytes(&mut self, max_len: usize) -> Vec<u8> {
+        let len = self.up_to(max_len);
+        (0..len).map(|_| self.u8()).collect()
+    }
+
+    // A string of 1 to `max_len` characters drawn from `alphabet`.
+    pub(crate) fn string(&mut self, alphabet: &[u8], max_len: usize) -> String {
+        let len = 1 + self.up_to(max_len - 1);
+        (0..len)
+            .map(|_| char::from(alphabet[self.up_to(alphabet.len() - 1)]))
+            .collect()
+    }
+}
@@ -0,0 +1,417 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::handshake::plain::{CredentialValidator, PlainCredentials};
+use futures::future::{self, BoxFuture, FutureExt};
+use std::{
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    fmt,
+    hash::{BuildHasher, Hash},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+type HandlerFn = dyn Fn(PlainCredentials) -> BoxFuture<'static, bool> + Send + Sync;
+
+/// Decides which clients may connect to a PLAIN server. Clones share the
+/// same handlers and cache, so replacing a handler, e.g. after reloading a
+/// credential store, takes effect for every connection set up afterwards
+/// without touching the sockets.
+///
+/// Like ZAP, an authenticator can have a handler for each domain, which
+/// servers pick with `ConnectionOptions::zap_domain`, so that one
+/// authenticator can serve every tenant of a broker. Clients of a domain
+/// without a handler of its own are checked by the default handler, if there
+/// is one, and let in or kept out by the `AuthPolicy` otherwise.
+#[derive(Clone)]
+pub struct Authenticator {
+    state: Arc<Mutex<AuthState>>,
+}
+
+struct AuthState {
+    handler: Option<Arc<HandlerFn>>,
+    domains: HashMap<String, Arc<HandlerFn>>,
+    policy: AuthPolicy,
+    cache: Option<VerdictCache>,
+}
+
+// How many clients the cache remembers letting in. Past that, the ones it's
+// remembered longest are forgotten first.
+const MAX_CACHED_VERDICTS: usize = 10_000;
+
+// The clients already let in, and when that stops counting. Clients that were
+// kept out aren't remembered, so that guessing passwords can't fill the cache,
+// and fixing a password takes effect straight away.
+struct VerdictCache {
+    ttl: Duration,
+    capacity: usize,
+    // Credentials are only kept as a hash, under keys picked at random for
+    // each cache, so the cache holds no passwords and collisions can't be
+    // found ahead of time.
+    keys: [RandomState; 2],
+    expiries: HashMap<CacheKey, Instant>,
+    // The same entries in the order they were made, which is also the order
+    // they expire in, so expired ones come off the front.
+    order: VecDeque<(CacheKey, Instant)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct CacheKey(u64, u64);
+
+/// What an `Authenticator` decides for clients that no handler is there to
+/// check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthPolicy {
+    Allow,
+    Deny,
+}
+
+impl Authenticator {
+    /// An authenticator that checks clients of every domain with `handler`,
+    /// unless a domain has a handler of its own.
+    pub fn new<F>(handler: F) -> Authenticator
+    where
+        F: Fn(&PlainCredentials) -> bool + Send + Sync + 'static,
+    {
+        Authenticator::build(Some(sync_handler(handler)), AuthPolicy::Deny)
+    }
+
+    /// An authenticator that checks clients of every domain with
+    /// `validator`, unless a domain has a handler of its own.
+    pub fn from_validator<V>(validator: V) -> Authenticator
+    where
+        V: CredentialValidator + Send + Sync + 'static,
+    {
+        Authenticator::build(Some(async_handler(validator)), AuthPolicy::Deny)
+    }
+
+    /// An authenticator without any handlers yet, which lets in or keeps out
+    /// every client according to `policy` until handlers are registered.
+    pub fn with_policy(policy: AuthPolicy) -> Authenticator {
+        Authenticator::build(None, policy)
+    }
+
+    fn build(handler: Option<Arc<HandlerFn>>, policy: AuthPolicy) -> Authenticator {
+        Authenticator {
+            state: Arc::new(Mutex::new(AuthState {
+                handler,
+                domains: HashMap::new(),
+                policy,
+                cache: None,
+            })),
+        }
+    }
+
+    /// Remembers each client that's let in for `ttl`, so that clients
+    /// reconnecting over and over don't each have to be checked with the
+    /// handler. Clients that are kept out are checked every time.
+    pub fn cache_verdicts(self, ttl: Duration) -> Authenticator {
+        self.lock().cache = Some(VerdictCache::new(ttl, MAX_CACHED_VERDICTS));
+        self
+    }
+
+    /// Swaps in a new default handler. Cached verdicts might have been
+    /// reached by the old one, so they're forgotten.
+    pub fn replace<F>(&self, handler: F)
+    where
+        F: Fn(&PlainCredentials) -> bool + Send + Sync + 'static,
+    {
+        let mut state = self.lock();
+        state.handler = Some(sync_handler(handler));
+        state.forget();
+    }
+
+    /// Checks the clients of servers in `domain` with `handler` rather than
+    /// the default handler, replacing any handler the domain already had.
+    pub fn register<F>(&self, domain: &str, handler: F)
+    where
+        F: Fn(&PlainCredentials) -> bool + Send + Sync + 'static,
+    {
+        self.register_handler(domain, sync_handler(handler));
+    }
+
+    /// Checks the clients of servers in `domain` with `validator`, as
+    /// `register` does with a handler.
+    pub fn register_validator<V>(&self, domain: &str, validator: V)
+    where
+        V: CredentialValidator + Send + Sync + 'static,
+    {
+        self.register_handler(domain, async_handler(validator));
+    }
+
+    fn register_handler(&self, domain: &str, handler: Arc<HandlerFn>) {
+        let mut state = self.lock();
+        state.domains.insert(domain.to_string(), handler);
+        state.forget();
+    }
+
+    /// Removes the handler of `domain`, whose clients go back to being
+    /// checked by the default handler, or the policy.
+    pub fn unregister(&self, domain: &str) {
+        let mut state = self.lock();
+        state.domains.remove(domain);
+        state.forget();
+    }
+
+    /// Changes what's decided for clients no handler is there to check.
+    pub fn set_policy(&self, policy: AuthPolicy) {
+        self.lock().policy = policy;
+    }
+
+    /// Forgets every cached verdict, e.g. after revoking credentials.
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &mut self.lock().cache {
+            cache.clear();
+        }
+    }
+
+    pub(crate) async fn check_plain(&self, domain: &str, credentials: &PlainCredentials) -> bool {
+        let fields = (
+            "PLAIN",
+            domain,
+            credentials.username(),
+            credentials.password(),
+        );
+
+        let handler = {
+            let mut state = self.lock();
+            if let Some(cache) = &mut state.cache {
+                if cache.contains(&fields) {
+                    return true;
+                }
+            }
+            match state.domains.get(domain).or(state.handler.as_ref()) {
+                Some(handler) => handler.clone(),
+                None => return state.policy == AuthPolicy::Allow,
+            }
+        };
+
+        // The handler might be slow, so other connections aren't held up
+        // while it runs.
+        let verdict = handler(credentials.clone()).await;
+        if verdict {
+            if let Some(cache) = &mut self.lock().cache {
+                cache.insert(&fields);
+            }
+        }
+        verdict
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, AuthState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn sync_handler<F>(handler: F) -> Arc<HandlerFn>
+where
+    F: Fn(&PlainCredentials) -> bool + Send + Sync + 'static,
+{
+    Arc::new(move |credentials| future::ready(handler(&credentials)).boxed())
+}
+
+fn async_handler<V>(validator: V) -> Arc<HandlerFn>
+where
+    V: CredentialValidator + Send + Sync + 'static,
+{
+    let validator = Arc::new(validator);
+    Arc::new(move |credentials| {
+        let validator = validator.clone();
+        async move { validator.validate(&credentials).await }.boxed()
+    })
+}
+
+impl AuthState {
+    // Forgets the cached verdicts, which the handlers might not agree with
+    // anymore.
+    fn forget(&mut self) {
+        if let Some(cache) = &mut self.cache {
+            cache.clear();
+        }
+    }
+}
+
+impl VerdictCache {
+    fn new(ttl: Duration, capacity: usize) -> VerdictCache {
+        VerdictCache {
+            ttl,
+            capacity,
+            keys: [RandomState::new(), RandomState::new()],
+            expiries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn key(&self, fields: &impl Hash) -> CacheKey {
+        let [first, second] = &self.keys;
+        CacheKey(first.hash_one(fields), second.hash_one(fields))
+    }
+
+    fn contains(&mut self, fields: &impl Hash) -> bool {
+        self.expire(Instant::now());
+        let key = self.key(fields);
+        self.expiries.contains_key(&key)
+    }
+
+    fn insert(&mut self, fields: &impl Hash) {
+        let now = Instant::now();
+        self.expire(now);
+        while self.order.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => self.remove(oldest),
+                None => return,
+            }
+        }
+
+        let key = self.key(fields);
+        let expires = now + self.ttl;
+        self.expiries.insert(key, expires);
+        self.order.push_back((key, expires));
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(_, expires)) = self.order.front() {
+            if expires > now {
+                break;
+            }
+            let oldest = self.order.pop_front().unwrap();
+            self.remove(oldest);
+        }
+    }
+
+    // Forgets an entry taken off the front of `order`, unless the client has
+    // been let in again since.
+    fn remove(&mut self, (key, expires): (CacheKey, Instant)) {
+        if self.expiries.get(&key) == Some(&expires) {
+            self.expiries.remove(&key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.expiries.clear();
+        self.order.clear();
+    }
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Authenticator")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_replace_and_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let auth = Authenticator::new(move |credentials| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            credentials.password() == "old"
+        })
+        .cache_verdicts(Duration::from_secs(60));
+
+        let old = PlainCredentials::new("admin", "old");
+        let new = PlainCredentials::new("admin", "new");
+        assert!(block_on(auth.check_plain("", &old)));
+        assert!(block_on(auth.check_plain("", &old)));
+        assert!(!block_on(auth.check_plain("", &new)));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Clones see the new handler, and nothing the old one decided.
+        auth.clone()
+            .replace(|credentials| credentials.password() == "new");
+        assert!(!block_on(auth.check_plain("", &old)));
+        assert!(block_on(auth.check_plain("", &new)));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Verdicts that have expired are asked for again.
+        let counted = calls.clone();
+        let expiring = Authenticator::new(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            true
+        })
+        .cache_verdicts(Duration::ZERO);
+        assert!(block_on(expiring.check_plain("", &old)));
+        assert!(block_on(expiring.check_plain("", &old)));
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_cache_limits() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let auth = Authenticator::new(move |credentials| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            credentials.password() == "secret"
+        });
+        auth.lock().cache = Some(VerdictCache::new(Duration::from_secs(60), 2));
+
+        // Clients that are kept out are asked about every time.
+        let intruder = PlainCredentials::new("admin", "guess");
+        assert!(!block_on(auth.check_plain("", &intruder)));
+        assert!(!block_on(auth.check_plain("", &intruder)));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Past the cache's capacity, the oldest clients are forgotten.
+        let users: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|username| PlainCredentials::new(username, "secret"))
+            .collect();
+        for user in &users {
+            assert!(block_on(auth.check_plain("", user)));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+        assert!(block_on(auth.check_plain("", &users[2])));
+        assert!(block_on(auth.check_plain("", &users[1])));
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+        assert!(block_on(auth.check_plain("", &users[0])));
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn test_domains_and_policy() {
+        let credentials = PlainCredentials::new("admin", "secret");
+        let auth = Authenticator::with_policy(AuthPolicy::Deny);
+        assert!(!block_on(auth.check_plain("tenant-a", &credentials)));
+        auth.set_policy(AuthPolicy::Allow);
+        assert!(block_on(auth.check_plain("tenant-a", &credentials)));
+
+        auth.register("tenant-a", |credentials| credentials.username() == "a");
+        assert!(!block_on(auth.check_plain("tenant-a", &credentials)));
+        assert!(block_on(auth.check_plain("tenant-b", &credentials)));
+
+        auth.replace(|_| false);
+        assert!(!block_on(auth.check_plain("tenant-b", &credentials)));
+        auth.unregister("tenant-a");
+        assert!(!block_on(auth.check_plain("tenant-a", &credentials)));
+    }
+
+    struct Directory {
+        users: Vec<PlainCredentials>,
+    }
+
+    impl CredentialValidator for Directory {
+        async fn validate(&self, credentials: &PlainCredentials) -> bool {
+            self.users.contains(credentials)
+        }
+    }
+
+    #[test]
+    fn test_validator() {
+        let user = PlainCredentials::new("admin", "secret");
+        let auth = Authenticator::from_validator(Directory {
+            users: vec![user.clone()],
+        });
+        assert!(block_on(auth.check_plain("", &user)));
+        let intruder = PlainCredentials::new("admin", "guess");
+        assert!(!block_on(auth.check_plain("", &intruder)));
+
+        auth.register_validator("tenant-a", Directory { users: Vec::new() });
+        assert!(!block_on(auth.check_plain("tenant-a", &user)));
+    }
+}
@@ -0,0 +1,120 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// Batching is an OxZMQ-only extension. Peers that both advertise the
+// `X-OxZMQ-Batch` property during the handshake may pack several messages
+// into the data of a single BATCH command. Other peers never see a BATCH
+// command, so nothing changes when talking to `libzmq`.
+//
+// The command data is a sequence of parts, each encoded as a flags byte
+// (bit 0 is MORE, as in a message frame), a 4-byte big-endian length, and the
+// part itself. A part without MORE ends a message.
+
+use crate::message::Message;
+use std::convert::TryFrom;
+
+pub(crate) const BATCH_PROPERTY: &str = "X-OxZMQ-Batch";
+
+const MORE_FLAG: u8 = 0x01;
+const PART_HEADER_LEN: usize = 5;
+
+pub(crate) fn encode(msgs: &[Message]) -> Vec<u8> {
+    let total_len = msgs
+        .iter()
+        .flat_map(|msg| msg.parts())
+        .map(|part| PART_HEADER_LEN + part.len())
+        .sum();
+    let mut data = Vec::with_capacity(total_len);
+
+    for msg in msgs {
+        let last_idx = msg.len().saturating_sub(1);
+        for (idx, part) in msg.parts().iter().enumerate() {
+            data.push(if idx == last_idx { 0 } else { MORE_FLAG });
+            data.extend_from_slice(&(part.len() as u32).to_be_bytes());
+            data.extend_from_slice(part);
+        }
+    }
+
+    data
+}
+
+pub(crate) fn decode(mut data: &[u8]) -> Result<Vec<Message>, BatchDecodeError> {
+    let mut msgs = Vec::new();
+    let mut msg = Message::new();
+
+    while !data.is_empty() {
+        if data.len() < PART_HEADER_LEN {
+            return Err(BatchDecodeError::Truncated);
+        }
+
+        let flags = data[0];
+        if flags & !MORE_FLAG != 0 {
+            return Err(BatchDecodeError::Flags);
+        }
+
+        // Can't fail; we just checked the length.
+        let len_bytes = <[u8; 4]>::try_from(&data[1..PART_HEADER_LEN]).unwrap();
+        let part_len = u32::from_be_bytes(len_bytes) as usize;
+        data = &data[PART_HEADER_LEN..];
+        if data.len() < part_len {
+            return Err(BatchDecodeError::Truncated);
+        }
+
+        msg.push_back(data[..part_len].to_vec());
+        data = &data[part_len..];
+
+        if flags & MORE_FLAG == 0 {
+            msgs.push(std::mem::take(&mut msg));
+        }
+    }
+
+    if !msg.is_empty() {
+        return Err(BatchDecodeError::Truncated);
+    }
+
+    Ok(msgs)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BatchDecodeError {
+    #[error("batch ended in the middle of a message")]
+    Truncated,
+
+    #[error("malformed flags in batched part")]
+    Flags,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let msgs = vec![
+            Message::from(&b"one"[..]),
+            Message::from(vec![b"two".to_vec(), Vec::new(), b"parts".to_vec()]),
+            Message::from(Vec::<u8>::new()),
+        ];
+        assert_eq!(decode(&encode(&msgs)).unwrap(), msgs);
+        assert!(decode(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_malformed() {
+        let data = encode(&[Message::from(&b"data"[..])]);
+        assert!(matches!(
+            decode(&data[..data.len() - 1]),
+            Err(BatchDecodeError::Truncated)
+        ));
+
+        let mut data = encode(&[Message::from(vec![b"a".to_vec(), b"b".to_vec()])]);
+        data.truncate(PART_HEADER_LEN + 1);
+        assert!(matches!(decode(&data), Err(BatchDecodeError::Truncated)));
+
+        assert!(matches!(
+            decode(&[0x02, 0, 0, 0, 0]),
+            Err(BatchDecodeError::Flags)
+        ));
+    }
+}
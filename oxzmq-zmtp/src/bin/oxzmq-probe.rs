@@ -0,0 +1,33 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// Checks that a ZMTP peer is up and reports what it is, for health checks
+// and deployment validation. Exits with a nonzero status if it isn't.
+//
+//     oxzmq-probe tcp://127.0.0.1:5555
+
+use std::process;
+
+fn main() {
+    let endpoint = match std::env::args().nth(1) {
+        Some(endpoint) => endpoint,
+        None => {
+            eprintln!("usage: oxzmq-probe <endpoint>");
+            process::exit(2);
+        }
+    };
+
+    match oxzmq_zmtp::probe(&endpoint) {
+        Ok(report) => {
+            let version = report.version();
+            println!("socket-type: {}", String::from(&report.socket_type()));
+            println!("version: {}.{}", version.major(), version.minor());
+            println!("mechanism: {}", report.mechanism());
+        }
+        Err(err) => {
+            eprintln!("{}: {}", endpoint, err);
+            process::exit(1);
+        }
+    }
+}
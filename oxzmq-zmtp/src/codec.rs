@@ -0,0 +1,193 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::frame::{Frame, FrameHeader, FrameParseError, MAX_PREALLOC};
+use std::convert::TryFrom;
+
+/// Splits ZMTP frames out of a buffer of bytes, and writes them into one,
+/// without doing any I/O. This is for running the framing over a transport
+/// this crate doesn't know about, with whatever runtime it uses: append
+/// incoming bytes to a buffer, call `decode` until it returns `None`, and
+/// write out whatever `encode` produces.
+///
+/// The codec only handles framing. The greeting and handshake that start a
+/// connection have to be done before any frames are decoded.
+#[derive(Debug, Clone)]
+pub struct ZmtpCodec {
+    max_frame_len: u64,
+}
+
+// Frames are capped by default, since their lengths come from the peer.
+pub(crate) const DEFAULT_MAX_FRAME_LEN: u64 = 64 * 1024 * 1024;
+
+impl ZmtpCodec {
+    /// A codec that rejects frames longer than 64 MiB.
+    pub fn new() -> ZmtpCodec {
+        ZmtpCodec::with_max_frame_len(DEFAULT_MAX_FRAME_LEN)
+    }
+
+    /// Rejects frames whose header says they're longer than `len` bytes,
+    /// rather than waiting for a body that may never fit in memory.
+    pub fn with_max_frame_len(len: u64) -> ZmtpCodec {
+        ZmtpCodec { max_frame_len: len }
+    }
+
+    /// Removes the first complete frame from the front of `src`. Returns
+    /// `None`, leaving `src` as it was, if the frame hasn't fully arrived.
+    pub fn decode(&mut self, src: &mut Vec<u8>) -> Result<Option<Frame>, FrameParseError> {
+        let (header, header_len) = match FrameHeader::parse(src)? {
+            Some(parsed) => parsed,
+            None => return Ok(None),
+        };
+        if header.len() > self.max_frame_len {
+            return Err(FrameParseError::FrameTooLong(header.len()));
+        }
+
+        let body_len = usize::try_from(header.len()).map_err(FrameParseError::MessageTooLarge)?;
+        let frame_len = header_len
+            .checked_add(body_len)
+            .ok_or(FrameParseError::FrameTooLong(header.len()))?;
+        if src.len() < frame_len {
+            // The length is only trusted so far, so the buffer grows in
+            // steps as the rest of a long frame arrives.
+            src.reserve((frame_len - src.len()).min(MAX_PREALLOC));
+            return Ok(None);
+        }
+
+        let body: Vec<u8> = src.drain(..frame_len).skip(header_len).collect();
+        match header.is_command() {
+            true => Frame::command_from_body(body).map(Some),
+            false => Ok(Some(Frame::new_message(header.more(), body))),
+        }
+    }
+
+    /// Appends `frame`, header and all, to `dst`.
+    pub fn encode(&mut self, frame: &Frame, dst: &mut Vec<u8>) {
+        let len = frame.body_len() as u64;
+        match frame.command_name() {
+            Some(name) => {
+                FrameHeader::command(len).encode(dst);
+                dst.push(name.len() as u8);
+                dst.extend_from_slice(name.as_bytes());
+            }
+            None => FrameHeader::message(frame.more(), len).encode(dst),
+        }
+        dst.extend_from_slice(frame.data());
+    }
+}
+
+impl Default for ZmtpCodec {
+    fn default() -> ZmtpCodec {
+        ZmtpCodec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::{Gen, CASES};
+
+    #[test]
+    fn test_round_trip() {
+        let mut codec = ZmtpCodec::new();
+        let mut buf = Vec::new();
+        codec.encode(
+            &Frame::new_command("PING".to_string(), vec![0, 1]),
+            &mut buf,
+        );
+        codec.encode(&Frame::new_message(true, vec![7; 300]), &mut buf);
+        codec.encode(&Frame::new_message(false, Vec::new()), &mut buf);
+
+        // Frames only come out once all of their bytes are there.
+        let mut src = Vec::new();
+        let mut frames = Vec::new();
+        for &byte in &buf {
+            src.push(byte);
+            while let Some(frame) = codec.decode(&mut src).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert!(src.is_empty());
+        assert_eq!(frames.len(), 3);
+
+        assert_eq!(frames[0].command_name(), Some("PING"));
+        assert_eq!(frames[0].data(), &[0, 1]);
+        assert!(frames[1].more());
+        assert_eq!(frames[1].data(), &[7; 300][..]);
+        assert!(!frames[2].more());
+        assert!(frames[2].data().is_empty());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut codec = ZmtpCodec::with_max_frame_len(100);
+        let mut buf = Vec::new();
+        codec.encode(&Frame::new_message(false, vec![0; 200]), &mut buf);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(FrameParseError::FrameTooLong(200))
+        ));
+
+        // A header claiming more than the default cap is refused before
+        // anything is allocated for it.
+        let mut buf = vec![0b10, 0x40, 0, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(
+            ZmtpCodec::new().decode(&mut buf),
+            Err(FrameParseError::FrameTooLong(_))
+        ));
+        let mut buf = vec![0b10, 0x40, 0, 0, 0, 0, 0, 0, 0];
+        let mut codec = ZmtpCodec::with_max_frame_len(u64::MAX);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.capacity() <= 2 * MAX_PREALLOC);
+
+        // Commands can't have the MORE flag set.
+        let mut buf = vec![0b101, 0];
+        assert!(matches!(
+            ZmtpCodec::new().decode(&mut buf),
+            Err(FrameParseError::MultipartCommand)
+        ));
+
+        // The name can't be longer than the command.
+        let mut buf = vec![0b100, 4, 5, b'P', b'I', b'N'];
+        assert!(matches!(
+            ZmtpCodec::new().decode(&mut buf),
+            Err(FrameParseError::CommandNameSize)
+        ));
+    }
+
+    #[test]
+    fn test_round_trip_arbitrary() {
+        const NAME_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+        for case in 0..CASES {
+            let mut gen = Gen::new(case);
+            let frames: Vec<_> = (0..1 + gen.up_to(4))
+                .map(|_| match gen.bool() {
+                    true => Frame::new_command(gen.string(NAME_CHARS, 20), gen.bytes(300)),
+                    false => Frame::new_message(gen.bool(), gen.bytes(600)),
+                })
+                .collect();
+
+            let mut codec = ZmtpCodec::new();
+            let mut buf = Vec::new();
+            for frame in &frames {
+                codec.encode(frame, &mut buf);
+            }
+
+            // Feed the bytes in at random boundaries.
+            let mut src = Vec::new();
+            let mut decoded = Vec::new();
+            let mut rest = buf.as_slice();
+            while !rest.is_empty() {
+                let (chunk, after) = rest.split_at(1 + gen.up_to(rest.len() - 1));
+                rest = after;
+                src.extend_from_slice(chunk);
+                while let Some(frame) = codec.decode(&mut src).unwrap() {
+                    decoded.push(frame);
+                }
+            }
+            assert!(src.is_empty(), "case {}", case);
+            assert_eq!(decoded, frames, "case {}", case);
+        }
+    }
+}
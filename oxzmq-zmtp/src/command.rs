@@ -0,0 +1,371 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    frame::{CommandFrame, Frame},
+    handshake::plain::PlainCredentials,
+    properties::{Properties, PropertiesParseError},
+};
+use std::{convert::TryFrom, fmt, sync::Arc};
+
+const READY: &str = "READY";
+const ERROR: &str = "ERROR";
+const SUBSCRIBE: &str = "SUBSCRIBE";
+const CANCEL: &str = "CANCEL";
+const PING: &str = "PING";
+const PONG: &str = "PONG";
+const HELLO: &str = "HELLO";
+const WELCOME: &str = "WELCOME";
+const INITIATE: &str = "INITIATE";
+const MESSAGE: &str = "MESSAGE";
+const BATCH: &str = "BATCH";
+const CLOSE: &str = "CLOSE";
+
+// Commands defined by ZMTP and its security mechanisms, or used internally by
+// OxZMQ. Applications can't send these or handle them themselves.
+const RESERVED_NAMES: [&str; 12] = [
+    READY, ERROR, SUBSCRIBE, CANCEL, PING, PONG, HELLO, WELCOME, INITIATE, MESSAGE, BATCH, CLOSE,
+];
+
+// A PING starts with a two-byte TTL, and the rest is context for the PONG
+// to echo back.
+const TTL_LEN: usize = 2;
+
+// More info: https://rfc.zeromq.org/spec/37/#commands
+//
+// A command frame, with its data parsed according to what ZMTP, its
+// security mechanisms and OxZMQ's own extensions say its name means.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ZmtpCommand {
+    // The sender's metadata, which ends the NULL handshake, and the PLAIN
+    // handshake from the server's side.
+    Ready(Properties),
+    // Why the sender is about to close the connection.
+    Error(String),
+    Subscribe(Vec<u8>),
+    Cancel(Vec<u8>),
+    // A heartbeat, which the peer answers with a PONG echoing the context.
+    // The TTL is in tenths of a second.
+    Ping { ttl: u16, context: Vec<u8> },
+    Pong { context: Vec<u8> },
+    // The PLAIN handshake. Credentials are checked to fit before a HELLO is
+    // sent, since each goes behind a one-byte length.
+    Hello(PlainCredentials),
+    Welcome,
+    Initiate(Properties),
+    // An OxZMQ peer shutting down on purpose.
+    Close,
+    // Messages packed together by an OxZMQ peer.
+    Batch(Vec<u8>),
+    // Everything else, including custom commands.
+    Unknown { name: String, data: Vec<u8> },
+}
+
+impl ZmtpCommand {
+    pub(crate) fn from_frame(cmd: CommandFrame) -> Result<ZmtpCommand, CommandParseError> {
+        let CommandFrame { name, data } = cmd;
+        let cmd = match name.as_str() {
+            READY => ZmtpCommand::Ready(Properties::decode(&data)?),
+            ERROR => ZmtpCommand::Error(decode_error(&data)),
+            SUBSCRIBE => ZmtpCommand::Subscribe(data),
+            CANCEL => ZmtpCommand::Cancel(data),
+            PING => {
+                let ttl = data
+                    .get(..TTL_LEN)
+                    .and_then(|ttl| <[u8; TTL_LEN]>::try_from(ttl).ok())
+                    .ok_or(CommandParseError::MalformedPing)?;
+                ZmtpCommand::Ping {
+                    ttl: u16::from_be_bytes(ttl),
+                    context: data[TTL_LEN..].to_vec(),
+                }
+            }
+            PONG => ZmtpCommand::Pong { context: data },
+            HELLO => ZmtpCommand::Hello(decode_hello(&data)?),
+            WELCOME => ZmtpCommand::Welcome,
+            INITIATE => ZmtpCommand::Initiate(Properties::decode(&data)?),
+            CLOSE => ZmtpCommand::Close,
+            BATCH => ZmtpCommand::Batch(data),
+            _ => ZmtpCommand::Unknown { name, data },
+        };
+        Ok(cmd)
+    }
+
+    pub(crate) fn into_frame(self) -> Frame {
+        let (name, data) = match self {
+            ZmtpCommand::Ready(properties) => (READY, properties.encode()),
+            ZmtpCommand::Error(reason) => (ERROR, encode_error(&reason)),
+            ZmtpCommand::Subscribe(topic) => (SUBSCRIBE, topic),
+            ZmtpCommand::Cancel(topic) => (CANCEL, topic),
+            ZmtpCommand::Ping { ttl, context } => {
+                let mut data = Vec::with_capacity(TTL_LEN + context.len());
+                data.extend_from_slice(&ttl.to_be_bytes());
+                data.extend_from_slice(&context);
+                (PING, data)
+            }
+            ZmtpCommand::Pong { context } => (PONG, context),
+            ZmtpCommand::Hello(credentials) => (HELLO, encode_hello(&credentials)),
+            ZmtpCommand::Welcome => (WELCOME, Vec::new()),
+            ZmtpCommand::Initiate(properties) => (INITIATE, properties.encode()),
+            ZmtpCommand::Close => (CLOSE, Vec::new()),
+            ZmtpCommand::Batch(data) => (BATCH, data),
+            ZmtpCommand::Unknown { name, data } => return Frame::new_command(name, data),
+        };
+        Frame::new_command(name.to_string(), data)
+    }
+}
+
+// The reason goes behind a one-byte length, so it's cut short at 255 bytes,
+// on a character boundary.
+fn encode_error(reason: &str) -> Vec<u8> {
+    let mut len = 0;
+    for c in reason.chars() {
+        if len + c.len_utf8() > usize::from(u8::MAX) {
+            break;
+        }
+        len += c.len_utf8();
+    }
+
+    let mut data = Vec::with_capacity(1 + len);
+    data.push(len as u8);
+    data.extend_from_slice(&reason.as_bytes()[..len]);
+    data
+}
+
+// Peers that get the length wrong still get their reason across.
+fn decode_error(data: &[u8]) -> String {
+    let reason = match data.split_first() {
+        Some((&len, rest)) => rest.get(..usize::from(len)).unwrap_or(rest),
+        None => &[],
+    };
+    String::from_utf8_lossy(reason).into_owned()
+}
+
+// HELLO carries the username and password, each behind a one-byte length.
+fn encode_hello(credentials: &PlainCredentials) -> Vec<u8> {
+    let (username, password) = (credentials.username(), credentials.password());
+    let mut data = Vec::with_capacity(2 + username.len() + password.len());
+    for field in [username, password] {
+        data.push(field.len() as u8);
+        data.extend_from_slice(field.as_bytes());
+    }
+    data
+}
+
+fn decode_hello(data: &[u8]) -> Result<PlainCredentials, CommandParseError> {
+    let mut fields = Vec::with_capacity(2);
+    let mut rest = data;
+    for _ in 0..2 {
+        let (&len, tail) = rest
+            .split_first()
+            .ok_or(CommandParseError::MalformedHello)?;
+        let field = tail
+            .get(..usize::from(len))
+            .ok_or(CommandParseError::MalformedHello)?;
+        let field = std::str::from_utf8(field).map_err(|_| CommandParseError::MalformedHello)?;
+        fields.push(field);
+        rest = &tail[usize::from(len)..];
+    }
+    if !rest.is_empty() {
+        return Err(CommandParseError::MalformedHello);
+    }
+    Ok(PlainCredentials::new(fields[0], fields[1]))
+}
+
+/// A custom command, for protocol extensions between cooperating peers.
+/// Peers that don't know a command ignore it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    name: String,
+    data: Vec<u8>,
+}
+
+impl Command {
+    /// Command names are 1 to 255 ASCII letters, and can't be the name of a
+    /// command that ZMTP already defines.
+    pub fn new(name: &str, data: Vec<u8>) -> Result<Command, CommandNameError> {
+        validate_name(name)?;
+        Ok(Command {
+            name: name.to_string(),
+            data,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub(crate) fn into_parts(self) -> (String, Vec<u8>) {
+        (self.name, self.data)
+    }
+}
+
+pub(crate) fn validate_name(name: &str) -> Result<(), CommandNameError> {
+    if name.is_empty() || name.len() > usize::from(u8::MAX) {
+        return Err(CommandNameError::Length);
+    }
+    if !name.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return Err(CommandNameError::InvalidChar);
+    }
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(name))
+    {
+        return Err(CommandNameError::Reserved(name.to_string()));
+    }
+
+    Ok(())
+}
+
+type HandlerFn = dyn Fn(&[u8]) -> Option<Command> + Send + Sync;
+
+// Called with the data of each received command it's registered for. If it
+// returns a command, that's sent back to the peer.
+#[derive(Clone)]
+pub(crate) struct CommandHandler(pub(crate) Arc<HandlerFn>);
+
+impl fmt::Debug for CommandHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CommandHandler")
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CommandParseError {
+    #[error("could not parse properties")]
+    Properties(#[from] PropertiesParseError),
+
+    #[error("PING command too short to hold a TTL")]
+    MalformedPing,
+
+    #[error("malformed HELLO command")]
+    MalformedHello,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CommandNameError {
+    #[error("command names must be 1 to 255 bytes long")]
+    Length,
+
+    #[error("command names can only contain ASCII letters")]
+    InvalidChar,
+
+    #[error("{0} is already defined by the protocol")]
+    Reserved(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(Command::new("XTRACE", Vec::new()).is_ok());
+        assert!(matches!(validate_name(""), Err(CommandNameError::Length)));
+        assert!(matches!(
+            validate_name(&"A".repeat(256)),
+            Err(CommandNameError::Length)
+        ));
+        assert!(matches!(
+            validate_name("X-TRACE"),
+            Err(CommandNameError::InvalidChar)
+        ));
+        assert!(matches!(
+            validate_name("Ping"),
+            Err(CommandNameError::Reserved(_))
+        ));
+    }
+
+    fn round_trip(cmd: ZmtpCommand) -> ZmtpCommand {
+        match cmd.into_frame() {
+            Frame::Command(frame) => ZmtpCommand::from_frame(frame).unwrap(),
+            Frame::Message(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut properties = Properties::new();
+        properties
+            .insert("Socket-Type", b"DEALER".to_vec())
+            .unwrap();
+        let cmds = [
+            ZmtpCommand::Ready(properties.clone()),
+            ZmtpCommand::Error("no thanks".to_string()),
+            ZmtpCommand::Subscribe(b"topic".to_vec()),
+            ZmtpCommand::Cancel(Vec::new()),
+            ZmtpCommand::Ping {
+                ttl: 300,
+                context: b"seq".to_vec(),
+            },
+            ZmtpCommand::Pong {
+                context: b"seq".to_vec(),
+            },
+            ZmtpCommand::Hello(PlainCredentials::new("admin", "secret")),
+            ZmtpCommand::Hello(PlainCredentials::new("", "")),
+            ZmtpCommand::Welcome,
+            ZmtpCommand::Initiate(properties),
+            ZmtpCommand::Close,
+            ZmtpCommand::Batch(vec![1, 2, 3]),
+            ZmtpCommand::Unknown {
+                name: "XTRACE".to_string(),
+                data: b"id".to_vec(),
+            },
+        ];
+        for cmd in cmds.iter() {
+            assert_eq!(round_trip(cmd.clone()), *cmd);
+        }
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let ping = ZmtpCommand::Ping {
+            ttl: 1,
+            context: b"ctx".to_vec(),
+        };
+        assert_eq!(ping.into_frame().data(), b"\x00\x01ctx");
+        let hello = ZmtpCommand::Hello(PlainCredentials::new("admin", "secret"));
+        assert_eq!(hello.into_frame().data(), b"\x05admin\x06secret");
+
+        // Reasons are cut short to fit behind their length.
+        let long = "\u{e9}".repeat(200);
+        let error = ZmtpCommand::Error(long.clone()).into_frame();
+        assert_eq!(error.data()[0], 254);
+        assert_eq!(
+            round_trip(ZmtpCommand::Error(long)),
+            ZmtpCommand::Error("\u{e9}".repeat(127))
+        );
+    }
+
+    #[test]
+    fn test_malformed() {
+        let parse = |name: &str, data: &[u8]| {
+            ZmtpCommand::from_frame(CommandFrame {
+                name: name.to_string(),
+                data: data.to_vec(),
+            })
+        };
+        assert!(matches!(
+            parse(PING, b"\x00"),
+            Err(CommandParseError::MalformedPing)
+        ));
+        assert!(matches!(
+            parse(READY, b"\x00"),
+            Err(CommandParseError::Properties(_))
+        ));
+        for malformed in [&b"\x05admin"[..], b"\x05admin\x06secre", b"\x00\x00\x00"] {
+            assert!(matches!(
+                parse(HELLO, malformed),
+                Err(CommandParseError::MalformedHello)
+            ));
+        }
+        assert_eq!(
+            parse(ERROR, b"\x10short").unwrap(),
+            ZmtpCommand::Error("short".to_string())
+        );
+    }
+}
@@ -0,0 +1,246 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{inproc::InprocContext, options::ConnectionOptions, runtime::Runtime};
+use futures::{
+    future::{self, AbortHandle, Abortable, Either},
+    pin_mut,
+    task::AtomicWaker,
+};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context as TaskContext, Poll, Waker},
+};
+
+/// The state that a group of sockets share, as in libzmq: the registry of
+/// `inproc://` endpoints, the options to connect with, and the tasks running
+/// in the background. Terminating the context stops all of it.
+///
+/// Sockets created with `with_context` belong to the context. Once it's been
+/// terminated, they fail every operation with `SocketError::Terminated`,
+/// including the ones that were already waiting. Clones share the same
+/// state.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    inproc: InprocContext,
+    options: ConnectionOptions,
+    terminated: AtomicBool,
+    // The sockets that belong to the context, to be woken on termination.
+    // Each one drops out once its socket is dropped.
+    sockets: Mutex<Vec<Weak<AtomicWaker>>>,
+    members: AtomicUsize,
+    // Tasks waiting for the sockets to be dropped.
+    terminators: Mutex<Vec<Waker>>,
+    tasks: Mutex<Vec<AbortHandle>>,
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    /// A context whose sockets connect with `options`, which can carry an
+    /// authenticator for every socket that acts as a PLAIN server.
+    pub fn with_options(options: ConnectionOptions) -> Context {
+        Context {
+            shared: Arc::new(Shared {
+                options,
+                ..Shared::default()
+            }),
+        }
+    }
+
+    /// Where the context's sockets bind and connect `inproc://` endpoints.
+    pub fn inproc(&self) -> &InprocContext {
+        &self.shared.inproc
+    }
+
+    /// The options to connect and accept with.
+    pub fn options(&self) -> &ConnectionOptions {
+        &self.shared.options
+    }
+
+    /// Runs `task` on `runtime` until it finishes or the context is
+    /// terminated, whichever comes first.
+    pub fn spawn<R, F>(&self, runtime: &R, task: F)
+    where
+        R: Runtime,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (handle, registration) = AbortHandle::new_pair();
+        if self.is_terminated() {
+            handle.abort();
+        }
+        lock(&self.shared.tasks).push(handle);
+        runtime.spawn(async move {
+            let _ = Abortable::new(task, registration).await;
+        });
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.shared.terminated.load(Ordering::SeqCst)
+    }
+
+    /// Stops the context's background tasks and makes its sockets fail, then
+    /// waits for all of them to be dropped.
+    pub async fn terminate(&self) {
+        self.shared.terminated.store(true, Ordering::SeqCst);
+        lock(&self.shared.tasks)
+            .drain(..)
+            .for_each(|task| task.abort());
+        let sockets = std::mem::take(&mut *lock(&self.shared.sockets));
+        sockets
+            .iter()
+            .filter_map(Weak::upgrade)
+            .for_each(|socket| socket.wake());
+        drop(sockets);
+
+        future::poll_fn(|cx| {
+            let mut terminators = lock(&self.shared.terminators);
+            if self.shared.members.load(Ordering::SeqCst) == 0 {
+                return Poll::Ready(());
+            }
+            if !terminators.iter().any(|w| w.will_wake(cx.waker())) {
+                terminators.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    // Sockets that belong to the context hold on to one of these.
+    pub(crate) fn register(&self) -> Membership {
+        let waker = Arc::new(AtomicWaker::new());
+        let mut sockets = lock(&self.shared.sockets);
+        sockets.retain(|socket| socket.strong_count() > 0);
+        sockets.push(Arc::downgrade(&waker));
+        self.shared.members.fetch_add(1, Ordering::SeqCst);
+        Membership {
+            context: self.clone(),
+            waker,
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// A socket's link to the context it belongs to.
+#[derive(Debug)]
+pub(crate) struct Membership {
+    context: Context,
+    waker: Arc<AtomicWaker>,
+}
+
+impl Membership {
+    // Ready once the context has been terminated. Until then, the task is
+    // woken when it is.
+    pub(crate) fn poll_terminated(&self, cx: &mut TaskContext<'_>) -> Poll<()> {
+        self.waker.register(cx.waker());
+        if self.context.is_terminated() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    pub(crate) fn is_terminated(&self) -> bool {
+        self.context.is_terminated()
+    }
+}
+
+impl Clone for Membership {
+    // Copies of a socket belong to the context too.
+    fn clone(&self) -> Membership {
+        self.context.register()
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        let shared = &self.context.shared;
+        // Taking the lock first means a terminator can't miss the wakeup
+        // between counting the sockets and waiting.
+        let mut terminators = lock(&shared.terminators);
+        shared.members.fetch_sub(1, Ordering::SeqCst);
+        terminators.drain(..).for_each(Waker::wake);
+    }
+}
+
+// Runs `fut` unless the context is terminated first. Sockets outside any
+// context just run it.
+pub(crate) async fn unless_terminated<F: Future>(
+    membership: Option<&Membership>,
+    fut: F,
+) -> Result<F::Output, Terminated> {
+    let membership = match membership {
+        Some(membership) => membership,
+        None => return Ok(fut.await),
+    };
+    let terminated = future::poll_fn(|cx| membership.poll_terminated(cx));
+    pin_mut!(fut, terminated);
+    match future::select(fut, terminated).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(((), _)) => Err(Terminated),
+    }
+}
+
+// The context was terminated. Sockets turn this into `SocketError::Terminated`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Terminated;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::Message,
+        peer::ChannelPeer,
+        runtime::ThreadRuntime,
+        socket::SocketType,
+        sockets::{Pull, Push, SocketError},
+    };
+    use futures::{channel::oneshot, executor::block_on, join};
+
+    #[test]
+    fn test_terminate() {
+        block_on(async {
+            let context = Context::new();
+            let mut pull = Pull::with_context(&context);
+            let (local, _remote) = ChannelPeer::pair(SocketType::Pull, SocketType::Push);
+            pull.attach(local).unwrap();
+            let (tx, rx) = oneshot::channel::<()>();
+            context.spawn(&ThreadRuntime, async move {
+                future::pending::<()>().await;
+                let _ = tx.send(());
+            });
+
+            // Terminating wakes the receive that's waiting, and then waits
+            // for the socket to be dropped.
+            let recv = async move {
+                let result = pull.recv().await;
+                drop(pull);
+                result
+            };
+            let (result, ()) = join!(recv, context.terminate());
+            assert!(matches!(result, Err(SocketError::Terminated)));
+            assert!(rx.await.is_err());
+
+            let mut push: Push<ChannelPeer> = Push::with_context(&context);
+            assert!(matches!(
+                push.send(Message::from(&b"late"[..])).await,
+                Err(SocketError::Terminated)
+            ));
+        });
+    }
+}
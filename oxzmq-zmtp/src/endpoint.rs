@@ -0,0 +1,394 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use futures::io;
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    str::FromStr,
+};
+
+const TCP_SCHEME: &str = "tcp";
+const IPC_SCHEME: &str = "ipc";
+const INPROC_SCHEME: &str = "inproc";
+const WS_SCHEME: &str = "ws";
+const SCHEME_SEPARATOR: &str = "://";
+const SERVICE_PREFIX: &str = "service:";
+const WILDCARD: &str = "*";
+
+/// Where to find a peer.
+///
+/// Endpoints are parsed from the same URIs libzmq takes, and normalized on
+/// the way: the transport and host names are lowercased and IP addresses
+/// are written the standard way, so endpoints that mean the same thing
+/// compare equal and display the same.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// `tcp://host:port`. When binding, `*` can stand for every interface
+    /// and for any free port; they're stored as `0.0.0.0` and port 0.
+    Tcp { host: Host, port: u16 },
+
+    /// `tcp://service:name`, which a `Resolver` turns into addresses every
+    /// time the endpoint is connected to. That way, a peer that moves is
+    /// found again when reconnecting.
+    Service(String),
+
+    /// `ipc://path`, for peers on the same machine. There's no IPC transport
+    /// yet, so these can only be parsed.
+    Ipc(PathBuf),
+
+    /// `inproc://name`, for peers in the same process. These are found
+    /// through an `InprocContext` rather than by address.
+    Inproc(String),
+
+    /// `ws://host:port/path`, for ZMTP over WebSocket. The path defaults to
+    /// `/`.
+    Ws { host: Host, port: u16, path: String },
+}
+
+/// The host part of a `tcp://` or `ws://` endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Host {
+    Ip(IpAddr),
+
+    /// A name to look up with the system resolver, in lowercase.
+    Name(String),
+}
+
+impl Endpoint {
+    /// Looks up the addresses the endpoint currently points to. Host names
+    /// are looked up with the system resolver, which blocks.
+    pub async fn resolve<R: Resolver>(&self, resolver: &R) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            Endpoint::Tcp { host, port } | Endpoint::Ws { host, port, .. } => match host {
+                Host::Ip(ip) => Ok(vec![SocketAddr::new(*ip, *port)]),
+                Host::Name(name) => Ok((name.as_str(), *port).to_socket_addrs()?.collect()),
+            },
+            Endpoint::Service(name) => resolver.resolve(name).await,
+            Endpoint::Ipc(_) | Endpoint::Inproc(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only tcp:// and ws:// endpoints have addresses",
+            )),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = EndpointError;
+
+    fn from_str(endpoint: &str) -> Result<Endpoint, EndpointError> {
+        let (scheme, rest) = endpoint
+            .split_once(SCHEME_SEPARATOR)
+            .ok_or_else(|| EndpointError::MissingTransport(endpoint.to_string()))?;
+
+        match scheme.to_ascii_lowercase().as_str() {
+            TCP_SCHEME => {
+                if let Some(name) = rest.strip_prefix(SERVICE_PREFIX) {
+                    if name.is_empty() {
+                        return Err(EndpointError::EmptyName(endpoint.to_string()));
+                    }
+                    return Ok(Endpoint::Service(name.to_string()));
+                }
+                let (host, port) = parse_host_port(endpoint, rest)?;
+                Ok(Endpoint::Tcp { host, port })
+            }
+            IPC_SCHEME => {
+                if rest.is_empty() {
+                    return Err(EndpointError::EmptyName(endpoint.to_string()));
+                }
+                Ok(Endpoint::Ipc(PathBuf::from(rest)))
+            }
+            INPROC_SCHEME => {
+                if rest.is_empty() {
+                    return Err(EndpointError::EmptyName(endpoint.to_string()));
+                }
+                Ok(Endpoint::Inproc(rest.to_string()))
+            }
+            WS_SCHEME => {
+                let (addr, path) = match rest.find('/') {
+                    Some(idx) => rest.split_at(idx),
+                    None => (rest, "/"),
+                };
+                let (host, port) = parse_host_port(endpoint, addr)?;
+                Ok(Endpoint::Ws {
+                    host,
+                    port,
+                    path: path.to_string(),
+                })
+            }
+            _ => Err(EndpointError::UnsupportedTransport(scheme.to_string())),
+        }
+    }
+}
+
+// Splits `host:port`. IPv6 addresses can come in brackets, so that their
+// colons aren't mistaken for the one before the port, or bare.
+fn parse_host_port(endpoint: &str, addr: &str) -> Result<(Host, u16), EndpointError> {
+    let (host, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| EndpointError::MissingPort(endpoint.to_string()))?;
+    let port = match port {
+        WILDCARD => 0,
+        port => port
+            .parse()
+            .map_err(|_| EndpointError::InvalidPort(endpoint.to_string()))?,
+    };
+    let host = parse_host(host).ok_or_else(|| EndpointError::InvalidHost(endpoint.to_string()))?;
+    Ok((host, port))
+}
+
+fn parse_host(host: &str) -> Option<Host> {
+    if host == WILDCARD {
+        return Some(Host::Ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED)));
+    }
+    if let Some(bracketed) = host.strip_prefix('[') {
+        let ip = bracketed.strip_suffix(']')?.parse::<Ipv6Addr>().ok()?;
+        return Some(Host::Ip(IpAddr::V6(ip)));
+    }
+    if let Ok(ip) = host.parse() {
+        return Some(Host::Ip(ip));
+    }
+
+    let valid = !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+    if !valid {
+        return None;
+    }
+    Some(Host::Name(host.to_ascii_lowercase()))
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::Ip(IpAddr::V6(ip)) => write!(f, "[{}]", ip),
+            Host::Ip(ip) => write!(f, "{}", ip),
+            Host::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp { host, port } => {
+                write!(f, "{}{}{}:{}", TCP_SCHEME, SCHEME_SEPARATOR, host, port)
+            }
+            Endpoint::Service(name) => write!(
+                f,
+                "{}{}{}{}",
+                TCP_SCHEME, SCHEME_SEPARATOR, SERVICE_PREFIX, name
+            ),
+            Endpoint::Ipc(path) => {
+                write!(f, "{}{}{}", IPC_SCHEME, SCHEME_SEPARATOR, path.display())
+            }
+            Endpoint::Inproc(name) => write!(f, "{}{}{}", INPROC_SCHEME, SCHEME_SEPARATOR, name),
+            Endpoint::Ws { host, port, path } => write!(
+                f,
+                "{}{}{}:{}{}",
+                WS_SCHEME, SCHEME_SEPARATOR, host, port, path
+            ),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum EndpointError {
+    #[error("endpoint has no transport: {0}")]
+    MissingTransport(String),
+
+    #[error("unsupported transport: {0}")]
+    UnsupportedTransport(String),
+
+    #[error("endpoint has no port: {0}")]
+    MissingPort(String),
+
+    #[error("invalid port in endpoint: {0}")]
+    InvalidPort(String),
+
+    #[error("invalid host in endpoint: {0}")]
+    InvalidHost(String),
+
+    #[error("endpoint has an empty name: {0}")]
+    EmptyName(String),
+}
+
+/// Turns service names into addresses, for endpoints like
+/// `tcp://service:orders`. Implementations might ask Consul, look up DNS SRV
+/// records, or read a static map.
+pub trait Resolver {
+    fn resolve(&self, service: &str) -> impl Future<Output = io::Result<Vec<SocketAddr>>>;
+}
+
+/// A resolver backed by a fixed map from service names to addresses.
+#[derive(Debug, Clone, Default)]
+pub struct StaticResolver {
+    services: HashMap<String, Vec<SocketAddr>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> StaticResolver {
+        StaticResolver::default()
+    }
+
+    /// Points the service at the addresses, replacing any it had before.
+    pub fn insert(&mut self, service: &str, addrs: Vec<SocketAddr>) {
+        self.services.insert(service.to_string(), addrs);
+    }
+}
+
+impl Resolver for StaticResolver {
+    async fn resolve(&self, service: &str) -> io::Result<Vec<SocketAddr>> {
+        self.services.get(service).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("unknown service: {}", service),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_parse() {
+        let endpoint: Endpoint = "tcp://127.0.0.1:5555".parse().unwrap();
+        assert_eq!(
+            endpoint,
+            Endpoint::Tcp {
+                host: Host::Ip("127.0.0.1".parse().unwrap()),
+                port: 5555
+            }
+        );
+        assert_eq!(endpoint.to_string(), "tcp://127.0.0.1:5555");
+
+        let endpoint: Endpoint = "tcp://service:orders".parse().unwrap();
+        assert_eq!(endpoint, Endpoint::Service(String::from("orders")));
+        assert_eq!(endpoint.to_string(), "tcp://service:orders");
+
+        let endpoint: Endpoint = "ipc:///tmp/socket".parse().unwrap();
+        assert_eq!(endpoint, Endpoint::Ipc(PathBuf::from("/tmp/socket")));
+        assert_eq!(endpoint.to_string(), "ipc:///tmp/socket");
+
+        let endpoint: Endpoint = "inproc://workers".parse().unwrap();
+        assert_eq!(endpoint, Endpoint::Inproc(String::from("workers")));
+        assert_eq!(endpoint.to_string(), "inproc://workers");
+
+        let endpoint: Endpoint = "ws://example.com:80".parse().unwrap();
+        assert_eq!(
+            endpoint,
+            Endpoint::Ws {
+                host: Host::Name(String::from("example.com")),
+                port: 80,
+                path: String::from("/")
+            }
+        );
+        assert_eq!(endpoint.to_string(), "ws://example.com:80/");
+        let endpoint: Endpoint = "ws://[::1]:8080/zmq".parse().unwrap();
+        assert_eq!(endpoint.to_string(), "ws://[::1]:8080/zmq");
+    }
+
+    #[test]
+    fn test_normalize() {
+        for (endpoint, normalized) in [
+            ("TCP://Example.COM:5555", "tcp://example.com:5555"),
+            ("tcp://[0:0:0:0:0:0:0:1]:5555", "tcp://[::1]:5555"),
+            ("tcp://::1:5555", "tcp://[::1]:5555"),
+            ("Inproc://Workers", "inproc://Workers"),
+            ("tcp://*:*", "tcp://0.0.0.0:0"),
+            ("ws://*:8080", "ws://0.0.0.0:8080/"),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                endpoint.parse::<Endpoint>().unwrap().to_string(),
+                *normalized
+            );
+        }
+        assert_eq!(
+            "tcp://[::1]:5555".parse::<Endpoint>().unwrap(),
+            "tcp://::1:5555".parse::<Endpoint>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse = |endpoint: &str| endpoint.parse::<Endpoint>().unwrap_err();
+        assert!(matches!(
+            parse("127.0.0.1:5555"),
+            EndpointError::MissingTransport(_)
+        ));
+        assert!(matches!(
+            parse("udp://127.0.0.1:5555"),
+            EndpointError::UnsupportedTransport(transport) if transport == "udp"
+        ));
+        assert!(matches!(
+            parse("tcp://no port"),
+            EndpointError::MissingPort(_)
+        ));
+        assert!(matches!(
+            parse("ws://host/zmq"),
+            EndpointError::MissingPort(_)
+        ));
+        assert!(matches!(
+            parse("tcp://host:port"),
+            EndpointError::InvalidPort(_)
+        ));
+        assert!(matches!(
+            parse("tcp://host:65536"),
+            EndpointError::InvalidPort(_)
+        ));
+        assert!(matches!(
+            parse("tcp://:5555"),
+            EndpointError::InvalidHost(_)
+        ));
+        assert!(matches!(
+            parse("tcp://a b:5555"),
+            EndpointError::InvalidHost(_)
+        ));
+        assert!(matches!(
+            parse("tcp://[host]:5555"),
+            EndpointError::InvalidHost(_)
+        ));
+        assert!(matches!(
+            parse("tcp://service:"),
+            EndpointError::EmptyName(_)
+        ));
+        assert!(matches!(parse("inproc://"), EndpointError::EmptyName(_)));
+        assert!(matches!(parse("ipc://"), EndpointError::EmptyName(_)));
+    }
+
+    #[test]
+    fn test_resolve() {
+        block_on(async {
+            let addr: SocketAddr = "10.0.0.1:5555".parse().unwrap();
+            let mut resolver = StaticResolver::new();
+            resolver.insert("orders", vec![addr]);
+
+            let orders = Endpoint::Service(String::from("orders"));
+            assert_eq!(orders.resolve(&resolver).await.unwrap(), vec![addr]);
+
+            // Services are looked up again each time, so changes show up.
+            let moved: SocketAddr = "10.0.0.2:5555".parse().unwrap();
+            resolver.insert("orders", vec![moved]);
+            assert_eq!(orders.resolve(&resolver).await.unwrap(), vec![moved]);
+
+            let missing = Endpoint::Service(String::from("billing"));
+            assert!(missing.resolve(&resolver).await.is_err());
+
+            let local: Endpoint = "tcp://[::1]:5555".parse().unwrap();
+            assert_eq!(
+                local.resolve(&resolver).await.unwrap(),
+                vec!["[::1]:5555".parse().unwrap()]
+            );
+        });
+    }
+}
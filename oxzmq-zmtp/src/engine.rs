@@ -0,0 +1,267 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    heartbeat::Liveness,
+    message::Message,
+    options::ConnectionOptions,
+    peer::{Origin, Peer, PeerError},
+    runtime::Runtime,
+    socket::SocketType,
+    split::{self, ConnectionReader, ConnectionWriter, SharedLiveness},
+    Connection,
+};
+use futures::{
+    channel::{
+        mpsc::{self, TryRecvError},
+        oneshot,
+    },
+    io::{AsyncBufRead, AsyncRead, AsyncWrite},
+    SinkExt, StreamExt,
+};
+use std::task::{Context as TaskContext, Poll};
+
+// What the socket asks the writing task to do.
+#[derive(Debug)]
+enum Outgoing {
+    Message(Message),
+    Batch(Vec<Message>),
+    Heartbeat,
+    // Answered once everything before it has been written out.
+    Flush(oneshot::Sender<()>),
+    Close(oneshot::Sender<()>),
+}
+
+impl<S> Connection<S>
+where
+    S: AsyncBufRead + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Runs the connection on `runtime`, with one task reading from it and
+    /// another writing to it, and returns the peer to attach to a socket.
+    /// The socket and the tasks pass messages over queues as long as the
+    /// high-water marks in `options`, so the connection keeps reading while
+    /// the socket is busy, and sending only waits once the peer has fallen
+    /// that far behind.
+    pub fn spawn<R: Runtime>(self, runtime: &R, options: &ConnectionOptions) -> EnginePeer {
+        let remote_socket_type = self.remote_socket_type;
+        let routing_id = self.remote_routing_id.clone();
+        let origin = self.origin.clone();
+        let weight = self.remote_weight;
+        let (reader, writer) = self.split();
+        let liveness = writer.liveness();
+        let shared_liveness = writer.shared_liveness();
+
+        // Each sender gets one guaranteed slot on top of the buffer.
+        let (outgoing, outgoing_rx) = mpsc::channel(options.send_capacity() - 1);
+        let (incoming_tx, incoming) = mpsc::channel(options.recv_capacity() - 1);
+        runtime.spawn(read(reader, incoming_tx));
+        runtime.spawn(write(writer, outgoing_rx));
+
+        EnginePeer {
+            remote_socket_type,
+            routing_id,
+            origin,
+            weight,
+            outgoing,
+            incoming,
+            liveness,
+            shared_liveness,
+        }
+    }
+}
+
+// Hands everything received to the socket, up to and including the error
+// that ends the connection.
+async fn read<S>(
+    mut reader: ConnectionReader<S>,
+    mut incoming: mpsc::Sender<Result<Message, PeerError>>,
+) where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    loop {
+        let received = reader.recv_message().await;
+        let failed = received.is_err();
+        // Flushing would wait for the socket to make room again, which would
+        // leave the last slot of the queue unused.
+        if incoming.feed(received).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+// Writes what the socket hands over until it closes the peer, drops it, or
+// writing fails. The socket finds out about failures from the reading task,
+// which sees the stream fail too.
+async fn write<S>(mut writer: ConnectionWriter<S>, mut outgoing: mpsc::Receiver<Outgoing>)
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    while let Some(next) = outgoing.next().await {
+        let written = match next {
+            Outgoing::Message(msg) => writer.send_message(msg).await,
+            Outgoing::Batch(msgs) => writer.send_batch(msgs).await,
+            Outgoing::Heartbeat => writer.send_heartbeat().await,
+            Outgoing::Flush(done) => writer.flush().await.map(|()| {
+                let _ = done.send(());
+            }),
+            Outgoing::Close(done) => {
+                // Nothing can be sent once the peer is closed.
+                outgoing.close();
+                if writer.close().await.is_ok() {
+                    let _ = done.send(());
+                }
+                return;
+            }
+        };
+        if written.is_err() {
+            return;
+        }
+    }
+
+    // The socket dropped the peer. Closing our end makes the other end close
+    // too, which ends the reading task.
+    let _ = writer.close().await;
+}
+
+/// A connection running in tasks of its own, from `Connection::spawn`.
+#[derive(Debug)]
+pub struct EnginePeer {
+    remote_socket_type: SocketType,
+    routing_id: Option<Vec<u8>>,
+    origin: Option<Origin>,
+    weight: Option<u32>,
+    outgoing: mpsc::Sender<Outgoing>,
+    incoming: mpsc::Receiver<Result<Message, PeerError>>,
+    // The tasks keep the liveness up to date. This is a copy of it from the
+    // last time the peer was used, so that it can be lent out.
+    liveness: Liveness,
+    shared_liveness: SharedLiveness,
+}
+
+impl EnginePeer {
+    async fn push(&mut self, outgoing: Outgoing) -> Result<(), PeerError> {
+        self.outgoing
+            .feed(outgoing)
+            .await
+            .map_err(|_| PeerError::Disconnected)
+    }
+
+    // Waits for the writing task to get through everything before `outgoing`.
+    async fn push_and_wait<F>(&mut self, outgoing: F) -> Result<(), PeerError>
+    where
+        F: FnOnce(oneshot::Sender<()>) -> Outgoing,
+    {
+        let (done, finished) = oneshot::channel();
+        self.push(outgoing(done)).await?;
+        finished.await.map_err(|_| PeerError::Disconnected)
+    }
+
+    fn refresh_liveness(&mut self) {
+        self.liveness = split::lock(&self.shared_liveness).clone();
+    }
+}
+
+impl Peer for EnginePeer {
+    fn remote_socket_type(&self) -> SocketType {
+        self.remote_socket_type
+    }
+
+    fn routing_id(&self) -> Option<&[u8]> {
+        self.routing_id.as_deref()
+    }
+
+    fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+
+    fn weight(&self) -> Option<u32> {
+        self.weight
+    }
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), PeerError>> {
+        self.refresh_liveness();
+        self.outgoing
+            .poll_ready(cx)
+            .map_err(|_| PeerError::Disconnected)
+    }
+
+    async fn send_message(&mut self, msg: Message) -> Result<(), PeerError> {
+        self.push(Outgoing::Message(msg)).await
+    }
+
+    async fn send_batch(&mut self, msgs: Vec<Message>) -> Result<(), PeerError> {
+        self.push(Outgoing::Batch(msgs)).await
+    }
+
+    async fn recv_message(&mut self) -> Result<Message, PeerError> {
+        let received = self.incoming.next().await;
+        self.refresh_liveness();
+        received.unwrap_or(Err(PeerError::Disconnected))
+    }
+
+    // Only whole messages are queued, so this never cuts one short.
+    fn try_recv_message(&mut self) -> Option<Result<Message, PeerError>> {
+        match self.incoming.try_recv() {
+            Ok(received) => Some(received),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Closed) => Some(Err(PeerError::Disconnected)),
+        }
+    }
+
+    async fn send_heartbeat(&mut self) -> Result<(), PeerError> {
+        self.refresh_liveness();
+        self.push(Outgoing::Heartbeat).await
+    }
+
+    fn liveness(&self) -> Option<&Liveness> {
+        Some(&self.liveness)
+    }
+
+    async fn flush(&mut self) -> Result<(), PeerError> {
+        self.push_and_wait(Outgoing::Flush).await
+    }
+
+    async fn close(&mut self) -> Result<(), PeerError> {
+        self.push_and_wait(Outgoing::Close).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime::ThreadRuntime, testing, tests::connection_over};
+    use futures::{executor::block_on, AsyncWriteExt};
+
+    #[test]
+    fn test_engine() {
+        block_on(async {
+            let (a, b) = testing::duplex();
+            let mut peer = connection_over(a).spawn(&ThreadRuntime, &ConnectionOptions::new());
+            let mut remote = connection_over(b);
+
+            // Messages are read without the socket asking for them.
+            let to_peer = [
+                Message::from(vec![b"to".to_vec(), b"peer".to_vec()]),
+                Message::from(&b"again"[..]),
+            ];
+            for msg in to_peer.iter().cloned() {
+                remote.send_message(msg).await.unwrap();
+            }
+            remote.stream.close().await.unwrap();
+            for msg in to_peer.iter() {
+                assert_eq!(&peer.recv_message().await.unwrap(), msg);
+            }
+            assert!(matches!(peer.recv_message().await, Err(PeerError::Closed)));
+
+            let to_remote = Message::from(&b"to remote"[..]);
+            peer.send_message(to_remote.clone()).await.unwrap();
+            peer.close().await.unwrap();
+            assert_eq!(remote.recv_message().await.unwrap(), to_remote);
+            assert!(matches!(
+                peer.send_message(to_remote).await,
+                Err(PeerError::Disconnected)
+            ));
+        });
+    }
+}
@@ -0,0 +1,523 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    endpoint::Endpoint,
+    peer::{CloseReason, Origin},
+    socket::SocketType,
+};
+use futures::channel::mpsc as async_mpsc;
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    net::{self, ToSocketAddrs},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const HTTP_PREFIX: &str = "http://";
+
+// How long to wait for the webhook's server to connect and answer.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something that happened to one of a socket's peers, or to a connection
+/// being set up for one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SocketEvent {
+    time: SystemTime,
+    socket_type: SocketType,
+    kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    Attached {
+        peer_type: SocketType,
+        routing_id: Option<Vec<u8>>,
+    },
+
+    /// The peer was dropped, either because it failed or because the socket
+    /// let go of it.
+    Detached {
+        peer_type: SocketType,
+        routing_id: Option<Vec<u8>>,
+        reason: CloseReason,
+    },
+
+    /// We connected to `endpoint`. The handshake comes next.
+    Connected { endpoint: Endpoint },
+
+    /// Connecting to `endpoint` failed before the handshake could start.
+    /// Connecting again is up to the application, which reports another
+    /// `Connected` or `ConnectFailed` when it tries.
+    ConnectFailed {
+        endpoint: Endpoint,
+        reason: CloseReason,
+    },
+
+    /// A peer connected to us on `endpoint`. The handshake comes next.
+    Accepted { endpoint: Endpoint },
+
+    /// The handshake with a peer finished, and the connection is ready to be
+    /// attached to a socket.
+    HandshakeSucceeded {
+        origin: Origin,
+        peer_type: SocketType,
+    },
+
+    /// The handshake with a peer failed, and the connection was dropped.
+    /// The reason is `CloseReason::AuthDenied` if the peer's credentials
+    /// were refused, or ours were, and
+    /// `CloseReason::InvalidSocketCombination` if its socket type can't
+    /// talk to ours.
+    HandshakeFailed { origin: Origin, reason: CloseReason },
+
+    /// Connecting to `endpoint` was given up on for good, after a handshake
+    /// with it failed for `reason`; see `ConnectionOptions::reconnect_stop`.
+    ReconnectStopped {
+        endpoint: Endpoint,
+        reason: CloseReason,
+    },
+}
+
+impl SocketEvent {
+    pub(crate) fn new(socket_type: SocketType, kind: EventKind) -> SocketEvent {
+        SocketEvent {
+            time: SystemTime::now(),
+            socket_type,
+            kind,
+        }
+    }
+
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    /// The type of the socket the event happened on.
+    pub fn socket_type(&self) -> SocketType {
+        self.socket_type
+    }
+
+    pub fn kind(&self) -> &EventKind {
+        &self.kind
+    }
+
+    /// The event as a JSON object, with the time in seconds since the Unix
+    /// epoch, the endpoint, if any, the routing ID, if any, in hex, and the
+    /// reason a connection ended or failed, if it did.
+    pub fn to_json(&self) -> String {
+        let (event, endpoint, peer_type, routing_id, reason) = match &self.kind {
+            EventKind::Attached {
+                peer_type,
+                routing_id,
+            } => ("attached", None, Some(peer_type), Some(routing_id), None),
+            EventKind::Detached {
+                peer_type,
+                routing_id,
+                reason,
+            } => (
+                "detached",
+                None,
+                Some(peer_type),
+                Some(routing_id),
+                Some(reason),
+            ),
+            EventKind::Connected { endpoint } => ("connected", Some(endpoint), None, None, None),
+            EventKind::ConnectFailed { endpoint, reason } => {
+                ("connect_failed", Some(endpoint), None, None, Some(reason))
+            }
+            EventKind::Accepted { endpoint } => ("accepted", Some(endpoint), None, None, None),
+            EventKind::HandshakeSucceeded { origin, peer_type } => (
+                "handshake_succeeded",
+                Some(origin.endpoint()),
+                Some(peer_type),
+                None,
+                None,
+            ),
+            EventKind::HandshakeFailed { origin, reason } => (
+                "handshake_failed",
+                Some(origin.endpoint()),
+                None,
+                None,
+                Some(reason),
+            ),
+            EventKind::ReconnectStopped { endpoint, reason } => (
+                "reconnect_stopped",
+                Some(endpoint),
+                None,
+                None,
+                Some(reason),
+            ),
+        };
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let mut json = format!(
+            "{{\"time\":{:.3},\"socket\":\"{}\",\"event\":\"{}\"",
+            time,
+            <&str>::from(&self.socket_type),
+            event
+        );
+        if let Some(endpoint) = endpoint {
+            json += &format!(",\"endpoint\":\"{}\"", json_escape(&endpoint.to_string()));
+        }
+        if let Some(peer_type) = peer_type {
+            json += &format!(",\"peer\":\"{}\"", <&str>::from(peer_type));
+        }
+        match routing_id {
+            Some(Some(id)) => {
+                let hex: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+                json += &format!(",\"routing_id\":\"{}\"", hex);
+            }
+            Some(None) => json += ",\"routing_id\":null",
+            None => (),
+        }
+        if let Some(reason) = reason {
+            json += &format!(",\"reason\":\"{}\"", json_escape(&reason.to_string()));
+        }
+        json.push('}');
+        json
+    }
+}
+
+// Close reasons can carry text a peer sent us, so it has to be escaped before
+// it goes into a JSON string.
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// How many events an `EventSink` collects before handing them over, and
+/// how long it holds on to the first one at most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Batching {
+    max_events: usize,
+    max_delay: Duration,
+}
+
+impl Batching {
+    /// Up to 100 events, held for up to a second.
+    pub fn new() -> Batching {
+        Batching {
+            max_events: 100,
+            max_delay: Duration::from_secs(1),
+        }
+    }
+
+    /// Batches hold at least one event.
+    pub fn max_events(mut self, max_events: usize) -> Batching {
+        self.max_events = max_events.max(1);
+        self
+    }
+
+    pub fn max_delay(mut self, max_delay: Duration) -> Batching {
+        self.max_delay = max_delay;
+        self
+    }
+}
+
+impl Default for Batching {
+    fn default() -> Batching {
+        Batching::new()
+    }
+}
+
+/// Where sockets report their events. Events are batched and delivered on a
+/// thread of the sink's own, so a slow destination never holds a socket up.
+/// Clones deliver to the same destination, so one sink can serve many
+/// sockets; the thread stops once every clone has been dropped, after
+/// delivering what's left.
+#[derive(Clone)]
+pub struct EventSink {
+    tx: mpsc::Sender<SocketEvent>,
+}
+
+impl EventSink {
+    /// Hands each batch of events to `callback`.
+    pub fn callback<F>(batching: Batching, mut callback: F) -> EventSink
+    where
+        F: FnMut(Vec<SocketEvent>) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            while let Some(batch) = next_batch(&rx, batching) {
+                callback(batch);
+            }
+        });
+        EventSink { tx }
+    }
+
+    /// Delivers every event, as it happens, to the returned stream, for
+    /// tasks that want to watch a socket's connections come and go. The
+    /// stream ends once every clone of the sink has been dropped.
+    pub fn channel() -> (EventSink, async_mpsc::UnboundedReceiver<SocketEvent>) {
+        let (tx, rx) = async_mpsc::unbounded();
+        let sink = EventSink::callback(Batching::new().max_events(1), move |batch| {
+            for event in batch {
+                let _ = tx.unbounded_send(event);
+            }
+        });
+        (sink, rx)
+    }
+
+    /// POSTs each batch of events as a JSON array to an `http://host:port/path`
+    /// URL. Batches that can't be delivered are dropped.
+    pub fn webhook(url: &str, batching: Batching) -> Result<EventSink, EventSinkError> {
+        let webhook = Webhook::parse(url)?;
+        Ok(EventSink::callback(batching, move |batch| {
+            let _ = webhook.post(&batch);
+        }))
+    }
+
+    // Sinks whose thread has gone away silently drop events.
+    pub(crate) fn emit(&self, event: SocketEvent) {
+        let _ = self.tx.send(event);
+    }
+}
+
+impl fmt::Debug for EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventSink")
+    }
+}
+
+// Waits for the first event, then collects more until the batch is full or
+// has waited long enough. Returns `None` once every sender is gone and
+// nothing is left.
+fn next_batch(rx: &mpsc::Receiver<SocketEvent>, batching: Batching) -> Option<Vec<SocketEvent>> {
+    let mut batch = vec![rx.recv().ok()?];
+    let deadline = Instant::now() + batching.max_delay;
+    while batch.len() < batching.max_events {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(timeout) {
+            Ok(event) => batch.push(event),
+            Err(_) => break,
+        }
+    }
+    Some(batch)
+}
+
+#[derive(Debug)]
+struct Webhook {
+    host: String,
+    path: String,
+}
+
+impl Webhook {
+    fn parse(url: &str) -> Result<Webhook, EventSinkError> {
+        let rest = url
+            .strip_prefix(HTTP_PREFIX)
+            .ok_or_else(|| EventSinkError::InvalidUrl(url.to_string()))?;
+        let (host, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+        if host.is_empty() || host.contains(char::is_whitespace) {
+            return Err(EventSinkError::InvalidUrl(url.to_string()));
+        }
+
+        // The port is optional in URLs, unlike in endpoints.
+        let host = match host.rsplit_once(':') {
+            Some((_, port)) if !port.contains(']') => host.to_string(),
+            _ => format!("{}:80", host),
+        };
+        Ok(Webhook {
+            host,
+            path: path.to_string(),
+        })
+    }
+
+    fn post(&self, batch: &[SocketEvent]) -> io::Result<()> {
+        let events: Vec<String> = batch.iter().map(SocketEvent::to_json).collect();
+        let body = format!("[{}]", events.join(","));
+
+        let addr = self
+            .host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "webhook host not found"))?;
+        let mut stream = net::TcpStream::connect_timeout(&addr, HTTP_TIMEOUT)?;
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        )?;
+        stream.flush()?;
+
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "webhook answered {}",
+                status.trim()
+            ))),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum EventSinkError {
+    #[error("invalid webhook URL, expected http://host[:port]/path: {0}")]
+    InvalidUrl(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{message::Message, peer::ChannelPeer, sockets::Router};
+    use futures::executor::block_on;
+    use std::io::Read;
+
+    fn attached(peer_type: SocketType) -> SocketEvent {
+        SocketEvent::new(
+            SocketType::Dealer,
+            EventKind::Attached {
+                peer_type,
+                routing_id: Some(b"id".to_vec()),
+            },
+        )
+    }
+
+    #[test]
+    fn test_callback() {
+        let (tx, rx) = mpsc::channel();
+        let sink = EventSink::callback(Batching::new().max_events(2), move |batch| {
+            tx.send(batch).unwrap();
+        });
+
+        let mut router = Router::new();
+        router.set_event_sink(sink.clone());
+        let (mut a, _a_remote) = ChannelPeer::pair(SocketType::Router, SocketType::Dealer);
+        let (mut b, b_remote) = ChannelPeer::pair(SocketType::Router, SocketType::Dealer);
+        a.routing_id = Some(b"a".to_vec());
+        b.routing_id = Some(b"b".to_vec());
+        let a_id = router.attach(a).unwrap();
+        let b_id = router.attach(b).unwrap();
+
+        let batch = rx.recv().unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!(
+            batch[0].kind(),
+            &EventKind::Attached {
+                peer_type: SocketType::Dealer,
+                routing_id: Some(a_id),
+            }
+        );
+
+        // A peer failing is reported too, and dropping every sink delivers
+        // what's left without waiting for the batch to fill up.
+        drop(b_remote);
+        block_on(router.send(Message::from(vec![b_id.clone(), b"hi".to_vec()]))).ok();
+        drop(router);
+        drop(sink);
+        let batch = rx.recv().unwrap();
+        assert_eq!(
+            batch,
+            vec![SocketEvent {
+                time: batch[0].time(),
+                socket_type: SocketType::Router,
+                kind: EventKind::Detached {
+                    peer_type: SocketType::Dealer,
+                    routing_id: Some(b_id),
+                    reason: CloseReason::Disconnected,
+                },
+            }]
+        );
+        assert!(rx.recv().is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        let event = SocketEvent {
+            time: UNIX_EPOCH + Duration::from_millis(1500),
+            ..attached(SocketType::Router)
+        };
+        assert_eq!(
+            event.to_json(),
+            "{\"time\":1.500,\"socket\":\"DEALER\",\"event\":\"attached\",\"peer\":\"ROUTER\",\"routing_id\":\"6964\"}"
+        );
+
+        let event = SocketEvent {
+            time: UNIX_EPOCH,
+            socket_type: SocketType::Dealer,
+            kind: EventKind::Detached {
+                peer_type: SocketType::Router,
+                routing_id: None,
+                reason: CloseReason::PeerError(String::from("no \"thanks\"")),
+            },
+        };
+        assert_eq!(
+            event.to_json(),
+            "{\"time\":0.000,\"socket\":\"DEALER\",\"event\":\"detached\",\"peer\":\"ROUTER\",\"routing_id\":null,\"reason\":\"peer error: no \\\"thanks\\\"\"}"
+        );
+
+        let event = SocketEvent {
+            time: UNIX_EPOCH,
+            socket_type: SocketType::Dealer,
+            kind: EventKind::HandshakeFailed {
+                origin: Origin::Connected("tcp://127.0.0.1:5555".parse().unwrap()),
+                reason: CloseReason::AuthDenied,
+            },
+        };
+        assert_eq!(
+            event.to_json(),
+            "{\"time\":0.000,\"socket\":\"DEALER\",\"event\":\"handshake_failed\",\"endpoint\":\"tcp://127.0.0.1:5555\",\"reason\":\"authentication denied\"}"
+        );
+    }
+
+    #[test]
+    fn test_webhook() {
+        assert!(matches!(
+            EventSink::webhook("https://example.com/events", Batching::new()),
+            Err(EventSinkError::InvalidUrl(_))
+        ));
+        assert_eq!(
+            Webhook::parse("http://example.com").unwrap().host,
+            "example.com:80"
+        );
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        let sink = EventSink::webhook(&url, Batching::new()).unwrap();
+        let event = attached(SocketType::Router);
+        sink.emit(event.clone());
+        drop(sink);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"]") {
+            let read = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..read]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .unwrap();
+
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /events HTTP/1.1\r\n"));
+        assert!(request.ends_with(&format!("[{}]", event.to_json())));
+    }
+}
@@ -2,8 +2,9 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use futures::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite};
-use std::convert::TryFrom;
+use crate::{codec::ZmtpCodec, pool::MessagePool};
+use futures::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use std::{convert::TryFrom, fmt, io::IoSlice, sync::Arc};
 
 const MORE_FLAG_IDX: u8 = 0;
 const LONG_FLAG_IDX: u8 = 1;
@@ -11,198 +12,424 @@ const KIND_FLAG_IDX: u8 = 2;
 
 const SHORT_SIZE_LEN: usize = 1;
 const LONG_SIZE_LEN: usize = 8;
+const COMMAND_NAME_SIZE_LEN: usize = 1;
 
-#[derive(Clone, Debug)]
+// A frame's length comes from the peer, so it's only trusted this far when
+// allocating up front. Longer frames grow their buffer as the data arrives.
+pub(crate) const MAX_PREALLOC: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Frame {
     Command(CommandFrame),
     Message(MessageFrame),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct CommandFrame {
     pub(crate) name: String,
     pub(crate) data: Vec<u8>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MessageFrame {
+    pub(crate) more: bool,
+    pub(crate) data: Vec<u8>,
+}
+
+/// The flags and length that start every frame on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
     more: bool,
-    data: Vec<u8>,
+    command: bool,
+    len: u64,
 }
 
-impl Frame {
-    pub(crate) fn new_command(cmd_name: String, data: Vec<u8>) -> Frame {
-        Frame::Command(CommandFrame {
-            name: cmd_name,
-            data,
-        })
+impl FrameHeader {
+    pub(crate) fn message(more: bool, len: u64) -> FrameHeader {
+        FrameHeader {
+            more,
+            command: false,
+            len,
+        }
     }
 
-    pub(crate) fn new_message(more: bool, data: Vec<u8>) -> Frame {
-        Frame::Message(MessageFrame { more, data })
+    pub(crate) fn command(len: u64) -> FrameHeader {
+        FrameHeader {
+            more: false,
+            command: true,
+            len,
+        }
     }
 
-    pub(crate) fn data(&self) -> &[u8] {
-        match self {
-            Frame::Command(cmd) => cmd.data.as_slice(),
-            Frame::Message(msg) => msg.data.as_slice(),
-        }
+    /// Whether more frames of the same message follow this one.
+    pub fn more(&self) -> bool {
+        self.more
     }
 
-    /// This creates a "fatal error" command from an error message, truncating
-    /// the length of the message at 255 characters.
-    pub(crate) fn new_fatal_error(msg: &str) -> Frame {
-        // Truncate the message to a max of 255 bytes.
-        let mut final_byte_idx = 0_usize;
-        for c in msg.chars() {
-            if final_byte_idx + c.len_utf8() > u8::max_value() as usize {
-                break;
-            }
-            final_byte_idx += c.len_utf8();
-        }
+    pub fn is_command(&self) -> bool {
+        self.command
+    }
+
+    /// How many bytes of body follow the header. For commands, this
+    /// includes the command's name.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
 
-        // Technically indexing like this can panic if the index isn't on the
-        // boundary of a `char`, but it never should if we did the previous
-        // truncation correctly. This is why we iterated over `char`s.
-        let msg = &msg[..final_byte_idx];
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
 
-        // This should never fail because we just
-        // truncated the value to under u8::max_value() bytes.
-        let msg_size = u8::try_from(msg.len()).unwrap();
+    // Parses a header from the start of `buf`, returning it along with how
+    // many bytes it took up, or `None` if `buf` doesn't hold all of it yet.
+    pub(crate) fn parse(buf: &[u8]) -> Result<Option<(FrameHeader, usize)>, FrameParseError> {
+        let flag_bits = match buf.first() {
+            Some(&flag_bits) => flag_bits,
+            None => return Ok(None),
+        };
+        let (more, long, command) = parse_flags(flag_bits)?;
 
-        let mut data: Vec<u8> = Vec::with_capacity(1 + msg.len());
-        data.push(msg_size);
-        data.extend_from_slice(msg.as_bytes());
+        let size_len = if long { LONG_SIZE_LEN } else { SHORT_SIZE_LEN };
+        let len_buf = match buf.get(1..1 + size_len) {
+            Some(len_buf) => len_buf,
+            None => return Ok(None),
+        };
+        let len = if long {
+            u64::from_be_bytes(<[u8; LONG_SIZE_LEN]>::try_from(len_buf).unwrap())
+        } else {
+            len_buf[0] as u64
+        };
 
-        Frame::Command(CommandFrame {
-            name: "ERROR".to_string(),
-            data,
-        })
+        Ok(Some((FrameHeader { more, command, len }, 1 + size_len)))
     }
 
-    pub(crate) async fn read_new<R: AsyncBufRead + Unpin>(
-        stream: &mut R,
-    ) -> Result<Frame, FrameParseError> {
-        let mut flags_buf = [0_u8; 1];
-        stream.read_exact(&mut flags_buf).await?;
-        let flag_bits = u8::from_be_bytes(flags_buf);
+    pub(crate) async fn write_to<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(1 + LONG_SIZE_LEN);
+        self.encode(&mut buf);
+        stream.write_all(&buf).await
+    }
 
-        let more_frames = get_bit(flag_bits, MORE_FLAG_IDX);
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let mut flags = 0_u8;
+        if self.more {
+            flags = set_bit(flags, MORE_FLAG_IDX);
+        }
+        if self.command {
+            flags = set_bit(flags, KIND_FLAG_IDX);
+        }
 
-        let long = get_bit(flag_bits, LONG_FLAG_IDX);
+        if self.len > u8::MAX as u64 {
+            buf.push(set_bit(flags, LONG_FLAG_IDX));
+            buf.extend_from_slice(&self.len.to_be_bytes());
+        } else {
+            buf.push(flags);
+            buf.push(self.len as u8);
+        }
+    }
+}
 
-        let kind = match get_bit(flag_bits, KIND_FLAG_IDX) {
-            true => FrameKind::Command,
-            false => FrameKind::Message,
-        };
+// Reads frames a piece at a time, keeping what it has read on itself rather
+// than in the future doing the reading. Each piece is copied out of the
+// stream's buffer and consumed with nothing awaited in between, so a read
+// that's dropped part of the way through a frame loses nothing, and the next
+// one carries on from where it stopped.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FrameReader {
+    // The bytes of a header that only partly arrived.
+    header_buf: Vec<u8>,
+    // The header of the frame being read, once it's all in, and as much of
+    // its body as has been read.
+    header: Option<FrameHeader>,
+    body: Option<Vec<u8>>,
+}
+
+impl FrameReader {
+    // Reads the next frame's header, or returns the header already read if
+    // its body hasn't been yet. Returns `None` if the stream ends cleanly
+    // before the frame starts.
+    pub(crate) async fn read_header<R: AsyncBufRead + Unpin>(
+        &mut self,
+        stream: &mut R,
+    ) -> Result<Option<FrameHeader>, FrameParseError> {
+        while self.header.is_none() {
+            let buf = stream.fill_buf().await?;
+            if buf.is_empty() {
+                if self.header_buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
 
-        // Bits 3–7 inclusive shall not be set (according to the spec).
-        for bit in 3..8 {
-            if get_bit(flag_bits, bit) {
-                return Err(FrameParseError::Flags);
+            if self.header_buf.is_empty() {
+                if let Some((header, used)) = FrameHeader::parse(buf)? {
+                    stream.consume_unpin(used);
+                    self.header = Some(header);
+                    break;
+                }
+            }
+
+            // Only as much is taken as the header still needs, which the
+            // flags byte gives once it's in.
+            let needed = match self.header_buf.first() {
+                Some(&flag_bits) => header_len(flag_bits)? - self.header_buf.len(),
+                None => 1,
+            };
+            let take = needed.min(buf.len());
+            self.header_buf.extend_from_slice(&buf[..take]);
+            stream.consume_unpin(take);
+            if let Some((header, _)) = FrameHeader::parse(&self.header_buf)? {
+                self.header_buf.clear();
+                self.header = Some(header);
             }
         }
+        Ok(self.header)
+    }
 
-        let data_len: u64 = if long {
-            let mut len_buf = [0_u8; LONG_SIZE_LEN];
-            stream.read_exact(&mut len_buf).await?;
-            u64::from_be_bytes(len_buf)
-        } else {
-            let mut len_buf = [0_u8; SHORT_SIZE_LEN];
-            stream.read_exact(&mut len_buf).await?;
-            u8::from_be_bytes(len_buf) as u64
-        };
-        let data_len =
-            usize::try_from(data_len).map_err(|e| FrameParseError::MessageTooLarge(e))?;
+    // Reads the body of the frame whose header was read last. A message
+    // frame's data goes into a buffer from `pool`, if there is one.
+    pub(crate) async fn read_body<R: AsyncBufRead + Unpin>(
+        &mut self,
+        stream: &mut R,
+        pool: Option<&MessagePool>,
+    ) -> Result<Frame, FrameParseError> {
+        let header = self.header.expect("frame body read before its header");
+        let data_len = usize::try_from(header.len).map_err(FrameParseError::MessageTooLarge)?;
+        let body = self.body.get_or_insert_with(|| {
+            let prealloc = data_len.min(MAX_PREALLOC);
+            let mut buf = match (header.command, pool) {
+                (false, Some(pool)) => pool.take(prealloc),
+                _ => Vec::new(),
+            };
+            buf.reserve(prealloc);
+            buf
+        });
+
+        // Only this frame's body is read, so whatever follows it is left on
+        // the stream for the next frame.
+        while body.len() < data_len {
+            let buf = stream.fill_buf().await?;
+            if buf.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let take = buf.len().min(data_len - body.len());
+            body.extend_from_slice(&buf[..take]);
+            stream.consume_unpin(take);
+        }
 
-        let frame = match kind {
-            FrameKind::Command => {
-                if more_frames {
-                    return Err(FrameParseError::MultipartCommand);
-                }
+        let data = self.body.take().unwrap_or_default();
+        self.header = None;
+        match header.command {
+            true => Frame::command_from_body(data),
+            false => Ok(Frame::Message(MessageFrame {
+                more: header.more,
+                data,
+            })),
+        }
+    }
 
-                // Read the command name.
-                let mut command_name_bytes = Vec::<u8>::with_capacity(10);
-                stream.read_until(0x00, &mut command_name_bytes).await?;
+    // Hands over the frame whose header was read last, along with as much of
+    // its body as has been read, for the rest to be read by someone else.
+    pub(crate) fn take_frame(&mut self) -> (FrameHeader, Vec<u8>) {
+        let header = self.header.take().expect("frame taken before its header");
+        (header, self.body.take().unwrap_or_default())
+    }
 
-                // Get rid of the null delimiter.
-                command_name_bytes.pop();
-                let command_name = String::from_utf8(command_name_bytes)?;
+    // Reads a whole frame, which has to be there: the stream ending before
+    // it starts is an error too.
+    pub(crate) async fn read_frame<R: AsyncBufRead + Unpin>(
+        &mut self,
+        stream: &mut R,
+        pool: Option<&MessagePool>,
+    ) -> Result<Frame, FrameParseError> {
+        if self.read_header(stream).await?.is_none() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.read_body(stream, pool).await
+    }
+}
 
-                let mut command_data = Vec::new();
-                stream.read_to_end(&mut command_data).await?;
+// How many bytes a header takes up, given its flags byte.
+fn header_len(flag_bits: u8) -> Result<usize, FrameParseError> {
+    let (_, long, _) = parse_flags(flag_bits)?;
+    match long {
+        true => Ok(1 + LONG_SIZE_LEN),
+        false => Ok(1 + SHORT_SIZE_LEN),
+    }
+}
 
-                Frame::Command(CommandFrame {
-                    name: command_name,
-                    data: command_data,
-                })
-            }
-            FrameKind::Message => {
-                let mut message_data = Vec::with_capacity(data_len);
-                stream.read_to_end(&mut message_data).await?;
-                Frame::Message(MessageFrame {
-                    more: more_frames,
-                    data: message_data,
-                })
-            }
-        };
+// Splits the flags byte into its MORE, LONG and COMMAND bits.
+fn parse_flags(flag_bits: u8) -> Result<(bool, bool, bool), FrameParseError> {
+    let more = get_bit(flag_bits, MORE_FLAG_IDX);
+    let long = get_bit(flag_bits, LONG_FLAG_IDX);
+    let command = get_bit(flag_bits, KIND_FLAG_IDX);
 
-        Ok(frame)
+    // Bits 3–7 inclusive shall not be set (according to the spec).
+    for bit in 3..8 {
+        if get_bit(flag_bits, bit) {
+            return Err(FrameParseError::Flags);
+        }
+    }
+    if command && more {
+        return Err(FrameParseError::MultipartCommand);
     }
 
-    pub(crate) async fn write_to<W: AsyncWrite + Unpin>(
-        &self,
-        stream: &mut W,
-    ) -> Result<(), io::Error> {
-        let mut flags = 0_u8;
+    Ok((more, long, command))
+}
+
+impl Frame {
+    pub fn new_command(cmd_name: String, data: Vec<u8>) -> Frame {
+        Frame::Command(CommandFrame {
+            name: cmd_name,
+            data,
+        })
+    }
+
+    pub fn new_message(more: bool, data: Vec<u8>) -> Frame {
+        Frame::Message(MessageFrame { more, data })
+    }
+
+    pub fn data(&self) -> &[u8] {
         match self {
-            Frame::Message(msg_frame) if msg_frame.more => flags = set_bit(flags, MORE_FLAG_IDX),
-            _ => (),
+            Frame::Command(cmd) => cmd.data.as_slice(),
+            Frame::Message(msg) => msg.data.as_slice(),
         }
+    }
 
-        if self.data().len() > u8::max_value() as usize {
-            flags = set_bit(flags, LONG_FLAG_IDX);
+    /// The command's name, or `None` for message frames.
+    pub fn command_name(&self) -> Option<&str> {
+        match self {
+            Frame::Command(cmd) => Some(&cmd.name),
+            Frame::Message(_) => None,
         }
-        if let Frame::Command(_) = self {
-            flags = set_bit(flags, KIND_FLAG_IDX);
+    }
+
+    /// Whether more frames of the same message follow this one. Always
+    /// `false` for commands.
+    pub fn more(&self) -> bool {
+        match self {
+            Frame::Command(_) => false,
+            Frame::Message(msg) => msg.more,
         }
-        let flags = flags; // make immutable
+    }
 
-        // Account for the length of the command name, which technically goes in the
-        // "data" field for the frame.
-        let total_data_len = if let Frame::Command(cmd) = self {
-            self.data().len() + cmd.name.len()
-        } else {
-            self.data().len()
+    // Reads a whole frame, however long it says it is. Connections read
+    // through a `FrameReader` instead, with their limits.
+    #[cfg(any(test, fuzzing))]
+    pub(crate) async fn read_new<R: AsyncBufRead + Unpin>(
+        stream: &mut R,
+    ) -> Result<Frame, FrameParseError> {
+        FrameReader::default().read_frame(stream, None).await
+    }
+
+    // A command's body is its name behind a one-byte size, and then its
+    // data.
+    pub(crate) fn command_from_body(mut body: Vec<u8>) -> Result<Frame, FrameParseError> {
+        let name_len = match body.first() {
+            Some(&name_len) => usize::from(name_len),
+            None => return Err(FrameParseError::CommandNameSize),
         };
+        if body.len() < COMMAND_NAME_SIZE_LEN + name_len {
+            return Err(FrameParseError::CommandNameSize);
+        }
 
-        // The length can either be encoded as 1 or 8 bytes.
-        let length_bytes_len = if total_data_len > u8::max_value() as usize {
-            LONG_SIZE_LEN
-        } else {
-            SHORT_SIZE_LEN
+        let data = body.split_off(COMMAND_NAME_SIZE_LEN + name_len);
+        body.drain(..COMMAND_NAME_SIZE_LEN);
+        let name = String::from_utf8(body)?;
+        Ok(Frame::Command(CommandFrame { name, data }))
+    }
+
+    // The length of the frame's body on the wire, which for commands takes
+    // in their name.
+    pub(crate) fn body_len(&self) -> usize {
+        match self {
+            Frame::Command(cmd) => COMMAND_NAME_SIZE_LEN + cmd.name.len() + cmd.data.len(),
+            Frame::Message(msg) => msg.data.len(),
+        }
+    }
+
+    // Appends the frame as it goes on the wire to `buf`.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let header = match self {
+            Frame::Command(_) => FrameHeader::command(self.body_len() as u64),
+            Frame::Message(msg) => FrameHeader::message(msg.more, self.body_len() as u64),
         };
-        let length_bytes = &self.data().len().to_be_bytes()[..length_bytes_len];
+        header.encode(buf);
+        if let Frame::Command(cmd) = self {
+            buf.push(cmd.name.len() as u8);
+            buf.extend_from_slice(cmd.name.as_bytes());
+        }
+        buf.extend_from_slice(self.data());
+    }
 
+    pub(crate) async fn write_to<W: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut W,
+    ) -> Result<(), io::Error> {
+        let header = match self {
+            Frame::Command(_) => FrameHeader::command(self.body_len() as u64),
+            Frame::Message(msg) => FrameHeader::message(msg.more, self.body_len() as u64),
+        };
 
         // Create a buffer to hold some small intermediate writes. We probably need no
-        // more than 20 bytes because flags=1, length<=8, and name is usually <= 5.
+        // more than 20 bytes because flags=1, length<=8, and name is usually <= 10.
         let mut pre_data_buf: Vec<u8> = Vec::with_capacity(20);
+        header.encode(&mut pre_data_buf);
 
-        pre_data_buf.push(flags);
-        pre_data_buf.extend_from_slice(length_bytes);
-
-        // If the frame is a command, send the command name and a null separator
-        // before the command data.
+        // If the frame is a command, its name goes before the command data.
         if let Frame::Command(cmd) = self {
-            pre_data_buf.extend_from_slice(&cmd.name.as_bytes());
-            pre_data_buf.push(0x00);
+            pre_data_buf.push(cmd.name.len() as u8);
+            pre_data_buf.extend_from_slice(cmd.name.as_bytes());
         }
 
-        io::copy(pre_data_buf.as_slice(), stream).await?;
-        io::copy(self.data(), stream).await?;
+        let mut bufs = [IoSlice::new(&pre_data_buf), IoSlice::new(self.data())];
+        write_all_vectored(stream, &mut bufs).await
+    }
+}
 
-        Ok(())
+// Writes all of `bufs`, in a single call if the stream takes them all at
+// once. Streams without vectored writes only take the first non-empty buffer
+// each time, which is no worse than writing the buffers one by one.
+async fn write_all_vectored<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    mut bufs: &mut [IoSlice<'_>],
+) -> io::Result<()> {
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        let written = stream.write_vectored(bufs).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut bufs, written);
+    }
+    Ok(())
+}
+
+/// Which way a frame passed to a frame hook was going.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    Sent,
+    Received,
+}
+
+type HookFn = dyn Fn(FrameDirection, &[u8]) + Send + Sync;
+
+// Called with the bytes of each frame a connection sends or receives.
+#[derive(Clone)]
+pub(crate) struct FrameHook(pub(crate) Arc<HookFn>);
+
+impl FrameHook {
+    // Received frames are encoded again, since only their parsed form is
+    // kept.
+    pub(crate) fn received(&self, frame: &Frame) {
+        let mut buf = Vec::new();
+        ZmtpCodec::new().encode(frame, &mut buf);
+        (self.0)(FrameDirection::Received, &buf);
+    }
+}
+
+impl fmt::Debug for FrameHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FrameHook")
     }
 }
 
@@ -240,23 +467,190 @@ pub enum FrameParseError {
 
     #[error("msg size indicates msg is too large to fit in memory")]
     MessageTooLarge(std::num::TryFromIntError),
-}
 
-#[derive(Clone, Debug)]
-pub(crate) enum FrameKind {
-    Command,
-    Message,
+    #[error("frame of {0} bytes is longer than allowed")]
+    FrameTooLong(u64),
+
+    #[error("command name size runs past the end of the command")]
+    CommandNameSize,
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test_get_bit() {
-        let u = 0b_1001_0001;
-        assert_eq!(get_bit(n, 0), true);
-        assert_eq!(get_bit(n, 1), false);
-        assert_eq!(get_bit(n, 4), true);
-        assert_eq!(get_bit(n, 7), true);
-        assert_eq!(get_bit(n, 8), false);
+        let n = 0b_1001_0001;
+        assert!(get_bit(n, 0));
+        assert!(!get_bit(n, 1));
+        assert!(get_bit(n, 4));
+        assert!(get_bit(n, 7));
+        assert!(!get_bit(n, 8));
+    }
+
+    // Records the size of every write, taking at most `limit` bytes at once.
+    struct Recorder {
+        writes: Vec<usize>,
+        data: Vec<u8>,
+        limit: usize,
+    }
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> std::task::Poll<io::Result<usize>> {
+            let mut written = 0;
+            for buf in bufs {
+                let take = buf.len().min(self.limit - written);
+                self.data.extend_from_slice(&buf[..take]);
+                written += take;
+            }
+            self.writes.push(written);
+            std::task::Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_vectored_write() {
+        futures::executor::block_on(async {
+            let frame = Frame::new_message(false, vec![1; 300]);
+            let mut expected = Vec::new();
+            FrameHeader::message(false, 300)
+                .write_to(&mut expected)
+                .await
+                .unwrap();
+            expected.extend_from_slice(&[1; 300]);
+
+            // The header and data go out together.
+            let mut recorder = Recorder {
+                writes: Vec::new(),
+                data: Vec::new(),
+                limit: usize::MAX,
+            };
+            frame.write_to(&mut recorder).await.unwrap();
+            assert_eq!(recorder.writes, vec![309]);
+            assert_eq!(recorder.data, expected);
+
+            // Short writes pick up where they left off, even in the middle of
+            // a buffer.
+            let mut recorder = Recorder {
+                writes: Vec::new(),
+                data: Vec::new(),
+                limit: 100,
+            };
+            frame.write_to(&mut recorder).await.unwrap();
+            assert_eq!(recorder.writes, vec![100, 100, 100, 9]);
+            assert_eq!(recorder.data, expected);
+        });
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        futures::executor::block_on(async {
+            for header in [
+                FrameHeader::message(true, 0),
+                FrameHeader::message(false, 255),
+                FrameHeader::message(false, 256),
+                FrameHeader::message(true, 5 << 32),
+            ]
+            .iter()
+            {
+                let mut buf = Vec::new();
+                header.write_to(&mut buf).await.unwrap();
+                let expected_len = if header.len() > 255 { 9 } else { 2 };
+                assert_eq!(buf.len(), expected_len);
+                assert_eq!(
+                    FrameHeader::parse(&buf).unwrap(),
+                    Some((*header, expected_len))
+                );
+            }
+
+            // Commands can't have more frames after them.
+            assert!(matches!(
+                FrameHeader::parse(&[0b0000_0101, 0]),
+                Err(FrameParseError::MultipartCommand)
+            ));
+        });
+    }
+
+    #[test]
+    fn test_untrusted_length() {
+        futures::executor::block_on(async {
+            // A long message frame that claims to hold 2^64 - 1 bytes. Reading
+            // it mustn't try to allocate all of that before any data arrives.
+            let mut buf: &[u8] = &[
+                0b0000_0010,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+                1,
+            ];
+            assert!(matches!(
+                Frame::read_new(&mut buf).await,
+                Err(FrameParseError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+            ));
+        });
+    }
+
+    #[test]
+    fn test_pipelined_frames() {
+        futures::executor::block_on(async {
+            let frames = [
+                Frame::new_command("READY".to_string(), vec![0; 300]),
+                Frame::new_message(true, b"first".to_vec()),
+                Frame::new_message(false, Vec::new()),
+                Frame::new_command("PING".to_string(), Vec::new()),
+            ];
+            let mut buf = Vec::new();
+            for frame in frames.iter() {
+                frame.write_to(&mut buf).await.unwrap();
+            }
+            assert_eq!(&buf[..2], &[0b0000_0110, 0][..]);
+            assert_eq!(&buf[9..15], &b"\x05READY"[..]);
+
+            // Each frame stops at its own length.
+            let mut stream = buf.as_slice();
+            for frame in frames.iter() {
+                assert_eq!(&Frame::read_new(&mut stream).await.unwrap(), frame);
+            }
+            assert!(stream.is_empty());
+
+            // The name has to fit within the command.
+            let mut stream: &[u8] = &[0b0000_0100, 3, 5, b'P', b'I'];
+            assert!(matches!(
+                Frame::read_new(&mut stream).await,
+                Err(FrameParseError::CommandNameSize)
+            ));
+        });
     }
 }
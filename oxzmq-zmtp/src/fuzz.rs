@@ -0,0 +1,27 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Entry points for the targets in `fuzz/`, which can't reach the parsers
+//! otherwise. This module only exists when built by `cargo fuzz`, which sets
+//! `--cfg fuzzing`.
+
+use crate::{frame::Frame, properties::Properties, Greeting};
+use futures::executor::block_on;
+
+pub fn greeting(mut data: &[u8]) {
+    let _ = block_on(Greeting::read_new(&mut data));
+}
+
+// Reads frames until the data runs out or stops making sense.
+pub fn frames(mut data: &[u8]) {
+    while !data.is_empty() {
+        if block_on(Frame::read_new(&mut data)).is_err() {
+            break;
+        }
+    }
+}
+
+pub fn properties(data: &[u8]) {
+    let _ = Properties::decode(data);
+}
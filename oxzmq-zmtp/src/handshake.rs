@@ -3,18 +3,47 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use crate::{
-    handshake::null::{NullHandshake, NullHandshakeError},
+    batch::BATCH_PROPERTY,
+    frame::{Frame, FrameParseError, FrameReader},
+    handshake::{
+        null::{NullHandshake, NullHandshakeError},
+        plain::{PlainHandshake, PlainHandshakeError},
+    },
+    options::ConnectionOptions,
+    properties::{Properties, PropertiesEncodeError},
     socket::SocketType,
-    Greeting, Mechanism,
+    AsServer, Greeting, Mechanism, CLOSE_PROPERTY,
 };
 use futures::io::{self, AsyncBufRead, AsyncRead, AsyncWrite};
-use std::{collections::HashMap, convert::TryFrom};
 
-mod null;
+pub(crate) mod null;
+pub(crate) mod plain;
+
+// The handshake happens before the peer has been let in, so the commands it
+// sends are held to this many bytes, which leaves plenty of room for
+// metadata.
+pub(crate) const MAX_COMMAND_LEN: u64 = 64 * 1024;
+
+// Reads the next frame of the handshake, refusing any that's longer than
+// `MAX_COMMAND_LEN` before its body is read.
+pub(crate) async fn read_frame<R: AsyncBufRead + Unpin>(
+    stream: &mut R,
+) -> Result<Frame, FrameParseError> {
+    let mut reader = FrameReader::default();
+    let header = match reader.read_header(stream).await? {
+        Some(header) => header,
+        None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+    };
+    if header.len() > MAX_COMMAND_LEN {
+        return Err(FrameParseError::FrameTooLong(header.len()));
+    }
+    reader.read_body(stream, None).await
+}
 
 #[derive(Debug, Clone)]
 pub(crate) enum Handshake {
     Null(NullHandshake),
+    Plain(PlainHandshake),
 }
 
 impl Handshake {
@@ -22,124 +51,100 @@ impl Handshake {
         stream: &mut S,
         greeting: &Greeting,
         socket_type: &SocketType,
+        options: &ConnectionOptions,
     ) -> Result<Handshake, HandshakeError>
     where
         S: AsyncWrite + AsyncRead + AsyncBufRead + Unpin,
     {
-        match greeting.mechanism {
-            Mechanism::Null => Ok(Handshake::Null(
-                NullHandshake::perform(stream, socket_type).await?,
+        Handshake::check_greeting(greeting, options)?;
+        match options.plain_role() {
+            None => Ok(Handshake::Null(
+                NullHandshake::perform(stream, socket_type, options).await?,
+            )),
+            Some(role) => Ok(Handshake::Plain(
+                PlainHandshake::perform(stream, socket_type, options, role).await?,
             )),
         }
     }
-}
 
-#[derive(thiserror::Error, Debug)]
-pub enum HandshakeError {
-    #[error("error in handshake with NULL mechanism")]
-    Null(#[from] NullHandshakeError),
-}
-
-#[derive(Debug, Clone)]
-pub(crate) struct Properties {
-    inner: HashMap<String, Vec<u8>>,
-}
+    // Checks that the peer's greeting asks for the mechanism we're set up
+    // for, in a role that fits ours.
+    pub(crate) fn check_greeting(
+        greeting: &Greeting,
+        options: &ConnectionOptions,
+    ) -> Result<(), HandshakeError> {
+        // Both ends have to use the same mechanism.
+        if greeting.mechanism != options.mechanism() {
+            return Err(HandshakeError::MechanismMismatch(greeting.mechanism.name()));
+        }
 
-impl Properties {
-    fn new() -> Self {
-        Self {
-            inner: HashMap::new(),
+        // NULL has no server, and PLAIN needs one at exactly one end.
+        let roles_fit = match options.mechanism() {
+            Mechanism::Null => greeting.as_server == AsServer::Client,
+            Mechanism::Plain => greeting.as_server != options.as_server_role(),
+        };
+        if !roles_fit {
+            return Err(HandshakeError::AsServerMismatch(greeting.mechanism.name()));
         }
+        Ok(())
     }
 
-    // More info: https://rfc.zeromq.org/spec/23/#the-null-security-mechanism
-    fn parse_from_slice(bytes: &[u8]) -> Result<Self, PropertiesParseError> {
-        let mut map = HashMap::<String, Vec<u8>>::new();
-
-        let mut rest = bytes;
-        while rest.len() > 0 {
-            let name_size = *rest.get(0).ok_or(PropertiesParseError::EmptySlice)? as usize;
-            if name_size == 0 {
-                return Err(PropertiesParseError::ZeroSizedName);
-            }
-            rest = &rest[1..];
-            if rest.len() < name_size as usize {
-                return Err(PropertiesParseError::NameSizeIncorrect);
-            }
-
-            let name = std::str::from_utf8(&rest[..name_size])
-                .map_err(|_| PropertiesParseError::NameInvalidChar)?;
-            if !name
-                .chars()
-                .all(|c| c.is_alphanumeric() && ['-', '_', '.', '+'].contains(&c))
-            {
-                return Err(PropertiesParseError::NameInvalidChar);
-            }
-            rest = &rest[name_size..];
-
-            let value_size_bytes = <[u8; 4]>::try_from(&rest[..4])
-                .map_err(|_| PropertiesParseError::ValueSizeIncomplete)?;
-            let value_size = u32::from_be_bytes(value_size_bytes) as usize;
-            rest = &rest[4..];
-            if rest.len() < value_size as usize {
-                return Err(PropertiesParseError::ValueSizeIncorrect);
-            }
-            let value_bytes = &rest[..value_size];
-
-            map.insert(name.to_lowercase(), value_bytes.to_vec());
+    // The metadata the peer sent.
+    // Who the peer authenticated as, if it had to.
+    pub(crate) fn user_id(&self) -> Option<&str> {
+        match self {
+            Handshake::Null(_) => None,
+            Handshake::Plain(plain_handshake) => plain_handshake.user_id.as_deref(),
         }
-
-        Ok(Properties { inner: map })
     }
 
-    async fn write_to<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> Result<(), io::Error> {
-        let mut write_buf = Vec::<u8>::new();
-
-        for (name, value) in self.inner.iter() {
-            let name_size_bytes = name.len().to_be_bytes();
-            write_buf.extend_from_slice(&name_size_bytes);
-            write_buf.extend_from_slice(name.as_bytes());
-
-            let value_size_bytes = value.len().to_be_bytes();
-            write_buf.extend_from_slice(&value_size_bytes);
-            write_buf.extend_from_slice(value.as_slice());
+    pub(crate) fn into_properties(self) -> Properties {
+        match self {
+            Handshake::Null(null_handshake) => null_handshake.properties,
+            Handshake::Plain(plain_handshake) => plain_handshake.properties,
         }
-
-        io::copy(write_buf.as_slice(), stream).await?;
-
-        Ok(())
     }
+}
 
-    // We `get` keys through a method because we have to ensure that we treat
-    // all keys as lowercase.
-    pub(crate) fn get(&self, key: String) -> Option<&[u8]> {
-        self.inner.get(&key.to_lowercase()).map(|v| v.as_slice())
-    }
+// The metadata we send in READY or INITIATE, whatever the mechanism.
+pub(crate) fn metadata(
+    socket_type: &SocketType,
+    options: &ConnectionOptions,
+) -> Result<Properties, PropertiesEncodeError> {
+    let mut properties = plain_metadata(socket_type, options)?;
+    properties.insert(BATCH_PROPERTY, b"1".to_vec())?;
+    properties.insert(CLOSE_PROPERTY, b"1".to_vec())?;
+    Ok(properties)
+}
 
-    // We `insert` keys through a method because we have to ensure that we treat
-    // all keys as lowercase.
-    fn insert(&mut self, key: String, value: Vec<u8>) {
-        self.inner.insert(key.to_lowercase(), value);
+// The metadata without OxZMQ's extensions, for ends that don't implement
+// them.
+pub(crate) fn plain_metadata(
+    socket_type: &SocketType,
+    options: &ConnectionOptions,
+) -> Result<Properties, PropertiesEncodeError> {
+    let mut properties = Properties::new();
+    properties.set_socket_type(*socket_type);
+    if let Some(routing_id) = options.identity() {
+        properties.set_identity(routing_id.to_vec())?;
+    }
+    if let Some(weight) = options.advertised_weight() {
+        properties.set_weight(weight);
     }
+    Ok(properties)
 }
 
 #[derive(thiserror::Error, Debug)]
-pub enum PropertiesParseError {
-    #[error("given slice was empty")]
-    EmptySlice,
-
-    #[error("name had size of zero")]
-    ZeroSizedName,
-
-    #[error("invalid character(s) in property name")]
-    NameInvalidChar,
+pub enum HandshakeError {
+    #[error("error in handshake with NULL mechanism")]
+    Null(#[from] NullHandshakeError),
 
-    #[error("name size indicated more bytes than were available")]
-    NameSizeIncorrect,
+    #[error("error in handshake with PLAIN mechanism")]
+    Plain(#[from] PlainHandshakeError),
 
-    #[error("not enough bytes left to read size of metadata value")]
-    ValueSizeIncomplete,
+    #[error("peer uses the {0} mechanism, which we aren't configured for")]
+    MechanismMismatch(&'static str),
 
-    #[error("value size indicated more bytes than were available")]
-    ValueSizeIncorrect,
+    #[error("peer's as-server flag doesn't fit our role in the {0} mechanism")]
+    AsServerMismatch(&'static str),
 }
@@ -3,8 +3,11 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use crate::{
+    command::{CommandParseError, ZmtpCommand},
     frame::{Frame, FrameParseError},
-    handshake::{Properties, PropertiesParseError},
+    handshake::{metadata, read_frame},
+    options::ConnectionOptions,
+    properties::{Properties, PropertiesEncodeError},
     socket::SocketType,
 };
 use futures::io::{self, AsyncBufRead, AsyncRead, AsyncWrite};
@@ -19,38 +22,30 @@ impl NullHandshake {
     pub(crate) async fn perform<S>(
         stream: &mut S,
         socket_type: &SocketType,
+        options: &ConnectionOptions,
     ) -> Result<NullHandshake, NullHandshakeError>
     where
         S: AsyncWrite + AsyncRead + AsyncBufRead + Unpin,
     {
         // As written in spec, send READY command first.
-        let mut ready_cmd_data = Vec::new();
-        let mut properties = Properties::new();
-        properties.insert(
-            "socket-type".to_string(),
-            String::from(socket_type).into_bytes(),
-        );
-        properties.write_to(&mut ready_cmd_data).await?;
+        let ready_cmd = ZmtpCommand::Ready(metadata(socket_type, options)?);
+        ready_cmd.into_frame().write_to(stream).await?;
 
-        let ready_cmd = Frame::new_command(String::from("READY"), ready_cmd_data);
-        ready_cmd.write_to(stream).await?;
+        let frame = read_frame(stream).await?;
+        NullHandshake::from_frame(frame)
+    }
 
-        // Receive and validate READY command frame.
-        let received_frame = Frame::read_new(stream).await?;
-        let received_cmd = match received_frame {
-            Frame::Command(cmd) => cmd,
+    // Receives and validates the peer's READY command frame.
+    pub(crate) fn from_frame(frame: Frame) -> Result<NullHandshake, NullHandshakeError> {
+        let received_cmd = match frame {
+            Frame::Command(cmd) => ZmtpCommand::from_frame(cmd)?,
             Frame::Message(_) => return Err(NullHandshakeError::NoReadyCommand),
         };
 
-        if received_cmd.name != "READY" {
-            return Err(NullHandshakeError::NoReadyCommand);
+        match received_cmd {
+            ZmtpCommand::Ready(properties) => Ok(NullHandshake { properties }),
+            _ => Err(NullHandshakeError::NoReadyCommand),
         }
-
-        let received_properties = Properties::parse_from_slice(received_cmd.data.as_slice())?;
-
-        Ok(NullHandshake {
-            properties: received_properties,
-        })
     }
 }
 
@@ -65,6 +60,9 @@ pub enum NullHandshakeError {
     #[error("could not parse frame")]
     FrameParse(#[from] FrameParseError),
 
-    #[error("could not parse properties")]
-    PropertiesParse(#[from] PropertiesParseError),
+    #[error("could not parse command")]
+    CommandParse(#[from] CommandParseError),
+
+    #[error("could not encode properties")]
+    PropertiesEncode(#[from] PropertiesEncodeError),
 }
@@ -0,0 +1,287 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    auth::Authenticator,
+    command::{CommandParseError, ZmtpCommand},
+    frame::{Frame, FrameParseError},
+    handshake::{metadata, read_frame},
+    options::ConnectionOptions,
+    properties::{Properties, PropertiesEncodeError},
+    socket::SocketType,
+};
+use futures::io::{self, AsyncBufRead, AsyncRead, AsyncWrite};
+use std::{convert::TryFrom, fmt, future::Future, pin::Pin, sync::Arc};
+
+/// A username and password for the PLAIN mechanism. Each is at most 255
+/// bytes.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PlainCredentials {
+    username: String,
+    password: String,
+}
+
+impl PlainCredentials {
+    pub fn new(username: &str, password: &str) -> PlainCredentials {
+        PlainCredentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+}
+
+// Passwords are kept out of logs.
+impl fmt::Debug for PlainCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PlainCredentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Supplies PLAIN credentials. It's asked again on every connect and
+/// reconnect, so short-lived tokens used as passwords can be refreshed in the
+/// meantime.
+pub trait CredentialProvider {
+    fn credentials(&self) -> impl Future<Output = io::Result<PlainCredentials>> + Send;
+}
+
+/// Checks the credentials of clients connecting to a PLAIN server, e.g.
+/// against a database or a directory, resolving to whether to let the client
+/// in. Other connections go on being set up while it runs.
+pub trait CredentialValidator {
+    fn validate(&self, credentials: &PlainCredentials) -> impl Future<Output = bool> + Send;
+}
+
+// Fixed credentials never change.
+impl CredentialProvider for PlainCredentials {
+    async fn credentials(&self) -> io::Result<PlainCredentials> {
+        Ok(self.clone())
+    }
+}
+
+type CredentialsFuture = Pin<Box<dyn Future<Output = io::Result<PlainCredentials>> + Send>>;
+type CredentialsFn = dyn Fn() -> CredentialsFuture + Send + Sync;
+
+// Which side of the PLAIN handshake we're on, and what we need for it.
+#[derive(Clone)]
+pub(crate) enum PlainRole {
+    Client(Arc<CredentialsFn>),
+    Server(Authenticator),
+}
+
+impl PlainRole {
+    pub(crate) fn client<C>(provider: C) -> PlainRole
+    where
+        C: CredentialProvider + Send + Sync + 'static,
+    {
+        let provider = Arc::new(provider);
+        PlainRole::Client(Arc::new(move || {
+            let provider = provider.clone();
+            Box::pin(async move { provider.credentials().await })
+        }))
+    }
+
+    pub(crate) fn server(authenticator: Authenticator) -> PlainRole {
+        PlainRole::Server(authenticator)
+    }
+
+    pub(crate) fn is_server(&self) -> bool {
+        matches!(self, PlainRole::Server(_))
+    }
+}
+
+impl fmt::Debug for PlainRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlainRole::Client(_) => f.write_str("PlainRole::Client"),
+            PlainRole::Server(_) => f.write_str("PlainRole::Server"),
+        }
+    }
+}
+
+// More info: https://rfc.zeromq.org/spec/24/
+#[derive(Debug, Clone)]
+pub(crate) struct PlainHandshake {
+    pub(crate) properties: Properties,
+    // The username of the client we let in, as the server.
+    pub(crate) user_id: Option<String>,
+}
+
+impl PlainHandshake {
+    pub(crate) async fn perform<S>(
+        stream: &mut S,
+        socket_type: &SocketType,
+        options: &ConnectionOptions,
+        role: &PlainRole,
+    ) -> Result<PlainHandshake, PlainHandshakeError>
+    where
+        S: AsyncWrite + AsyncRead + AsyncBufRead + Unpin,
+    {
+        let our_metadata = metadata(socket_type, options)?;
+
+        match role {
+            PlainRole::Client(provider) => {
+                let credentials = provider().await.map_err(PlainHandshakeError::Credentials)?;
+                check_len(&credentials)?;
+                send(stream, ZmtpCommand::Hello(credentials)).await?;
+                match recv(stream, "WELCOME").await? {
+                    ZmtpCommand::Welcome => (),
+                    _ => return Err(unexpected("WELCOME")),
+                }
+                send(stream, ZmtpCommand::Initiate(our_metadata)).await?;
+                match recv(stream, "READY").await? {
+                    ZmtpCommand::Ready(properties) => Ok(PlainHandshake {
+                        properties,
+                        user_id: None,
+                    }),
+                    _ => Err(unexpected("READY")),
+                }
+            }
+            PlainRole::Server(authenticator) => {
+                let credentials = match recv(stream, "HELLO").await? {
+                    ZmtpCommand::Hello(credentials) => credentials,
+                    _ => return Err(unexpected("HELLO")),
+                };
+                if !authenticator
+                    .check_plain(options.domain(), &credentials)
+                    .await
+                {
+                    let reason = "invalid username or password".to_string();
+                    send(stream, ZmtpCommand::Error(reason)).await?;
+                    return Err(PlainHandshakeError::Denied);
+                }
+                send(stream, ZmtpCommand::Welcome).await?;
+                let properties = match recv(stream, "INITIATE").await? {
+                    ZmtpCommand::Initiate(properties) => properties,
+                    _ => return Err(unexpected("INITIATE")),
+                };
+                send(stream, ZmtpCommand::Ready(our_metadata)).await?;
+
+                Ok(PlainHandshake {
+                    properties,
+                    user_id: Some(credentials.username().to_string()),
+                })
+            }
+        }
+    }
+}
+
+async fn send<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    cmd: ZmtpCommand,
+) -> Result<(), PlainHandshakeError> {
+    cmd.into_frame().write_to(stream).await?;
+    Ok(())
+}
+
+// Reads the next command, which should be `expected`. The peer refusing us
+// with an ERROR is reported with its reason.
+async fn recv<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    expected: &str,
+) -> Result<ZmtpCommand, PlainHandshakeError> {
+    let cmd = match read_frame(stream).await? {
+        Frame::Command(cmd) => ZmtpCommand::from_frame(cmd)?,
+        Frame::Message(_) => return Err(unexpected(expected)),
+    };
+    match cmd {
+        ZmtpCommand::Error(reason) => Err(PlainHandshakeError::Rejected(reason)),
+        cmd => Ok(cmd),
+    }
+}
+
+fn unexpected(expected: &str) -> PlainHandshakeError {
+    PlainHandshakeError::Unexpected(expected.to_string())
+}
+
+// HELLO puts the username and password behind a one-byte length each.
+fn check_len(credentials: &PlainCredentials) -> Result<(), PlainHandshakeError> {
+    for field in [credentials.username(), credentials.password()] {
+        u8::try_from(field.len()).map_err(|_| PlainHandshakeError::CredentialTooLong)?;
+    }
+    Ok(())
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PlainHandshakeError {
+    #[error("error reading data stream")]
+    Io(#[from] io::Error),
+
+    #[error("could not get credentials")]
+    Credentials(#[source] io::Error),
+
+    #[error("usernames and passwords must be at most 255 bytes long")]
+    CredentialTooLong,
+
+    #[error("expected a {0} command")]
+    Unexpected(String),
+
+    #[error("peer refused the handshake: {0}")]
+    Rejected(String),
+
+    #[error("client's credentials were refused")]
+    Denied,
+
+    #[error("could not parse frame")]
+    FrameParse(#[from] FrameParseError),
+
+    #[error("could not parse command")]
+    CommandParse(#[from] CommandParseError),
+
+    #[error("could not encode properties")]
+    PropertiesEncode(#[from] PropertiesEncodeError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Hands out a new token every time it's asked.
+    struct Tokens(AtomicUsize);
+
+    impl CredentialProvider for Tokens {
+        async fn credentials(&self) -> io::Result<PlainCredentials> {
+            let n = self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(PlainCredentials::new("service", &format!("token-{}", n)))
+        }
+    }
+
+    #[test]
+    fn test_refreshed_credentials() {
+        block_on(async {
+            let role = PlainRole::client(Tokens(AtomicUsize::new(0)));
+            let provider = match &role {
+                PlainRole::Client(provider) => provider.clone(),
+                PlainRole::Server(_) => unreachable!(),
+            };
+
+            // Each connect asks again.
+            let first = provider().await.unwrap();
+            let second = provider().await.unwrap();
+            assert_eq!(first.password(), "token-0");
+            assert_eq!(second.password(), "token-1");
+            assert!(!format!("{:?}", first).contains("token"));
+        });
+    }
+
+    #[test]
+    fn test_credential_len() {
+        assert!(check_len(&PlainCredentials::new("admin", "")).is_ok());
+        assert!(matches!(
+            check_len(&PlainCredentials::new(&"a".repeat(256), "")),
+            Err(PlainHandshakeError::CredentialTooLong)
+        ));
+    }
+}
@@ -0,0 +1,156 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::command::ZmtpCommand;
+use std::{
+    convert::TryFrom,
+    time::{Duration, Instant},
+};
+
+// More info: https://rfc.zeromq.org/spec/37/#connection-heartbeating
+//
+// A PING carries a 2-byte TTL followed by up to 16 bytes of context, which
+// the peer echoes back in its PONG. We put a sequence number in the context
+// so that each PONG can be matched up with the PING that caused it.
+const SEQ_LEN: usize = 8;
+
+// How many heartbeats in a row have to go unanswered before a peer is
+// considered degraded.
+const DEGRADED_MISSES: u32 = 1;
+
+/// How responsive a peer has been to heartbeats.
+#[derive(Debug, Clone, Default)]
+pub struct Liveness {
+    next_seq: u64,
+    outstanding: Option<(u64, Instant)>,
+    consecutive_misses: u32,
+    rtt: Option<Duration>,
+    jitter: Duration,
+}
+
+impl Liveness {
+    pub(crate) fn new() -> Liveness {
+        Liveness::default()
+    }
+
+    /// The number of heartbeats in a row that the peer didn't answer before
+    /// the next one was sent.
+    pub fn consecutive_misses(&self) -> u32 {
+        self.consecutive_misses
+    }
+
+    /// The round trip time of the last answered heartbeat.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// A smoothed estimate of how much the round trip time varies, computed
+    /// the same way as RTP's interarrival jitter.
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// Degraded peers are still connected, but are only sent messages when no
+    /// healthier peer can take them.
+    pub fn is_degraded(&self) -> bool {
+        self.consecutive_misses >= DEGRADED_MISSES
+    }
+
+    // Records that a PING is being sent and returns its sequence number. If
+    // the last one never got a PONG, that counts as a miss.
+    pub(crate) fn ping_sent(&mut self, now: Instant) -> u64 {
+        if self.outstanding.is_some() {
+            self.consecutive_misses += 1;
+        }
+
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.outstanding = Some((seq, now));
+        seq
+    }
+
+    // PONGs for anything but the latest PING arrived too late to count.
+    pub(crate) fn pong_received(&mut self, seq: u64, now: Instant) {
+        let sent_at = match self.outstanding {
+            Some((outstanding_seq, sent_at)) if outstanding_seq == seq => sent_at,
+            _ => return,
+        };
+
+        let rtt = now.saturating_duration_since(sent_at);
+        if let Some(last_rtt) = self.rtt {
+            let delta = rtt.abs_diff(last_rtt);
+            if delta > self.jitter {
+                self.jitter += (delta - self.jitter) / 16;
+            } else {
+                self.jitter -= (self.jitter - delta) / 16;
+            }
+        }
+
+        self.rtt = Some(rtt);
+        self.outstanding = None;
+        self.consecutive_misses = 0;
+    }
+}
+
+pub(crate) fn ping(seq: u64) -> ZmtpCommand {
+    // We don't ask the peer to time us out, so the TTL is zero.
+    ZmtpCommand::Ping {
+        ttl: 0,
+        context: seq.to_be_bytes().to_vec(),
+    }
+}
+
+// Returns `None` if the context isn't one of our sequence numbers.
+pub(crate) fn pong_seq(context: &[u8]) -> Option<u64> {
+    let seq_bytes = <[u8; SEQ_LEN]>::try_from(context).ok()?;
+    Some(u64::from_be_bytes(seq_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_misses_and_recovery() {
+        let start = Instant::now();
+        let mut liveness = Liveness::new();
+        assert!(!liveness.is_degraded());
+
+        let seq = liveness.ping_sent(start);
+        liveness.ping_sent(start + Duration::from_secs(1));
+        assert_eq!(liveness.consecutive_misses(), 1);
+        assert!(liveness.is_degraded());
+
+        // A late PONG for the missed PING doesn't count.
+        liveness.pong_received(seq, start + Duration::from_secs(2));
+        assert!(liveness.is_degraded());
+
+        let seq = liveness.ping_sent(start + Duration::from_secs(3));
+        liveness.pong_received(seq, start + Duration::from_millis(3010));
+        assert!(!liveness.is_degraded());
+        assert_eq!(liveness.rtt(), Some(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let start = Instant::now();
+        let mut liveness = Liveness::new();
+        for (i, rtt_ms) in [10, 26, 10].iter().enumerate() {
+            let sent_at = start + Duration::from_secs(i as u64);
+            let seq = liveness.ping_sent(sent_at);
+            liveness.pong_received(seq, sent_at + Duration::from_millis(*rtt_ms));
+        }
+        assert_eq!(liveness.jitter(), Duration::from_nanos(1_937_500));
+    }
+
+    #[test]
+    fn test_ping_pong_data() {
+        let context = match ping(42) {
+            ZmtpCommand::Ping { ttl: 0, context } => context,
+            _ => unreachable!(),
+        };
+        assert_eq!(pong_seq(&context), Some(42));
+        assert_eq!(pong_seq(b"someone else's"), None);
+    }
+}
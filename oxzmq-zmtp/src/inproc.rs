@@ -0,0 +1,377 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    endpoint::Endpoint,
+    message::Message,
+    options::ConnectionOptions,
+    peer::{Origin, Peer, PeerError},
+    socket::SocketType,
+};
+use futures::{
+    channel::mpsc::{self, TryRecvError},
+    SinkExt, Stream, StreamExt,
+};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context as TaskContext, Poll},
+};
+
+/// The registry of `inproc://` endpoints. Only peers that share a context (or
+/// a clone of it) can find each other.
+#[derive(Debug, Clone, Default)]
+pub struct InprocContext {
+    endpoints: Arc<Mutex<HashMap<String, Binding>>>,
+}
+
+#[derive(Debug)]
+struct Binding {
+    socket_type: SocketType,
+    incoming: mpsc::UnboundedSender<InprocPeer>,
+}
+
+impl InprocContext {
+    pub fn new() -> InprocContext {
+        InprocContext::default()
+    }
+
+    /// Registers the endpoint for a socket of type `socket_type`. It stays
+    /// registered until the listener is dropped. Endpoints have to be bound
+    /// before anything can connect to them.
+    pub fn bind(
+        &self,
+        endpoint: &Endpoint,
+        socket_type: SocketType,
+    ) -> Result<InprocListener, InprocError> {
+        let name = inproc_name(endpoint)?;
+        let mut endpoints = self.endpoints.lock().unwrap_or_else(|e| e.into_inner());
+        if endpoints.contains_key(name) {
+            return Err(InprocError::AddressInUse(name.to_string()));
+        }
+
+        let (tx, rx) = mpsc::unbounded();
+        endpoints.insert(
+            name.to_string(),
+            Binding {
+                socket_type,
+                incoming: tx,
+            },
+        );
+        Ok(InprocListener {
+            name: name.to_string(),
+            endpoints: self.endpoints.clone(),
+            incoming: rx,
+        })
+    }
+
+    /// Connects a socket of type `socket_type` to a bound endpoint. The
+    /// routing ID in `options` is passed on, and its high-water marks size the
+    /// queues in each direction; nothing else in them applies to in-process
+    /// peers.
+    pub fn connect(
+        &self,
+        endpoint: &Endpoint,
+        socket_type: SocketType,
+        options: &ConnectionOptions,
+    ) -> Result<InprocPeer, InprocError> {
+        let name = inproc_name(endpoint)?;
+        let endpoints = self.endpoints.lock().unwrap_or_else(|e| e.into_inner());
+        let binding = endpoints
+            .get(name)
+            .ok_or_else(|| InprocError::ConnectionRefused(name.to_string()))?;
+        if !socket_type.valid_socket_combo(&binding.socket_type) {
+            return Err(InprocError::InvalidSocketCombination(
+                socket_type,
+                binding.socket_type,
+            ));
+        }
+
+        let (connecting, bound) = InprocPeer::pair(socket_type, binding.socket_type, options);
+        let connecting = InprocPeer {
+            origin: Some(Origin::Connected(endpoint.clone())),
+            ..connecting
+        };
+        let bound = InprocPeer {
+            routing_id: options.identity().map(<[u8]>::to_vec),
+            origin: Some(Origin::Accepted(endpoint.clone())),
+            ..bound
+        };
+        binding
+            .incoming
+            .unbounded_send(bound)
+            .map_err(|_| InprocError::ConnectionRefused(name.to_string()))?;
+        Ok(connecting)
+    }
+}
+
+fn inproc_name(endpoint: &Endpoint) -> Result<&str, InprocError> {
+    match endpoint {
+        Endpoint::Inproc(name) => Ok(name),
+        _ => Err(InprocError::NotInproc(endpoint.to_string())),
+    }
+}
+
+/// The peers that connect to a bound `inproc://` endpoint, in the order they
+/// connected.
+#[derive(Debug)]
+pub struct InprocListener {
+    name: String,
+    endpoints: Arc<Mutex<HashMap<String, Binding>>>,
+    incoming: mpsc::UnboundedReceiver<InprocPeer>,
+}
+
+impl InprocListener {
+    /// Waits for the next peer to connect.
+    pub async fn accept(&mut self) -> Option<InprocPeer> {
+        self.incoming.next().await
+    }
+}
+
+impl Stream for InprocListener {
+    type Item = InprocPeer;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<InprocPeer>> {
+        self.incoming.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for InprocListener {
+    fn drop(&mut self) {
+        let mut endpoints = self.endpoints.lock().unwrap_or_else(|e| e.into_inner());
+        endpoints.remove(&self.name);
+    }
+}
+
+/// One end of an in-process connection. Messages are handed over through a
+/// channel as they are, without being encoded.
+#[derive(Debug)]
+pub struct InprocPeer {
+    remote_socket_type: SocketType,
+    routing_id: Option<Vec<u8>>,
+    tx: mpsc::Sender<Message>,
+    rx: mpsc::Receiver<Message>,
+    // Whether each end closed the connection on purpose, so the other can
+    // tell that apart from it being dropped.
+    closed: Arc<AtomicBool>,
+    remote_closed: Arc<AtomicBool>,
+    origin: Option<Origin>,
+}
+
+impl InprocPeer {
+    // The high-water marks in `options` are from `a`'s point of view.
+    fn pair(a: SocketType, b: SocketType, options: &ConnectionOptions) -> (InprocPeer, InprocPeer) {
+        // Each sender gets one guaranteed slot on top of the buffer.
+        let (a_tx, b_rx) = mpsc::channel(options.send_capacity() - 1);
+        let (b_tx, a_rx) = mpsc::channel(options.recv_capacity() - 1);
+        let a_closed = Arc::new(AtomicBool::new(false));
+        let b_closed = Arc::new(AtomicBool::new(false));
+        let a_end = InprocPeer {
+            remote_socket_type: b,
+            routing_id: None,
+            tx: a_tx,
+            rx: a_rx,
+            closed: a_closed.clone(),
+            remote_closed: b_closed.clone(),
+            origin: None,
+        };
+        let b_end = InprocPeer {
+            remote_socket_type: a,
+            routing_id: None,
+            tx: b_tx,
+            rx: b_rx,
+            closed: b_closed,
+            remote_closed: a_closed,
+            origin: None,
+        };
+        (a_end, b_end)
+    }
+}
+
+impl Peer for InprocPeer {
+    fn remote_socket_type(&self) -> SocketType {
+        self.remote_socket_type
+    }
+
+    fn routing_id(&self) -> Option<&[u8]> {
+        self.routing_id.as_deref()
+    }
+
+    fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), PeerError>> {
+        self.tx.poll_ready(cx).map_err(|_| PeerError::Disconnected)
+    }
+
+    async fn send_message(&mut self, msg: Message) -> Result<(), PeerError> {
+        // Flushing would wait for the receiver to make room again, which
+        // would leave the last slot of the queue unused.
+        self.tx.feed(msg).await.map_err(|_| PeerError::Disconnected)
+    }
+
+    async fn recv_message(&mut self) -> Result<Message, PeerError> {
+        match self.rx.next().await {
+            Some(msg) => Ok(msg),
+            None if self.remote_closed.load(Ordering::Acquire) => Err(PeerError::Closed),
+            None => Err(PeerError::Disconnected),
+        }
+    }
+
+    fn try_recv_message(&mut self) -> Option<Result<Message, PeerError>> {
+        match self.rx.try_recv() {
+            Ok(msg) => Some(Ok(msg)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Closed) if self.remote_closed.load(Ordering::Acquire) => {
+                Some(Err(PeerError::Closed))
+            }
+            Err(TryRecvError::Closed) => Some(Err(PeerError::Disconnected)),
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), PeerError> {
+        self.closed.store(true, Ordering::Release);
+        self.tx.close_channel();
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum InprocError {
+    #[error("not an inproc endpoint: {0}")]
+    NotInproc(String),
+
+    #[error("inproc endpoint is already bound: {0}")]
+    AddressInUse(String),
+
+    #[error("nothing is bound to inproc endpoint: {0}")]
+    ConnectionRefused(String),
+
+    #[error("invalid socket combination: {:?} with {:?}", .0, .1)]
+    InvalidSocketCombination(SocketType, SocketType),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sockets::{Dealer, Router};
+    use futures::{executor::block_on, FutureExt};
+    use std::time::Duration;
+
+    #[test]
+    fn test_bind_and_connect() {
+        block_on(async {
+            let context = InprocContext::new();
+            let endpoint: Endpoint = "inproc://backend".parse().unwrap();
+            assert!(matches!(
+                context.connect(&endpoint, SocketType::Dealer, &ConnectionOptions::new()),
+                Err(InprocError::ConnectionRefused(_))
+            ));
+
+            let mut listener = context.bind(&endpoint, SocketType::Router).unwrap();
+            assert!(matches!(
+                context.bind(&endpoint, SocketType::Router),
+                Err(InprocError::AddressInUse(_))
+            ));
+            assert!(matches!(
+                context.connect(&endpoint, SocketType::Pub, &ConnectionOptions::new()),
+                Err(InprocError::InvalidSocketCombination(..))
+            ));
+
+            let mut dealer = Dealer::new();
+            let options = ConnectionOptions::new().routing_id(b"worker".to_vec());
+            dealer
+                .attach(
+                    context
+                        .connect(&endpoint, SocketType::Dealer, &options)
+                        .unwrap(),
+                )
+                .unwrap();
+            let mut router = Router::new();
+            let routing_id = router.attach(listener.accept().await.unwrap()).unwrap();
+            assert_eq!(routing_id, b"worker");
+
+            dealer.send(Message::from(&b"ready"[..])).await.unwrap();
+            assert_eq!(
+                router.recv().await.unwrap(),
+                Message::from(vec![b"worker".to_vec(), b"ready".to_vec()])
+            );
+
+            // Dropping the listener frees the name up again.
+            drop(listener);
+            context.bind(&endpoint, SocketType::Router).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_zero_linger() {
+        block_on(async {
+            // Closing with a linger of zero still says goodbye, so the other
+            // end sees an orderly close rather than a disconnection.
+            let options = ConnectionOptions::new();
+            let (local, mut remote) =
+                InprocPeer::pair(SocketType::Dealer, SocketType::Dealer, &options);
+            let mut dealer = Dealer::new();
+            dealer.set_linger(Some(Duration::ZERO));
+            dealer.attach(local).unwrap();
+            dealer.close().await;
+            assert!(matches!(
+                remote.recv_message().await,
+                Err(PeerError::Closed)
+            ));
+        });
+    }
+
+    #[test]
+    fn test_hwm() {
+        block_on(async {
+            let options = ConnectionOptions::new().send_hwm(2);
+            let (mut a, mut b) = InprocPeer::pair(SocketType::Pair, SocketType::Pair, &options);
+            for i in 0..2_u8 {
+                a.send_message(Message::from(vec![i])).await.unwrap();
+            }
+            assert!(a
+                .send_message(Message::from(vec![2]))
+                .now_or_never()
+                .is_none());
+
+            // The other direction has the default mark.
+            for i in 0..1000_u16 {
+                b.send_message(Message::from(i.to_be_bytes().to_vec()))
+                    .await
+                    .unwrap();
+            }
+            assert!(b.send_message(Message::new()).now_or_never().is_none());
+        });
+    }
+
+    #[test]
+    fn test_orderly_close() {
+        block_on(async {
+            let (mut a, mut b) = InprocPeer::pair(
+                SocketType::Pair,
+                SocketType::Pair,
+                &ConnectionOptions::new(),
+            );
+            a.close().await.unwrap();
+            assert!(matches!(b.recv_message().await, Err(PeerError::Closed)));
+
+            let (a, mut b) = InprocPeer::pair(
+                SocketType::Pair,
+                SocketType::Pair,
+                &ConnectionOptions::new(),
+            );
+            drop(a);
+            assert!(matches!(
+                b.recv_message().await,
+                Err(PeerError::Disconnected)
+            ));
+        });
+    }
+}
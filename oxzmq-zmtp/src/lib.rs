@@ -3,76 +3,1144 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use crate::{
-    frame::{Frame, FrameParseError, MessageFrame},
+    batch::BATCH_PROPERTY,
+    command::{CommandHandler, ZmtpCommand},
+    context::{unless_terminated, Membership},
+    frame::FrameHook,
     handshake::{Handshake, HandshakeError},
+    metrics::message_bytes,
+    properties::{PEER_ADDRESS, USER_ID},
+    sockets::fair_queue::FairQueue,
+    split::{Received, RecvHalf, SendHalf},
+};
+use futures::{
+    future::{self, FutureExt},
+    io::{self, AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use std::{
+    marker::Unpin,
+    net::IpAddr,
+    sync::Arc,
+    task::{Context as TaskContext, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+pub use crate::{
+    adapter::{RecvSocket, SendSocket},
+    auth::{AuthPolicy, Authenticator},
+    codec::ZmtpCodec,
+    command::{Command, CommandNameError, CommandParseError},
+    context::Context,
+    endpoint::{Endpoint, EndpointError, Host, Resolver, StaticResolver},
+    engine::EnginePeer,
+    event::{Batching, EventKind, EventSink, EventSinkError, SocketEvent},
+    frame::{Frame, FrameDirection, FrameHeader, FrameParseError},
+    handshake::plain::{
+        CredentialProvider, CredentialValidator, PlainCredentials, PlainHandshakeError,
+    },
+    heartbeat::Liveness,
+    inproc::{InprocContext, InprocError, InprocListener, InprocPeer},
+    message::{Message, Parts, Timestamp},
+    metrics::{ConnectCounters, Metrics},
+    options::{ConnectionOptions, ReconnectStop, SocketOption, SocketOptionName, SocketOptions},
+    peer::{CloseReason, Origin, Peer, PeerError},
+    pool::{MessagePool, PooledMessage},
+    probe::{probe, probe_stream, probe_with_resolver, ProbeError, ProbeReport},
+    properties::{Properties, PropertiesEncodeError, PropertiesParseError},
+    proxy::{proxy, proxy_steerable, ProxyCommand},
+    raw::RawPeer,
+    runtime::{Elapsed, Runtime, ThreadRuntime},
     socket::{SocketType, SocketTypeFromBytesError},
+    sockets::{
+        AwaitingReply, Client, Dealer, Dish, Gather, HwmPolicy, Pair, Pub, Pull, Push, Radio, Rep,
+        ReplyError, Req, ReqSocket, Router, RoutingIdConflict, Scatter, Server, SocketError,
+        Stream, Sub, XPub, XSub,
+    },
+    split::{ConnectionReader, ConnectionWriter},
+    tcp::{TcpConnection, TcpKeepalive, TcpListener, TcpOptions, TcpStream},
+    timer::timeout,
+    transfer::{recv_file, send_file, TransferError},
+    ws::{UpgradeError, WsConnection, WsError, WsMessagePeer, WsPeer},
 };
-use futures::io::{self, AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite};
-use std::{convert::TryFrom, marker::Unpin};
 
+mod adapter;
+#[cfg(test)]
+mod arbitrary;
+mod auth;
+mod batch;
+mod codec;
+mod command;
+mod context;
+mod endpoint;
+mod engine;
+mod event;
 mod frame;
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzz;
 mod handshake;
+mod heartbeat;
+mod inproc;
+mod message;
+mod metrics;
+mod options;
+mod peer;
+mod pool;
+mod probe;
+mod properties;
+mod proxy;
+mod raw;
+mod runtime;
+// `Session` stays out of the public API until `Connection` is built on it,
+// and so does nothing outside of its tests yet.
+#[allow(dead_code)]
+mod session;
 mod socket;
+mod sockets;
+mod split;
+mod tcp;
+pub mod testing;
+mod timer;
+mod transfer;
+mod ws;
+
+const SIGNATURE_LEN: usize = 10;
+const MECHANISM_LEN: usize = 20;
+const GREETING_LEN: usize = 64;
+
+// OxZMQ peers that advertise this property tell each other when they're
+// shutting down on purpose, with a CLOSE command, so that it isn't mistaken
+// for a failure.
+pub(crate) const CLOSE_PROPERTY: &str = "X-OxZMQ-Close";
 
-const PADDING_LEN: usize = 80;
-const FILLER_LEN: usize = 31;
+// OxZMQ peers can ask DEALER and PUSH sockets to send them a bigger share of
+// the messages, as an ASCII decimal weight.
+pub(crate) const WEIGHT_PROPERTY: &str = "X-OxZMQ-Weight";
 
+/// The state shared by every socket type: the set of connected peers and the
+/// bookkeeping needed to load-balance across them.
 #[derive(Debug, Clone)]
-pub struct ZmtpSocket<S> {
-    connections: Vec<Connection<S>>,
+pub struct ZmtpSocket<P> {
+    connections: Vec<P>,
+    // How big a share of the sent messages each peer gets, in the same order
+    // as the peers.
+    weights: Vec<SendWeight>,
     socket_type: SocketType,
+    next_send: usize,
+    // Whose turn it is to be received from.
+    fair: FairQueue,
+    // While receiving is paused, nothing is read from the peers, and the
+    // tasks that tried are remembered so they can be woken on resuming.
+    recv_paused: bool,
+    resume_waiters: Vec<Waker>,
+    events: Option<EventSink>,
+    max_misses: Option<u32>,
+    hwm_policy: HwmPolicy,
+    conflate: bool,
+    metrics: Metrics,
+    context: Option<Membership>,
+    linger: Option<Duration>,
+    send_timeout: Option<Duration>,
+    recv_timeout: Option<Duration>,
+}
+
+impl<P: Peer> ZmtpSocket<P> {
+    pub(crate) fn new(socket_type: SocketType) -> ZmtpSocket<P> {
+        ZmtpSocket {
+            connections: Vec::new(),
+            weights: Vec::new(),
+            socket_type,
+            next_send: 0,
+            fair: FairQueue::new(),
+            recv_paused: false,
+            resume_waiters: Vec::new(),
+            events: None,
+            max_misses: None,
+            hwm_policy: HwmPolicy::default_for(socket_type),
+            metrics: Metrics::default(),
+            context: None,
+            linger: None,
+            send_timeout: None,
+            recv_timeout: None,
+            conflate: false,
+        }
+    }
+
+    pub(crate) fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        let remote_socket_type = peer.remote_socket_type();
+        if !self.socket_type.valid_socket_combo(&remote_socket_type) {
+            return Err(SocketError::InvalidSocketCombination(
+                self.socket_type,
+                remote_socket_type,
+            ));
+        }
+
+        self.weights.push(SendWeight {
+            weight: peer.weight().unwrap_or(1),
+            credit: 0,
+        });
+        self.emit(
+            |peer_type, routing_id| EventKind::Attached {
+                peer_type,
+                routing_id,
+            },
+            &peer,
+        );
+        self.connections.push(peer);
+        self.fair.attach();
+        Ok(())
+    }
+
+    pub(crate) fn set_event_sink(&mut self, sink: EventSink) {
+        self.events = Some(sink);
+    }
+
+    pub(crate) fn set_hwm_policy(&mut self, policy: HwmPolicy) {
+        self.hwm_policy = policy;
+    }
+
+    pub(crate) fn hwm_policy(&self) -> HwmPolicy {
+        self.hwm_policy
+    }
+
+    // While conflating, `recv_fair` skips to the newest message each peer
+    // has waiting.
+    pub(crate) fn set_conflate(&mut self, conflate: bool) {
+        self.conflate = conflate;
+    }
+
+    // Once `context` is terminated, everything the socket does fails.
+    pub(crate) fn set_context(&mut self, context: &Context) {
+        self.context = Some(context.register());
+    }
+
+    fn check_terminated(&self) -> Result<(), SocketError> {
+        match &self.context {
+            Some(context) if context.is_terminated() => Err(SocketError::Terminated),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> Metrics {
+        self.metrics
+    }
+
+    fn emit<F>(&self, kind: F, peer: &P)
+    where
+        F: FnOnce(SocketType, Option<Vec<u8>>) -> EventKind,
+    {
+        if let Some(events) = &self.events {
+            let kind = kind(
+                peer.remote_socket_type(),
+                peer.routing_id().map(<[u8]>::to_vec),
+            );
+            events.emit(SocketEvent::new(self.socket_type, kind));
+        }
+    }
+
+    // Weights below 1 count as 1.
+    pub(crate) fn set_weight(&mut self, idx: usize, weight: u32) {
+        self.weights[idx].weight = weight.max(1);
+    }
+
+    pub(crate) fn connections(&self) -> &[P] {
+        self.connections.as_slice()
+    }
+
+    pub(crate) fn connections_mut(&mut self) -> &mut [P] {
+        self.connections.as_mut_slice()
+    }
+
+    pub(crate) fn remove(&mut self, idx: usize, reason: CloseReason) -> P {
+        self.weights.remove(idx);
+        self.fair.remove(idx);
+        let peer = self.connections.remove(idx);
+        self.emit(
+            |peer_type, routing_id| EventKind::Detached {
+                peer_type,
+                routing_id,
+                reason,
+            },
+            &peer,
+        );
+        peer
+    }
+
+    // Closes and drops every peer that was reached through `origin`, and
+    // returns the indices they had, highest first.
+    pub(crate) async fn detach(&mut self, origin: &Origin) -> Vec<usize> {
+        let mut detached = Vec::new();
+        for idx in (0..self.connections.len()).rev() {
+            if self.connections[idx].origin() == Some(origin) {
+                let mut peer = self.remove(idx, CloseReason::Dropped);
+                // The peer is gone either way, so failing to say goodbye
+                // doesn't matter.
+                let _ = peer.close().await;
+                detached.push(idx);
+            }
+        }
+        detached
+    }
+
+    // Detaches the peers that were connected to `endpoint`, and returns how
+    // many there were.
+    pub(crate) async fn disconnect(&mut self, endpoint: &Endpoint) -> usize {
+        let origin = Origin::Connected(endpoint.clone());
+        self.detach(&origin).await.len()
+    }
+
+    // Detaches the peers that were accepted on `endpoint`, and returns how
+    // many there were.
+    pub(crate) async fn unbind(&mut self, endpoint: &Endpoint) -> usize {
+        let origin = Origin::Accepted(endpoint.clone());
+        self.detach(&origin).await.len()
+    }
+
+    pub(crate) fn pause_recv(&mut self) {
+        self.recv_paused = true;
+    }
+
+    pub(crate) fn resume_recv(&mut self) {
+        self.recv_paused = false;
+        self.resume_waiters.drain(..).for_each(Waker::wake);
+    }
+
+    pub(crate) fn recv_paused(&self) -> bool {
+        self.recv_paused
+    }
+
+    async fn recv_resumed(&mut self) {
+        future::poll_fn(|cx| {
+            // Receiving fails straight away once the context is terminated.
+            let terminated = match &self.context {
+                Some(context) => context.poll_terminated(cx).is_ready(),
+                None => false,
+            };
+            if !self.recv_paused || terminated {
+                return Poll::Ready(());
+            }
+            if !self.resume_waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                self.resume_waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    // Sends to the next peer in turn, skipping over peers that aren't ready
+    // for another message. If none are ready, the high-water mark policy
+    // decides whether to wait for the first one that is, drop the message or
+    // fail. Returns the index of the peer the message went to, if it wasn't
+    // dropped. Peers that fail are dropped.
+    pub(crate) async fn send_round_robin(
+        &mut self,
+        msg: Message,
+    ) -> Result<Option<usize>, SocketError> {
+        let timeout = self.send_timeout;
+        let idx = match self.hwm_policy {
+            HwmPolicy::Block => {
+                let ready = future::poll_fn(|cx| self.poll_next_ready(cx));
+                timer::timeout(timeout, ready).await??
+            }
+            policy => match future::poll_fn(|cx| Poll::Ready(self.poll_next_ready(cx))).await {
+                Poll::Ready(idx) => idx?,
+                Poll::Pending if policy == HwmPolicy::Drop => {
+                    self.metrics.dropped();
+                    return Ok(None);
+                }
+                Poll::Pending => return Err(SocketError::HighWaterMark),
+            },
+        };
+        self.send_to(idx, msg).await?;
+        Ok(Some(idx))
+    }
+
+    // Peers with degraded liveness are only picked when no healthy peer is
+    // ready.
+    pub(crate) fn poll_next_ready(
+        &mut self,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<usize, SocketError>> {
+        if let Some(context) = &self.context {
+            if context.poll_terminated(cx).is_ready() {
+                return Poll::Ready(Err(SocketError::Terminated));
+            }
+        }
+
+        let first_weight = self.weights.first().map(|w| w.weight);
+        if self.weights.iter().any(|w| Some(w.weight) != first_weight) {
+            return self.poll_next_weighted(cx);
+        }
+
+        let mut fallback = None;
+        let mut offset = 0;
+        while offset < self.connections.len() {
+            let idx = (self.next_send + offset) % self.connections.len();
+            match self.connections[idx].poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    let degraded = self.connections[idx]
+                        .liveness()
+                        .map(Liveness::is_degraded)
+                        .unwrap_or(false);
+                    if !degraded {
+                        self.next_send = idx + 1;
+                        return Poll::Ready(Ok(idx));
+                    }
+                    fallback = fallback.or(Some(idx));
+                    offset += 1;
+                }
+                Poll::Ready(Err(err)) => {
+                    self.remove(idx, err.close_reason());
+                    fallback = None;
+                    offset = 0;
+                }
+                Poll::Pending => offset += 1,
+            }
+        }
+
+        if let Some(idx) = fallback {
+            self.next_send = idx + 1;
+            Poll::Ready(Ok(idx))
+        } else if self.connections.is_empty() {
+            Poll::Ready(Err(SocketError::NoPeers))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    // Smooth weighted round-robin, as nginx does it: every ready peer earns
+    // credit in proportion to its weight, and the one with the most credit is
+    // picked and pays for it with what all of them earned. That spreads each
+    // peer's share evenly over time instead of sending it in bursts.
+    fn poll_next_weighted(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<usize, SocketError>> {
+        let mut healthy = Vec::new();
+        let mut degraded = Vec::new();
+        let mut idx = 0;
+        while idx < self.connections.len() {
+            match self.connections[idx].poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    let is_degraded = self.connections[idx]
+                        .liveness()
+                        .map(Liveness::is_degraded)
+                        .unwrap_or(false);
+                    match is_degraded {
+                        true => degraded.push(idx),
+                        false => healthy.push(idx),
+                    }
+                    idx += 1;
+                }
+                // Only peers we've already passed were collected, so their
+                // indices are unaffected.
+                Poll::Ready(Err(err)) => {
+                    self.remove(idx, err.close_reason());
+                }
+                Poll::Pending => idx += 1,
+            }
+        }
+
+        let candidates = if healthy.is_empty() {
+            degraded
+        } else {
+            healthy
+        };
+        if candidates.is_empty() {
+            return match self.connections.is_empty() {
+                true => Poll::Ready(Err(SocketError::NoPeers)),
+                false => Poll::Pending,
+            };
+        }
+
+        let mut total = 0;
+        let mut picked = candidates[0];
+        for &idx in candidates.iter() {
+            let weight = &mut self.weights[idx];
+            weight.credit += i64::from(weight.weight);
+            total += i64::from(weight.weight);
+            if weight.credit > self.weights[picked].credit {
+                picked = idx;
+            }
+        }
+        self.weights[picked].credit -= total;
+        Poll::Ready(Ok(picked))
+    }
+
+    // Sends a heartbeat to every peer. Peers that fail are dropped, as are
+    // peers that have missed too many heartbeats in a row, if there's a
+    // limit.
+    pub(crate) async fn heartbeat(&mut self) {
+        for idx in (0..self.connections.len()).rev() {
+            let misses = self.connections[idx]
+                .liveness()
+                .map(Liveness::consecutive_misses)
+                .unwrap_or(0);
+            if matches!(self.max_misses, Some(max) if misses >= max) {
+                self.remove(idx, CloseReason::HeartbeatTimeout);
+                continue;
+            }
+
+            if let Err(err) = self.connections[idx].send_heartbeat().await {
+                self.remove(idx, err.close_reason());
+            }
+        }
+    }
+
+    // Writes out whatever the peers are holding back. Peers that fail are
+    // dropped, and returned for sockets that announce them.
+    pub(crate) async fn flush(&mut self) -> Vec<P> {
+        let mut dropped = Vec::new();
+        for idx in (0..self.connections.len()).rev() {
+            if let Err(err) = self.connections[idx].flush().await {
+                dropped.push(self.remove(idx, err.close_reason()));
+            }
+        }
+        dropped
+    }
+
+    // How long `close` waits for the peers to write out what they're holding
+    // back and say goodbye. `None` waits for as long as it takes.
+    pub(crate) fn set_linger(&mut self, linger: Option<Duration>) {
+        self.linger = linger;
+    }
+
+    // Closes every peer, giving them up to the linger period to finish, and
+    // drops them whether or not they did.
+    pub(crate) async fn close(mut self) {
+        let mut peers = Vec::new();
+        while !self.connections.is_empty() {
+            peers.push(self.remove(0, CloseReason::Dropped));
+        }
+        let closing = future::join_all(peers.iter_mut().map(|peer| async move {
+            // The peer is dropped either way.
+            let _ = peer.close().await;
+        }));
+
+        match self.linger {
+            // Peers still get one go at closing, which is all that in-process
+            // peers and ones with nothing left to write need to say goodbye.
+            Some(linger) if linger.is_zero() => {
+                let _ = closing.now_or_never();
+            }
+            linger => {
+                let _ = timer::timeout(linger, closing).await;
+            }
+        }
+    }
+
+    // Peers that miss this many heartbeats in a row are dropped.
+    pub(crate) fn set_max_misses(&mut self, max_misses: Option<u32>) {
+        self.max_misses = max_misses;
+    }
+
+    // Applies the socket's own settings. The connection options are for
+    // whatever makes its connections.
+    pub(crate) fn set_options(&mut self, options: &SocketOptions) {
+        for option in options.settings() {
+            self.set_option(option.clone());
+        }
+        if let Some(sink) = options.events() {
+            self.set_event_sink(sink.clone());
+        }
+    }
+
+    pub(crate) fn set_option(&mut self, option: SocketOption) {
+        match option {
+            SocketOption::Linger(linger) => self.set_linger(linger),
+            SocketOption::HeartbeatTimeout(misses) => self.set_max_misses(misses),
+            SocketOption::HwmPolicy(policy) => self.set_hwm_policy(policy),
+            SocketOption::Conflate(conflate) => self.set_conflate(conflate),
+            SocketOption::SendTimeout(timeout) => self.send_timeout = timeout,
+            SocketOption::RecvTimeout(timeout) => self.recv_timeout = timeout,
+        }
+    }
+
+    pub(crate) fn get_option(&self, name: SocketOptionName) -> SocketOption {
+        match name {
+            SocketOptionName::Linger => SocketOption::Linger(self.linger),
+            SocketOptionName::HeartbeatTimeout => SocketOption::HeartbeatTimeout(self.max_misses),
+            SocketOptionName::HwmPolicy => SocketOption::HwmPolicy(self.hwm_policy),
+            SocketOptionName::Conflate => SocketOption::Conflate(self.conflate),
+            SocketOptionName::SendTimeout => SocketOption::SendTimeout(self.send_timeout),
+            SocketOptionName::RecvTimeout => SocketOption::RecvTimeout(self.recv_timeout),
+        }
+    }
+
+    pub(crate) fn send_timeout(&self) -> Option<Duration> {
+        self.send_timeout
+    }
+
+    pub(crate) fn recv_timeout(&self) -> Option<Duration> {
+        self.recv_timeout
+    }
+
+    // Only waiting for the peer to be ready to take the message counts
+    // towards the send timeout. Once writing it has begun, it's finished,
+    // since giving up partway would leave the peer with half a message.
+    pub(crate) async fn send_to(&mut self, idx: usize, msg: Message) -> Result<(), SocketError> {
+        self.check_terminated()?;
+        if let Some(timeout) = self.send_timeout {
+            let peer = &mut self.connections[idx];
+            let ready = future::poll_fn(|cx| peer.poll_ready(cx));
+            if let Err(err) = timer::timeout(Some(timeout), ready).await? {
+                self.remove(idx, err.close_reason());
+                return Err(err.into());
+            }
+        }
+        let bytes = message_bytes(&msg);
+        if let Err(err) = self.connections[idx].send_message(msg).await {
+            self.remove(idx, err.close_reason());
+            return Err(err.into());
+        }
+
+        self.metrics.sent(bytes);
+        Ok(())
+    }
+
+    pub(crate) async fn recv_from(&mut self, idx: usize) -> Result<Message, SocketError> {
+        self.recv_resumed().await;
+        let recv = self.connections[idx].recv_message();
+        let recv = unless_terminated(self.context.as_ref(), recv);
+        match timer::timeout(self.recv_timeout, recv).await?? {
+            Ok(msg) => {
+                self.metrics.received(&msg);
+                Ok(msg)
+            }
+            Err(err) => {
+                self.remove(idx, err.close_reason());
+                Err(err.into())
+            }
+        }
+    }
+
+    // Sends a copy of the message to every peer for which `filter` returns
+    // true. Peers that fail are dropped without reporting an error. Unless
+    // the high-water mark policy is to block, peers that aren't ready are
+    // skipped, or fail the whole send before anything goes out.
+    pub(crate) async fn send_filtered<F>(
+        &mut self,
+        msg: &Message,
+        mut filter: F,
+    ) -> Result<(), SocketError>
+    where
+        F: FnMut(&P) -> bool,
+    {
+        self.check_terminated()?;
+        let mut targets = Vec::new();
+        // Go backwards so that dropping a peer doesn't shift the ones we
+        // haven't visited yet.
+        for idx in (0..self.connections.len()).rev() {
+            if !filter(&self.connections[idx]) {
+                continue;
+            }
+            if self.hwm_policy == HwmPolicy::Block {
+                targets.push(idx);
+                continue;
+            }
+
+            let peer = &mut self.connections[idx];
+            match future::poll_fn(|cx| Poll::Ready(peer.poll_ready(cx))).await {
+                Poll::Ready(Ok(())) => targets.push(idx),
+                Poll::Ready(Err(err)) => {
+                    self.remove(idx, err.close_reason());
+                    // The peers collected so far come after this one.
+                    targets.iter_mut().for_each(|target| *target -= 1);
+                }
+                Poll::Pending if self.hwm_policy == HwmPolicy::Error => {
+                    return Err(SocketError::HighWaterMark);
+                }
+                Poll::Pending => self.metrics.dropped(),
+            }
+        }
+
+        // Highest first, as collected.
+        for idx in targets {
+            let _ = self.send_to(idx, msg.clone()).await;
+        }
+        Ok(())
+    }
+
+    // Waits for a message from any peer, returning it along with the index of
+    // the peer it came from. The peer after the last one we received from is
+    // polled first so that a single busy peer can't starve the others. Peers
+    // that fail are dropped.
+    pub(crate) async fn recv_fair(&mut self) -> Result<(usize, Message), SocketError> {
+        loop {
+            match self.recv_next().await? {
+                (idx, Ok(msg)) => return Ok((idx, self.conflated(idx, msg))),
+                (idx, Err(err)) => {
+                    self.remove(idx, err.close_reason());
+                }
+            }
+        }
+    }
+
+    // `msg`, which just came from the peer at `idx`, or the newest message
+    // the peer has waiting if the socket is conflating.
+    pub(crate) fn conflated(&mut self, idx: usize, msg: Message) -> Message {
+        match self.conflate {
+            true => self.newest_from(idx, msg),
+            false => msg,
+        }
+    }
+
+    // Replaces `msg`, which just came from the peer at `idx`, with the newest
+    // message the peer already has waiting. If the peer has failed, the
+    // messages from before the failure still count, and the failure is left
+    // for the next receive to find.
+    fn newest_from(&mut self, idx: usize, mut msg: Message) -> Message {
+        while let Some(Ok(newer)) = self.connections[idx].try_recv_message() {
+            self.metrics.received(&newer);
+            msg = newer;
+        }
+        msg
+    }
+
+    // Like `recv_fair`, but a peer that fails is returned along with its
+    // error instead of being dropped, for socket types that need to know.
+    //
+    // Peers take turns through the fair queue, which skips the ones that had
+    // nothing the last time they were asked. Only when none of them has
+    // anything does this wait on all of them at once. Receives that are
+    // dropped, whether because the peer had nothing yet or because another
+    // peer won, lose nothing since peers keep whatever they had read so far.
+    pub(crate) async fn recv_next(
+        &mut self,
+    ) -> Result<(usize, Result<Message, PeerError>), SocketError> {
+        self.recv_resumed().await;
+        self.check_terminated()?;
+        if self.connections.is_empty() {
+            return Err(SocketError::NoPeers);
+        }
+
+        while let Some(idx) = self.fair.current() {
+            match self.connections[idx].recv_message().now_or_never() {
+                Some(result) => return Ok((idx, self.received(idx, result))),
+                None => self.fair.deactivate(idx),
+            }
+        }
+
+        let recvs = self
+            .connections
+            .iter_mut()
+            .map(|peer| Box::pin(peer.recv_message()));
+        let recv = unless_terminated(self.context.as_ref(), future::select_all(recvs));
+        let (result, idx, _) = timer::timeout(self.recv_timeout, recv).await??;
+        // Any of them may have something by now.
+        self.fair.activate_all();
+        Ok((idx, self.received(idx, result)))
+    }
+
+    // Passes the turn on from the peer at `idx` if it gave us a message. A
+    // failed peer is about to be dropped, which takes it out of the queue.
+    fn received(
+        &mut self,
+        idx: usize,
+        result: Result<Message, PeerError>,
+    ) -> Result<Message, PeerError> {
+        if let Ok(msg) = &result {
+            self.metrics.received(msg);
+            self.fair.served(idx);
+        }
+        result
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SendWeight {
+    weight: u32,
+    credit: i64,
 }
 
 #[derive(Debug, Clone)]
 pub struct Connection<S> {
     remote_version: Version,
+    version: Version,
     remote_socket_type: SocketType,
-    multipart_buffer: Vec<MessageFrame>,
+    remote_routing_id: Option<Vec<u8>>,
+    remote_weight: Option<u32>,
+    recv: RecvHalf,
+    send: SendHalf,
+    liveness: Liveness,
+    origin: Option<Origin>,
     stream: S,
 }
 
 impl<S: AsyncBufRead + AsyncRead + AsyncWrite + Unpin> Connection<S> {
     pub async fn new(
+        stream: S,
+        socket_type: &SocketType,
+    ) -> Result<Connection<S>, ConnectionError> {
+        Connection::with_options(stream, socket_type, &ConnectionOptions::new()).await
+    }
+
+    pub async fn with_options(
         mut stream: S,
         socket_type: &SocketType,
+        options: &ConnectionOptions,
     ) -> Result<Connection<S>, ConnectionError> {
+        options.check_routing_id()?;
+        options.check_as_server()?;
+
+        // We advertise the newest version we're willing to speak, and the peer
+        // falls back to ours if it's older.
+        let our_greeting = Greeting {
+            version: options.advertised_version(),
+            mechanism: options.mechanism(),
+            as_server: options.as_server_role(),
+        };
+        our_greeting.write_to(&mut stream).await?;
+
         let greeting = Greeting::read_new(&mut stream).await?;
         let remote_version = greeting.version;
 
-        // TODO: Send error here if remote_version isn't supported.
+        // The spec has us close the connection, without an ERROR command,
+        // if we can't agree on a version.
+        let version = options.negotiate_version(remote_version)?;
 
-        let handshake = Handshake::perform(&mut stream, &greeting, &socket_type).await?;
+        let handshake = Handshake::perform(&mut stream, &greeting, socket_type, options).await?;
 
-        let remote_socket_type_bytes = match handshake {
-            Handshake::Null(null_handshake) => {
-                null_handshake.properties.get(String::from("socket-type")).map(|slice| slice.to_vec())
+        let user_id = handshake.user_id().map(str::to_string);
+        let properties = handshake.into_properties();
+        let remote = match Remote::check(&properties, socket_type) {
+            Ok(remote) => remote,
+            Err(err) => {
+                if let Some(reason) = err.error_command() {
+                    reason.into_frame().write_to(&mut stream).await?;
+                }
+                return Err(err);
             }
         };
-        let remote_socket_type_bytes =
-            remote_socket_type_bytes.ok_or(ConnectionError::MissingRemoteSocketType)?;
-        let remote_socket_type = SocketType::try_from(remote_socket_type_bytes.as_slice())?;
+
+        // Only SUB sockets send subscriptions, and ZMTP 3.1 has commands
+        // for them.
+        let mut send = SendHalf::new(
+            remote.batching,
+            remote.announces_close,
+            options.cork_bytes(),
+        );
+        send.subscription_commands = version >= Version::new(3, 1)
+            && matches!(socket_type, SocketType::Sub | SocketType::XSub);
+
+        let mut recv = RecvHalf::new(remote.batching, options);
+        recv.metadata = Arc::new(message_metadata(properties, user_id));
+
+        Ok(Self {
+            remote_version,
+            version,
+            remote_socket_type: remote.socket_type,
+            remote_routing_id: remote.routing_id,
+            remote_weight: remote.weight,
+            recv,
+            send,
+            liveness: Liveness::new(),
+            origin: None,
+            stream,
+        })
+    }
+
+    pub async fn recv_frame(&mut self) -> Result<Frame, RecvFrameError> {
+        self.flush_corked().await?;
+        let frame = self.recv.reader.read_frame(&mut self.stream, None).await?;
+        if let Some(hook) = &self.recv.frame_hook {
+            hook.received(&frame);
+        }
+        Ok(frame)
+    }
+
+    /// Reads the next frame's header and hands back a reader for exactly the
+    /// body that follows, for frames too big to hold in memory. The body has
+    /// to be read to the end before anything else is received from the
+    /// peer. Commands come through here too, with their name, behind a
+    /// one-byte size, at the start of the body.
+    ///
+    /// A frame that a dropped `recv_message` had started on is picked up
+    /// where it left off. A message it had received part of has to be
+    /// finished with `recv_message` first, though, and this fails with
+    /// `MessageInProgress` until it is.
+    pub async fn recv_frame_streaming(
+        &mut self,
+    ) -> Result<(FrameHeader, StreamedBody<'_, S>), RecvFrameError> {
+        if !self.recv.multipart_buffer.is_empty() || !self.recv.unbatched.is_empty() {
+            return Err(RecvFrameError::MessageInProgress);
+        }
+        self.flush_corked().await?;
+        if self
+            .recv
+            .reader
+            .read_header(&mut self.stream)
+            .await?
+            .is_none()
+        {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let (header, read) = self.recv.reader.take_frame();
+        let rest = header.len() - read.len() as u64;
+        let body = io::Cursor::new(read).chain((&mut self.stream).take(rest));
+        Ok((header, body))
+    }
+
+    /// Sends a message frame of `len` bytes read from `reader`, without
+    /// holding it in memory. `more` says whether further frames of the same
+    /// message follow. If `reader` ends before `len` bytes, this fails with
+    /// `UnexpectedEof`, and the connection can't be used any more because
+    /// the peer is still waiting for the rest of the frame.
+    pub async fn send_frame_from_reader<R: AsyncRead + Unpin>(
+        &mut self,
+        more: bool,
+        len: u64,
+        reader: R,
+    ) -> Result<(), PeerError> {
+        self.flush_corked().await?;
+        FrameHeader::message(more, len)
+            .write_to(&mut self.stream)
+            .await?;
+        let copied = io::copy(reader.take(len), &mut self.stream).await?;
+        if copied < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// The version the peer advertised in its greeting.
+    pub fn remote_version(&self) -> Version {
+        self.remote_version
+    }
+
+    /// The version both ends agreed to speak.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Records how the peer was reached, for connections set up over streams
+    /// of the application's own.
+    pub fn set_origin(&mut self, origin: Origin) {
+        self.origin = Some(origin);
+    }
+
+    // Records the IP address of the peer in the metadata of the messages it
+    // sends us.
+    pub(crate) fn set_peer_address(&mut self, addr: IpAddr) {
+        let metadata = Arc::make_mut(&mut self.recv.metadata);
+        metadata.set(PEER_ADDRESS, addr.to_string().into_bytes());
+    }
+
+    /// Reads received messages into buffers from `pool`. Messages only give
+    /// their buffers back if they're leased from the pool, as with
+    /// `recv_pooled`.
+    pub fn set_message_pool(&mut self, pool: MessagePool) {
+        self.recv.pool = Some(pool);
+    }
+
+    /// Calls `handler` with the data of every command named `name` that the
+    /// peer sends. If it returns a command, that's sent back. Registering a
+    /// name again replaces its handler.
+    pub fn register_command<F>(&mut self, name: &str, handler: F) -> Result<(), CommandNameError>
+    where
+        F: Fn(&[u8]) -> Option<Command> + Send + Sync + 'static,
+    {
+        command::validate_name(name)?;
+        self.recv
+            .handlers
+            .insert(name.to_string(), CommandHandler(Arc::new(handler)));
+        Ok(())
+    }
+
+    /// Calls `hook` with the bytes of every frame sent or received from now
+    /// on, for debugging problems with other ZMTP implementations. Sent
+    /// frames are passed as they were written. Received ones are passed as
+    /// encoded again after parsing, which gives the same bytes for frames
+    /// that follow the spec. Frames streamed with `send_frame_from_reader`
+    /// or `recv_frame_streaming` aren't passed.
+    pub fn set_frame_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(FrameDirection, &[u8]) + Send + Sync + 'static,
+    {
+        let hook = FrameHook(Arc::new(hook));
+        self.recv.frame_hook = Some(hook.clone());
+        self.send.frame_hook = Some(hook);
+    }
+
+    /// How much has been sent and received over the connection, not counting
+    /// the handshake or commands.
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            messages_received: self.recv.metrics.messages_received,
+            bytes_received: self.recv.metrics.bytes_received,
+            ..self.send.metrics
+        }
+    }
+
+    pub async fn send_command(&mut self, cmd: Command) -> Result<(), PeerError> {
+        self.write_frame_now(&split::command_frame(cmd)).await?;
+        Ok(())
+    }
+
+    /// Splits the connection into halves that receive and send
+    /// independently, so that one task can wait for messages while another
+    /// sends them. Anything already received or held back to be sent goes
+    /// with the half it belongs to.
+    pub fn split(self) -> (ConnectionReader<S>, ConnectionWriter<S>) {
+        split::split(
+            self.stream,
+            self.version,
+            self.remote_socket_type,
+            self.recv,
+            self.send,
+            self.liveness,
+        )
+    }
+
+    async fn write_frame_now(&mut self, frame: &Frame) -> io::Result<()> {
+        self.send.write_frame_now(&mut self.stream, frame).await
+    }
+
+    async fn flush_corked(&mut self) -> io::Result<()> {
+        self.send.flush_corked(&mut self.stream).await
+    }
+
+    /// Receives a message that returns its buffers to the connection's pool
+    /// when dropped. Without a pool, the buffers are simply freed.
+    pub async fn recv_pooled(&mut self) -> Result<PooledMessage, PeerError> {
+        let msg = self.recv_message().await?;
+        let pool = self
+            .recv
+            .pool
+            .get_or_insert_with(|| MessagePool::with_max_buffers(0));
+        Ok(pool.lease(msg))
+    }
+}
+
+impl<S: AsyncBufRead + AsyncRead + AsyncWrite + Unpin> Peer for Connection<S> {
+    fn remote_socket_type(&self) -> SocketType {
+        self.remote_socket_type
+    }
+
+    fn routing_id(&self) -> Option<&[u8]> {
+        self.remote_routing_id.as_deref()
+    }
+
+    fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+
+    fn weight(&self) -> Option<u32> {
+        self.remote_weight
+    }
+
+    async fn send_message(&mut self, msg: Message) -> Result<(), PeerError> {
+        self.send.send_message(&mut self.stream, msg).await
+    }
+
+    async fn send_batch(&mut self, msgs: Vec<Message>) -> Result<(), PeerError> {
+        self.send.send_batch(&mut self.stream, msgs).await
+    }
+
+    // Only messages left over from a batch are known to have arrived in
+    // full; reading from the stream could stop part of the way through one.
+    fn try_recv_message(&mut self) -> Option<Result<Message, PeerError>> {
+        self.recv.pop_unbatched().map(Ok)
+    }
+
+    async fn recv_message(&mut self) -> Result<Message, PeerError> {
+        if let Some(msg) = self.recv.pop_unbatched() {
+            return Ok(msg);
+        }
+        // The peer may not send anything until it's seen what we held back.
+        self.flush_corked().await?;
+
+        loop {
+            match self.recv.recv(&mut self.stream).await? {
+                Received::Message(msg) => return Ok(msg),
+                Received::Reply(frame) => {
+                    self.send.cork(&frame);
+                    self.flush_corked().await?;
+                }
+                Received::Pong(seq) => self.liveness.pong_received(seq, Instant::now()),
+            }
+        }
+    }
+
+    async fn send_heartbeat(&mut self) -> Result<(), PeerError> {
+        // Heartbeats were added in ZMTP 3.1.
+        if self.version < Version::new(3, 1) {
+            return Ok(());
+        }
+
+        let seq = self.liveness.ping_sent(Instant::now());
+        let ping = heartbeat::ping(seq).into_frame();
+        self.write_frame_now(&ping).await?;
+        Ok(())
+    }
+
+    fn liveness(&self) -> Option<&Liveness> {
+        Some(&self.liveness)
+    }
+
+    async fn flush(&mut self) -> Result<(), PeerError> {
+        Ok(self.flush_corked().await?)
+    }
+
+    async fn close(&mut self) -> Result<(), PeerError> {
+        self.send.close(&mut self.stream).await
+    }
+}
+
+// The metadata of the messages a peer sends: the properties from its
+// handshake, without any it had no business sending, and who it
+// authenticated as.
+pub(crate) fn message_metadata(mut properties: Properties, user_id: Option<String>) -> Properties {
+    properties.remove(USER_ID);
+    properties.remove(PEER_ADDRESS);
+    if let Some(user_id) = user_id {
+        properties.set(USER_ID, user_id.into_bytes());
+    }
+    properties
+}
+
+// What the peer said about itself in the handshake.
+#[derive(Debug, Clone)]
+pub(crate) struct Remote {
+    pub(crate) socket_type: SocketType,
+    pub(crate) routing_id: Option<Vec<u8>>,
+    pub(crate) weight: Option<u32>,
+    pub(crate) batching: bool,
+    pub(crate) announces_close: bool,
+}
+
+impl Remote {
+    // Reads the peer's metadata, and checks that a socket of type
+    // `socket_type` can talk to it.
+    pub(crate) fn check(
+        properties: &Properties,
+        socket_type: &SocketType,
+    ) -> Result<Remote, ConnectionError> {
+        let remote_socket_type = properties
+            .socket_type()?
+            .ok_or(ConnectionError::MissingRemoteSocketType)?;
+        let routing_id = properties.identity().map(<[u8]>::to_vec);
+        if let Some(routing_id) = &routing_id {
+            if routing_id.len() > options::MAX_ROUTING_ID_LEN {
+                return Err(ConnectionError::RemoteRoutingIdTooLong(routing_id.len()));
+            }
+        }
 
         // Check if the socket types are a valid combination.
         if !socket_type.valid_socket_combo(&remote_socket_type) {
-            let err_cmd = Frame::new_fatal_error("invalid socket combination");
-            err_cmd.write_to(&mut stream).await?;
             return Err(ConnectionError::InvalidSocketCombination(
                 *socket_type,
                 remote_socket_type,
             ));
         }
 
-        Ok(Self {
-            remote_version,
-            remote_socket_type,
-            multipart_buffer: Vec::new(),
-            stream,
+        Ok(Remote {
+            socket_type: remote_socket_type,
+            routing_id,
+            weight: properties.weight(),
+            batching: properties.get(BATCH_PROPERTY).is_some(),
+            announces_close: properties.get(CLOSE_PROPERTY).is_some(),
         })
     }
-
-    pub async fn recv_frame(&mut self) -> Result<Frame, RecvFrameError> {
-        Ok(Frame::read_new(&mut self.stream).await?)
-    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -94,6 +1162,43 @@ pub enum ConnectionError {
 
     #[error("remote peer must provide socket type")]
     MissingRemoteSocketType,
+
+    #[error("routing ID must be 1 to 255 bytes and not start with a zero byte: {0:?}")]
+    InvalidRoutingId(Vec<u8>),
+
+    #[error("remote peer asked for a routing ID of {0} bytes, more than 255")]
+    RemoteRoutingIdTooLong(usize),
+
+    #[error("as-server setting doesn't fit our {0} role")]
+    AsServerConflict(&'static str),
+
+    #[error("gave up connecting to {0} after its handshake failed")]
+    ReconnectStopped(Endpoint),
+}
+
+impl ConnectionError {
+    // The ERROR command to tell the peer with before closing, for the errors
+    // in its handshake that call for one.
+    pub(crate) fn error_command(&self) -> Option<ZmtpCommand> {
+        let reason = match self {
+            ConnectionError::RemoteRoutingIdTooLong(_) => "routing ID too long",
+            ConnectionError::InvalidSocketCombination(..) => "invalid socket combination",
+            _ => return None,
+        };
+        Some(ZmtpCommand::Error(reason.to_string()))
+    }
+
+    /// Why the connection ended before it was set up.
+    pub fn close_reason(&self) -> CloseReason {
+        match self {
+            ConnectionError::Io(err) => CloseReason::Io(err.kind()),
+            ConnectionError::Handshake(HandshakeError::Plain(
+                PlainHandshakeError::Denied | PlainHandshakeError::Rejected(_),
+            )) => CloseReason::AuthDenied,
+            ConnectionError::InvalidSocketCombination(..) => CloseReason::InvalidSocketCombination,
+            _ => CloseReason::Protocol,
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -103,9 +1208,17 @@ pub enum RecvFrameError {
 
     #[error("could not parse frame")]
     MalformedFrame(#[from] FrameParseError),
+
+    /// Part of a message, or of a batch of them, has been received but not
+    /// handed over yet.
+    #[error("a message is partly received")]
+    MessageInProgress,
 }
 
-#[derive(Debug, Clone)]
+/// The body of a frame received with `Connection::recv_frame_streaming`.
+pub type StreamedBody<'a, S> = io::Chain<io::Cursor<Vec<u8>>, io::Take<&'a mut S>>;
+
+#[derive(Debug, Clone, PartialEq)]
 struct Greeting {
     version: Version,
     mechanism: Mechanism,
@@ -117,41 +1230,30 @@ impl Greeting {
     where
         R: AsyncRead + Unpin,
     {
-        // Read signature
-        let mut sig_first_byte_buf = [0_u8; 1];
-        let mut sig_padding_buf = [0_u8; PADDING_LEN];
-        let mut sig_last_byte_buf = [0_u8; 1];
-
-        stream.read_exact(&mut sig_first_byte_buf).await?;
-        stream.read_exact(&mut sig_padding_buf).await?;
-        stream.read_exact(&mut sig_last_byte_buf).await?;
-
-        let sig_first_byte = u8::from_be_bytes(sig_first_byte_buf);
-        let sig_last_byte = u8::from_be_bytes(sig_last_byte_buf);
+        // The signature is checked as soon as it arrives, so that a peer that
+        // doesn't speak ZMTP finds out before it would have to send the rest.
+        let mut buf = [0_u8; GREETING_LEN];
+        let (signature, rest) = buf.split_at_mut(SIGNATURE_LEN);
+        stream.read_exact(signature).await?;
+        check_signature(signature)?;
+        stream.read_exact(rest).await?;
 
-        if sig_first_byte != 0xFF {
-            return Err(GreetingError::Signature);
-        }
-
-        if sig_last_byte != 0x7F {
-            return Err(GreetingError::Signature);
-        }
-
-        // Read version
-        let mut version_major_buf = [0_u8; 1];
-        let mut version_minor_buf = [0_u8; 1];
+        Greeting::decode(&buf)
+    }
 
-        stream.read_exact(&mut version_major_buf).await?;
-        stream.read_exact(&mut version_minor_buf).await?;
+    pub fn decode(buf: &[u8; GREETING_LEN]) -> Result<Greeting, GreetingError> {
+        check_signature(&buf[..SIGNATURE_LEN])?;
+        let mut rest = &buf[SIGNATURE_LEN..];
 
         let version = Version {
-            major: u8::from_be_bytes(version_major_buf),
-            minor: u8::from_be_bytes(version_minor_buf),
+            major: rest[0],
+            minor: rest[1],
         };
+        rest = &rest[2..];
 
         // Read mechanism
-        let mut mechanism_buf = [0_u8; 20];
-        stream.read_exact(&mut mechanism_buf).await?;
+        let mechanism_buf = &rest[..MECHANISM_LEN];
+        rest = &rest[MECHANISM_LEN..];
         let null_idx = mechanism_buf
             .iter()
             .position(|&x| x == 0x00)
@@ -164,28 +1266,61 @@ impl Greeting {
         }
         let mechanism = match mechanism_str {
             "NULL" => Mechanism::Null,
+            "PLAIN" => Mechanism::Plain,
             _ => return Err(GreetingError::MechanismUnsupported),
         };
 
-        // Read as-server
-        let mut as_server_buf = [0_u8; 1];
-        stream.read_exact(&mut as_server_buf).await?;
-        let as_server = match as_server_buf {
-            [0x00] => AsServer::Client,
-            [0x01] => AsServer::Server,
-            [x] => return Err(GreetingError::AsServer(x)),
+        // The filler that follows isn't checked.
+        let as_server = match rest[0] {
+            0x00 => AsServer::Client,
+            0x01 => AsServer::Server,
+            x => return Err(GreetingError::AsServer(x)),
         };
 
-        // Read filler
-        let mut filler_buf = [0_u8; FILLER_LEN];
-        stream.read_exact(&mut filler_buf).await?;
-
         Ok(Self {
             version,
             mechanism,
             as_server,
         })
     }
+
+    pub fn encode(&self) -> [u8; GREETING_LEN] {
+        let mut buf = [0_u8; GREETING_LEN];
+        buf[0] = 0xFF;
+        buf[SIGNATURE_LEN - 1] = 0x7F;
+
+        let mut rest = &mut buf[SIGNATURE_LEN..];
+        rest[0] = self.version.major;
+        rest[1] = self.version.minor;
+        rest = &mut rest[2..];
+
+        let mechanism_name = self.mechanism.name().as_bytes();
+        rest[..mechanism_name.len()].copy_from_slice(mechanism_name);
+        rest = &mut rest[MECHANISM_LEN..];
+
+        rest[0] = match self.as_server {
+            AsServer::Client => 0x00,
+            AsServer::Server => 0x01,
+        };
+
+        buf
+    }
+
+    pub async fn write_to<W>(&self, stream: &mut W) -> Result<(), io::Error>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        stream.write_all(&self.encode()).await?;
+        stream.flush().await
+    }
+}
+
+// The signature is 0xFF, eight bytes of padding, and 0x7F.
+fn check_signature(signature: &[u8]) -> Result<(), GreetingError> {
+    if signature[0] != 0xFF || signature[SIGNATURE_LEN - 1] != 0x7F {
+        return Err(GreetingError::Signature);
+    }
+    Ok(())
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -212,21 +1347,48 @@ pub enum GreetingError {
     AsServer(u8),
 }
 
-
 /// `Version` can be returned as part of an error in `GreetingError`. It
 /// might be helpful for downstream crates to use this information.
-#[derive(Debug, Clone, Copy)]
+///
+/// Versions are ordered by major version, then minor version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Version {
     major: u8,
     minor: u8,
 }
 
-#[derive(Debug, Clone)]
+impl Version {
+    pub const fn new(major: u8, minor: u8) -> Version {
+        Version { major, minor }
+    }
+
+    pub fn major(&self) -> u8 {
+        self.major
+    }
+
+    pub fn minor(&self) -> u8 {
+        self.minor
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Mechanism {
     Null,
+    Plain,
 }
 
-#[derive(Debug, Clone)]
+impl Mechanism {
+    fn name(&self) -> &'static str {
+        match self {
+            Mechanism::Null => "NULL",
+            Mechanism::Plain => "PLAIN",
+        }
+    }
+}
+
+// Which end of the handshake runs the server side of the mechanism. It has
+// nothing to do with which end connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum AsServer {
     Server,
     Client,
@@ -234,8 +1396,556 @@ enum AsServer {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::{
+        arbitrary::{Gen, CASES},
+        frame::MessageFrame,
+    };
+    use futures::{executor::block_on, io::AsyncBufReadExt, FutureExt};
+    use std::sync::Mutex;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    // A connection that skipped the handshake and reads from `input`.
+    fn connection(input: Vec<u8>) -> Connection<io::Cursor<Vec<u8>>> {
+        connection_over(io::Cursor::new(input))
+    }
+
+    // A connection over `stream` that skipped the handshake.
+    pub(crate) fn connection_over<S>(stream: S) -> Connection<S> {
+        Connection {
+            remote_version: Version::new(3, 1),
+            version: Version::new(3, 1),
+            remote_socket_type: SocketType::Dealer,
+            remote_routing_id: None,
+            remote_weight: None,
+            recv: RecvHalf::new(true, &ConnectionOptions::new()),
+            send: SendHalf::new(true, true, 0),
+            liveness: Liveness::new(),
+            origin: None,
+            stream,
+        }
+    }
+
+    #[test]
+    fn test_cork() {
+        block_on(async {
+            let msgs = [
+                Message::from(vec![1; 4]),
+                Message::from(vec![2; 20]),
+                Message::from(vec![3; 4]),
+            ];
+            let mut expected = connection(Vec::new());
+            for msg in msgs.iter().cloned() {
+                expected.send_message(msg).await.unwrap();
+            }
+            let expected = expected.stream.into_inner();
+
+            let mut conn = connection(Vec::new());
+            conn.send.max_corked = 16;
+            conn.send_message(msgs[0].clone()).await.unwrap();
+            assert!(conn.stream.get_ref().is_empty());
+
+            // Going over the limit writes everything held back so far.
+            conn.send_message(msgs[1].clone()).await.unwrap();
+            assert_eq!(conn.stream.get_ref().len(), 2 + 4 + 2 + 20);
+            conn.send_message(msgs[2].clone()).await.unwrap();
+            assert_eq!(conn.stream.get_ref().len(), 2 + 4 + 2 + 20);
+
+            conn.flush().await.unwrap();
+            assert_eq!(conn.metrics().messages_sent, 3);
+            assert_eq!(conn.metrics().bytes_sent, 28);
+            assert_eq!(conn.stream.into_inner(), expected);
+        });
+    }
+
+    #[test]
+    fn test_frame_hook() {
+        block_on(async {
+            let mut input = Vec::new();
+            ZmtpCodec::new().encode(&Frame::new_message(false, b"in".to_vec()), &mut input);
+            let len = input.len();
+
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let mut conn = connection(input.clone());
+            let hook_seen = seen.clone();
+            conn.set_frame_hook(move |direction, bytes| {
+                hook_seen.lock().unwrap().push((direction, bytes.to_vec()));
+            });
+            assert_eq!(
+                conn.recv_message().await.unwrap(),
+                Message::from(&b"in"[..])
+            );
+            conn.send_message(Message::from(&b"out"[..])).await.unwrap();
+
+            let output = conn.stream.into_inner();
+            let seen = seen.lock().unwrap();
+            assert_eq!(
+                *seen,
+                vec![
+                    (FrameDirection::Received, input),
+                    (FrameDirection::Sent, output[len..].to_vec()),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_orderly_close() {
+        block_on(async {
+            let mut conn = connection(Vec::new());
+            assert!(matches!(conn.recv_message().await, Err(PeerError::Closed)));
+
+            // Closing in the middle of a multipart message isn't orderly.
+            let mut conn = connection(Vec::new());
+            conn.recv.multipart_buffer.push(MessageFrame {
+                more: true,
+                data: b"first".to_vec(),
+            });
+            assert!(matches!(conn.recv_message().await, Err(PeerError::Io(_))));
+
+            // Messages that arrived before the CLOSE are still delivered.
+            let mut conn = connection(Vec::new());
+            conn.recv.unbatched.push_back(Message::from(&b"last"[..]));
+            conn.recv.closed_by_peer = true;
+            assert_eq!(
+                conn.recv_message().await.unwrap(),
+                Message::from(&b"last"[..])
+            );
+            assert!(matches!(conn.recv_message().await, Err(PeerError::Closed)));
+        });
+    }
+
+    #[test]
+    fn test_message_limits() {
+        block_on(async {
+            let options = ConnectionOptions::new()
+                .max_message_size(8)
+                .max_message_parts(2);
+            let frame = |len| {
+                let mut input = Vec::new();
+                ZmtpCodec::new().encode(&Frame::new_message(false, vec![1; len]), &mut input);
+                input
+            };
+
+            let mut conn = connection(frame(8));
+            conn.recv = RecvHalf::new(true, &options);
+            assert_eq!(
+                conn.recv_message().await.unwrap(),
+                Message::from(vec![1; 8])
+            );
+
+            let mut conn = connection(frame(9));
+            conn.recv = RecvHalf::new(true, &options);
+            assert!(matches!(
+                conn.recv_message().await,
+                Err(PeerError::MessageTooLarge)
+            ));
+
+            // The parts received so far count too.
+            let mut conn = connection(frame(4));
+            conn.recv = RecvHalf::new(true, &options);
+            conn.recv.multipart_buffer.push(MessageFrame {
+                more: true,
+                data: vec![2; 5],
+            });
+            assert!(matches!(
+                conn.recv_message().await,
+                Err(PeerError::MessageTooLarge)
+            ));
+
+            let mut conn = connection(frame(1));
+            conn.recv = RecvHalf::new(true, &options);
+            for _ in 0..2 {
+                conn.recv.multipart_buffer.push(MessageFrame {
+                    more: true,
+                    data: vec![2],
+                });
+            }
+            assert!(matches!(
+                conn.recv_message().await,
+                Err(PeerError::MessageTooLarge)
+            ));
+
+            // So do commands, batches included, before they're read.
+            let mut input = Vec::new();
+            FrameHeader::command(1 << 30).encode(&mut input);
+            let mut conn = connection(input);
+            conn.recv = RecvHalf::new(true, &options);
+            assert!(matches!(
+                conn.recv_message().await,
+                Err(PeerError::MessageTooLarge)
+            ));
+        });
+    }
+
+    #[test]
+    fn test_handshake_command_limit() {
+        block_on(async {
+            // The peer's READY claims to be too long to be worth waiting for.
+            let (mut a, b) = testing::duplex();
+            let greeting = Greeting {
+                version: Version::new(3, 1),
+                mechanism: Mechanism::Null,
+                as_server: AsServer::Client,
+            };
+            greeting.write_to(&mut a).await.unwrap();
+            let mut header = Vec::new();
+            FrameHeader::command(1 << 30).encode(&mut header);
+            a.write_all(&header).await.unwrap();
+
+            assert!(matches!(
+                Connection::new(b, &SocketType::Dealer).await,
+                Err(ConnectionError::Handshake(HandshakeError::Null(_)))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_streaming_frames() {
+        block_on(async {
+            let mut input = Vec::new();
+            let big = vec![7; 300];
+            FrameHeader::message(true, 300)
+                .write_to(&mut input)
+                .await
+                .unwrap();
+            input.extend_from_slice(&big);
+            FrameHeader::message(false, 4)
+                .write_to(&mut input)
+                .await
+                .unwrap();
+            input.extend_from_slice(b"tail");
+
+            let mut conn = connection(input.clone());
+            let (header, mut body) = conn.recv_frame_streaming().await.unwrap();
+            assert!(header.more() && !header.is_command());
+            assert_eq!(header.len(), 300);
+            let mut data = Vec::new();
+            body.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data, big);
+
+            let (header, mut body) = conn.recv_frame_streaming().await.unwrap();
+            assert!(!header.more());
+            let mut data = Vec::new();
+            body.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data, b"tail");
+
+            // A frame whose header was already read is picked up from there.
+            let mut conn = connection(input.clone());
+            conn.recv
+                .reader
+                .read_header(&mut conn.stream)
+                .await
+                .unwrap();
+            let (header, mut body) = conn.recv_frame_streaming().await.unwrap();
+            assert_eq!(header.len(), 300);
+            let mut data = Vec::new();
+            body.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data, big);
+
+            // Frames can't be streamed out of the middle of a message.
+            let mut conn = connection(input);
+            conn.recv.multipart_buffer.push(MessageFrame {
+                more: true,
+                data: b"head".to_vec(),
+            });
+            assert!(matches!(
+                conn.recv_frame_streaming().await,
+                Err(RecvFrameError::MessageInProgress)
+            ));
+
+            let mut conn = connection(Vec::new());
+            conn.send_frame_from_reader(false, 5, &b"hello, world"[..])
+                .await
+                .unwrap();
+            assert_eq!(conn.stream.get_ref(), b"\x00\x05hello");
+            assert!(matches!(
+                conn.send_frame_from_reader(false, 5, &b"hi"[..]).await,
+                Err(PeerError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+            ));
+        });
+    }
+
+    #[test]
+    fn test_command_handler() {
+        block_on(async {
+            let mut input = Vec::new();
+            let trace = Frame::new_command(String::from("XTRACE"), b"id".to_vec());
+            trace.write_to(&mut input).await.unwrap();
+            let len = input.len();
+
+            let mut conn = connection(input);
+            conn.register_command("XTRACE", |data| Command::new("XTRACED", data.to_vec()).ok())
+                .unwrap();
+            assert!(conn.register_command("PING", |_| None).is_err());
+            assert!(matches!(conn.recv_message().await, Err(PeerError::Closed)));
+
+            // The reply went out after the command was read.
+            let output = conn.stream.into_inner();
+            let mut reply = &output[len..];
+            match Frame::read_new(&mut reply).await.unwrap() {
+                Frame::Command(cmd) => {
+                    assert_eq!(cmd.name, "XTRACED");
+                    assert_eq!(cmd.data, b"id");
+                }
+                Frame::Message(_) => panic!("expected a command"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_greeting_round_trip() {
+        block_on(async {
+            let greeting = Greeting {
+                version: Version::new(3, 1),
+                mechanism: Mechanism::Null,
+                as_server: AsServer::Client,
+            };
+            let mut buf = Vec::new();
+            greeting.write_to(&mut buf).await.unwrap();
+            assert_eq!(buf.len(), GREETING_LEN);
+            assert_eq!(&buf[10..16], b"\x03\x01NULL");
+
+            let read = Greeting::read_new(&mut buf.as_slice()).await.unwrap();
+            assert_eq!(read.version, Version::new(3, 1));
+            assert!(matches!(read.mechanism, Mechanism::Null));
+            assert!(matches!(read.as_server, AsServer::Client));
+        });
+    }
+
+    #[test]
+    fn test_greeting_round_trip_arbitrary() {
+        for case in 0..CASES {
+            let mut gen = Gen::new(case);
+            let greeting = Greeting {
+                version: Version::new(gen.u8(), gen.u8()),
+                mechanism: match gen.bool() {
+                    true => Mechanism::Null,
+                    false => Mechanism::Plain,
+                },
+                as_server: match gen.bool() {
+                    true => AsServer::Server,
+                    false => AsServer::Client,
+                },
+            };
+            let encoded = greeting.encode();
+            assert_eq!(
+                Greeting::decode(&encoded).unwrap(),
+                greeting,
+                "case {}",
+                case
+            );
+
+            // Reading it from a stream gives the same answer.
+            let read = block_on(Greeting::read_new(&mut &encoded[..])).unwrap();
+            assert_eq!(read, greeting, "case {}", case);
+        }
+    }
+
+    #[test]
+    fn test_connection_pair() {
+        block_on(async {
+            // Every frame arrives in pieces, a little late.
+            let options = testing::DuplexOptions::new()
+                .latency(Duration::from_millis(1))
+                .max_read(3);
+            let (a, b) = testing::duplex_with(&options);
+            let (a, b) = futures::join!(
+                Connection::new(a, &SocketType::Dealer),
+                Connection::new(b, &SocketType::Router),
+            );
+            let (mut a, mut b) = (a.unwrap(), b.unwrap());
+            assert_eq!(a.remote_socket_type(), SocketType::Router);
+            assert_eq!(b.remote_socket_type(), SocketType::Dealer);
+
+            // Several multipart messages are in flight at once.
+            let msgs = [
+                Message::from(vec![vec![9; 300], Vec::new(), b"end".to_vec()]),
+                Message::from(&b"short"[..]),
+                Message::from(vec![b"two".to_vec(), b"parts".to_vec()]),
+            ];
+            for msg in msgs.iter().cloned() {
+                a.send_message(msg).await.unwrap();
+            }
+            a.close().await.unwrap();
+            for msg in msgs.iter() {
+                assert_eq!(&b.recv_message().await.unwrap(), msg);
+            }
+            assert!(matches!(b.recv_message().await, Err(PeerError::Closed)));
+        });
+    }
+
+    #[test]
+    fn test_message_metadata() {
+        block_on(async {
+            let client =
+                ConnectionOptions::new().plain_client(PlainCredentials::new("admin", "secret"));
+            let server = ConnectionOptions::new().plain_server(|_| true);
+            let (a, b) = testing::duplex();
+            let (a, b) = futures::join!(
+                Connection::with_options(a, &SocketType::Rep, &server),
+                Connection::with_options(b, &SocketType::Req, &client),
+            );
+            let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+            let request = Message::from(vec![Vec::new(), b"hello".to_vec()]);
+            b.send_message(request.clone()).await.unwrap();
+            let received = a.recv_message().await.unwrap();
+            let metadata = received.metadata().unwrap();
+            assert_eq!(metadata.get("User-Id"), Some(&b"admin"[..]));
+            assert_eq!(metadata.get("Socket-Type"), Some(&b"REQ"[..]));
+
+            // The client didn't let anyone in.
+            a.send_message(request).await.unwrap();
+            let received = b.recv_message().await.unwrap();
+            assert_eq!(received.metadata().unwrap().get("User-Id"), None);
+
+            // Peers can't vouch for themselves.
+            let (mut a, b) = testing::duplex();
+            let greeting = Greeting {
+                version: Version::new(3, 1),
+                mechanism: Mechanism::Null,
+                as_server: AsServer::Client,
+            };
+            greeting.write_to(&mut a).await.unwrap();
+            let mut properties = Properties::new();
+            properties.set_socket_type(SocketType::Dealer);
+            properties.insert("User-Id", b"root".to_vec()).unwrap();
+            properties
+                .insert("Peer-Address", b"10.0.0.1".to_vec())
+                .unwrap();
+            let ready = ZmtpCommand::Ready(properties).into_frame();
+            ready.write_to(&mut a).await.unwrap();
+            let frame = Frame::new_message(false, b"hello".to_vec());
+            frame.write_to(&mut a).await.unwrap();
+
+            let mut conn = Connection::new(b, &SocketType::Router).await.unwrap();
+            let received = conn.recv_message().await.unwrap();
+            let metadata = received.metadata().unwrap();
+            assert_eq!(metadata.get("User-Id"), None);
+            assert_eq!(metadata.get("Peer-Address"), None);
+            assert_eq!(metadata.get("Socket-Type"), Some(&b"DEALER"[..]));
+        });
+    }
+
+    #[test]
+    fn test_as_server() {
+        block_on(async {
+            let client = ConnectionOptions::new()
+                .plain_client(PlainCredentials::new("admin", "secret"))
+                .as_server(false);
+            let server = ConnectionOptions::new().plain_server(|_| true);
+
+            // Either end can be the server, whichever one connected.
+            let (a, b) = testing::duplex();
+            let (a, b) = futures::join!(
+                Connection::with_options(a, &SocketType::Req, &server),
+                Connection::with_options(b, &SocketType::Rep, &client),
+            );
+            assert!(a.is_ok() && b.is_ok());
+
+            // Two clients find out from the greetings alone.
+            let (a, b) = testing::duplex();
+            let (a, b) = futures::join!(
+                Connection::with_options(a, &SocketType::Req, &client),
+                Connection::with_options(b, &SocketType::Rep, &client),
+            );
+            for result in [a.map(drop), b.map(drop)] {
+                assert!(matches!(
+                    result,
+                    Err(ConnectionError::Handshake(
+                        HandshakeError::AsServerMismatch("PLAIN")
+                    ))
+                ));
+            }
+
+            // NULL peers can't claim to be servers.
+            let (mut a, b) = testing::duplex();
+            let greeting = Greeting {
+                version: Version::new(3, 1),
+                mechanism: Mechanism::Null,
+                as_server: AsServer::Server,
+            };
+            greeting.write_to(&mut a).await.unwrap();
+            let result = Connection::new(b, &SocketType::Rep).await;
+            assert!(matches!(
+                result.map(drop),
+                Err(ConnectionError::Handshake(
+                    HandshakeError::AsServerMismatch("NULL")
+                ))
+            ));
+
+            // Nor can we, and we don't get as far as sending a greeting.
+            let options = ConnectionOptions::new().as_server(true);
+            let (mut a, b) = testing::duplex();
+            let result = Connection::with_options(b, &SocketType::Req, &options).await;
+            assert!(matches!(
+                result.map(drop),
+                Err(ConnectionError::AsServerConflict("NULL"))
+            ));
+            assert!(Greeting::read_new(&mut a).await.is_err());
+        });
+    }
+
+    // Receives that are given up on part of the way through a frame lose
+    // nothing, and the PING among the frames is still answered once.
+    #[test]
+    fn test_cancelled_recv() {
+        block_on(async {
+            let msgs = [
+                Message::from(vec![vec![5; 300], b"tail".to_vec()]),
+                Message::from(&b"next"[..]),
+            ];
+            let mut input = Vec::new();
+            heartbeat::ping(7).into_frame().encode(&mut input);
+            let mut sender = connection(Vec::new());
+            for msg in msgs.iter().cloned() {
+                sender.send_message(msg).await.unwrap();
+            }
+            input.extend(sender.stream.into_inner());
+
+            let (mut a, b) = testing::duplex();
+            let mut conn = connection_over(b);
+            let mut received = Vec::new();
+            for chunk in input.chunks(7) {
+                a.write_all(chunk).await.unwrap();
+                if let Some(msg) = conn.recv_message().now_or_never() {
+                    received.push(msg.unwrap());
+                }
+            }
+            assert_eq!(received, msgs);
+
+            let pong = Frame::read_new(&mut a).await.unwrap();
+            assert_eq!(pong.command_name(), Some("PONG"));
+            assert!(a.fill_buf().now_or_never().is_none());
+        });
+    }
+
+    #[test]
+    fn test_split() {
+        block_on(async {
+            let (a, b) = testing::duplex();
+            let mut a = connection_over(a);
+            let (mut reader, mut writer) = connection_over(b).split();
+
+            // The writer gets on with sending while the reader waits.
+            let to_a = Message::from(&b"to a"[..]);
+            let to_b = Message::from(&b"to b"[..]);
+            let (received, ()) = futures::join!(reader.recv_message(), async {
+                writer.send_message(to_a.clone()).await.unwrap();
+                a.send_message(to_b.clone()).await.unwrap();
+                a.stream.close().await.unwrap();
+            });
+            assert_eq!(received.unwrap(), to_b);
+            assert_eq!(reader.metrics().messages_received, 1);
+            assert_eq!(writer.metrics().messages_sent, 1);
+
+            drop((reader, writer));
+            assert_eq!(a.recv_message().await.unwrap(), to_a);
+        });
+    }
 }
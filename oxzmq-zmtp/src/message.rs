@@ -0,0 +1,275 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::properties::Properties;
+use std::{
+    iter::FromIterator,
+    slice,
+    sync::Arc,
+    time::{Instant, SystemTime},
+    vec,
+};
+
+/// A complete, possibly multipart, message. Each part is sent as its own
+/// frame on the wire, with the MORE flag set on every frame but the last.
+///
+/// Messages compare equal if their parts are equal, regardless of when they
+/// were received, which peer they're for, or which group they're in.
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    parts: Vec<Vec<u8>>,
+    timestamp: Option<Timestamp>,
+    routing_id: Option<u32>,
+    group: Option<String>,
+    // Shared by every message from the same connection.
+    metadata: Option<Arc<Properties>>,
+}
+
+/// When a message's last frame was read, for connections that record it.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp {
+    monotonic: Instant,
+    wall_clock: SystemTime,
+}
+
+impl Timestamp {
+    pub(crate) fn now() -> Timestamp {
+        Timestamp {
+            monotonic: Instant::now(),
+            wall_clock: SystemTime::now(),
+        }
+    }
+
+    /// For measuring how long the message has been waiting since it arrived.
+    pub fn monotonic(&self) -> Instant {
+        self.monotonic
+    }
+
+    /// For comparing with a time the sender put in the message. This clock
+    /// can jump, and the two ends' clocks may not agree.
+    pub fn wall_clock(&self) -> SystemTime {
+        self.wall_clock
+    }
+}
+
+impl Message {
+    pub fn new() -> Message {
+        Message::default()
+    }
+
+    pub fn parts(&self) -> &[Vec<u8>] {
+        self.parts.as_slice()
+    }
+
+    pub fn into_parts(self) -> Vec<Vec<u8>> {
+        self.parts
+    }
+
+    /// The part at `idx`, if there are that many.
+    pub fn get(&self, idx: usize) -> Option<&[u8]> {
+        self.parts.get(idx).map(Vec::as_slice)
+    }
+
+    /// The parts in order, borrowed from the message.
+    pub fn iter(&self) -> Parts<'_> {
+        Parts {
+            parts: self.parts.iter(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.parts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    pub fn push_back(&mut self, part: Vec<u8>) {
+        self.parts.push(part);
+    }
+
+    pub fn pop_back(&mut self) -> Option<Vec<u8>> {
+        self.parts.pop()
+    }
+
+    // Envelopes (routing IDs, delimiters) are added and removed at the front
+    // of a message, so these get their own methods.
+    pub fn push_front(&mut self, part: Vec<u8>) {
+        self.parts.insert(0, part);
+    }
+
+    pub fn pop_front(&mut self) -> Option<Vec<u8>> {
+        if self.parts.is_empty() {
+            None
+        } else {
+            Some(self.parts.remove(0))
+        }
+    }
+
+    /// When the message was received, if the connection it came in on was set
+    /// to record timestamps.
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+
+    pub(crate) fn set_timestamp(&mut self, timestamp: Timestamp) {
+        self.timestamp = Some(timestamp);
+    }
+
+    /// The metadata of the ZMTP connection the message came in on, like
+    /// libzmq's `zmq_msg_gets`: the properties the peer sent in its
+    /// handshake, such as `Socket-Type`, along with `User-Id`, the username a
+    /// PLAIN server let the peer in as, and `Peer-Address`, the IP address of
+    /// TCP peers. Peers can't send those two themselves.
+    pub fn metadata(&self) -> Option<&Properties> {
+        self.metadata.as_deref()
+    }
+
+    pub(crate) fn set_metadata(&mut self, metadata: Arc<Properties>) {
+        self.metadata = Some(metadata);
+    }
+
+    /// The peer a SERVER socket received the message from, or should send it
+    /// to. Other socket types put routing IDs in the message parts instead.
+    pub fn routing_id(&self) -> Option<u32> {
+        self.routing_id
+    }
+
+    pub fn set_routing_id(&mut self, routing_id: u32) {
+        self.routing_id = Some(routing_id);
+    }
+
+    /// The group a RADIO socket sends the message to, or that a DISH socket
+    /// received it for.
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    pub fn set_group(&mut self, group: &str) {
+        self.group = Some(group.to_string());
+    }
+}
+
+impl PartialEq for Message {
+    fn eq(&self, other: &Message) -> bool {
+        self.parts == other.parts
+    }
+}
+
+impl Eq for Message {}
+
+impl From<Vec<Vec<u8>>> for Message {
+    fn from(parts: Vec<Vec<u8>>) -> Message {
+        Message {
+            parts,
+            timestamp: None,
+            routing_id: None,
+            group: None,
+            metadata: None,
+        }
+    }
+}
+
+impl FromIterator<Vec<u8>> for Message {
+    fn from_iter<I: IntoIterator<Item = Vec<u8>>>(parts: I) -> Message {
+        Message::from(parts.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl Extend<Vec<u8>> for Message {
+    fn extend<I: IntoIterator<Item = Vec<u8>>>(&mut self, parts: I) {
+        self.parts.extend(parts);
+    }
+}
+
+impl IntoIterator for Message {
+    type Item = Vec<u8>;
+    type IntoIter = vec::IntoIter<Vec<u8>>;
+
+    fn into_iter(self) -> vec::IntoIter<Vec<u8>> {
+        self.parts.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Message {
+    type Item = &'a [u8];
+    type IntoIter = Parts<'a>;
+
+    fn into_iter(self) -> Parts<'a> {
+        self.iter()
+    }
+}
+
+/// An iterator over the parts of a message, as returned by `Message::iter`.
+#[derive(Debug, Clone)]
+pub struct Parts<'a> {
+    parts: slice::Iter<'a, Vec<u8>>,
+}
+
+impl<'a> Iterator for Parts<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        self.parts.next().map(Vec::as_slice)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.parts.size_hint()
+    }
+}
+
+impl<'a> DoubleEndedIterator for Parts<'a> {
+    fn next_back(&mut self) -> Option<&'a [u8]> {
+        self.parts.next_back().map(Vec::as_slice)
+    }
+}
+
+impl ExactSizeIterator for Parts<'_> {}
+
+impl From<Vec<u8>> for Message {
+    fn from(part: Vec<u8>) -> Message {
+        Message::from(vec![part])
+    }
+}
+
+impl From<&[u8]> for Message {
+    fn from(part: &[u8]) -> Message {
+        Message::from(part.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_ignored_by_eq() {
+        let mut received = Message::from(&b"data"[..]);
+        assert!(received.timestamp().is_none());
+
+        received.set_timestamp(Timestamp::now());
+        assert!(received.timestamp().unwrap().monotonic() <= Instant::now());
+        assert_eq!(received, Message::from(&b"data"[..]));
+    }
+
+    #[test]
+    fn test_parts() {
+        let mut msg: Message = vec![b"id".to_vec(), Vec::new()].into_iter().collect();
+        msg.extend(vec![b"body".to_vec()]);
+        assert_eq!(msg.get(2), Some(&b"body"[..]));
+        assert_eq!(msg.get(3), None);
+
+        let parts: Vec<&[u8]> = msg.iter().collect();
+        assert_eq!(parts, vec![&b"id"[..], &b""[..], &b"body"[..]]);
+        assert_eq!(msg.iter().next_back(), Some(&b"body"[..]));
+        assert_eq!((&msg).into_iter().len(), 3);
+
+        assert_eq!(msg.pop_back(), Some(b"body".to_vec()));
+        assert_eq!(
+            msg.into_iter().collect::<Vec<_>>(),
+            vec![b"id".to_vec(), Vec::new()]
+        );
+    }
+}
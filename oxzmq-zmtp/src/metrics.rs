@@ -0,0 +1,118 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{event::EventKind, message::Message};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// How much a socket or connection has sent and received since it was
+/// created. Bytes are counted from the message parts, leaving out framing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    /// Messages a socket didn't send to a peer because the peer was at its
+    /// high-water mark and the socket's policy is to drop. Always 0 for
+    /// connections.
+    pub dropped_at_hwm: u64,
+}
+
+impl Metrics {
+    pub(crate) fn sent(&mut self, bytes: u64) {
+        self.messages_sent += 1;
+        self.bytes_sent += bytes;
+    }
+
+    pub(crate) fn received(&mut self, msg: &Message) {
+        self.messages_received += 1;
+        self.bytes_received += message_bytes(msg);
+    }
+
+    pub(crate) fn dropped(&mut self) {
+        self.dropped_at_hwm += 1;
+    }
+}
+
+pub(crate) fn message_bytes(msg: &Message) -> u64 {
+    msg.iter().map(|part| part.len() as u64).sum()
+}
+
+/// Counts the connections that couldn't be set up with a set of
+/// `ConnectionOptions`. Clones of the options, and of the counters, share
+/// the same counts.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectCounters {
+    inner: Arc<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    connect_failures: AtomicU64,
+    handshake_failures: AtomicU64,
+}
+
+impl ConnectCounters {
+    /// Attempts to connect that failed before the handshake started. OxZMQ
+    /// doesn't reconnect on its own, so this also counts the failed attempts
+    /// of an application that retries.
+    pub fn connect_failures(&self) -> u64 {
+        self.inner.connect_failures.load(Ordering::Relaxed)
+    }
+
+    /// Connections, made or accepted, whose handshake failed.
+    pub fn handshake_failures(&self) -> u64 {
+        self.inner.handshake_failures.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record(&self, kind: &EventKind) {
+        let counter = match kind {
+            EventKind::ConnectFailed { .. } => &self.inner.connect_failures,
+            EventKind::HandshakeFailed { .. } => &self.inner.handshake_failures,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{endpoint::Endpoint, peer::CloseReason};
+    use std::io;
+
+    #[test]
+    fn test_counts() {
+        let mut metrics = Metrics::default();
+        metrics.sent(5);
+        metrics.received(&Message::from(vec![b"ab".to_vec(), b"cde".to_vec()]));
+        metrics.dropped();
+        assert_eq!(
+            metrics,
+            Metrics {
+                messages_sent: 1,
+                bytes_sent: 5,
+                messages_received: 1,
+                bytes_received: 5,
+                dropped_at_hwm: 1,
+            }
+        );
+
+        let counters = ConnectCounters::default();
+        let shared = counters.clone();
+        let endpoint = "tcp://127.0.0.1:5555".parse::<Endpoint>().unwrap();
+        shared.record(&EventKind::Connected {
+            endpoint: endpoint.clone(),
+        });
+        shared.record(&EventKind::ConnectFailed {
+            endpoint,
+            reason: CloseReason::Io(io::ErrorKind::ConnectionRefused),
+        });
+        assert_eq!(counters.connect_failures(), 1);
+        assert_eq!(counters.handshake_failures(), 0);
+    }
+}
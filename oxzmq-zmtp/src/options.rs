@@ -0,0 +1,704 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    auth::Authenticator,
+    endpoint::Endpoint,
+    event::{EventKind, EventSink, SocketEvent},
+    handshake::plain::{CredentialProvider, CredentialValidator, PlainCredentials, PlainRole},
+    metrics::ConnectCounters,
+    peer::{CloseReason, Origin, PeerError},
+    socket::SocketType,
+    sockets::HwmPolicy,
+    tcp::TcpOptions,
+    AsServer, ConnectionError, GreetingError, Mechanism, Version,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// We only speak ZMTP 3.x; the greeting of earlier versions has a different
+// layout.
+const DEFAULT_MIN_VERSION: Version = Version::new(3, 0);
+const DEFAULT_MAX_VERSION: Version = Version::new(3, 1);
+
+// libzmq's limit on routing IDs, which have to fit behind a one-byte size
+// in messages from ROUTER to ROUTER.
+pub(crate) const MAX_ROUTING_ID_LEN: usize = 255;
+
+// libzmq's default high-water mark, in messages.
+const DEFAULT_HWM: usize = 1000;
+
+// How big a message a peer may send, if there's a limit; see
+// `ConnectionOptions::max_message_size` and `max_message_parts`.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct MessageLimits {
+    pub(crate) max_size: Option<u64>,
+    pub(crate) max_parts: Option<usize>,
+}
+
+impl MessageLimits {
+    // Fills in the limits that aren't set from `defaults`.
+    pub(crate) fn or(self, defaults: MessageLimits) -> MessageLimits {
+        MessageLimits {
+            max_size: self.max_size.or(defaults.max_size),
+            max_parts: self.max_parts.or(defaults.max_parts),
+        }
+    }
+
+    // Fails if a message of `parts` parts and `bytes` bytes in all is over
+    // either limit.
+    pub(crate) fn check(&self, parts: usize, bytes: u64) -> Result<(), PeerError> {
+        let too_many_parts = matches!(self.max_parts, Some(max) if parts > max);
+        let too_many_bytes = matches!(self.max_size, Some(max) if bytes > max);
+        match too_many_parts || too_many_bytes {
+            true => Err(PeerError::MessageTooLarge),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Settings for setting up a `Connection`.
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    min_version: Version,
+    max_version: Version,
+    routing_id: Option<Vec<u8>>,
+    weight: Option<u32>,
+    timestamps: bool,
+    plain: Option<PlainRole>,
+    zap_domain: String,
+    as_server: Option<bool>,
+    send_hwm: usize,
+    recv_hwm: usize,
+    cork_bytes: usize,
+    max_message_size: Option<u64>,
+    max_message_parts: Option<usize>,
+    tcp: TcpOptions,
+    events: Option<EventSink>,
+    counters: ConnectCounters,
+    reconnect_stop: ReconnectStop,
+    // The endpoints given up on, shared by every clone of the options.
+    stopped: Arc<Mutex<Vec<Endpoint>>>,
+}
+
+impl ConnectionOptions {
+    pub fn new() -> ConnectionOptions {
+        ConnectionOptions {
+            min_version: DEFAULT_MIN_VERSION,
+            max_version: DEFAULT_MAX_VERSION,
+            routing_id: None,
+            weight: None,
+            timestamps: false,
+            plain: None,
+            zap_domain: String::new(),
+            as_server: None,
+            send_hwm: DEFAULT_HWM,
+            recv_hwm: DEFAULT_HWM,
+            cork_bytes: 0,
+            max_message_size: None,
+            max_message_parts: None,
+            tcp: TcpOptions::new(),
+            events: None,
+            counters: ConnectCounters::default(),
+            reconnect_stop: ReconnectStop::Never,
+            stopped: Arc::default(),
+        }
+    }
+
+    /// Refuse peers that can't speak at least this version, e.g. 3.1 to make
+    /// sure heartbeats are available. Versions below 3.0 aren't supported no
+    /// matter what this is set to.
+    pub fn min_version(mut self, version: Version) -> ConnectionOptions {
+        self.min_version = version.max(DEFAULT_MIN_VERSION);
+        self
+    }
+
+    /// Never speak a version newer than this, even if the peer can.
+    pub fn max_version(mut self, version: Version) -> ConnectionOptions {
+        self.max_version = version.min(DEFAULT_MAX_VERSION);
+        self
+    }
+
+    /// Asks ROUTER peers to know us by this routing ID rather than one they
+    /// generate, so that we keep it when we reconnect. Routing IDs are 1 to
+    /// 255 bytes, and can't start with a zero byte, which marks the IDs
+    /// ROUTER sockets generate; connecting fails with any other.
+    pub fn routing_id(mut self, routing_id: Vec<u8>) -> ConnectionOptions {
+        self.routing_id = Some(routing_id);
+        self
+    }
+
+    /// Asks DEALER and PUSH peers to send us this many messages for every one
+    /// they send to a peer of weight 1, e.g. because we have that many
+    /// workers. Weights below 1 count as 1.
+    pub fn weight(mut self, weight: u32) -> ConnectionOptions {
+        self.weight = Some(weight.max(1));
+        self
+    }
+
+    /// Records when each message is received; see `Message::timestamp`.
+    pub fn timestamps(mut self, timestamps: bool) -> ConnectionOptions {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// How many outgoing messages can be queued for the peer before sending
+    /// has to wait, 1000 by default. What the socket does then depends on its
+    /// `HwmPolicy`. Marks below 1 count as 1.
+    ///
+    /// Only in-process peers and connections running on their own with
+    /// `Connection::spawn` queue messages. Other peers write each message
+    /// straight to the connection, so they're held back by the operating
+    /// system's socket buffers instead.
+    pub fn send_hwm(mut self, hwm: usize) -> ConnectionOptions {
+        self.send_hwm = hwm.max(1);
+        self
+    }
+
+    /// How many incoming messages can be queued from the peer before it has
+    /// to wait for them to be received, 1000 by default. As with `send_hwm`,
+    /// this only applies to in-process peers and spawned connections.
+    pub fn recv_hwm(mut self, hwm: usize) -> ConnectionOptions {
+        self.recv_hwm = hwm.max(1);
+        self
+    }
+
+    /// Holds outgoing messages back until at least `max_bytes` of them have
+    /// built up, and then writes them all at once, to save on small writes
+    /// when many small messages are sent in a row. Held back messages are
+    /// also written when the connection receives or sends a command, and
+    /// when it's flushed, which the application has to do once it's sent
+    /// the last message of a burst. Nothing is held back by default, or if
+    /// `max_bytes` is 0.
+    pub fn cork(mut self, max_bytes: usize) -> ConnectionOptions {
+        self.cork_bytes = max_bytes;
+        self
+    }
+
+    /// Drops peers that send a message of more than `max_bytes`, counting
+    /// every part, as libzmq's `ZMQ_MAXMSGSIZE` does. The size of each part
+    /// is checked before it's read, so a peer can't make the connection
+    /// allocate more than this for one message. Commands are held to it
+    /// too, as they are by libzmq, and that includes batches of messages.
+    /// Messages can be any size by default. This applies to ZMTP and
+    /// WebSocket connections, not to in-process peers.
+    pub fn max_message_size(mut self, max_bytes: u64) -> ConnectionOptions {
+        self.max_message_size = Some(max_bytes);
+        self
+    }
+
+    /// Drops peers that send a message of more than `max_parts` parts. Like
+    /// `max_message_size`, this applies to ZMTP and WebSocket connections,
+    /// and there's no limit by default.
+    pub fn max_message_parts(mut self, max_parts: usize) -> ConnectionOptions {
+        self.max_message_parts = Some(max_parts);
+        self
+    }
+
+    /// Tunes TCP streams with `options` as they're connected and accepted,
+    /// before the greeting is sent; see `TcpOptions`.
+    pub fn tcp(mut self, options: TcpOptions) -> ConnectionOptions {
+        self.tcp = options;
+        self
+    }
+
+    /// Reports connections being made and accepted, and how their handshakes
+    /// went, to `sink`. Sockets report what happens once the connections are
+    /// attached to their own sinks, which can be clones of this one.
+    pub fn event_sink(mut self, sink: EventSink) -> ConnectionOptions {
+        self.events = Some(sink);
+        self
+    }
+
+    /// How many connections made or accepted with these options, or clones
+    /// of them, couldn't be set up.
+    pub fn connect_counters(&self) -> &ConnectCounters {
+        &self.counters
+    }
+
+    /// Gives up on an endpoint once connecting to it fails in a way that
+    /// trying again won't fix, as libzmq's `ZMQ_RECONNECT_STOP` does.
+    /// OxZMQ doesn't reconnect on its own, so this stops the application's
+    /// retries instead: connecting to the endpoint again with these options,
+    /// or clones of them, fails straight away with `ReconnectStopped`. A
+    /// `ReconnectStopped` event says when an endpoint is given up on.
+    pub fn reconnect_stop(mut self, policy: ReconnectStop) -> ConnectionOptions {
+        self.reconnect_stop = policy;
+        self
+    }
+
+    /// Whether connecting to `endpoint` was given up on.
+    pub fn is_stopped(&self, endpoint: &Endpoint) -> bool {
+        self.stopped
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(endpoint)
+    }
+
+    /// Logs in to PLAIN servers with credentials from `provider`, which is
+    /// asked again every time we connect. Note that PLAIN sends the password
+    /// in the clear.
+    pub fn plain_client<C>(mut self, provider: C) -> ConnectionOptions
+    where
+        C: CredentialProvider + Send + Sync + 'static,
+    {
+        self.plain = Some(PlainRole::client(provider));
+        self
+    }
+
+    /// Acts as a PLAIN server, letting in the clients whose credentials
+    /// `validate` accepts.
+    pub fn plain_server<F>(self, validate: F) -> ConnectionOptions
+    where
+        F: Fn(&PlainCredentials) -> bool + Send + Sync + 'static,
+    {
+        self.plain_authenticator(Authenticator::new(validate))
+    }
+
+    /// Acts as a PLAIN server, letting in the clients whose credentials
+    /// `validator` accepts.
+    pub fn plain_validator<V>(self, validator: V) -> ConnectionOptions
+    where
+        V: CredentialValidator + Send + Sync + 'static,
+    {
+        self.plain_authenticator(Authenticator::from_validator(validator))
+    }
+
+    /// Acts as a PLAIN server that asks `authenticator`, whose handler can be
+    /// replaced while the options are in use.
+    pub fn plain_authenticator(mut self, authenticator: Authenticator) -> ConnectionOptions {
+        self.plain = Some(PlainRole::server(authenticator));
+        self
+    }
+
+    /// The domain we authenticate clients in as a PLAIN server, which picks
+    /// the `Authenticator` handler that checks them. Servers are in the
+    /// empty domain by default.
+    pub fn zap_domain(mut self, domain: &str) -> ConnectionOptions {
+        self.zap_domain = domain.to_string();
+        self
+    }
+
+    /// Whether we're the security server, rather than the client, which we
+    /// tell the peer in our greeting. By default we're the server when
+    /// we're a PLAIN server, whichever end connected. NULL has no servers,
+    /// and PLAIN needs exactly one at either end, so connecting fails if
+    /// this disagrees with the mechanism.
+    pub fn as_server(mut self, as_server: bool) -> ConnectionOptions {
+        self.as_server = Some(as_server);
+        self
+    }
+
+    pub(crate) fn domain(&self) -> &str {
+        &self.zap_domain
+    }
+
+    pub(crate) fn plain_role(&self) -> Option<&PlainRole> {
+        self.plain.as_ref()
+    }
+
+    // The mechanism we put in our greeting, and expect in the peer's.
+    pub(crate) fn mechanism(&self) -> Mechanism {
+        match self.plain {
+            Some(_) => Mechanism::Plain,
+            None => Mechanism::Null,
+        }
+    }
+
+    // The role we put in our greeting, which the mechanism decides.
+    pub(crate) fn as_server_role(&self) -> AsServer {
+        match &self.plain {
+            Some(role) if role.is_server() => AsServer::Server,
+            _ => AsServer::Client,
+        }
+    }
+
+    pub(crate) fn records_timestamps(&self) -> bool {
+        self.timestamps
+    }
+
+    // The routing ID we send in our handshake.
+    pub(crate) fn identity(&self) -> Option<&[u8]> {
+        self.routing_id.as_deref()
+    }
+
+    // Checks the routing ID before anything is sent, so that a bad one fails
+    // the same way whoever we connect to.
+    pub(crate) fn check_routing_id(&self) -> Result<(), ConnectionError> {
+        match &self.routing_id {
+            Some(routing_id)
+                if routing_id.is_empty()
+                    || routing_id.len() > MAX_ROUTING_ID_LEN
+                    || routing_id[0] == 0x00 =>
+            {
+                Err(ConnectionError::InvalidRoutingId(routing_id.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Checks that the role asked for is the one the mechanism gives us.
+    pub(crate) fn check_as_server(&self) -> Result<(), ConnectionError> {
+        let role = self.as_server_role();
+        match self.as_server {
+            Some(as_server) if as_server != (role == AsServer::Server) => {
+                Err(ConnectionError::AsServerConflict(self.mechanism().name()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn send_capacity(&self) -> usize {
+        self.send_hwm
+    }
+
+    pub(crate) fn recv_capacity(&self) -> usize {
+        self.recv_hwm
+    }
+
+    pub(crate) fn cork_bytes(&self) -> usize {
+        self.cork_bytes
+    }
+
+    pub(crate) fn message_limits(&self) -> MessageLimits {
+        MessageLimits {
+            max_size: self.max_message_size,
+            max_parts: self.max_message_parts,
+        }
+    }
+
+    pub(crate) fn tcp_options(&self) -> &TcpOptions {
+        &self.tcp
+    }
+
+    // Counts an event on the connection being set up for a socket of type
+    // `socket_type`, and reports it if there's a sink to report it to.
+    pub(crate) fn emit(&self, socket_type: SocketType, kind: EventKind) {
+        self.counters.record(&kind);
+        let stopped = self.stop_after(&kind);
+        if let Some(events) = &self.events {
+            events.emit(SocketEvent::new(socket_type, kind));
+            if let Some(stopped) = stopped {
+                events.emit(SocketEvent::new(socket_type, stopped));
+            }
+        }
+    }
+
+    // Gives up on the endpoint a handshake failed with, if the policy says
+    // to, and returns the event that says so.
+    fn stop_after(&self, kind: &EventKind) -> Option<EventKind> {
+        let (endpoint, reason) = match kind {
+            EventKind::HandshakeFailed {
+                origin: Origin::Connected(endpoint),
+                reason,
+            } if self.reconnect_stop.stops_on(reason) => (endpoint, reason),
+            _ => return None,
+        };
+        let mut stopped = self.stopped.lock().unwrap_or_else(|e| e.into_inner());
+        if !stopped.contains(endpoint) {
+            stopped.push(endpoint.clone());
+        }
+        Some(EventKind::ReconnectStopped {
+            endpoint: endpoint.clone(),
+            reason: reason.clone(),
+        })
+    }
+
+    // The weight we send in our handshake.
+    pub(crate) fn advertised_weight(&self) -> Option<u32> {
+        self.weight
+    }
+
+    // The version we put in our greeting.
+    pub(crate) fn advertised_version(&self) -> Version {
+        self.max_version
+    }
+
+    // Peers speak the older of the two versions they advertise. Returns the
+    // version to use with a peer that advertised `remote`.
+    pub(crate) fn negotiate_version(&self, remote: Version) -> Result<Version, GreetingError> {
+        let version = remote.min(self.max_version);
+        if version < self.min_version {
+            return Err(GreetingError::Version(remote));
+        }
+
+        Ok(version)
+    }
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions::new()
+    }
+}
+
+/// When connecting to an endpoint is given up on for good.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconnectStop {
+    /// Never, however the last attempt went. This is the default.
+    Never,
+
+    /// Once a handshake fails because the peer refused our credentials, or
+    /// we refused its, or because its socket type can't talk to ours.
+    HandshakeFailed,
+}
+
+impl ReconnectStop {
+    fn stops_on(&self, reason: &CloseReason) -> bool {
+        match self {
+            ReconnectStop::Never => false,
+            ReconnectStop::HandshakeFailed => matches!(
+                reason,
+                CloseReason::AuthDenied | CloseReason::InvalidSocketCombination
+            ),
+        }
+    }
+}
+
+/// Everything a socket can be set up with in one place: the options its
+/// connections are made with, and the settings of the socket itself. Pass
+/// it to the socket's `with_options`, and `connection_options` to whatever
+/// connects or accepts on the socket's behalf.
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    connection: ConnectionOptions,
+    // At most one of each kind, in the order they were set.
+    settings: Vec<SocketOption>,
+    events: Option<EventSink>,
+}
+
+impl SocketOptions {
+    pub fn new() -> SocketOptions {
+        SocketOptions::default()
+    }
+
+    /// Starts from `options` for the socket's connections, replacing the
+    /// connection options set so far.
+    pub fn connection(mut self, options: ConnectionOptions) -> SocketOptions {
+        self.connection = options;
+        self
+    }
+
+    /// See `ConnectionOptions::routing_id`.
+    pub fn routing_id(mut self, routing_id: Vec<u8>) -> SocketOptions {
+        self.connection = self.connection.routing_id(routing_id);
+        self
+    }
+
+    /// See `ConnectionOptions::send_hwm`.
+    pub fn send_hwm(mut self, hwm: usize) -> SocketOptions {
+        self.connection = self.connection.send_hwm(hwm);
+        self
+    }
+
+    /// See `ConnectionOptions::recv_hwm`.
+    pub fn recv_hwm(mut self, hwm: usize) -> SocketOptions {
+        self.connection = self.connection.recv_hwm(hwm);
+        self
+    }
+
+    /// See `ConnectionOptions::max_message_size`.
+    pub fn max_message_size(mut self, max_bytes: u64) -> SocketOptions {
+        self.connection = self.connection.max_message_size(max_bytes);
+        self
+    }
+
+    /// See `ConnectionOptions::tcp`.
+    pub fn tcp(mut self, options: TcpOptions) -> SocketOptions {
+        self.connection = self.connection.tcp(options);
+        self
+    }
+
+    /// Reports peers being attached and dropped to `sink`.
+    pub fn event_sink(mut self, sink: EventSink) -> SocketOptions {
+        self.events = Some(sink);
+        self
+    }
+
+    /// How long closing the socket waits for its peers to finish sending.
+    /// See `SocketOption::Linger`.
+    pub fn linger(self, linger: Option<Duration>) -> SocketOptions {
+        self.set(SocketOption::Linger(linger))
+    }
+
+    /// Drops peers once they've missed this many heartbeats in a row. See
+    /// `SocketOption::HeartbeatTimeout`.
+    pub fn heartbeat_timeout(self, misses: Option<u32>) -> SocketOptions {
+        self.set(SocketOption::HeartbeatTimeout(misses))
+    }
+
+    /// What sending does when peers are at their high-water mark, rather
+    /// than the socket type's default.
+    pub fn hwm_policy(self, policy: HwmPolicy) -> SocketOptions {
+        self.set(SocketOption::HwmPolicy(policy))
+    }
+
+    /// Keeps only the newest message each peer has waiting. See
+    /// `SocketOption::Conflate`.
+    pub fn conflate(self, conflate: bool) -> SocketOptions {
+        self.set(SocketOption::Conflate(conflate))
+    }
+
+    /// Fails sends that wait longer than this for a peer. See
+    /// `SocketOption::SendTimeout`.
+    pub fn send_timeout(self, timeout: Option<Duration>) -> SocketOptions {
+        self.set(SocketOption::SendTimeout(timeout))
+    }
+
+    /// Fails receives that wait longer than this for a message. See
+    /// `SocketOption::RecvTimeout`.
+    pub fn recv_timeout(self, timeout: Option<Duration>) -> SocketOptions {
+        self.set(SocketOption::RecvTimeout(timeout))
+    }
+
+    /// Sets `option`, replacing the one of the same name if it was set
+    /// before.
+    pub fn set(mut self, option: SocketOption) -> SocketOptions {
+        self.settings.retain(|set| set.name() != option.name());
+        self.settings.push(option);
+        self
+    }
+
+    /// The option called `name`, if it was set. Sockets use their own
+    /// defaults for the rest.
+    pub fn get(&self, name: SocketOptionName) -> Option<&SocketOption> {
+        self.settings.iter().find(|set| set.name() == name)
+    }
+
+    /// The options to make and accept the socket's connections with.
+    pub fn connection_options(&self) -> &ConnectionOptions {
+        &self.connection
+    }
+
+    pub(crate) fn settings(&self) -> &[SocketOption] {
+        &self.settings
+    }
+
+    pub(crate) fn events(&self) -> Option<&EventSink> {
+        self.events.as_ref()
+    }
+}
+
+/// A setting of the socket itself, which can be changed while it's in use
+/// with the socket's `set_option`. Settings of its connections, which are
+/// fixed once they're made, are in `ConnectionOptions` instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SocketOption {
+    /// How long `close` waits for the peers to write out what they're
+    /// holding back. `None`, the default, waits for as long as it takes, and
+    /// a linger of zero drops them straight away.
+    Linger(Option<Duration>),
+
+    /// Drops peers once they've missed this many heartbeats in a row, as
+    /// `CloseReason::HeartbeatTimeout`. Peers are kept however many they
+    /// miss by default.
+    HeartbeatTimeout(Option<u32>),
+
+    /// What sending does when the peers a message should go to can't take
+    /// it; see `HwmPolicy`.
+    HwmPolicy(HwmPolicy),
+
+    /// Whether receiving skips to the newest message each peer has waiting.
+    /// Off by default.
+    Conflate(bool),
+
+    /// How long sending waits for a peer to be ready to take the message
+    /// before failing with `SocketError::Timeout`, in which case the message
+    /// is dropped. Only waiting counts: a message the peer has started
+    /// taking is always finished, however long the write takes. `None`, the
+    /// default, waits for as long as it takes.
+    SendTimeout(Option<Duration>),
+
+    /// How long receiving waits for a message to arrive before failing with
+    /// `SocketError::Timeout`. Sockets that skip some messages, such as SUB
+    /// sockets, wait this long again after each one. `None`, the default,
+    /// waits for as long as it takes.
+    RecvTimeout(Option<Duration>),
+}
+
+/// The names of the `SocketOption`s, to ask a socket for one with
+/// `get_option`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketOptionName {
+    Linger,
+    HeartbeatTimeout,
+    HwmPolicy,
+    Conflate,
+    SendTimeout,
+    RecvTimeout,
+}
+
+impl SocketOption {
+    pub fn name(&self) -> SocketOptionName {
+        match self {
+            SocketOption::Linger(_) => SocketOptionName::Linger,
+            SocketOption::HeartbeatTimeout(_) => SocketOptionName::HeartbeatTimeout,
+            SocketOption::HwmPolicy(_) => SocketOptionName::HwmPolicy,
+            SocketOption::Conflate(_) => SocketOptionName::Conflate,
+            SocketOption::SendTimeout(_) => SocketOptionName::SendTimeout,
+            SocketOption::RecvTimeout(_) => SocketOptionName::RecvTimeout,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socket_options() {
+        let options = SocketOptions::new()
+            .conflate(true)
+            .linger(None)
+            .conflate(false)
+            .routing_id(b"worker".to_vec());
+        assert_eq!(
+            options.get(SocketOptionName::Conflate),
+            Some(&SocketOption::Conflate(false))
+        );
+        assert_eq!(options.get(SocketOptionName::HwmPolicy), None);
+        assert_eq!(
+            options.settings(),
+            &[SocketOption::Linger(None), SocketOption::Conflate(false)]
+        );
+        assert_eq!(
+            options.connection_options().identity(),
+            Some(&b"worker"[..])
+        );
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        let options = ConnectionOptions::new();
+        assert_eq!(
+            options.negotiate_version(Version::new(3, 0)).unwrap(),
+            Version::new(3, 0)
+        );
+        assert_eq!(
+            options.negotiate_version(Version::new(3, 7)).unwrap(),
+            Version::new(3, 1)
+        );
+        assert!(options.negotiate_version(Version::new(2, 0)).is_err());
+
+        let options = ConnectionOptions::new().min_version(Version::new(3, 1));
+        assert!(matches!(
+            options.negotiate_version(Version::new(3, 0)),
+            Err(GreetingError::Version(_))
+        ));
+
+        let options = ConnectionOptions::new().max_version(Version::new(3, 0));
+        assert_eq!(
+            options.negotiate_version(Version::new(3, 1)).unwrap(),
+            Version::new(3, 0)
+        );
+
+        // Pinning both ends to versions that don't overlap refuses everyone.
+        let options = ConnectionOptions::new()
+            .min_version(Version::new(3, 1))
+            .max_version(Version::new(3, 0));
+        assert!(options.negotiate_version(Version::new(3, 1)).is_err());
+    }
+}
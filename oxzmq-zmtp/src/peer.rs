@@ -0,0 +1,343 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    batch::BatchDecodeError, command::CommandParseError, endpoint::Endpoint,
+    frame::FrameParseError, heartbeat::Liveness, message::Message, socket::SocketType,
+};
+use futures::io;
+use std::{
+    fmt,
+    future::Future,
+    task::{Context, Poll},
+};
+
+/// A single remote peer that whole messages can be exchanged with.
+///
+/// Socket types (DEALER, ROUTER, ...) are written against this trait rather
+/// than against `Connection` directly so that their routing logic doesn't
+/// depend on how the bytes actually get to the peer.
+pub trait Peer {
+    fn remote_socket_type(&self) -> SocketType;
+
+    /// The routing ID the peer asked to be known by, if it picked one.
+    fn routing_id(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// How the peer was reached, if it was through an endpoint. Sockets use
+    /// this to find the peers to drop when disconnecting or unbinding.
+    fn origin(&self) -> Option<&Origin> {
+        None
+    }
+
+    /// How many messages the peer asked to be sent for every one that goes to
+    /// a peer of weight 1, if it said.
+    fn weight(&self) -> Option<u32> {
+        None
+    }
+
+    /// Whether the peer can take another message without waiting, for socket
+    /// types that skip over busy peers. Peers that never hold back are always
+    /// ready.
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), PeerError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn send_message(&mut self, msg: Message) -> impl Future<Output = Result<(), PeerError>>;
+
+    /// Sends several messages at once. Peers that can pack them together on
+    /// the wire override this; the messages are received one by one either way.
+    fn send_batch(&mut self, msgs: Vec<Message>) -> impl Future<Output = Result<(), PeerError>> {
+        async move {
+            for msg in msgs {
+                self.send_message(msg).await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Receives the next message.
+    ///
+    /// This has to be cancel-safe: the future may be dropped at any point,
+    /// such as when it loses a `select!`, and nothing it has read may be
+    /// lost. Whatever has arrived of a frame or a message is kept on the
+    /// peer, so that the next call carries on from there. Sockets rely on
+    /// this whenever they wait on several peers at once.
+    fn recv_message(&mut self) -> impl Future<Output = Result<Message, PeerError>>;
+
+    /// A message that has already arrived in full, if there is one, without
+    /// waiting. Peers that can't tell without risking a partial read return
+    /// `None`.
+    fn try_recv_message(&mut self) -> Option<Result<Message, PeerError>> {
+        None
+    }
+
+    /// Sends a heartbeat. Peers that don't support heartbeats ignore this.
+    fn send_heartbeat(&mut self) -> impl Future<Output = Result<(), PeerError>> {
+        async { Ok(()) }
+    }
+
+    /// How well the peer has been answering heartbeats, if it supports them.
+    fn liveness(&self) -> Option<&Liveness> {
+        None
+    }
+
+    /// Writes out any messages the peer has been holding back. Peers that
+    /// write every message straight away have nothing to do.
+    fn flush(&mut self) -> impl Future<Output = Result<(), PeerError>> {
+        async { Ok(()) }
+    }
+
+    /// Shuts the connection down on purpose. Peers that can tell the other
+    /// end it was deliberate do so, and it then sees `PeerError::Closed`.
+    fn close(&mut self) -> impl Future<Output = Result<(), PeerError>> {
+        async { Ok(()) }
+    }
+}
+
+/// How a peer was reached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    /// We connected to the peer at this endpoint.
+    Connected(Endpoint),
+
+    /// The peer connected to us, on this endpoint we were bound to.
+    Accepted(Endpoint),
+}
+
+impl Origin {
+    /// The endpoint we connected to, or accepted the peer on.
+    pub fn endpoint(&self) -> &Endpoint {
+        match self {
+            Origin::Connected(endpoint) | Origin::Accepted(endpoint) => endpoint,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PeerError {
+    #[error("error reading data stream")]
+    Io(#[from] io::Error),
+
+    #[error("could not parse frame")]
+    MalformedFrame(#[from] FrameParseError),
+
+    #[error("could not parse command")]
+    MalformedCommand(#[from] CommandParseError),
+
+    #[error("could not unpack batched messages")]
+    MalformedBatch(#[from] BatchDecodeError),
+
+    /// The peer sent a message with more bytes or parts than the
+    /// connection allows.
+    #[error("peer sent a message over the size limit")]
+    MessageTooLarge,
+
+    #[error("peer disconnected")]
+    Disconnected,
+
+    /// The peer shut the connection down in an orderly way, after everything
+    /// it sent was received.
+    #[error("peer closed the connection")]
+    Closed,
+
+    /// The peer sent an ERROR command, which ends the connection.
+    #[error("peer sent an error: {0}")]
+    Rejected(String),
+}
+
+impl PeerError {
+    /// Why the connection ended, for reporting to the application.
+    pub fn close_reason(&self) -> CloseReason {
+        match self {
+            PeerError::Io(err) => CloseReason::Io(err.kind()),
+            PeerError::MalformedFrame(_)
+            | PeerError::MalformedCommand(_)
+            | PeerError::MalformedBatch(_)
+            | PeerError::MessageTooLarge => CloseReason::Protocol,
+            PeerError::Disconnected => CloseReason::Disconnected,
+            PeerError::Closed => CloseReason::Closed,
+            PeerError::Rejected(reason) => CloseReason::PeerError(reason.clone()),
+        }
+    }
+}
+
+/// Why a connection to a peer ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The peer shut the connection down in an orderly way.
+    Closed,
+
+    /// The connection went away without warning.
+    Disconnected,
+
+    /// Reading from or writing to the connection failed.
+    Io(io::ErrorKind),
+
+    /// The peer broke the protocol, e.g. by sending a malformed frame.
+    Protocol,
+
+    /// The peer sent an ERROR command with this reason.
+    PeerError(String),
+
+    /// The peer stopped answering heartbeats.
+    HeartbeatTimeout,
+
+    /// The peer refused our credentials, or we refused its.
+    AuthDenied,
+
+    /// The peer's socket type can't talk to ours.
+    InvalidSocketCombination,
+
+    /// The socket let go of the peer itself, e.g. because another peer took
+    /// over its routing ID.
+    Dropped,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Closed => f.write_str("closed by peer"),
+            CloseReason::Disconnected => f.write_str("disconnected"),
+            CloseReason::Io(kind) => write!(f, "I/O error: {}", kind),
+            CloseReason::Protocol => f.write_str("protocol error"),
+            CloseReason::PeerError(reason) => write!(f, "peer error: {}", reason),
+            CloseReason::HeartbeatTimeout => f.write_str("heartbeat timeout"),
+            CloseReason::AuthDenied => f.write_str("authentication denied"),
+            CloseReason::InvalidSocketCombination => f.write_str("invalid socket combination"),
+            CloseReason::Dropped => f.write_str("dropped by socket"),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) use self::channel::{BrokenPipe, ChannelPeer};
+
+#[cfg(test)]
+mod channel {
+    use super::*;
+    use futures::{channel::mpsc, future, SinkExt, StreamExt};
+    use std::time::Instant;
+
+    const DEFAULT_CAPACITY: usize = 1000;
+
+    // An in-memory peer used to test socket types without a real connection.
+    #[derive(Debug)]
+    pub(crate) struct ChannelPeer {
+        remote_socket_type: SocketType,
+        tx: mpsc::Sender<Message>,
+        rx: mpsc::Receiver<Message>,
+        pub(crate) routing_id: Option<Vec<u8>>,
+        // Heartbeats aren't carried over the channel, so they're never
+        // answered. Tests can use this to make a peer look unresponsive.
+        pub(crate) liveness: Liveness,
+        pub(crate) origin: Option<Origin>,
+    }
+
+    impl ChannelPeer {
+        // Creates two connected ends. The first is handed to the socket under
+        // test (so its remote is `remote`) and the second plays the remote.
+        pub(crate) fn pair(local: SocketType, remote: SocketType) -> (ChannelPeer, ChannelPeer) {
+            ChannelPeer::pair_with_capacity(local, remote, DEFAULT_CAPACITY)
+        }
+
+        // Like `pair`, but each direction only holds `capacity` messages
+        // before the sender has to wait.
+        pub(crate) fn pair_with_capacity(
+            local: SocketType,
+            remote: SocketType,
+            capacity: usize,
+        ) -> (ChannelPeer, ChannelPeer) {
+            // Each sender gets one guaranteed slot on top of the buffer.
+            let buffer = capacity.saturating_sub(1);
+            let (a_tx, b_rx) = mpsc::channel(buffer);
+            let (b_tx, a_rx) = mpsc::channel(buffer);
+            let a = ChannelPeer {
+                remote_socket_type: remote,
+                tx: a_tx,
+                rx: a_rx,
+                routing_id: None,
+                liveness: Liveness::new(),
+                origin: None,
+            };
+            let b = ChannelPeer {
+                remote_socket_type: local,
+                tx: b_tx,
+                rx: b_rx,
+                routing_id: None,
+                liveness: Liveness::new(),
+                origin: None,
+            };
+            (a, b)
+        }
+    }
+
+    impl Peer for ChannelPeer {
+        fn remote_socket_type(&self) -> SocketType {
+            self.remote_socket_type
+        }
+
+        fn routing_id(&self) -> Option<&[u8]> {
+            self.routing_id.as_deref()
+        }
+
+        fn origin(&self) -> Option<&Origin> {
+            self.origin.as_ref()
+        }
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), PeerError>> {
+            self.tx.poll_ready(cx).map_err(|_| PeerError::Disconnected)
+        }
+
+        async fn send_message(&mut self, msg: Message) -> Result<(), PeerError> {
+            self.tx.feed(msg).await.map_err(|_| PeerError::Disconnected)
+        }
+
+        async fn recv_message(&mut self) -> Result<Message, PeerError> {
+            self.rx.next().await.ok_or(PeerError::Disconnected)
+        }
+
+        fn try_recv_message(&mut self) -> Option<Result<Message, PeerError>> {
+            match self.rx.try_recv() {
+                Ok(msg) => Some(Ok(msg)),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Closed) => Some(Err(PeerError::Disconnected)),
+            }
+        }
+
+        async fn send_heartbeat(&mut self) -> Result<(), PeerError> {
+            self.liveness.ping_sent(Instant::now());
+            Ok(())
+        }
+
+        fn liveness(&self) -> Option<&Liveness> {
+            Some(&self.liveness)
+        }
+    }
+
+    // A peer that's always ready for a message, but fails to take one or to
+    // write out what it's holding back. It claims to be a socket of the type
+    // it's given.
+    #[derive(Debug)]
+    pub(crate) struct BrokenPipe(pub(crate) SocketType);
+
+    impl Peer for BrokenPipe {
+        fn remote_socket_type(&self) -> SocketType {
+            self.0
+        }
+
+        async fn send_message(&mut self, _msg: Message) -> Result<(), PeerError> {
+            Err(PeerError::Disconnected)
+        }
+
+        async fn recv_message(&mut self) -> Result<Message, PeerError> {
+            future::pending().await
+        }
+
+        async fn flush(&mut self) -> Result<(), PeerError> {
+            Err(PeerError::Disconnected)
+        }
+    }
+}
@@ -0,0 +1,240 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::message::Message;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+const DEFAULT_MAX_BUFFERS: usize = 1024;
+
+/// A pool of part buffers that received messages are read into, so that
+/// busy services don't go through the allocator for every frame.
+///
+/// Buffers can be sorted into size classes by their capacity, so that a
+/// small frame doesn't take a big buffer that a big frame could have used,
+/// and a big frame doesn't take a small buffer that then has to grow.
+///
+/// Messages get their buffers back into the pool by being leased: a
+/// `PooledMessage` hands its buffers back when it's dropped. Clones share the
+/// same pool.
+#[derive(Debug, Clone)]
+pub struct MessagePool {
+    inner: Arc<Mutex<PoolInner>>,
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    // Ordered by size, smallest first.
+    classes: Vec<SizeClass>,
+    max_buffers: usize,
+}
+
+// Holds the idle buffers whose capacity is at least `size`, but less than
+// the size of the next class up.
+#[derive(Debug)]
+struct SizeClass {
+    size: usize,
+    buffers: Vec<Vec<u8>>,
+}
+
+impl MessagePool {
+    pub fn new() -> MessagePool {
+        MessagePool::with_max_buffers(DEFAULT_MAX_BUFFERS)
+    }
+
+    /// Creates a pool that holds on to at most `max_buffers` idle buffers.
+    /// Buffers returned beyond that are freed.
+    pub fn with_max_buffers(max_buffers: usize) -> MessagePool {
+        MessagePool::with_size_classes(&[0], max_buffers)
+    }
+
+    /// Creates a pool that sorts buffers by capacity into a class for each
+    /// of `sizes`, and holds on to at most `max_buffers` idle buffers in
+    /// each. Buffers smaller than the smallest size are freed rather than
+    /// pooled.
+    pub fn with_size_classes(sizes: &[usize], max_buffers: usize) -> MessagePool {
+        let mut sizes = sizes.to_vec();
+        sizes.sort_unstable();
+        sizes.dedup();
+        if sizes.is_empty() {
+            sizes.push(0);
+        }
+
+        let classes = sizes
+            .into_iter()
+            .map(|size| SizeClass {
+                size,
+                buffers: Vec::new(),
+            })
+            .collect();
+        MessagePool {
+            inner: Arc::new(Mutex::new(PoolInner {
+                classes,
+                max_buffers,
+            })),
+        }
+    }
+
+    /// Wraps a message so that its buffers return to this pool when it's
+    /// dropped.
+    pub fn lease(&self, msg: Message) -> PooledMessage {
+        PooledMessage {
+            msg: Some(msg),
+            pool: self.clone(),
+        }
+    }
+
+    /// The number of idle buffers in the pool.
+    pub fn available(&self) -> usize {
+        self.lock()
+            .classes
+            .iter()
+            .map(|class| class.buffers.len())
+            .sum()
+    }
+
+    // Returns an empty buffer for `len` bytes of data. It comes from the
+    // smallest class whose buffers are all big enough, or failing that, from
+    // the class `len` falls in, where it may have to grow. If there isn't
+    // one, a new one is allocated.
+    pub(crate) fn take(&self, len: usize) -> Vec<u8> {
+        let mut inner = self.lock();
+        let classes = &mut inner.classes;
+        let fits = classes.iter().position(|class| class.size >= len);
+        let containing = classes.iter().rposition(|class| class.size <= len);
+        let count = classes.len();
+        let from_fits = fits.into_iter().flat_map(|idx| idx..count);
+        for idx in from_fits.chain(containing) {
+            if let Some(buf) = classes[idx].buffers.pop() {
+                return buf;
+            }
+        }
+        Vec::with_capacity(len)
+    }
+
+    fn recycle(&self, parts: Vec<Vec<u8>>) {
+        let mut inner = self.lock();
+        let max_buffers = inner.max_buffers;
+        for mut part in parts {
+            let capacity = part.capacity();
+            let class = match inner.classes.iter_mut().rfind(|c| c.size <= capacity) {
+                Some(class) => class,
+                None => continue,
+            };
+            if class.buffers.len() < max_buffers {
+                part.clear();
+                class.buffers.push(part);
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PoolInner> {
+        // The pool is only ever left with whole buffers in it, so it's still
+        // usable if another thread panicked while holding the lock.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for MessagePool {
+    fn default() -> MessagePool {
+        MessagePool::new()
+    }
+}
+
+/// A received message whose buffers go back to a `MessagePool` when it's
+/// dropped. It can be used anywhere a `&Message` can.
+#[derive(Debug)]
+pub struct PooledMessage {
+    // Only `None` after the message has been taken out of the lease.
+    msg: Option<Message>,
+    pool: MessagePool,
+}
+
+impl PooledMessage {
+    /// Ends the lease and keeps the message, whose buffers then never return
+    /// to the pool.
+    pub fn into_message(mut self) -> Message {
+        self.msg.take().unwrap_or_default()
+    }
+}
+
+impl Deref for PooledMessage {
+    type Target = Message;
+
+    fn deref(&self) -> &Message {
+        // Can't fail; the message is only taken when the lease is consumed.
+        self.msg.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledMessage {
+    fn deref_mut(&mut self) -> &mut Message {
+        self.msg.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledMessage {
+    fn drop(&mut self) {
+        if let Some(msg) = self.msg.take() {
+            self.pool.recycle(msg.into_parts());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_recycled() {
+        let pool = MessagePool::with_max_buffers(2);
+        let mut buf = pool.take(0);
+        buf.extend_from_slice(b"hello");
+        let capacity = buf.capacity();
+
+        let msg = pool.lease(Message::from(vec![buf, b"a".to_vec(), b"b".to_vec()]));
+        assert_eq!(msg.len(), 3);
+        drop(msg);
+        assert_eq!(pool.available(), 2);
+
+        // Buffers come back empty, but keep their allocation.
+        let buf = pool.take(0);
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 1);
+        let buf = pool.take(0);
+        assert_eq!(buf.capacity(), capacity);
+        assert_eq!(pool.available(), 0);
+
+        let msg = pool.lease(Message::from(buf)).into_message();
+        assert_eq!(msg.len(), 1);
+        assert_eq!(pool.available(), 0);
+    }
+
+    #[test]
+    fn test_size_classes() {
+        let pool = MessagePool::with_size_classes(&[1024, 64], 1);
+        let parts: Vec<Vec<u8>> = [8, 100, 200, 2000]
+            .iter()
+            .map(|&capacity| Vec::with_capacity(capacity))
+            .collect();
+        drop(pool.lease(Message::from(parts)));
+
+        // The smallest buffer was too small to keep, and the second 64-byte
+        // class buffer didn't fit.
+        assert_eq!(pool.available(), 2);
+
+        // Frames take the smallest buffer that's big enough, and only take
+        // one that has to grow when there's nothing else.
+        let buf = pool.take(10);
+        assert!(buf.capacity() >= 64 && buf.capacity() < 1024);
+        drop(pool.lease(Message::from(buf)));
+        assert!(pool.take(500).capacity() >= 1024);
+        assert!(pool.take(500).capacity() < 1024);
+        assert_eq!(pool.available(), 0);
+    }
+}
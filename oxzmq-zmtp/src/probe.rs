@@ -0,0 +1,220 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    endpoint::{Endpoint, Resolver, StaticResolver},
+    handshake::Handshake,
+    options::ConnectionOptions,
+    socket::SocketType,
+    AsServer, ConnectionError, Greeting, Mechanism, Version,
+};
+use futures::{
+    executor::block_on,
+    io::{self, AllowStdIo, AsyncBufRead, AsyncRead, AsyncWrite, BufReader},
+};
+use std::{net::TcpStream, time::Duration};
+
+// How long a probe waits to connect, and then for each read or write.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The probe has to tell the peer some socket type. DEALER can talk to the
+// request/reply family; other peers may log an invalid combination, but they
+// still send their READY command first.
+const PROBE_SOCKET_TYPE: SocketType = SocketType::Dealer;
+
+/// What a peer said about itself while setting up a connection.
+#[derive(Debug, Clone)]
+pub struct ProbeReport {
+    socket_type: SocketType,
+    version: Version,
+    mechanism: &'static str,
+}
+
+impl ProbeReport {
+    pub fn socket_type(&self) -> SocketType {
+        self.socket_type
+    }
+
+    /// The version the peer advertised in its greeting.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn mechanism(&self) -> &str {
+        self.mechanism
+    }
+}
+
+/// Connects to a `tcp://host:port` endpoint, completes the greeting and
+/// handshake, and disconnects again. Blocks the current thread until done,
+/// which takes at most a few seconds per step.
+pub fn probe(endpoint: &str) -> Result<ProbeReport, ProbeError> {
+    probe_with_resolver(endpoint, &StaticResolver::new())
+}
+
+/// Like `probe`, but `tcp://service:name` endpoints can be used too, and are
+/// looked up with `resolver`.
+pub fn probe_with_resolver<R: Resolver>(
+    endpoint: &str,
+    resolver: &R,
+) -> Result<ProbeReport, ProbeError> {
+    let parsed: Endpoint = endpoint
+        .parse()
+        .map_err(|_| ProbeError::InvalidEndpoint(endpoint.to_string()))?;
+    let addr = block_on(parsed.resolve(resolver))
+        .map_err(|_| ProbeError::InvalidEndpoint(endpoint.to_string()))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| ProbeError::InvalidEndpoint(endpoint.to_string()))?;
+
+    let tcp_stream = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)?;
+    tcp_stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    tcp_stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+    tcp_stream.set_nodelay(true)?;
+
+    let mut stream = BufReader::new(AllowStdIo::new(tcp_stream));
+    Ok(block_on(probe_stream(&mut stream))?)
+}
+
+/// Like `probe`, but over a stream that's already connected to the peer. The
+/// stream is left just after the handshake.
+pub async fn probe_stream<S>(stream: &mut S) -> Result<ProbeReport, ConnectionError>
+where
+    S: AsyncBufRead + AsyncRead + AsyncWrite + Unpin,
+{
+    let options = ConnectionOptions::new();
+    let our_greeting = Greeting {
+        version: options.advertised_version(),
+        mechanism: Mechanism::Null,
+        as_server: AsServer::Client,
+    };
+    our_greeting.write_to(stream).await?;
+
+    let greeting = Greeting::read_new(stream).await?;
+    options.negotiate_version(greeting.version)?;
+
+    let handshake = Handshake::perform(stream, &greeting, &PROBE_SOCKET_TYPE, &options).await?;
+    let properties = handshake.into_properties();
+    let socket_type = properties
+        .socket_type()?
+        .ok_or(ConnectionError::MissingRemoteSocketType)?;
+
+    Ok(ProbeReport {
+        socket_type,
+        version: greeting.version,
+        mechanism: greeting.mechanism.name(),
+    })
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProbeError {
+    #[error("invalid endpoint: {0}")]
+    InvalidEndpoint(String),
+
+    #[error("could not connect to endpoint")]
+    Io(#[from] io::Error),
+
+    #[error("{0}")]
+    Connection(#[from] ConnectionError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GreetingError;
+    use futures::io::Cursor;
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    // Reads canned bytes and records everything written.
+    struct Scripted {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl AsyncRead for Scripted {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.input).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncBufRead for Scripted {
+        fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+            Pin::new(&mut self.get_mut().input).poll_fill_buf(cx)
+        }
+
+        fn consume(mut self: Pin<&mut Self>, amt: usize) {
+            Pin::new(&mut self.input).consume(amt)
+        }
+    }
+
+    impl AsyncWrite for Scripted {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.output).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.output).poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.output).poll_close(cx)
+        }
+    }
+
+    #[test]
+    fn test_old_peer_refused() {
+        block_on(async {
+            let mut peer_greeting = Vec::new();
+            let greeting = Greeting {
+                version: Version::new(2, 0),
+                mechanism: Mechanism::Null,
+                as_server: AsServer::Client,
+            };
+            greeting.write_to(&mut peer_greeting).await.unwrap();
+
+            let mut stream = Scripted {
+                input: Cursor::new(peer_greeting),
+                output: Vec::new(),
+            };
+            assert!(matches!(
+                probe_stream(&mut stream).await,
+                Err(ConnectionError::Greeting(GreetingError::Version(_)))
+            ));
+
+            // Our own greeting still went out first.
+            let ours = Greeting::read_new(&mut stream.output.as_slice())
+                .await
+                .unwrap();
+            assert_eq!(ours.version, Version::new(3, 1));
+        });
+    }
+
+    #[test]
+    fn test_invalid_endpoint() {
+        assert!(matches!(
+            probe("ipc:///tmp/socket"),
+            Err(ProbeError::InvalidEndpoint(_))
+        ));
+        assert!(matches!(
+            probe("tcp://no port"),
+            Err(ProbeError::InvalidEndpoint(_))
+        ));
+        // Services can't be found without a resolver that knows them.
+        assert!(matches!(
+            probe("tcp://service:orders"),
+            Err(ProbeError::InvalidEndpoint(_))
+        ));
+    }
+}
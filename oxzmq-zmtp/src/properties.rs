@@ -0,0 +1,333 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    socket::{SocketType, SocketTypeFromBytesError},
+    WEIGHT_PROPERTY,
+};
+use std::convert::TryFrom;
+
+// More info: https://rfc.zeromq.org/spec/23/#the-null-security-mechanism
+//
+// Each property is a name of 1 to 255 bytes behind a one-byte length, and
+// then a value behind a four-byte, big-endian length.
+const NAME_SIZE_LEN: usize = 1;
+const VALUE_SIZE_LEN: usize = 4;
+
+const SOCKET_TYPE: &str = "Socket-Type";
+const IDENTITY: &str = "Identity";
+
+// Metadata that only our end of a connection can vouch for, which peers
+// aren't allowed to send us.
+pub(crate) const USER_ID: &str = "User-Id";
+pub(crate) const PEER_ADDRESS: &str = "Peer-Address";
+
+/// The metadata a peer sends in its handshake, such as its socket type and
+/// routing ID. Properties keep the order and spelling they were inserted
+/// with, so that what we send is byte-for-byte what libzmq would send, but
+/// names are compared case-insensitively.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Properties {
+    inner: Vec<(String, Vec<u8>)>,
+}
+
+impl Properties {
+    pub fn new() -> Properties {
+        Properties::default()
+    }
+
+    /// Parses properties as they appear in the body of a READY or INITIATE
+    /// command. A property that appears twice replaces the earlier one.
+    pub fn decode(bytes: &[u8]) -> Result<Properties, PropertiesParseError> {
+        let mut properties = Properties::new();
+
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let name_size = usize::from(rest[0]);
+            if name_size == 0 {
+                return Err(PropertiesParseError::ZeroSizedName);
+            }
+            rest = &rest[NAME_SIZE_LEN..];
+            if rest.len() < name_size {
+                return Err(PropertiesParseError::NameSizeIncorrect);
+            }
+
+            let name = std::str::from_utf8(&rest[..name_size])
+                .map_err(|_| PropertiesParseError::NameInvalidChar)?;
+            if !is_valid_name(name) {
+                return Err(PropertiesParseError::NameInvalidChar);
+            }
+            rest = &rest[name_size..];
+
+            let value_size_bytes = rest
+                .get(..VALUE_SIZE_LEN)
+                .and_then(|bytes| <[u8; VALUE_SIZE_LEN]>::try_from(bytes).ok())
+                .ok_or(PropertiesParseError::ValueSizeIncomplete)?;
+            let value_size = u32::from_be_bytes(value_size_bytes) as usize;
+            rest = &rest[VALUE_SIZE_LEN..];
+            if rest.len() < value_size {
+                return Err(PropertiesParseError::ValueSizeIncorrect);
+            }
+
+            properties.set(name, rest[..value_size].to_vec());
+            rest = &rest[value_size..];
+        }
+
+        Ok(properties)
+    }
+
+    /// The properties as they're sent, which `decode` turns back into the
+    /// same properties.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write_to(&mut buf);
+        buf
+    }
+
+    /// The number of bytes `encode` produces.
+    pub fn encoded_len(&self) -> usize {
+        self.inner
+            .iter()
+            .map(|(name, value)| NAME_SIZE_LEN + name.len() + VALUE_SIZE_LEN + value.len())
+            .sum()
+    }
+
+    // Every name and value was checked on the way in, so their sizes are
+    // known to fit in their fields.
+    pub(crate) fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.reserve(self.encoded_len());
+        for (name, value) in self.inner.iter() {
+            buf.push(name.len() as u8);
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            buf.extend_from_slice(value);
+        }
+    }
+
+    /// The value of the property called `name`, whatever its case.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.inner
+            .iter()
+            .find(|(other, _)| other.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Adds a property, or replaces the value of one with the same name.
+    /// Names are 1 to 255 letters, digits and `-_.+`, and values are at most
+    /// `u32::MAX` bytes.
+    pub fn insert(&mut self, name: &str, value: Vec<u8>) -> Result<(), PropertiesEncodeError> {
+        if name.is_empty() || name.len() > usize::from(u8::MAX) {
+            return Err(PropertiesEncodeError::NameLength(name.len()));
+        }
+        if !is_valid_name(name) {
+            return Err(PropertiesEncodeError::NameInvalidChar(name.to_string()));
+        }
+        if u32::try_from(value.len()).is_err() {
+            return Err(PropertiesEncodeError::ValueTooLong(value.len()));
+        }
+
+        self.set(name, value);
+        Ok(())
+    }
+
+    /// Every property's name and value, in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.inner
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// The `Socket-Type` property, which every peer has to send.
+    pub fn socket_type(&self) -> Result<Option<SocketType>, SocketTypeFromBytesError> {
+        self.get(SOCKET_TYPE).map(SocketType::try_from).transpose()
+    }
+
+    pub fn set_socket_type(&mut self, socket_type: SocketType) {
+        self.set(SOCKET_TYPE, String::from(&socket_type).into_bytes());
+    }
+
+    /// The `Identity` property, the routing ID the peer asked to be known
+    /// by. libzmq sends an empty one when the peer didn't pick one, which
+    /// counts as not having one.
+    pub fn identity(&self) -> Option<&[u8]> {
+        self.get(IDENTITY).filter(|identity| !identity.is_empty())
+    }
+
+    pub fn set_identity(&mut self, identity: Vec<u8>) -> Result<(), PropertiesEncodeError> {
+        self.insert(IDENTITY, identity)
+    }
+
+    /// The share of messages an OxZMQ peer asked for, if it sent a valid
+    /// one. Weights below 1 count as 1.
+    pub fn weight(&self) -> Option<u32> {
+        let weight = std::str::from_utf8(self.get(WEIGHT_PROPERTY)?).ok()?;
+        weight.parse().ok().map(|weight: u32| weight.max(1))
+    }
+
+    pub fn set_weight(&mut self, weight: u32) {
+        self.set(WEIGHT_PROPERTY, weight.to_string().into_bytes());
+    }
+
+    // A later property replaces an earlier one of the same name.
+    pub(crate) fn remove(&mut self, name: &str) {
+        self.inner
+            .retain(|(other, _)| !other.eq_ignore_ascii_case(name));
+    }
+
+    pub(crate) fn set(&mut self, name: &str, value: Vec<u8>) {
+        match self
+            .inner
+            .iter_mut()
+            .find(|(other, _)| other.eq_ignore_ascii_case(name))
+        {
+            Some((_, old_value)) => *old_value = value,
+            None => self.inner.push((name.to_string(), value)),
+        }
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    name.bytes()
+        .all(|b| b.is_ascii_alphanumeric() || [b'-', b'_', b'.', b'+'].contains(&b))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PropertiesEncodeError {
+    #[error("property names must be 1 to 255 bytes long, not {0}")]
+    NameLength(usize),
+
+    #[error("invalid character(s) in property name: {0}")]
+    NameInvalidChar(String),
+
+    #[error("property values must fit in 4 GiB, not {0} bytes")]
+    ValueTooLong(usize),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PropertiesParseError {
+    #[error("name had size of zero")]
+    ZeroSizedName,
+
+    #[error("invalid character(s) in property name")]
+    NameInvalidChar,
+
+    #[error("name size indicated more bytes than were available")]
+    NameSizeIncorrect,
+
+    #[error("not enough bytes left to read size of metadata value")]
+    ValueSizeIncomplete,
+
+    #[error("value size indicated more bytes than were available")]
+    ValueSizeIncorrect,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::{Gen, CASES};
+
+    // The body of the READY command libzmq 4.3 sends from a DEALER socket
+    // without a routing ID, and from a ROUTER socket with one.
+    const LIBZMQ_DEALER_READY: &[u8] =
+        b"\x0bSocket-Type\x00\x00\x00\x06DEALER\x08Identity\x00\x00\x00\x00";
+    const LIBZMQ_ROUTER_READY: &[u8] =
+        b"\x0bSocket-Type\x00\x00\x00\x06ROUTER\x08Identity\x00\x00\x00\x04peer";
+
+    #[test]
+    fn test_parse_libzmq_ready() {
+        let properties = Properties::decode(LIBZMQ_DEALER_READY).unwrap();
+        assert_eq!(properties.get("socket-type"), Some(&b"DEALER"[..]));
+        assert_eq!(properties.socket_type().unwrap(), Some(SocketType::Dealer));
+        assert_eq!(properties.get("identity"), Some(&b""[..]));
+        assert_eq!(properties.identity(), None);
+
+        let properties = Properties::decode(LIBZMQ_ROUTER_READY).unwrap();
+        assert_eq!(properties.get("Identity"), Some(&b"peer"[..]));
+        assert_eq!(properties.identity(), Some(&b"peer"[..]));
+        assert_eq!(properties.weight(), None);
+    }
+
+    #[test]
+    fn test_write_like_libzmq() {
+        let mut properties = Properties::new();
+        properties.set_socket_type(SocketType::Router);
+        properties.set_identity(b"peer".to_vec()).unwrap();
+        assert_eq!(properties.encoded_len(), LIBZMQ_ROUTER_READY.len());
+        assert_eq!(properties.encode(), LIBZMQ_ROUTER_READY);
+    }
+
+    #[test]
+    fn test_typed_properties() {
+        let mut properties = Properties::new();
+        assert_eq!(properties.socket_type().unwrap(), None);
+        properties.insert("socket-type", b"NOPE".to_vec()).unwrap();
+        assert!(properties.socket_type().is_err());
+
+        properties.set_weight(0);
+        assert_eq!(properties.weight(), Some(1));
+        properties.insert(WEIGHT_PROPERTY, b"x".to_vec()).unwrap();
+        assert_eq!(properties.weight(), None);
+
+        // Setting a property again keeps its first spelling.
+        properties.set_socket_type(SocketType::Pub);
+        assert_eq!(
+            properties.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            ["socket-type", WEIGHT_PROPERTY]
+        );
+    }
+
+    #[test]
+    fn test_invalid_properties() {
+        let mut properties = Properties::new();
+        assert!(matches!(
+            properties.insert("", Vec::new()),
+            Err(PropertiesEncodeError::NameLength(0))
+        ));
+        assert!(matches!(
+            properties.insert(&"a".repeat(256), Vec::new()),
+            Err(PropertiesEncodeError::NameLength(256))
+        ));
+        assert!(matches!(
+            properties.insert("Socket Type", Vec::new()),
+            Err(PropertiesEncodeError::NameInvalidChar(_))
+        ));
+
+        for bytes in [
+            &b"\x00"[..],
+            b"\x08Identity\x00\x00",
+            b"\x08Identity\x00\x00\x00\x01",
+            b"\x09Identity!\x00\x00\x00\x00",
+            b"\x09Identity",
+        ] {
+            assert!(Properties::decode(bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn test_round_trip_arbitrary() {
+        const NAME_CHARS: &[u8] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_.+";
+        for case in 0..CASES {
+            let mut gen = Gen::new(case);
+            let mut properties = Properties::new();
+            for _ in 0..gen.up_to(5) {
+                let name = gen.string(NAME_CHARS, 255);
+                properties.insert(&name, gen.bytes(300)).unwrap();
+            }
+
+            let buf = properties.encode();
+            assert_eq!(buf.len(), properties.encoded_len(), "case {}", case);
+            let parsed = Properties::decode(&buf).unwrap();
+            assert_eq!(parsed, properties, "case {}", case);
+        }
+    }
+}
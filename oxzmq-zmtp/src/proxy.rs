@@ -0,0 +1,215 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    adapter::{RecvSocket, SendSocket},
+    message::Message,
+    sockets::SocketError,
+};
+use futures::{
+    future::{self, Either},
+    pin_mut,
+    stream::{self, Stream},
+    Sink, SinkExt, StreamExt,
+};
+use std::convert::TryFrom;
+
+/// Tells a steerable proxy what to do, as libzmq's PAUSE, RESUME and
+/// TERMINATE commands do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyCommand {
+    /// Stop forwarding messages until told to resume. Messages wait with the
+    /// sockets' peers in the meantime.
+    Pause,
+    Resume,
+    /// Stop the proxy for good.
+    Terminate,
+}
+
+impl TryFrom<&Message> for ProxyCommand {
+    type Error = SocketError;
+
+    /// Parses a command sent to the control socket the way libzmq expects:
+    /// a single frame holding the command's name.
+    fn try_from(msg: &Message) -> Result<ProxyCommand, SocketError> {
+        match msg.parts() {
+            [name] if name == b"PAUSE" => Ok(ProxyCommand::Pause),
+            [name] if name == b"RESUME" => Ok(ProxyCommand::Resume),
+            [name] if name == b"TERMINATE" => Ok(ProxyCommand::Terminate),
+            _ => Err(SocketError::InvalidState),
+        }
+    }
+}
+
+/// Forwards messages both ways between two sockets, and sends a copy of each
+/// one to `capture`, until either socket fails. This is all a broker needs:
+/// a ROUTER frontend and a DEALER backend share requests out between
+/// workers, and an XSUB frontend and an XPUB backend pass messages one way
+/// and subscriptions the other. Use `futures::sink::drain()` to capture
+/// nothing.
+///
+/// Sockets that only go one way, such as PULL and PUSH, don't need a proxy:
+/// `pull.incoming().forward(push.outgoing())` does the same.
+pub async fn proxy<F, B, C>(
+    frontend: &mut F,
+    backend: &mut B,
+    capture: C,
+) -> Result<(), SocketError>
+where
+    F: RecvSocket + SendSocket,
+    B: RecvSocket + SendSocket,
+    C: Sink<Message> + Unpin,
+{
+    proxy_steerable(frontend, backend, capture, stream::pending()).await
+}
+
+/// Like `proxy`, but pauses, resumes and stops as `control` says. It also
+/// stops, without an error, once `control` ends. Commands from a control
+/// socket can be parsed with `ProxyCommand::try_from`.
+pub async fn proxy_steerable<F, B, C, S>(
+    frontend: &mut F,
+    backend: &mut B,
+    mut capture: C,
+    mut control: S,
+) -> Result<(), SocketError>
+where
+    F: RecvSocket + SendSocket,
+    B: RecvSocket + SendSocket,
+    C: Sink<Message> + Unpin,
+    S: Stream<Item = ProxyCommand> + Unpin,
+{
+    let mut paused = false;
+    loop {
+        if paused {
+            match control.next().await {
+                Some(ProxyCommand::Resume) => paused = false,
+                Some(ProxyCommand::Pause) => (),
+                Some(ProxyCommand::Terminate) | None => return Ok(()),
+            }
+            continue;
+        }
+
+        // Commands go first, so that a busy proxy can still be paused.
+        let received = {
+            let command = control.next();
+            let from_frontend = frontend.recv();
+            let from_backend = backend.recv();
+            pin_mut!(from_frontend, from_backend);
+            match future::select(command, future::select(from_frontend, from_backend)).await {
+                Either::Left((command, _)) => Either::Left(command),
+                Either::Right((Either::Left((msg, _)), _)) => Either::Right(Either::Left(msg?)),
+                Either::Right((Either::Right((msg, _)), _)) => Either::Right(Either::Right(msg?)),
+            }
+        };
+
+        let msg = match received {
+            Either::Left(Some(ProxyCommand::Pause)) => {
+                paused = true;
+                continue;
+            }
+            Either::Left(Some(ProxyCommand::Resume)) => continue,
+            Either::Left(Some(ProxyCommand::Terminate)) | Either::Left(None) => return Ok(()),
+            Either::Right(msg) => msg,
+        };
+
+        // A capture that stops taking messages doesn't stop the proxy.
+        match msg {
+            Either::Left(msg) => {
+                let _ = capture.feed(msg.clone()).await;
+                backend.send(msg).await?;
+            }
+            Either::Right(msg) => {
+                let _ = capture.feed(msg.clone()).await;
+                frontend.send(msg).await?;
+            }
+        }
+        let _ = capture.flush().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        peer::{ChannelPeer, Peer},
+        socket::SocketType,
+        sockets::{Dealer, Router},
+    };
+    use futures::{channel::mpsc, executor::block_on, join, FutureExt};
+    use std::task::{Context, Poll};
+
+    // Lets the other futures in a `join!` run.
+    async fn yield_now() {
+        let mut yielded = false;
+        future::poll_fn(|cx: &mut Context<'_>| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await
+    }
+
+    #[test]
+    fn test_broker() {
+        block_on(async {
+            let mut frontend = Router::new();
+            let mut backend = Dealer::new();
+            let (local, mut client) = ChannelPeer::pair(SocketType::Router, SocketType::Dealer);
+            frontend.attach(local).unwrap();
+            let (local, mut worker) = ChannelPeer::pair(SocketType::Dealer, SocketType::Router);
+            backend.attach(local).unwrap();
+
+            let (capture, captured) = mpsc::unbounded();
+            let (control, commands) = mpsc::unbounded();
+            let proxied = proxy_steerable(&mut frontend, &mut backend, capture, commands);
+            let requests = async {
+                client
+                    .send_message(Message::from(&b"hello"[..]))
+                    .await
+                    .unwrap();
+                let request = worker.recv_message().await.unwrap();
+                assert_eq!(request.get(1), Some(&b"hello"[..]));
+
+                let mut reply = request.clone();
+                reply.pop_back();
+                reply.push_back(b"world".to_vec());
+                worker.send_message(reply).await.unwrap();
+                assert_eq!(
+                    client.recv_message().await.unwrap(),
+                    Message::from(&b"world"[..])
+                );
+
+                // Nothing gets through while the proxy is paused.
+                control.unbounded_send(ProxyCommand::Pause).unwrap();
+                client
+                    .send_message(Message::from(&b"later"[..]))
+                    .await
+                    .unwrap();
+                yield_now().await;
+                assert!(worker.recv_message().now_or_never().is_none());
+                control.unbounded_send(ProxyCommand::Resume).unwrap();
+                let request = worker.recv_message().await.unwrap();
+                assert_eq!(request.get(1), Some(&b"later"[..]));
+
+                control.unbounded_send(ProxyCommand::Terminate).unwrap();
+            };
+            let (result, ()) = join!(proxied, requests);
+            result.unwrap();
+
+            let captured: Vec<_> = captured.take(3).collect().await;
+            assert_eq!(captured.len(), 3);
+            assert_eq!(captured[1].get(1), Some(&b"world"[..]));
+        });
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let pause = Message::from(&b"PAUSE"[..]);
+        assert_eq!(ProxyCommand::try_from(&pause).unwrap(), ProxyCommand::Pause);
+        assert!(ProxyCommand::try_from(&Message::from(&b"STATISTICS"[..])).is_err());
+    }
+}
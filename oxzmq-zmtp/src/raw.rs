@@ -0,0 +1,103 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::{Origin, Peer, PeerError},
+    socket::SocketType,
+};
+use futures::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use std::marker::Unpin;
+
+/// A peer that doesn't speak ZMTP at all, for STREAM sockets. There's no
+/// handshake and no framing: each received message is whatever bytes were
+/// available, and the parts of each sent message are written out back to
+/// back.
+#[derive(Debug, Clone)]
+pub struct RawPeer<S> {
+    stream: S,
+    origin: Option<Origin>,
+}
+
+impl<S: AsyncBufRead + AsyncWrite + Unpin> RawPeer<S> {
+    pub fn new(stream: S) -> RawPeer<S> {
+        RawPeer {
+            stream,
+            origin: None,
+        }
+    }
+
+    /// Records how the peer was reached, so that sockets can disconnect from
+    /// or unbind its endpoint later.
+    pub fn set_origin(&mut self, origin: Origin) {
+        self.origin = Some(origin);
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: AsyncBufRead + AsyncWrite + Unpin> Peer for RawPeer<S> {
+    fn remote_socket_type(&self) -> SocketType {
+        SocketType::Stream
+    }
+
+    fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+
+    async fn send_message(&mut self, msg: Message) -> Result<(), PeerError> {
+        for part in msg.parts() {
+            self.stream.write_all(part).await?;
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    // Raw peers can't say anything about why they went away, so the end of
+    // the stream always counts as an orderly close.
+    async fn recv_message(&mut self) -> Result<Message, PeerError> {
+        let buf = self.stream.fill_buf().await?;
+        if buf.is_empty() {
+            return Err(PeerError::Closed);
+        }
+
+        let data = buf.to_vec();
+        self.stream.consume_unpin(data.len());
+        Ok(Message::from(data))
+    }
+
+    async fn close(&mut self) -> Result<(), PeerError> {
+        self.stream.close().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::Cursor};
+
+    #[test]
+    fn test_raw_bytes() {
+        block_on(async {
+            let mut peer = RawPeer::new(Cursor::new(b"GET / HTTP/1.1\r\n".to_vec()));
+            assert_eq!(
+                peer.recv_message().await.unwrap(),
+                Message::from(&b"GET / HTTP/1.1\r\n"[..])
+            );
+            assert!(matches!(peer.recv_message().await, Err(PeerError::Closed)));
+
+            let mut peer = RawPeer::new(Cursor::new(Vec::new()));
+            peer.send_message(Message::from(vec![
+                b"HTTP/1.1 ".to_vec(),
+                b"200 OK".to_vec(),
+            ]))
+            .await
+            .unwrap();
+            assert_eq!(peer.into_inner().into_inner(), b"HTTP/1.1 200 OK");
+        });
+    }
+}
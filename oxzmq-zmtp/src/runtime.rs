@@ -0,0 +1,96 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use futures::{
+    channel::oneshot,
+    executor::block_on,
+    future::{self, Either},
+    pin_mut,
+};
+use std::{future::Future, thread, time::Duration};
+
+/// Runs background work, such as heartbeats and reconnects, on whatever
+/// executor the application uses. OxZMQ doesn't depend on any runtime, so
+/// implementing this for tokio or async-std is left to the application; it
+/// takes a few lines, forwarding to the runtime's `spawn` and `sleep`.
+pub trait Runtime {
+    /// Runs `task` to completion in the background.
+    fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static;
+
+    /// Finishes once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send;
+
+    /// Runs `fut`, giving up on it if it hasn't finished after `duration`.
+    fn timeout<F: Future>(
+        &self,
+        duration: Duration,
+        fut: F,
+    ) -> impl Future<Output = Result<F::Output, Elapsed>> {
+        let sleep = self.sleep(duration);
+        async move {
+            pin_mut!(fut, sleep);
+            match future::select(fut, sleep).await {
+                Either::Left((output, _)) => Ok(output),
+                Either::Right(((), _)) => Err(Elapsed),
+            }
+        }
+    }
+}
+
+/// Returned by `Runtime::timeout` when the future took too long.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("deadline elapsed")]
+pub struct Elapsed;
+
+/// A runtime for applications that don't have one, which runs every task on
+/// a thread of its own and sleeps on a thread too. That's fine for a few
+/// long-lived tasks, but not for many short ones.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadRuntime;
+
+impl Runtime for ThreadRuntime {
+    fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        thread::spawn(move || block_on(task));
+    }
+
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            thread::sleep(duration);
+            let _ = tx.send(());
+        });
+        async move {
+            let _ = rx.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_runtime() {
+        block_on(async {
+            let runtime = ThreadRuntime;
+            let (tx, rx) = oneshot::channel();
+            runtime.spawn(async move {
+                let _ = tx.send(7);
+            });
+            assert_eq!(rx.await, Ok(7));
+
+            let done = runtime.timeout(Duration::from_secs(5), async { 1 }).await;
+            assert_eq!(done, Ok(1));
+            let never = runtime
+                .timeout(Duration::from_millis(10), future::pending::<()>())
+                .await;
+            assert_eq!(never, Err(Elapsed));
+        });
+    }
+}
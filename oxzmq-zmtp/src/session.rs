@@ -0,0 +1,458 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    codec::{ZmtpCodec, DEFAULT_MAX_FRAME_LEN},
+    command::ZmtpCommand,
+    frame::Frame,
+    handshake::{null::NullHandshake, plain_metadata, Handshake, HandshakeError, MAX_COMMAND_LEN},
+    message::Message,
+    message_metadata,
+    options::{ConnectionOptions, MessageLimits},
+    peer::PeerError,
+    properties::Properties,
+    socket::SocketType,
+    sockets::subscription::Subscription,
+    ConnectionError, Greeting, Remote, Version, GREETING_LEN,
+};
+use std::{collections::VecDeque, convert::TryFrom, sync::Arc};
+
+// A ZMTP connection as a state machine that does no I/O of its own, for
+// transports, runtimes and tests that want to move the bytes themselves.
+// Bytes that arrive go in through `feed_bytes`, what they amount to comes
+// out of `poll_event`, and whatever has to be sent is taken with
+// `poll_output`.
+//
+// The session does the greeting, the NULL handshake and the framing, with
+// the same checks as `Connection`. It doesn't offer OxZMQ's extensions,
+// such as batching, so it talks to any ZMTP 3 peer the way libzmq does.
+//
+// Messages are held to the limits in the options. The session keeps each
+// message until it's whole, so without them, it allows 64 MiB and 65536
+// parts at most.
+#[derive(Debug)]
+pub(crate) struct Session {
+    socket_type: SocketType,
+    options: ConnectionOptions,
+    state: State,
+    codec: ZmtpCodec,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    events: VecDeque<Result<SessionEvent, SessionError>>,
+    parts: Message,
+    // How many bytes `parts` holds so far.
+    parts_bytes: u64,
+    limits: MessageLimits,
+    // What to attach to every received message; see `Message::metadata`.
+    metadata: Arc<Properties>,
+}
+
+// A session holds on to every message until it's whole, so unlike a
+// `Connection`, it limits them even when the options don't.
+const DEFAULT_LIMITS: MessageLimits = MessageLimits {
+    max_size: Some(DEFAULT_MAX_FRAME_LEN),
+    max_parts: Some(64 * 1024),
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Greeting,
+    Handshake { version: Version },
+    Ready,
+    Failed,
+}
+
+// What the bytes fed to a `Session` amounted to.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SessionEvent {
+    // The handshake is done, and messages can be sent.
+    Ready {
+        // The version both ends agreed on.
+        version: Version,
+        remote_socket_type: SocketType,
+        remote_routing_id: Option<Vec<u8>>,
+        properties: Properties,
+    },
+
+    // A whole message arrived. Subscriptions sent as ZMTP 3.1 commands
+    // arrive as messages too, as they do on sockets.
+    Message(Message),
+}
+
+impl Session {
+    // Starts a session for a socket of type `socket_type`. Our greeting is
+    // ready to be sent straight away.
+    pub(crate) fn new(
+        socket_type: SocketType,
+        options: &ConnectionOptions,
+    ) -> Result<Session, SessionError> {
+        if options.plain_role().is_some() {
+            return Err(SessionError::UnsupportedMechanism);
+        }
+        options.check_routing_id()?;
+        options.check_as_server()?;
+
+        let greeting = Greeting {
+            version: options.advertised_version(),
+            mechanism: options.mechanism(),
+            as_server: options.as_server_role(),
+        };
+        let limits = options.message_limits().or(DEFAULT_LIMITS);
+        Ok(Session {
+            socket_type,
+            options: options.clone(),
+            state: State::Greeting,
+            codec: ZmtpCodec::with_max_frame_len(MAX_COMMAND_LEN),
+            input: Vec::new(),
+            output: greeting.encode().to_vec(),
+            events: VecDeque::new(),
+            parts: Message::new(),
+            parts_bytes: 0,
+            limits,
+            metadata: Arc::default(),
+        })
+    }
+
+    // Whether the handshake is done.
+    pub(crate) fn is_ready(&self) -> bool {
+        self.state == State::Ready
+    }
+
+    // Hands the session bytes that arrived from the peer. They can be cut
+    // anywhere; whatever isn't a whole frame yet is kept for next time.
+    pub(crate) fn feed_bytes(&mut self, bytes: &[u8]) {
+        if self.state == State::Failed {
+            return;
+        }
+        self.input.extend_from_slice(bytes);
+        while let Some(result) = self.advance().transpose() {
+            let failed = result.is_err();
+            self.events.push_back(result);
+            if failed {
+                self.state = State::Failed;
+                self.input.clear();
+                return;
+            }
+        }
+    }
+
+    // The next thing that came of the bytes fed so far, if there's
+    // anything. After an error, the session is over and yields nothing
+    // more, apart from an ERROR command for the peer if one is called for.
+    pub(crate) fn poll_event(&mut self) -> Option<Result<SessionEvent, SessionError>> {
+        self.events.pop_front()
+    }
+
+    // The bytes to send to the peer, if there are any. Whatever is returned
+    // has to be sent before anything returned later.
+    pub(crate) fn poll_output(&mut self) -> Option<Vec<u8>> {
+        match self.output.is_empty() {
+            true => None,
+            false => Some(std::mem::take(&mut self.output)),
+        }
+    }
+
+    // Queues a message to go to the peer, once the handshake is done.
+    pub(crate) fn send_message(&mut self, msg: Message) -> Result<(), SessionError> {
+        if self.state != State::Ready {
+            return Err(SessionError::NotReady);
+        }
+        let last = msg.len().saturating_sub(1);
+        for (idx, part) in msg.into_parts().into_iter().enumerate() {
+            let frame = Frame::new_message(idx != last, part);
+            self.codec.encode(&frame, &mut self.output);
+        }
+        Ok(())
+    }
+
+    // Takes the next step that the input so far allows, returning the event
+    // it led to, if any. Returns `Ok(None)` once more input is needed.
+    fn advance(&mut self) -> Result<Option<SessionEvent>, SessionError> {
+        loop {
+            match self.state {
+                State::Greeting => {
+                    if self.input.len() < GREETING_LEN {
+                        return Ok(None);
+                    }
+                    let buf = <[u8; GREETING_LEN]>::try_from(&self.input[..GREETING_LEN]).unwrap();
+                    self.input.drain(..GREETING_LEN);
+                    let greeting = Greeting::decode(&buf).map_err(ConnectionError::from)?;
+
+                    // The spec has us close the connection, without an ERROR
+                    // command, if we can't agree on a version.
+                    let version = self
+                        .options
+                        .negotiate_version(greeting.version)
+                        .map_err(ConnectionError::from)?;
+                    Handshake::check_greeting(&greeting, &self.options)
+                        .map_err(ConnectionError::from)?;
+
+                    let metadata = plain_metadata(&self.socket_type, &self.options)
+                        .map_err(|err| handshake_error(err.into()))?;
+                    self.queue(ZmtpCommand::Ready(metadata).into_frame());
+                    self.state = State::Handshake { version };
+                }
+                State::Handshake { version } => {
+                    let frame = match self.codec.decode(&mut self.input) {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => return Ok(None),
+                        Err(err) => return Err(handshake_error(err.into())),
+                    };
+                    let handshake = NullHandshake::from_frame(frame).map_err(handshake_error)?;
+                    let properties = handshake.properties;
+                    let remote = match Remote::check(&properties, &self.socket_type) {
+                        Ok(remote) => remote,
+                        Err(err) => {
+                            if let Some(reason) = err.error_command() {
+                                self.queue(reason.into_frame());
+                            }
+                            return Err(err.into());
+                        }
+                    };
+                    self.metadata = Arc::new(message_metadata(properties.clone(), None));
+                    let max_frame_len = self.limits.max_size.unwrap_or(DEFAULT_MAX_FRAME_LEN);
+                    self.codec = ZmtpCodec::with_max_frame_len(max_frame_len);
+                    self.state = State::Ready;
+                    return Ok(Some(SessionEvent::Ready {
+                        version,
+                        remote_socket_type: remote.socket_type,
+                        remote_routing_id: remote.routing_id,
+                        properties,
+                    }));
+                }
+                State::Ready => {
+                    let frame = match self
+                        .codec
+                        .decode(&mut self.input)
+                        .map_err(PeerError::from)?
+                    {
+                        Some(frame) => frame,
+                        None => return Ok(None),
+                    };
+                    let cmd = match frame {
+                        Frame::Message(msg_frame) => {
+                            self.parts_bytes += msg_frame.data.len() as u64;
+                            self.limits.check(self.parts.len() + 1, self.parts_bytes)?;
+                            self.parts.push_back(msg_frame.data);
+                            if msg_frame.more {
+                                continue;
+                            }
+                            self.parts_bytes = 0;
+                            let mut msg = std::mem::take(&mut self.parts);
+                            msg.set_metadata(self.metadata.clone());
+                            return Ok(Some(SessionEvent::Message(msg)));
+                        }
+                        Frame::Command(cmd) => {
+                            ZmtpCommand::from_frame(cmd).map_err(PeerError::from)?
+                        }
+                    };
+
+                    match cmd {
+                        ZmtpCommand::Ping { context, .. } => {
+                            self.queue(ZmtpCommand::Pong { context }.into_frame());
+                        }
+                        ZmtpCommand::Error(reason) => {
+                            return Err(PeerError::Rejected(reason).into());
+                        }
+                        ZmtpCommand::Subscribe(topic) => {
+                            let msg = Subscription::Subscribe(topic).to_message();
+                            return Ok(Some(SessionEvent::Message(msg)));
+                        }
+                        ZmtpCommand::Cancel(topic) => {
+                            let msg = Subscription::Cancel(topic).to_message();
+                            return Ok(Some(SessionEvent::Message(msg)));
+                        }
+                        // Other commands are ignored, as peers ignore the
+                        // commands they don't know.
+                        _ => (),
+                    }
+                }
+                State::Failed => return Ok(None),
+            }
+        }
+    }
+
+    fn queue(&mut self, frame: Frame) {
+        self.codec.encode(&frame, &mut self.output);
+    }
+}
+
+fn handshake_error(err: crate::handshake::null::NullHandshakeError) -> SessionError {
+    ConnectionError::Handshake(HandshakeError::Null(err)).into()
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum SessionError {
+    #[error("{0}")]
+    Connection(#[from] ConnectionError),
+
+    #[error("{0}")]
+    Peer(#[from] PeerError),
+
+    #[error("sessions only support the NULL mechanism")]
+    UnsupportedMechanism,
+
+    #[error("the handshake isn't done yet")]
+    NotReady,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sockets::Pair, testing, Connection};
+    use futures::{
+        executor::block_on,
+        io::{AsyncReadExt, AsyncWriteExt},
+        join,
+    };
+
+    // Passes everything each session has to send to the other, until neither
+    // has anything more.
+    fn exchange(a: &mut Session, b: &mut Session) {
+        loop {
+            let mut moved = false;
+            if let Some(bytes) = a.poll_output() {
+                b.feed_bytes(&bytes);
+                moved = true;
+            }
+            if let Some(bytes) = b.poll_output() {
+                a.feed_bytes(&bytes);
+                moved = true;
+            }
+            if !moved {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn test_sessions() {
+        let options = ConnectionOptions::new();
+        let mut dealer = Session::new(SocketType::Dealer, &options).unwrap();
+        let mut router = Session::new(SocketType::Router, &options).unwrap();
+        assert!(matches!(
+            dealer.send_message(Message::new()),
+            Err(SessionError::NotReady)
+        ));
+        exchange(&mut dealer, &mut router);
+
+        assert!(dealer.is_ready() && router.is_ready());
+        match router.poll_event() {
+            Some(Ok(SessionEvent::Ready {
+                remote_socket_type, ..
+            })) => assert_eq!(remote_socket_type, SocketType::Dealer),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert!(matches!(
+            dealer.poll_event(),
+            Some(Ok(SessionEvent::Ready { .. }))
+        ));
+
+        // Bytes can arrive in any pieces.
+        let msg = Message::from(vec![b"hello".to_vec(), b"world".to_vec()]);
+        dealer.send_message(msg.clone()).unwrap();
+        let bytes = dealer.poll_output().unwrap();
+        for byte in bytes {
+            router.feed_bytes(&[byte]);
+        }
+        assert!(matches!(
+            router.poll_event(),
+            Some(Ok(SessionEvent::Message(received))) if received == msg
+        ));
+        assert!(router.poll_event().is_none());
+    }
+
+    #[test]
+    fn test_limits() {
+        let options = ConnectionOptions::new()
+            .max_message_size(8)
+            .max_message_parts(2);
+
+        // Neither a long frame's header nor a long chain of parts gets to
+        // use up memory.
+        for bytes in [
+            vec![0b10, 0x40, 0, 0, 0, 0, 0, 0, 0],
+            vec![1, 0, 1, 0, 1, 0],
+        ] {
+            let mut dealer = Session::new(SocketType::Dealer, &options).unwrap();
+            let mut router = Session::new(SocketType::Router, &options).unwrap();
+            exchange(&mut dealer, &mut router);
+            router.poll_event().unwrap().unwrap();
+
+            router.feed_bytes(&bytes);
+            assert!(matches!(
+                router.poll_event(),
+                Some(Err(SessionError::Peer(_)))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_invalid_socket_combination() {
+        let options = ConnectionOptions::new();
+        let mut publisher = Session::new(SocketType::Pub, &options).unwrap();
+        let mut rep = Session::new(SocketType::Rep, &options).unwrap();
+        exchange(&mut publisher, &mut rep);
+        assert!(matches!(
+            publisher.poll_event(),
+            Some(Err(SessionError::Connection(
+                ConnectionError::InvalidSocketCombination(..)
+            )))
+        ));
+        assert!(publisher.poll_event().is_none());
+    }
+
+    #[test]
+    fn test_session_with_connection() {
+        block_on(async {
+            let (mut local, remote) = testing::duplex();
+            let mut session = Session::new(SocketType::Pair, &ConnectionOptions::new()).unwrap();
+
+            // Drive the session over one end of the duplex, by hand.
+            let drive = async {
+                let mut buf = [0; 256];
+                while !session.is_ready() {
+                    if let Some(bytes) = session.poll_output() {
+                        local.write_all(&bytes).await.unwrap();
+                    }
+                    let len = local.read(&mut buf).await.unwrap();
+                    session.feed_bytes(&buf[..len]);
+                }
+                if let Some(bytes) = session.poll_output() {
+                    local.write_all(&bytes).await.unwrap();
+                }
+            };
+            let (conn, ()) = join!(Connection::new(remote, &SocketType::Pair), drive);
+            let mut pair = Pair::new();
+            pair.attach(conn.unwrap()).unwrap();
+            assert!(matches!(
+                session.poll_event(),
+                Some(Ok(SessionEvent::Ready { .. }))
+            ));
+
+            session
+                .send_message(Message::from(b"ping".to_vec()))
+                .unwrap();
+            local
+                .write_all(&session.poll_output().unwrap())
+                .await
+                .unwrap();
+            assert_eq!(pair.recv().await.unwrap(), Message::from(b"ping".to_vec()));
+
+            pair.send(Message::from(b"pong".to_vec())).await.unwrap();
+            let mut buf = [0; 256];
+            let event = loop {
+                if let Some(event) = session.poll_event() {
+                    break event.unwrap();
+                }
+                let len = local.read(&mut buf).await.unwrap();
+                session.feed_bytes(&buf[..len]);
+            };
+            assert_eq!(
+                event,
+                SessionEvent::Message(Message::from(b"pong".to_vec()))
+            );
+        });
+    }
+}
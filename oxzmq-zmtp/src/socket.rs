@@ -4,7 +4,26 @@
 
 use std::convert::TryFrom;
 
-const SUPPORTED_SOCKET_TYPES: [SocketType; 2] = [SocketType::Req, SocketType::Rep];
+const SUPPORTED_SOCKET_TYPES: [SocketType; 18] = [
+    SocketType::Req,
+    SocketType::Rep,
+    SocketType::Dealer,
+    SocketType::Router,
+    SocketType::Pub,
+    SocketType::Sub,
+    SocketType::XPub,
+    SocketType::XSub,
+    SocketType::Push,
+    SocketType::Pull,
+    SocketType::Pair,
+    SocketType::Client,
+    SocketType::Server,
+    SocketType::Radio,
+    SocketType::Dish,
+    SocketType::Scatter,
+    SocketType::Gather,
+    SocketType::Stream,
+];
 
 #[derive(Clone, Debug, Copy, PartialEq)]
 pub enum SocketType {
@@ -19,6 +38,13 @@ pub enum SocketType {
     Push,
     Pull,
     Pair,
+    Client,
+    Server,
+    Radio,
+    Dish,
+    Scatter,
+    Gather,
+    Stream,
 }
 
 impl SocketType {
@@ -39,6 +65,15 @@ impl SocketType {
             SocketType::Push => [SocketType::Pull].contains(other),
             SocketType::Pull => [SocketType::Push].contains(other),
             SocketType::Pair => [SocketType::Pair].contains(other),
+            SocketType::Client => [SocketType::Server].contains(other),
+            SocketType::Server => [SocketType::Client].contains(other),
+            SocketType::Radio => [SocketType::Dish].contains(other),
+            SocketType::Dish => [SocketType::Radio].contains(other),
+            SocketType::Scatter => [SocketType::Gather].contains(other),
+            SocketType::Gather => [SocketType::Scatter].contains(other),
+            // STREAM peers are raw connections, which don't have a socket
+            // type of their own.
+            SocketType::Stream => [SocketType::Stream].contains(other),
         }
     }
 }
@@ -60,6 +95,13 @@ impl TryFrom<&[u8]> for SocketType {
             "PUSH" => SocketType::Push,
             "PULL" => SocketType::Pull,
             "PAIR" => SocketType::Pair,
+            "CLIENT" => SocketType::Client,
+            "SERVER" => SocketType::Server,
+            "RADIO" => SocketType::Radio,
+            "DISH" => SocketType::Dish,
+            "SCATTER" => SocketType::Scatter,
+            "GATHER" => SocketType::Gather,
+            "STREAM" => SocketType::Stream,
             s => return Err(SocketTypeFromBytesError::Unknown(s.to_string())),
         };
 
@@ -97,6 +139,13 @@ impl From<&SocketType> for &'static str {
             SocketType::Push => "PUSH",
             SocketType::Pull => "PULL",
             SocketType::Pair => "PAIR",
+            SocketType::Client => "CLIENT",
+            SocketType::Server => "SERVER",
+            SocketType::Radio => "RADIO",
+            SocketType::Dish => "DISH",
+            SocketType::Scatter => "SCATTER",
+            SocketType::Gather => "GATHER",
+            SocketType::Stream => "STREAM",
         }
     }
 }
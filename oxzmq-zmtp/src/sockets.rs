@@ -0,0 +1,343 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    context::Terminated,
+    peer::{CloseReason, PeerError},
+    runtime::Elapsed,
+    socket::SocketType,
+};
+
+pub use self::{
+    client::Client,
+    dealer::Dealer,
+    dish::Dish,
+    gather::Gather,
+    pair::Pair,
+    publish::Pub,
+    pull::Pull,
+    push::Push,
+    radio::Radio,
+    reply::Rep,
+    request::{AwaitingReply, ReplyError, Req, ReqSocket},
+    router::{Router, RoutingIdConflict},
+    scatter::Scatter,
+    server::Server,
+    stream::Stream,
+    subscribe::Sub,
+    xpublish::XPub,
+    xsubscribe::XSub,
+};
+
+// The methods that every socket type hands straight on to the socket
+// underneath, so that they're written (and documented) once. Each type names
+// the groups it has: `options` for the constructors and settings, `close`,
+// `detach` for `disconnect` and `unbind`, `flush`, and `pause_recv` for
+// pausing and resuming `recv`. The thread-safe types start with `shared:`,
+// and take `&self` wherever the others take `&mut self`.
+macro_rules! socket_methods {
+    (@options) => {
+        /// A socket that belongs to `context`, and fails once it's terminated.
+        pub fn with_context(context: &$crate::context::Context) -> Self {
+            let mut socket = Self::new();
+            socket.socket.set_context(context);
+            socket
+        }
+
+        /// A socket with the settings in `options`. Whatever makes its
+        /// connections should use `options.connection_options()`.
+        pub fn with_options(options: &$crate::options::SocketOptions) -> Self {
+            let mut socket = Self::new();
+            socket.socket.set_options(options);
+            socket
+        }
+
+        /// Changes one of the socket's settings.
+        pub fn set_option(&mut self, option: $crate::options::SocketOption) {
+            self.socket.set_option(option)
+        }
+
+        /// The current value of one of the socket's settings.
+        pub fn get_option(
+            &self,
+            name: $crate::options::SocketOptionName,
+        ) -> $crate::options::SocketOption {
+            self.socket.get_option(name)
+        }
+
+        /// Reports peers being attached and dropped to `sink`.
+        pub fn set_event_sink(&mut self, sink: $crate::event::EventSink) {
+            self.socket.set_event_sink(sink)
+        }
+
+        /// How much the socket has sent and received so far.
+        pub fn metrics(&self) -> $crate::metrics::Metrics {
+            self.socket.metrics()
+        }
+
+        /// How long `close` waits for the peers to write out what they're
+        /// holding back. `None`, the default, waits for as long as it takes,
+        /// and a linger of zero drops whatever they can't write out without
+        /// waiting.
+        pub fn set_linger(&mut self, linger: Option<std::time::Duration>) {
+            self.socket.set_linger(linger)
+        }
+    };
+    (@close) => {
+        /// Closes every peer, waiting up to the linger period for them to
+        /// finish sending. Dropping the socket instead drops whatever they're
+        /// holding back.
+        pub async fn close(self) {
+            self.socket.close().await
+        }
+    };
+    (@detach) => {
+        /// Closes the connections that were made to `endpoint` and drops their
+        /// peers. Returns how many there were.
+        pub async fn disconnect(&mut self, endpoint: &$crate::endpoint::Endpoint) -> usize {
+            self.socket.disconnect(endpoint).await
+        }
+
+        /// Closes the connections that were accepted on `endpoint` and drops
+        /// their peers. Returns how many there were. Dropping the listener
+        /// stops new ones from being accepted.
+        pub async fn unbind(&mut self, endpoint: &$crate::endpoint::Endpoint) -> usize {
+            self.socket.unbind(endpoint).await
+        }
+    };
+    (@flush) => {
+        /// Writes out the messages that peers set to cork them are holding
+        /// back; see `ConnectionOptions::cork`. Peers that fail are dropped.
+        pub async fn flush(&mut self) {
+            self.socket.flush().await;
+        }
+    };
+    (@pause_recv) => {
+        /// Stops reading from peers until `resume_recv` is called, so that
+        /// they're held back by flow control instead of their messages piling
+        /// up here. `recv` waits in the meantime.
+        pub fn pause_recv(&mut self) {
+            self.socket.pause_recv()
+        }
+
+        pub fn resume_recv(&mut self) {
+            self.socket.resume_recv()
+        }
+    };
+    (@shared options) => {
+        /// A socket that belongs to `context`, and fails once it's terminated.
+        pub fn with_context(context: &$crate::context::Context) -> Self {
+            let mut socket = Self::new();
+            socket.socket.set_context(context);
+            socket
+        }
+
+        /// A socket with the settings in `options`. Whatever makes its
+        /// connections should use `options.connection_options()`.
+        pub fn with_options(options: &$crate::options::SocketOptions) -> Self {
+            let mut socket = Self::new();
+            socket.socket.set_options(options);
+            socket
+        }
+
+        /// Changes one of the socket's settings.
+        pub async fn set_option(&self, option: $crate::options::SocketOption) {
+            self.socket.lock().await.set_option(option)
+        }
+
+        /// The current value of one of the socket's settings.
+        pub async fn get_option(
+            &self,
+            name: $crate::options::SocketOptionName,
+        ) -> $crate::options::SocketOption {
+            self.socket.lock().await.get_option(name)
+        }
+
+        /// Reports peers being attached and dropped to `sink`.
+        pub async fn set_event_sink(&self, sink: $crate::event::EventSink) {
+            self.socket.lock().await.set_event_sink(sink)
+        }
+
+        /// How much the socket has sent and received so far.
+        pub async fn metrics(&self) -> $crate::metrics::Metrics {
+            self.socket.lock().await.metrics()
+        }
+
+        /// How long `close` waits for the peers to write out what they're
+        /// holding back. `None`, the default, waits for as long as it takes,
+        /// and a linger of zero drops whatever they can't write out without
+        /// waiting.
+        pub async fn set_linger(&self, linger: Option<std::time::Duration>) {
+            self.socket.lock().await.set_linger(linger)
+        }
+    };
+    (@shared close) => {
+        socket_methods!(@close);
+    };
+    (@shared detach) => {
+        /// Closes the connections that were made to `endpoint` and drops their
+        /// peers. Returns how many there were.
+        pub async fn disconnect(&self, endpoint: &$crate::endpoint::Endpoint) -> usize {
+            let origin = $crate::peer::Origin::Connected(endpoint.clone());
+            self.socket.detach(&origin).await
+        }
+
+        /// Closes the connections that were accepted on `endpoint` and drops
+        /// their peers. Returns how many there were. Dropping the listener
+        /// stops new ones from being accepted.
+        pub async fn unbind(&self, endpoint: &$crate::endpoint::Endpoint) -> usize {
+            let origin = $crate::peer::Origin::Accepted(endpoint.clone());
+            self.socket.detach(&origin).await
+        }
+    };
+    (@shared flush) => {
+        /// Writes out the messages that peers set to cork them are holding
+        /// back; see `ConnectionOptions::cork`. Peers that fail are dropped.
+        pub async fn flush(&self) {
+            self.socket.flush().await;
+        }
+    };
+    (@shared pause_recv) => {
+        /// Stops reading from peers until `resume_recv` is called, so that
+        /// they're held back by flow control instead of their messages piling
+        /// up here. Tasks calling `recv` wait in the meantime.
+        pub async fn pause_recv(&self) {
+            self.socket.lock().await.pause_recv()
+        }
+
+        pub async fn resume_recv(&self) {
+            self.socket.lock().await.resume_recv()
+        }
+    };
+    (shared: $($group:ident),*) => {
+        $(socket_methods!(@shared $group);)*
+    };
+    ($($group:ident),*) => {
+        $(socket_methods!(@$group);)*
+    };
+}
+
+mod client;
+mod dealer;
+mod dish;
+pub(crate) mod fair_queue;
+mod gather;
+mod group;
+mod pair;
+mod peer_table;
+mod publish;
+mod pull;
+mod push;
+mod radio;
+mod reply;
+mod request;
+mod router;
+mod scatter;
+mod server;
+mod shared;
+mod stream;
+mod subscribe;
+pub(crate) mod subscription;
+mod trie;
+mod xpublish;
+mod xsubscribe;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SocketError {
+    #[error("error communicating with peer")]
+    Peer(#[from] PeerError),
+
+    #[error("socket has no connected peers")]
+    NoPeers,
+
+    #[error("socket already has a peer")]
+    AlreadyConnected,
+
+    #[error("invalid socket combination: {:?} with {:?}", .0, .1)]
+    InvalidSocketCombination(SocketType, SocketType),
+
+    #[error("message has no routing ID")]
+    MissingRoutingId,
+
+    #[error("operation not allowed in the socket's current state")]
+    InvalidState,
+
+    #[error("no peer with routing ID {0:?}")]
+    HostUnreachable(Vec<u8>),
+
+    #[error("peer with routing ID {0:?} can't take any more messages")]
+    PeerBusy(Vec<u8>),
+
+    #[error("routing ID {0:?} is already taken by another peer")]
+    DuplicateRoutingId(Vec<u8>),
+
+    #[error("socket type only supports single-part messages")]
+    MultipartNotAllowed,
+
+    #[error("message needs a group of at most 255 bytes")]
+    InvalidGroup,
+
+    #[error("peers are at their high-water mark")]
+    HighWaterMark,
+
+    #[error("the socket's context was terminated")]
+    Terminated,
+
+    #[error("timed out waiting for a peer")]
+    Timeout,
+}
+
+impl From<Terminated> for SocketError {
+    fn from(_: Terminated) -> SocketError {
+        SocketError::Terminated
+    }
+}
+
+impl From<Elapsed> for SocketError {
+    fn from(_: Elapsed) -> SocketError {
+        SocketError::Timeout
+    }
+}
+
+/// What sending does when the peers a message should go to are at their
+/// high-water mark, i.e. can't take another message without waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwmPolicy {
+    /// Wait until they can take it. This is what most socket types do.
+    Block,
+
+    /// Drop the message for the peers that can't take it, and send it to the
+    /// rest. This is what PUB, XPUB and RADIO sockets do, so that one slow
+    /// subscriber can't hold back all the others, and what ROUTER sockets do
+    /// unless they're mandatory.
+    Drop,
+
+    /// Fail with `SocketError::HighWaterMark` without sending the message to
+    /// anyone. ROUTER and SERVER sockets, which send to one peer they're
+    /// given, fail with `SocketError::PeerBusy` instead. This is what
+    /// mandatory ROUTER sockets do.
+    Error,
+}
+
+impl HwmPolicy {
+    pub(crate) fn default_for(socket_type: SocketType) -> HwmPolicy {
+        match socket_type {
+            SocketType::Pub | SocketType::XPub | SocketType::Radio | SocketType::Router => {
+                HwmPolicy::Drop
+            }
+            _ => HwmPolicy::Block,
+        }
+    }
+}
+
+impl SocketError {
+    /// Why the connection to the peer ended, for errors caused by a peer
+    /// going away.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        match self {
+            SocketError::Peer(err) => Some(err.close_reason()),
+            _ => None,
+        }
+    }
+}
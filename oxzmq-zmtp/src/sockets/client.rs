@@ -0,0 +1,119 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::Peer,
+    socket::SocketType,
+    sockets::{shared::SharedSocket, SocketError},
+};
+
+/// A CLIENT socket is a thread-safe DEALER for single-part messages. Every
+/// method takes `&self`, so one socket can be shared between tasks (in an
+/// `Arc`, say) without a lock around it.
+#[derive(Debug)]
+pub struct Client<P> {
+    socket: SharedSocket<P>,
+}
+
+impl<P: Peer> Client<P> {
+    pub fn new() -> Client<P> {
+        Client {
+            socket: SharedSocket::new(SocketType::Client),
+        }
+    }
+
+    socket_methods!(shared: options, close, detach, flush, pause_recv);
+
+    pub async fn attach(&self, peer: P) -> Result<(), SocketError> {
+        self.socket.lock().await.attach(peer)
+    }
+
+    pub async fn send(&self, msg: Message) -> Result<(), SocketError> {
+        if msg.len() != 1 {
+            return Err(SocketError::MultipartNotAllowed);
+        }
+
+        self.socket
+            .send(msg, |socket, cx| socket.poll_next_ready(cx))
+            .await
+    }
+
+    pub async fn recv(&self) -> Result<Message, SocketError> {
+        loop {
+            let msg = self.socket.recv(|_, msg| msg).await?;
+            // Peers aren't allowed to send multipart messages.
+            if msg.len() == 1 {
+                return Ok(msg);
+            }
+        }
+    }
+}
+
+impl<P: Peer> Default for Client<P> {
+    fn default() -> Client<P> {
+        Client::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        options::{SocketOption, SocketOptionName, SocketOptions},
+        peer::ChannelPeer,
+    };
+    use futures::{executor::block_on, join};
+    use std::time::Duration;
+
+    #[test]
+    fn test_recv_timeout() {
+        block_on(async {
+            let options = SocketOptions::new().recv_timeout(Some(Duration::from_millis(20)));
+            let client = Client::with_options(&options);
+            let (local, _remote) = ChannelPeer::pair(SocketType::Client, SocketType::Server);
+            client.attach(local).await.unwrap();
+
+            assert!(matches!(client.recv().await, Err(SocketError::Timeout)));
+            client.set_option(SocketOption::RecvTimeout(None)).await;
+            assert_eq!(
+                client.get_option(SocketOptionName::RecvTimeout).await,
+                SocketOption::RecvTimeout(None)
+            );
+        });
+    }
+
+    #[test]
+    fn test_shared_between_tasks() {
+        block_on(async {
+            let client = Client::new();
+            let (local, mut remote) = ChannelPeer::pair(SocketType::Client, SocketType::Server);
+            client.attach(local).await.unwrap();
+
+            assert!(matches!(
+                client
+                    .send(Message::from(vec![b"a".to_vec(), b"b".to_vec()]))
+                    .await,
+                Err(SocketError::MultipartNotAllowed)
+            ));
+
+            // A task waiting to receive doesn't hold up one that's sending.
+            let echo = async {
+                let msg = remote.recv_message().await.unwrap();
+                remote
+                    .send_message(Message::from(vec![b"multi".to_vec(), b"part".to_vec()]))
+                    .await
+                    .unwrap();
+                remote.send_message(msg).await.unwrap();
+            };
+            let (received, sent, _) = join!(
+                client.recv(),
+                client.send(Message::from(&b"ping"[..])),
+                echo
+            );
+            sent.unwrap();
+            assert_eq!(received.unwrap(), Message::from(&b"ping"[..]));
+        });
+    }
+}
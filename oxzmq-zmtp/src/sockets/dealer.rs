@@ -0,0 +1,417 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::Peer,
+    socket::SocketType,
+    sockets::{HwmPolicy, SocketError},
+    ZmtpSocket,
+};
+
+/// A DEALER socket load-balances outgoing messages across its peers and
+/// fair-queues incoming messages from them. Messages pass through unchanged.
+#[derive(Debug, Clone)]
+pub struct Dealer<P> {
+    socket: ZmtpSocket<P>,
+}
+
+impl<P: Peer> Dealer<P> {
+    pub fn new() -> Dealer<P> {
+        Dealer {
+            socket: ZmtpSocket::new(SocketType::Dealer),
+        }
+    }
+
+    socket_methods!(options, close, detach, flush, pause_recv);
+
+    pub fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        self.socket.attach(peer)
+    }
+
+    pub fn peers(&self) -> &[P] {
+        self.socket.connections()
+    }
+
+    /// Sends this many messages to the peer at `idx` in `peers` for every one
+    /// sent to a peer of weight 1, whatever weight the peer asked for. Weights
+    /// can be changed at any time, e.g. as workers report their capacity.
+    pub fn set_weight(&mut self, idx: usize, weight: u32) {
+        self.socket.set_weight(idx, weight)
+    }
+
+    /// Sends a heartbeat to every peer. Messages are steered away from peers
+    /// that stop answering them.
+    pub async fn heartbeat(&mut self) {
+        self.socket.heartbeat().await
+    }
+
+    /// Drops peers once they've missed this many heartbeats in a row, as
+    /// `CloseReason::HeartbeatTimeout`. Peers are kept however many they miss
+    /// by default.
+    pub fn set_heartbeat_timeout(&mut self, misses: Option<u32>) {
+        self.socket.set_max_misses(misses)
+    }
+
+    /// What `send` does when no peer can take another message: wait for one
+    /// (the default), drop the message or fail.
+    pub fn set_hwm_policy(&mut self, policy: HwmPolicy) {
+        self.socket.set_hwm_policy(policy)
+    }
+
+    pub async fn send(&mut self, msg: Message) -> Result<(), SocketError> {
+        self.socket.send_round_robin(msg).await?;
+        Ok(())
+    }
+
+    /// Keeps only the newest message each peer has waiting, skipping the
+    /// older ones, for feeds where only the latest value matters. Only
+    /// messages that have arrived in full are skipped; for peers over TCP,
+    /// those are the rest of a batch.
+    pub fn set_conflate(&mut self, conflate: bool) {
+        self.socket.set_conflate(conflate)
+    }
+
+    pub async fn recv(&mut self) -> Result<Message, SocketError> {
+        let (_, msg) = self.socket.recv_fair().await?;
+        Ok(msg)
+    }
+}
+
+impl<P: Peer> Default for Dealer<P> {
+    fn default() -> Dealer<P> {
+        Dealer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        endpoint::Endpoint,
+        event::{Batching, EventKind, EventSink},
+        options::{SocketOption, SocketOptionName, SocketOptions},
+        peer::{ChannelPeer, CloseReason, Origin, PeerError},
+        runtime::{Runtime, ThreadRuntime},
+    };
+    use futures::{executor::block_on, future};
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_round_robin_send() {
+        block_on(async {
+            let mut dealer = Dealer::new();
+            let mut remotes = Vec::new();
+            for _ in 0..3 {
+                let (local, remote) = ChannelPeer::pair(SocketType::Dealer, SocketType::Router);
+                dealer.attach(local).unwrap();
+                remotes.push(remote);
+            }
+
+            for i in 0..6_u8 {
+                dealer.send(Message::from(vec![i])).await.unwrap();
+            }
+
+            for (idx, remote) in remotes.iter_mut().enumerate() {
+                let idx = idx as u8;
+                assert_eq!(
+                    remote.recv_message().await.unwrap(),
+                    Message::from(vec![idx])
+                );
+                assert_eq!(
+                    remote.recv_message().await.unwrap(),
+                    Message::from(vec![idx + 3])
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_fair_queued_recv() {
+        block_on(async {
+            let mut dealer = Dealer::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::Dealer, SocketType::Rep);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::Dealer, SocketType::Rep);
+            dealer.attach(local_a).unwrap();
+            dealer.attach(local_b).unwrap();
+
+            for _ in 0..2 {
+                remote_a
+                    .send_message(Message::from(&b"a"[..]))
+                    .await
+                    .unwrap();
+                remote_b
+                    .send_message(Message::from(&b"b"[..]))
+                    .await
+                    .unwrap();
+            }
+
+            let mut received = Vec::new();
+            for _ in 0..4 {
+                received.push(dealer.recv().await.unwrap().into_parts().remove(0));
+            }
+            assert_eq!(
+                received,
+                vec![b"a".to_vec(), b"b".to_vec(), b"a".to_vec(), b"b".to_vec()]
+            );
+        });
+    }
+
+    #[test]
+    fn test_degraded_peers_deprioritized() {
+        block_on(async {
+            let mut dealer = Dealer::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::Dealer, SocketType::Router);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::Dealer, SocketType::Router);
+            dealer.attach(local_a).unwrap();
+            dealer.attach(local_b).unwrap();
+
+            // Neither peer answers heartbeats, so after two both are degraded.
+            dealer.heartbeat().await;
+            dealer.heartbeat().await;
+            assert!(dealer
+                .peers()
+                .iter()
+                .all(|peer| peer.liveness.is_degraded()));
+            dealer.send(Message::from(vec![0])).await.unwrap();
+            dealer.send(Message::from(vec![1])).await.unwrap();
+            assert_eq!(
+                remote_a.recv_message().await.unwrap(),
+                Message::from(vec![0])
+            );
+            assert_eq!(
+                remote_b.recv_message().await.unwrap(),
+                Message::from(vec![1])
+            );
+
+            // Once one recovers, it gets everything.
+            let seq = dealer.socket.connections_mut()[1]
+                .liveness
+                .ping_sent(Instant::now());
+            dealer.socket.connections_mut()[1]
+                .liveness
+                .pong_received(seq, Instant::now());
+            for i in 2..4_u8 {
+                dealer.send(Message::from(vec![i])).await.unwrap();
+                assert_eq!(
+                    remote_b.recv_message().await.unwrap(),
+                    Message::from(vec![i])
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_heartbeat_timeout() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let sink = EventSink::callback(Batching::new(), move |batch| {
+            tx.send(batch).unwrap();
+        });
+
+        let mut dealer = Dealer::new();
+        dealer.set_event_sink(sink.clone());
+        dealer.set_heartbeat_timeout(Some(2));
+        let (local, _remote) = ChannelPeer::pair(SocketType::Dealer, SocketType::Router);
+        dealer.attach(local).unwrap();
+
+        // The first heartbeat goes unanswered at the second and third, and
+        // the peer is dropped at the fourth.
+        block_on(async {
+            for _ in 0..3 {
+                dealer.heartbeat().await;
+            }
+            assert_eq!(dealer.peers().len(), 1);
+            dealer.heartbeat().await;
+        });
+        assert!(dealer.peers().is_empty());
+
+        drop(dealer);
+        drop(sink);
+        let events: Vec<_> = rx.iter().flatten().collect();
+        assert!(matches!(
+            events.last().unwrap().kind(),
+            EventKind::Detached {
+                reason: CloseReason::HeartbeatTimeout,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_disconnect() {
+        block_on(async {
+            let first: Endpoint = "tcp://10.0.0.1:5555".parse().unwrap();
+            let second: Endpoint = "tcp://10.0.0.2:5555".parse().unwrap();
+
+            let mut dealer = Dealer::new();
+            let mut remotes = Vec::new();
+            for origin in [
+                Origin::Connected(first.clone()),
+                Origin::Connected(second.clone()),
+                Origin::Accepted(first.clone()),
+            ]
+            .iter()
+            {
+                let (mut local, remote) = ChannelPeer::pair(SocketType::Dealer, SocketType::Router);
+                local.origin = Some(origin.clone());
+                dealer.attach(local).unwrap();
+                remotes.push(remote);
+            }
+
+            // Only the peer we connected to goes, not the one that connected
+            // to us on the same endpoint.
+            assert_eq!(dealer.disconnect(&first).await, 1);
+            assert_eq!(dealer.disconnect(&first).await, 0);
+            assert_eq!(dealer.peers().len(), 2);
+            assert!(remotes[0].recv_message().await.is_err());
+
+            assert_eq!(dealer.unbind(&first).await, 1);
+            dealer.send(Message::from(vec![0])).await.unwrap();
+            assert_eq!(
+                remotes[1].recv_message().await.unwrap(),
+                Message::from(vec![0])
+            );
+        });
+    }
+
+    #[test]
+    fn test_options() {
+        let options = SocketOptions::new()
+            .linger(Some(Duration::ZERO))
+            .hwm_policy(HwmPolicy::Error)
+            .conflate(true);
+        let mut dealer: Dealer<ChannelPeer> = Dealer::with_options(&options);
+        assert_eq!(
+            dealer.get_option(SocketOptionName::Linger),
+            SocketOption::Linger(Some(Duration::ZERO))
+        );
+        assert_eq!(
+            dealer.get_option(SocketOptionName::HwmPolicy),
+            SocketOption::HwmPolicy(HwmPolicy::Error)
+        );
+        assert_eq!(
+            dealer.get_option(SocketOptionName::HeartbeatTimeout),
+            SocketOption::HeartbeatTimeout(None)
+        );
+
+        // Settings can be changed later, the old way or the new one.
+        dealer.set_option(SocketOption::Conflate(false));
+        dealer.set_heartbeat_timeout(Some(3));
+        assert_eq!(
+            dealer.get_option(SocketOptionName::Conflate),
+            SocketOption::Conflate(false)
+        );
+        assert_eq!(
+            dealer.get_option(SocketOptionName::HeartbeatTimeout),
+            SocketOption::HeartbeatTimeout(Some(3))
+        );
+    }
+
+    #[test]
+    fn test_timeouts() {
+        block_on(async {
+            let timeout = Some(Duration::from_millis(20));
+            let options = SocketOptions::new()
+                .send_timeout(timeout)
+                .recv_timeout(timeout);
+            let mut dealer = Dealer::with_options(&options);
+            let (local, mut remote) =
+                ChannelPeer::pair_with_capacity(SocketType::Dealer, SocketType::Router, 1);
+            dealer.attach(local).unwrap();
+
+            assert!(matches!(dealer.recv().await, Err(SocketError::Timeout)));
+            dealer.send(Message::from(&b"first"[..])).await.unwrap();
+            assert!(matches!(
+                dealer.send(Message::from(&b"second"[..])).await,
+                Err(SocketError::Timeout)
+            ));
+
+            // Timing out doesn't cost the peer.
+            assert_eq!(dealer.peers().len(), 1);
+            assert_eq!(
+                remote.recv_message().await.unwrap(),
+                Message::from(&b"first"[..])
+            );
+            dealer.send(Message::from(&b"third"[..])).await.unwrap();
+            remote
+                .send_message(Message::from(&b"reply"[..]))
+                .await
+                .unwrap();
+            assert_eq!(dealer.recv().await.unwrap(), Message::from(&b"reply"[..]));
+        });
+    }
+
+    #[test]
+    fn test_invalid_socket_combination() {
+        let mut dealer = Dealer::new();
+        let (local, _remote) = ChannelPeer::pair(SocketType::Dealer, SocketType::Req);
+        assert!(matches!(
+            dealer.attach(local),
+            Err(SocketError::InvalidSocketCombination(
+                SocketType::Dealer,
+                SocketType::Req
+            ))
+        ));
+    }
+
+    // A peer that takes `delay` to close, and says when it's done.
+    #[derive(Debug)]
+    struct SlowToClose {
+        delay: Duration,
+        closed: Arc<AtomicBool>,
+    }
+
+    impl Peer for SlowToClose {
+        fn remote_socket_type(&self) -> SocketType {
+            SocketType::Dealer
+        }
+
+        async fn send_message(&mut self, _msg: Message) -> Result<(), PeerError> {
+            Ok(())
+        }
+
+        async fn recv_message(&mut self) -> Result<Message, PeerError> {
+            future::pending().await
+        }
+
+        async fn close(&mut self) -> Result<(), PeerError> {
+            ThreadRuntime.sleep(self.delay).await;
+            self.closed.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_linger() {
+        block_on(async {
+            let closing = |linger, delay| async move {
+                let closed = Arc::new(AtomicBool::new(false));
+                let mut dealer = Dealer::new();
+                dealer.set_linger(linger);
+                dealer
+                    .attach(SlowToClose {
+                        delay,
+                        closed: closed.clone(),
+                    })
+                    .unwrap();
+                dealer.close().await;
+                closed.load(Ordering::SeqCst)
+            };
+
+            assert!(closing(None, Duration::from_millis(20)).await);
+            assert!(!closing(Some(Duration::ZERO), Duration::from_millis(20)).await);
+
+            // Peers that don't finish in time are dropped anyway.
+            let start = Instant::now();
+            assert!(!closing(Some(Duration::from_millis(20)), Duration::from_secs(10)).await);
+            assert!(start.elapsed() < Duration::from_secs(5));
+        });
+    }
+}
@@ -0,0 +1,105 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::Peer,
+    socket::SocketType,
+    sockets::{
+        group::{self, Membership},
+        SocketError,
+    },
+    ZmtpSocket,
+};
+
+/// A DISH socket receives the messages that RADIO peers send to the groups it
+/// has joined.
+#[derive(Debug, Clone)]
+pub struct Dish<P> {
+    socket: ZmtpSocket<P>,
+    groups: Vec<Vec<u8>>,
+}
+
+impl<P: Peer> Dish<P> {
+    pub fn new() -> Dish<P> {
+        Dish {
+            socket: ZmtpSocket::new(SocketType::Dish),
+            groups: Vec::new(),
+        }
+    }
+
+    socket_methods!(options, close, detach, pause_recv);
+
+    /// Attaches a peer and tells it about all the groups we've joined.
+    pub async fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        self.socket.attach(peer)?;
+
+        let idx = self.socket.connections().len() - 1;
+        for group in self.groups.iter() {
+            let join = Membership::Join(group.clone());
+            self.socket.send_to(idx, join.to_message()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts receiving messages sent to `group`. Joining a group twice has
+    /// no effect.
+    pub async fn join(&mut self, group: &str) -> Result<(), SocketError> {
+        group::validate(group)?;
+        let group = group.as_bytes().to_vec();
+        if self.groups.contains(&group) {
+            return Ok(());
+        }
+
+        let join = Membership::Join(group.clone()).to_message();
+        self.groups.push(group);
+        self.socket.send_filtered(&join, |_| true).await
+    }
+
+    pub async fn leave(&mut self, group: &str) -> Result<(), SocketError> {
+        let group = group.as_bytes().to_vec();
+        let idx = match self.groups.iter().position(|joined| *joined == group) {
+            Some(idx) => idx,
+            None => return Ok(()),
+        };
+
+        self.groups.swap_remove(idx);
+        let leave = Membership::Leave(group).to_message();
+        self.socket.send_filtered(&leave, |_| true).await
+    }
+
+    /// Receives the next message for one of our groups, with
+    /// `Message::group` set.
+    pub async fn recv(&mut self) -> Result<Message, SocketError> {
+        loop {
+            let (_, msg) = self.socket.recv_fair().await?;
+            let mut parts = msg.into_parts().into_iter();
+            let (group, data) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(group), Some(data), None) => (group, data),
+                _ => continue,
+            };
+
+            // Radios filter too, but they may not have seen our latest joins
+            // and leaves yet.
+            if !self.groups.contains(&group) {
+                continue;
+            }
+            let group = match String::from_utf8(group) {
+                Ok(group) => group,
+                Err(_) => continue,
+            };
+
+            let mut msg = Message::from(data);
+            msg.set_group(&group);
+            return Ok(msg);
+        }
+    }
+}
+
+impl<P: Peer> Default for Dish<P> {
+    fn default() -> Dish<P> {
+        Dish::new()
+    }
+}
@@ -0,0 +1,154 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// Takes turns receiving from a socket's peers, like libzmq's fair queue.
+//
+// Peers are either active, meaning they may have a message waiting, or
+// inactive, meaning they had nothing the last time they were asked. The
+// active ones take turns, and each is deactivated as soon as it runs dry, so
+// receiving doesn't keep asking peers with nothing to say. Both take a swap
+// in `order`, the peers by index with the active ones first.
+//
+// Peers can't tell us when they have something again, so every peer is made
+// active again at the end of each round. A peer that just ran dry is asked
+// once per round, which keeps a busy peer from starving one that went quiet
+// for a moment, and the socket only has to wait on all of them at once when
+// none of them has anything.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FairQueue {
+    order: Vec<usize>,
+    // Where each peer is in `order`.
+    positions: Vec<usize>,
+    active: usize,
+    // Where the next turn is in `order`, among the active peers.
+    current: usize,
+}
+
+impl FairQueue {
+    pub(crate) fn new() -> FairQueue {
+        FairQueue::default()
+    }
+
+    // Adds a peer after the others, as active, since it may well have
+    // something already.
+    pub(crate) fn attach(&mut self) {
+        let idx = self.order.len();
+        self.order.push(idx);
+        self.positions.push(idx);
+        self.activate(idx);
+    }
+
+    // Forgets the peer at `idx`, moving the ones after it down by one, as
+    // the socket's peers are.
+    pub(crate) fn remove(&mut self, idx: usize) {
+        self.deactivate(idx);
+        self.order.remove(self.positions[idx]);
+        for peer in self.order.iter_mut() {
+            if *peer > idx {
+                *peer -= 1;
+            }
+        }
+        self.positions.pop();
+        for (pos, &peer) in self.order.iter().enumerate() {
+            self.positions[peer] = pos;
+        }
+    }
+
+    pub(crate) fn activate(&mut self, idx: usize) {
+        if self.positions[idx] >= self.active {
+            self.swap(self.positions[idx], self.active);
+            self.active += 1;
+        }
+    }
+
+    // The next turn goes to whichever active peer takes this one's place.
+    pub(crate) fn deactivate(&mut self, idx: usize) {
+        if self.positions[idx] < self.active {
+            self.active -= 1;
+            self.swap(self.positions[idx], self.active);
+            if self.current >= self.active {
+                self.current = 0;
+            }
+        }
+    }
+
+    pub(crate) fn activate_all(&mut self) {
+        self.active = self.order.len();
+    }
+
+    // The peer whose turn it is, unless none of them are active.
+    pub(crate) fn current(&self) -> Option<usize> {
+        match self.active {
+            0 => None,
+            _ => Some(self.order[self.current]),
+        }
+    }
+
+    // Passes the turn on from the peer at `idx`, which was just received
+    // from. After the last active peer, a new round starts with all of them.
+    pub(crate) fn served(&mut self, idx: usize) {
+        self.current = self.positions[idx] + 1;
+        if self.current >= self.active {
+            self.current = 0;
+            self.activate_all();
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.order.swap(a, b);
+        self.positions[self.order[a]] = a;
+        self.positions[self.order[b]] = b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_turns() {
+        let mut queue = FairQueue::new();
+        (0..3).for_each(|_| queue.attach());
+
+        // Peer 1 runs dry, and the others take turns until the round ends.
+        // Then it's asked again in the next one.
+        assert_eq!(queue.current(), Some(0));
+        queue.served(0);
+        assert_eq!(queue.current(), Some(1));
+        queue.deactivate(1);
+        assert_eq!(queue.current(), Some(2));
+        queue.served(2);
+        assert_eq!(queue.current(), Some(0));
+        queue.served(0);
+        assert_eq!(queue.current(), Some(2));
+        queue.served(2);
+        assert_eq!(queue.current(), Some(1));
+
+        // Once all of them have run dry, there's nobody left to ask.
+        for idx in [1, 0, 2] {
+            assert_eq!(queue.current(), Some(idx));
+            queue.deactivate(idx);
+        }
+        assert_eq!(queue.current(), None);
+        queue.activate(2);
+        assert_eq!(queue.current(), Some(2));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut queue = FairQueue::new();
+        (0..4).for_each(|_| queue.attach());
+        queue.deactivate(0);
+        queue.remove(1);
+
+        // What was peer 3 is now peer 2, and peer 0 is still inactive.
+        let mut turns = Vec::new();
+        while let Some(idx) = queue.current() {
+            turns.push(idx);
+            queue.deactivate(idx);
+        }
+        turns.sort_unstable();
+        assert_eq!(turns, [1, 2]);
+    }
+}
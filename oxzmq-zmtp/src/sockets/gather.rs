@@ -0,0 +1,48 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::Peer,
+    socket::SocketType,
+    sockets::{shared::SharedSocket, SocketError},
+};
+
+/// A GATHER socket is a thread-safe PULL for single-part messages. Every
+/// method takes `&self`, so one socket can be shared between tasks without a
+/// lock around it. It never sends.
+#[derive(Debug)]
+pub struct Gather<P> {
+    socket: SharedSocket<P>,
+}
+
+impl<P: Peer> Gather<P> {
+    pub fn new() -> Gather<P> {
+        Gather {
+            socket: SharedSocket::new(SocketType::Gather),
+        }
+    }
+
+    socket_methods!(shared: options, close, detach, pause_recv);
+
+    pub async fn attach(&self, peer: P) -> Result<(), SocketError> {
+        self.socket.lock().await.attach(peer)
+    }
+
+    pub async fn recv(&self) -> Result<Message, SocketError> {
+        loop {
+            let msg = self.socket.recv(|_, msg| msg).await?;
+            // Peers aren't allowed to send multipart messages.
+            if msg.len() == 1 {
+                return Ok(msg);
+            }
+        }
+    }
+}
+
+impl<P: Peer> Default for Gather<P> {
+    fn default() -> Gather<P> {
+        Gather::new()
+    }
+}
@@ -0,0 +1,74 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{message::Message, sockets::SocketError};
+
+// Group names are limited to the size of a short frame, as in libzmq.
+pub(crate) const MAX_GROUP_LEN: usize = 255;
+
+const JOIN_PREFIX: &[u8] = b"\x04JOIN";
+const LEAVE_PREFIX: &[u8] = b"\x05LEAVE";
+
+// More info: https://rfc.zeromq.org/spec/48/
+//
+// DISH peers tell RADIO peers which groups they're in. libzmq sends these as
+// JOIN and LEAVE commands; peers pass them around as single-part messages
+// holding the same bytes as those commands' bodies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Membership {
+    Join(Vec<u8>),
+    Leave(Vec<u8>),
+}
+
+impl Membership {
+    pub(crate) fn parse(msg: &Message) -> Option<Membership> {
+        let part = match msg.parts() {
+            [part] => part,
+            _ => return None,
+        };
+
+        if let Some(group) = part.strip_prefix(JOIN_PREFIX) {
+            Some(Membership::Join(group.to_vec()))
+        } else {
+            part.strip_prefix(LEAVE_PREFIX)
+                .map(|group| Membership::Leave(group.to_vec()))
+        }
+    }
+
+    pub(crate) fn to_message(&self) -> Message {
+        let (prefix, group) = match self {
+            Membership::Join(group) => (JOIN_PREFIX, group),
+            Membership::Leave(group) => (LEAVE_PREFIX, group),
+        };
+
+        let mut part = Vec::with_capacity(prefix.len() + group.len());
+        part.extend_from_slice(prefix);
+        part.extend_from_slice(group);
+        Message::from(part)
+    }
+}
+
+pub(crate) fn validate(group: &str) -> Result<(), SocketError> {
+    if group.len() > MAX_GROUP_LEN {
+        return Err(SocketError::InvalidGroup);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let join = Membership::Join(b"weather".to_vec());
+        assert_eq!(join.to_message().parts(), &[b"\x04JOINweather".to_vec()]);
+        assert_eq!(Membership::parse(&join.to_message()), Some(join));
+
+        let leave = Membership::Leave(Vec::new());
+        assert_eq!(Membership::parse(&leave.to_message()), Some(leave));
+
+        assert_eq!(Membership::parse(&Message::from(&b"JOIN"[..])), None);
+    }
+}
@@ -0,0 +1,91 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{message::Message, peer::Peer, socket::SocketType, sockets::SocketError, ZmtpSocket};
+
+/// A PAIR socket talks to exactly one other PAIR socket, in both directions.
+/// Attaching a second peer fails until the first one goes away.
+#[derive(Debug, Clone)]
+pub struct Pair<P> {
+    socket: ZmtpSocket<P>,
+}
+
+impl<P: Peer> Pair<P> {
+    pub fn new() -> Pair<P> {
+        Pair {
+            socket: ZmtpSocket::new(SocketType::Pair),
+        }
+    }
+
+    socket_methods!(options, close, detach, flush, pause_recv);
+
+    pub fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        if !self.socket.connections().is_empty() {
+            return Err(SocketError::AlreadyConnected);
+        }
+
+        self.socket.attach(peer)
+    }
+
+    pub fn peer(&self) -> Option<&P> {
+        self.socket.connections().first()
+    }
+
+    pub async fn send(&mut self, msg: Message) -> Result<(), SocketError> {
+        if self.socket.connections().is_empty() {
+            return Err(SocketError::NoPeers);
+        }
+
+        self.socket.send_to(0, msg).await
+    }
+
+    pub async fn recv(&mut self) -> Result<Message, SocketError> {
+        let (_, msg) = self.socket.recv_fair().await?;
+        Ok(msg)
+    }
+}
+
+impl<P: Peer> Default for Pair<P> {
+    fn default() -> Pair<P> {
+        Pair::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::ChannelPeer;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_exclusive_peer() {
+        block_on(async {
+            let mut pair = Pair::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::Pair, SocketType::Pair);
+            let (local_b, _remote_b) = ChannelPeer::pair(SocketType::Pair, SocketType::Pair);
+            pair.attach(local_a).unwrap();
+            assert!(matches!(
+                pair.attach(local_b),
+                Err(SocketError::AlreadyConnected)
+            ));
+
+            pair.send(Message::from(&b"ping"[..])).await.unwrap();
+            assert_eq!(
+                remote_a.recv_message().await.unwrap(),
+                Message::from(&b"ping"[..])
+            );
+            remote_a
+                .send_message(Message::from(&b"pong"[..]))
+                .await
+                .unwrap();
+            assert_eq!(pair.recv().await.unwrap(), Message::from(&b"pong"[..]));
+
+            // Once the first peer is gone, another one can take its place.
+            drop(remote_a);
+            assert!(matches!(pair.recv().await, Err(SocketError::NoPeers)));
+            let (local_c, _remote_c) = ChannelPeer::pair(SocketType::Pair, SocketType::Pair);
+            pair.attach(local_c).unwrap();
+        });
+    }
+}
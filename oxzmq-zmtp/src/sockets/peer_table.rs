@@ -0,0 +1,83 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::sockets::router::RoutedPeer;
+use std::collections::HashMap;
+
+// Finds routed peers by their routing IDs without going through all of them.
+//
+// The socket drops peers on its own, e.g. when they fail, which shifts the
+// ones after them, so the table doesn't try to follow along. It checks each
+// index it finds against the peer there instead, and rebuilds itself when
+// that isn't the peer asked for. Every peer is added through `insert`, so an
+// ID that isn't in the table at all isn't attached.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PeerTable {
+    indices: HashMap<Vec<u8>, usize>,
+}
+
+impl PeerTable {
+    pub(crate) fn new() -> PeerTable {
+        PeerTable::default()
+    }
+
+    // Records that the peer at `idx` goes by `routing_id`.
+    pub(crate) fn insert<P>(&mut self, peers: &[RoutedPeer<P>], routing_id: Vec<u8>, idx: usize) {
+        self.indices.insert(routing_id, idx);
+        // IDs of dropped peers are only cleared out by rebuilding, so keep
+        // them from piling up when lookups don't come along to do it.
+        if self.indices.len() > 2 * peers.len() + 16 {
+            self.rebuild(peers);
+        }
+    }
+
+    // The index of the peer that goes by `routing_id`, if it's attached.
+    pub(crate) fn find<P>(&mut self, peers: &[RoutedPeer<P>], routing_id: &[u8]) -> Option<usize> {
+        let idx = *self.indices.get(routing_id)?;
+        match peers.get(idx) {
+            Some(routed) if routed.routing_id == routing_id => Some(idx),
+            _ => {
+                self.rebuild(peers);
+                self.indices.get(routing_id).copied()
+            }
+        }
+    }
+
+    fn rebuild<P>(&mut self, peers: &[RoutedPeer<P>]) {
+        self.indices = peers
+            .iter()
+            .enumerate()
+            .map(|(idx, routed)| (routed.routing_id.clone(), idx))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routed(routing_id: &[u8]) -> RoutedPeer<()> {
+        RoutedPeer {
+            routing_id: routing_id.to_vec(),
+            peer: (),
+        }
+    }
+
+    #[test]
+    fn test_follows_removals() {
+        let mut peers = Vec::new();
+        let mut table = PeerTable::new();
+        for routing_id in [&b"a"[..], b"b", b"c"] {
+            peers.push(routed(routing_id));
+            table.insert(&peers, routing_id.to_vec(), peers.len() - 1);
+        }
+        assert_eq!(table.find(&peers, b"b"), Some(1));
+
+        // The socket drops a peer without telling the table.
+        peers.remove(0);
+        assert_eq!(table.find(&peers, b"a"), None);
+        assert_eq!(table.find(&peers, b"c"), Some(1));
+        assert_eq!(table.find(&peers, b"d"), None);
+    }
+}
@@ -0,0 +1,142 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::Peer,
+    socket::SocketType,
+    sockets::{xpublish::XPub, HwmPolicy, SocketError},
+};
+
+/// A PUB socket sends each message to every peer subscribed to a prefix of
+/// the message's first part. It never receives messages; anything a peer
+/// sends other than a subscription is ignored.
+#[derive(Debug, Clone)]
+pub struct Pub<P> {
+    socket: XPub<P>,
+}
+
+impl<P: Peer> Pub<P> {
+    pub fn new() -> Pub<P> {
+        Pub {
+            socket: XPub::with_socket_type(SocketType::Pub),
+        }
+    }
+
+    socket_methods!(options, close, detach, flush);
+
+    pub fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        self.socket.attach(peer)
+    }
+
+    /// What `send` does when subscribed peers can't take another message:
+    /// drop it for those peers (the default), wait for them or fail.
+    pub fn set_hwm_policy(&mut self, policy: HwmPolicy) {
+        self.socket.set_hwm_policy(policy)
+    }
+
+    /// Sends the message to all subscribed peers. Having no subscribed peers
+    /// isn't an error; the message is simply dropped, as are peers that fail.
+    pub async fn send(&mut self, msg: Message) -> Result<(), SocketError> {
+        self.socket.send(msg).await
+    }
+}
+
+impl<P: Peer> Default for Pub<P> {
+    fn default() -> Pub<P> {
+        Pub::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{peer::ChannelPeer, sockets::subscription::Subscription};
+    use futures::{executor::block_on, FutureExt};
+
+    #[test]
+    fn test_send_filters_by_subscription() {
+        block_on(async {
+            let mut publisher = Pub::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::Pub, SocketType::Sub);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::Pub, SocketType::Sub);
+            publisher.attach(local_a).unwrap();
+            publisher.attach(local_b).unwrap();
+
+            let sub = Subscription::Subscribe(b"a.".to_vec());
+            remote_a.send_message(sub.to_message()).await.unwrap();
+            let sub = Subscription::Subscribe(b"b.".to_vec());
+            remote_b.send_message(sub.to_message()).await.unwrap();
+
+            publisher.send(Message::from(&b"a.1"[..])).await.unwrap();
+            publisher.send(Message::from(&b"b.1"[..])).await.unwrap();
+            publisher.send(Message::from(&b"c.1"[..])).await.unwrap();
+
+            let cancel = Subscription::Cancel(b"a.".to_vec());
+            remote_a.send_message(cancel.to_message()).await.unwrap();
+            publisher.send(Message::from(&b"a.2"[..])).await.unwrap();
+            publisher.send(Message::from(&b"b.2"[..])).await.unwrap();
+
+            assert_eq!(
+                remote_a.recv_message().await.unwrap(),
+                Message::from(&b"a.1"[..])
+            );
+            assert_eq!(
+                remote_b.recv_message().await.unwrap(),
+                Message::from(&b"b.1"[..])
+            );
+            assert_eq!(
+                remote_b.recv_message().await.unwrap(),
+                Message::from(&b"b.2"[..])
+            );
+            assert!(remote_a.recv_message().now_or_never().is_none());
+        });
+    }
+
+    #[test]
+    fn test_slow_subscriber() {
+        block_on(async {
+            let mut publisher = Pub::new();
+            let (local_slow, mut remote_slow) =
+                ChannelPeer::pair_with_capacity(SocketType::Pub, SocketType::Sub, 1);
+            let (local_fast, mut remote_fast) = ChannelPeer::pair(SocketType::Pub, SocketType::Sub);
+            publisher.attach(local_slow).unwrap();
+            publisher.attach(local_fast).unwrap();
+            let sub = Subscription::Subscribe(Vec::new());
+            remote_slow.send_message(sub.to_message()).await.unwrap();
+            remote_fast.send_message(sub.to_message()).await.unwrap();
+
+            // The slow subscriber misses what it has no room for.
+            publisher.send(Message::from(vec![0])).await.unwrap();
+            publisher.send(Message::from(vec![1])).await.unwrap();
+            assert_eq!(
+                remote_slow.recv_message().await.unwrap(),
+                Message::from(vec![0])
+            );
+            assert!(remote_slow.recv_message().now_or_never().is_none());
+            for i in 0..2_u8 {
+                assert_eq!(
+                    remote_fast.recv_message().await.unwrap(),
+                    Message::from(vec![i])
+                );
+            }
+            let metrics = publisher.metrics();
+            assert_eq!((metrics.messages_sent, metrics.bytes_sent), (3, 3));
+            assert_eq!(metrics.dropped_at_hwm, 1);
+
+            // Failing sends reach nobody.
+            publisher.send(Message::from(vec![2])).await.unwrap();
+            publisher.set_hwm_policy(HwmPolicy::Error);
+            assert!(matches!(
+                publisher.send(Message::from(vec![3])).await,
+                Err(SocketError::HighWaterMark)
+            ));
+            assert_eq!(
+                remote_fast.recv_message().await.unwrap(),
+                Message::from(vec![2])
+            );
+            assert!(remote_fast.recv_message().now_or_never().is_none());
+        });
+    }
+}
@@ -0,0 +1,177 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{message::Message, peer::Peer, socket::SocketType, sockets::SocketError, ZmtpSocket};
+
+/// A PULL socket fair-queues messages from all of its peers. It never sends.
+#[derive(Debug, Clone)]
+pub struct Pull<P> {
+    socket: ZmtpSocket<P>,
+}
+
+impl<P: Peer> Pull<P> {
+    pub fn new() -> Pull<P> {
+        Pull {
+            socket: ZmtpSocket::new(SocketType::Pull),
+        }
+    }
+
+    socket_methods!(options, close, detach, pause_recv);
+
+    pub fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        self.socket.attach(peer)
+    }
+
+    /// Keeps only the newest message each peer has waiting, skipping the
+    /// older ones, for feeds where only the latest value matters. Only
+    /// messages that have arrived in full are skipped; for peers over TCP,
+    /// those are the rest of a batch.
+    pub fn set_conflate(&mut self, conflate: bool) {
+        self.socket.set_conflate(conflate)
+    }
+
+    pub async fn recv(&mut self) -> Result<Message, SocketError> {
+        let (_, msg) = self.socket.recv_fair().await?;
+        Ok(msg)
+    }
+}
+
+impl<P: Peer> Default for Pull<P> {
+    fn default() -> Pull<P> {
+        Pull::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::ChannelPeer;
+    use futures::{executor::block_on, FutureExt};
+
+    #[test]
+    fn test_fair_queued_recv() {
+        block_on(async {
+            let mut pull = Pull::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::Pull, SocketType::Push);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::Pull, SocketType::Push);
+            pull.attach(local_a).unwrap();
+            pull.attach(local_b).unwrap();
+
+            for i in 0..2_u8 {
+                remote_a.send_message(Message::from(vec![i])).await.unwrap();
+            }
+            remote_b.send_message(Message::from(vec![9])).await.unwrap();
+
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                received.push(pull.recv().await.unwrap());
+            }
+            assert_eq!(
+                received,
+                vec![
+                    Message::from(vec![0]),
+                    Message::from(vec![9]),
+                    Message::from(vec![1])
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_quiet_peer_gets_turn() {
+        block_on(async {
+            let mut pull = Pull::new();
+            let mut remotes = Vec::new();
+            for _ in 0..3 {
+                let (local, remote) = ChannelPeer::pair(SocketType::Pull, SocketType::Push);
+                pull.attach(local).unwrap();
+                remotes.push(remote);
+            }
+
+            for i in 0..4_u8 {
+                remotes[0]
+                    .send_message(Message::from(vec![i]))
+                    .await
+                    .unwrap();
+            }
+            for i in 20..22_u8 {
+                remotes[2]
+                    .send_message(Message::from(vec![i]))
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(pull.recv().await.unwrap(), Message::from(vec![0]));
+            assert_eq!(pull.recv().await.unwrap(), Message::from(vec![20]));
+
+            // The middle peer had nothing before, but isn't left out of the
+            // next round for it.
+            remotes[1]
+                .send_message(Message::from(vec![10]))
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            for _ in 0..4 {
+                received.push(pull.recv().await.unwrap());
+            }
+            assert_eq!(
+                received,
+                vec![
+                    Message::from(vec![1]),
+                    Message::from(vec![21]),
+                    Message::from(vec![10]),
+                    Message::from(vec![2])
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_conflate() {
+        block_on(async {
+            let mut pull = Pull::new();
+            pull.set_conflate(true);
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::Pull, SocketType::Push);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::Pull, SocketType::Push);
+            pull.attach(local_a).unwrap();
+            pull.attach(local_b).unwrap();
+
+            for i in 0..3_u8 {
+                remote_a.send_message(Message::from(vec![i])).await.unwrap();
+            }
+            remote_b.send_message(Message::from(vec![9])).await.unwrap();
+
+            // Each peer's backlog comes down to its newest message.
+            assert_eq!(pull.recv().await.unwrap(), Message::from(vec![2]));
+            assert_eq!(pull.recv().await.unwrap(), Message::from(vec![9]));
+            assert!(pull.recv().now_or_never().is_none());
+
+            // Nothing's skipped once the peers are caught up.
+            remote_a.send_message(Message::from(vec![3])).await.unwrap();
+            assert_eq!(pull.recv().await.unwrap(), Message::from(vec![3]));
+        });
+    }
+
+    #[test]
+    fn test_paused_recv() {
+        block_on(async {
+            let mut pull = Pull::new();
+            let (local, mut remote) =
+                ChannelPeer::pair_with_capacity(SocketType::Pull, SocketType::Push, 1);
+            pull.attach(local).unwrap();
+
+            pull.pause_recv();
+            remote.send_message(Message::from(vec![0])).await.unwrap();
+            assert!(pull.recv().now_or_never().is_none());
+
+            // Nothing was read, so the peer is held back.
+            assert!(remote
+                .send_message(Message::from(vec![1]))
+                .now_or_never()
+                .is_none());
+
+            pull.resume_recv();
+            assert_eq!(pull.recv().await.unwrap(), Message::from(vec![0]));
+        });
+    }
+}
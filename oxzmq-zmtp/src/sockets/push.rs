@@ -0,0 +1,176 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::Peer,
+    socket::SocketType,
+    sockets::{HwmPolicy, SocketError},
+    ZmtpSocket,
+};
+
+/// A PUSH socket hands each message to one of its peers in turn, skipping
+/// peers that can't take another message right now. It never receives.
+#[derive(Debug, Clone)]
+pub struct Push<P> {
+    socket: ZmtpSocket<P>,
+}
+
+impl<P: Peer> Push<P> {
+    pub fn new() -> Push<P> {
+        Push {
+            socket: ZmtpSocket::new(SocketType::Push),
+        }
+    }
+
+    socket_methods!(options, close, detach, flush);
+
+    pub fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        self.socket.attach(peer)
+    }
+
+    pub fn peers(&self) -> &[P] {
+        self.socket.connections()
+    }
+
+    /// Sends this many messages to the peer at `idx` in `peers` for every one
+    /// sent to a peer of weight 1, whatever weight the peer asked for. Weights
+    /// can be changed at any time, e.g. as workers report their capacity.
+    pub fn set_weight(&mut self, idx: usize, weight: u32) {
+        self.socket.set_weight(idx, weight)
+    }
+
+    /// Sends a heartbeat to every peer. Messages are steered away from peers
+    /// that stop answering them.
+    pub async fn heartbeat(&mut self) {
+        self.socket.heartbeat().await
+    }
+
+    /// Drops peers once they've missed this many heartbeats in a row, as
+    /// `CloseReason::HeartbeatTimeout`. Peers are kept however many they miss
+    /// by default.
+    pub fn set_heartbeat_timeout(&mut self, misses: Option<u32>) {
+        self.socket.set_max_misses(misses)
+    }
+
+    /// What `send` does when no peer can take another message: wait for one
+    /// (the default), drop the message or fail.
+    pub fn set_hwm_policy(&mut self, policy: HwmPolicy) {
+        self.socket.set_hwm_policy(policy)
+    }
+
+    pub async fn send(&mut self, msg: Message) -> Result<(), SocketError> {
+        self.socket.send_round_robin(msg).await?;
+        Ok(())
+    }
+}
+
+impl<P: Peer> Default for Push<P> {
+    fn default() -> Push<P> {
+        Push::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::ChannelPeer;
+    use futures::{executor::block_on, FutureExt};
+
+    #[test]
+    fn test_full_peers_skipped() {
+        block_on(async {
+            let mut push = Push::new();
+            let (local_a, mut remote_a) =
+                ChannelPeer::pair_with_capacity(SocketType::Push, SocketType::Pull, 1);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::Push, SocketType::Pull);
+            push.attach(local_a).unwrap();
+            push.attach(local_b).unwrap();
+
+            for i in 0..4_u8 {
+                push.send(Message::from(vec![i])).await.unwrap();
+            }
+
+            // The first peer only had room for one message.
+            assert_eq!(
+                remote_a.recv_message().await.unwrap(),
+                Message::from(vec![0])
+            );
+            assert!(remote_a.recv_message().now_or_never().is_none());
+            for i in 1..4_u8 {
+                assert_eq!(
+                    remote_b.recv_message().await.unwrap(),
+                    Message::from(vec![i])
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_waits_for_ready_peer() {
+        block_on(async {
+            let mut push = Push::new();
+            let (local, mut remote) =
+                ChannelPeer::pair_with_capacity(SocketType::Push, SocketType::Pull, 1);
+            push.attach(local).unwrap();
+
+            push.send(Message::from(vec![0])).await.unwrap();
+            assert!(push.send(Message::from(vec![1])).now_or_never().is_none());
+
+            assert_eq!(remote.recv_message().await.unwrap(), Message::from(vec![0]));
+            push.send(Message::from(vec![1])).await.unwrap();
+            assert_eq!(remote.recv_message().await.unwrap(), Message::from(vec![1]));
+        });
+    }
+
+    #[test]
+    fn test_hwm_policy() {
+        block_on(async {
+            let mut push = Push::new();
+            let (local, mut remote) =
+                ChannelPeer::pair_with_capacity(SocketType::Push, SocketType::Pull, 1);
+            push.attach(local).unwrap();
+            push.send(Message::from(vec![0])).await.unwrap();
+
+            push.set_hwm_policy(HwmPolicy::Error);
+            assert!(matches!(
+                push.send(Message::from(vec![1])).await,
+                Err(SocketError::HighWaterMark)
+            ));
+            push.set_hwm_policy(HwmPolicy::Drop);
+            push.send(Message::from(vec![2])).await.unwrap();
+
+            assert_eq!(remote.recv_message().await.unwrap(), Message::from(vec![0]));
+            assert!(remote.recv_message().now_or_never().is_none());
+        });
+    }
+
+    #[test]
+    fn test_weighted() {
+        block_on(async {
+            let mut push = Push::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::Push, SocketType::Pull);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::Push, SocketType::Pull);
+            push.attach(local_a).unwrap();
+            push.attach(local_b).unwrap();
+            push.set_weight(0, 3);
+
+            for i in 0..8_u8 {
+                push.send(Message::from(vec![i])).await.unwrap();
+            }
+
+            // The heavier peer's share is spread out rather than sent at once.
+            let mut received_a = Vec::new();
+            while let Some(Ok(msg)) = remote_a.recv_message().now_or_never() {
+                received_a.push(msg.into_parts().remove(0)[0]);
+            }
+            let mut received_b = Vec::new();
+            while let Some(Ok(msg)) = remote_b.recv_message().now_or_never() {
+                received_b.push(msg.into_parts().remove(0)[0]);
+            }
+            assert_eq!(received_a, vec![0, 1, 3, 4, 5, 7]);
+            assert_eq!(received_b, vec![2, 6]);
+        });
+    }
+}
@@ -0,0 +1,144 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::Peer,
+    socket::SocketType,
+    sockets::{
+        group::{self, Membership},
+        subscription::SubscribedPeer,
+        trie::Trie,
+        HwmPolicy, SocketError,
+    },
+    ZmtpSocket,
+};
+use futures::FutureExt;
+
+/// A RADIO socket sends each message to the peers that joined the message's
+/// group. Unlike PUB topics, groups have to match exactly.
+#[derive(Debug, Clone)]
+pub struct Radio<P> {
+    // The topics of each peer are the groups it joined.
+    socket: ZmtpSocket<SubscribedPeer<P>>,
+}
+
+impl<P: Peer> Radio<P> {
+    pub fn new() -> Radio<P> {
+        Radio {
+            socket: ZmtpSocket::new(SocketType::Radio),
+        }
+    }
+
+    socket_methods!(options, close, detach, flush);
+
+    pub fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        self.socket.attach(SubscribedPeer {
+            topics: Trie::new(),
+            peer,
+        })
+    }
+
+    /// What `send` does when peers in the group can't take another message:
+    /// drop it for those peers (the default), wait for them or fail.
+    pub fn set_hwm_policy(&mut self, policy: HwmPolicy) {
+        self.socket.set_hwm_policy(policy)
+    }
+
+    /// Sends the single-part message to every peer in its group, which has to
+    /// be set with `Message::set_group`. Having no peers in the group isn't
+    /// an error; the message is simply dropped, as are peers that fail.
+    pub async fn send(&mut self, msg: Message) -> Result<(), SocketError> {
+        let group = msg.group().ok_or(SocketError::InvalidGroup)?;
+        group::validate(group)?;
+        if msg.len() != 1 {
+            return Err(SocketError::MultipartNotAllowed);
+        }
+
+        self.process_incoming();
+
+        // On the wire, the group goes in a frame of its own in front of the
+        // data.
+        let group = group.as_bytes().to_vec();
+        let mut wire_msg = msg;
+        wire_msg.push_front(group.clone());
+        self.socket
+            .send_filtered(&wire_msg, |subscribed| subscribed.topics.contains(&group))
+            .await
+    }
+
+    // Applies the joins and leaves that peers have already sent, without
+    // waiting for more. Anything else from a peer is ignored.
+    fn process_incoming(&mut self) {
+        for idx in (0..self.socket.connections().len()).rev() {
+            loop {
+                let subscribed = &mut self.socket.connections_mut()[idx];
+                match subscribed.peer.recv_message().now_or_never() {
+                    Some(Ok(msg)) => match Membership::parse(&msg) {
+                        Some(Membership::Join(group)) if !subscribed.topics.contains(&group) => {
+                            subscribed.topics.add(&group);
+                        }
+                        Some(Membership::Leave(group)) => {
+                            subscribed.topics.remove(&group);
+                        }
+                        _ => (),
+                    },
+                    Some(Err(err)) => {
+                        self.socket.remove(idx, err.close_reason());
+                        break;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+impl<P: Peer> Default for Radio<P> {
+    fn default() -> Radio<P> {
+        Radio::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{peer::ChannelPeer, Dish};
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_groups() {
+        block_on(async {
+            let mut radio = Radio::new();
+            let mut dish = Dish::new();
+            let (radio_end, dish_end) = ChannelPeer::pair(SocketType::Radio, SocketType::Dish);
+            radio.attach(radio_end).unwrap();
+            dish.join("weather").await.unwrap();
+            dish.attach(dish_end).await.unwrap();
+
+            let mut msg = Message::from(&b"sunny"[..]);
+            assert!(matches!(
+                radio.send(msg.clone()).await,
+                Err(SocketError::InvalidGroup)
+            ));
+
+            for group in ["weath", "weather", "weatherman"].iter() {
+                msg.set_group(group);
+                radio.send(msg.clone()).await.unwrap();
+            }
+
+            let received = dish.recv().await.unwrap();
+            assert_eq!(received, Message::from(&b"sunny"[..]));
+            assert_eq!(received.group(), Some("weather"));
+
+            dish.leave("weather").await.unwrap();
+            dish.join("sports").await.unwrap();
+            msg.set_group("weather");
+            radio.send(msg.clone()).await.unwrap();
+            msg.set_group("sports");
+            radio.send(msg).await.unwrap();
+            assert_eq!(dish.recv().await.unwrap().group(), Some("sports"));
+        });
+    }
+}
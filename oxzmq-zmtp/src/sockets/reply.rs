@@ -0,0 +1,155 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    endpoint::Endpoint,
+    message::Message,
+    peer::{Origin, Peer},
+    socket::SocketType,
+    sockets::SocketError,
+    ZmtpSocket,
+};
+
+/// A REP socket receives a request from any of its peers and then sends the
+/// reply back to that same peer. Receives and sends must alternate, starting
+/// with a receive; anything else fails with `SocketError::InvalidState`.
+#[derive(Debug, Clone)]
+pub struct Rep<P> {
+    socket: ZmtpSocket<P>,
+    state: RepState,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum RepState {
+    Ready,
+    // The index of the peer the request came from, and the envelope (routing
+    // IDs and delimiter) to put back on the reply.
+    Replying(usize, Vec<Vec<u8>>),
+}
+
+impl<P: Peer> Rep<P> {
+    pub fn new() -> Rep<P> {
+        Rep {
+            socket: ZmtpSocket::new(SocketType::Rep),
+            state: RepState::Ready,
+        }
+    }
+
+    socket_methods!(options, close, pause_recv);
+
+    pub fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        self.socket.attach(peer)
+    }
+
+    /// Closes the connections that were made to `endpoint` and drops their
+    /// peers. Returns how many there were.
+    ///
+    /// A request that came from one of them is dropped without a reply.
+    pub async fn disconnect(&mut self, endpoint: &Endpoint) -> usize {
+        self.detach(Origin::Connected(endpoint.clone())).await
+    }
+
+    /// Closes the connections that were accepted on `endpoint` and drops
+    /// their peers. Returns how many there were. Dropping the listener stops
+    /// new ones from being accepted.
+    ///
+    /// A request that came from one of them is dropped without a reply.
+    pub async fn unbind(&mut self, endpoint: &Endpoint) -> usize {
+        self.detach(Origin::Accepted(endpoint.clone())).await
+    }
+
+    async fn detach(&mut self, origin: Origin) -> usize {
+        let detached = self.socket.detach(&origin).await;
+        if let RepState::Replying(idx, envelope) =
+            std::mem::replace(&mut self.state, RepState::Ready)
+        {
+            if !detached.contains(&idx) {
+                let idx = idx - detached.iter().filter(|&&d| d < idx).count();
+                self.state = RepState::Replying(idx, envelope);
+            }
+        }
+        detached.len()
+    }
+
+    pub async fn recv(&mut self) -> Result<Message, SocketError> {
+        if self.state != RepState::Ready {
+            return Err(SocketError::InvalidState);
+        }
+
+        loop {
+            let (idx, mut msg) = self.socket.recv_fair().await?;
+
+            let mut envelope = Vec::new();
+            while let Some(part) = msg.pop_front() {
+                let is_delimiter = part.is_empty();
+                envelope.push(part);
+                if is_delimiter {
+                    self.state = RepState::Replying(idx, envelope);
+                    return Ok(msg);
+                }
+            }
+
+            // Requests without a delimiter are malformed and ignored.
+        }
+    }
+
+    pub async fn send(&mut self, mut msg: Message) -> Result<(), SocketError> {
+        let (idx, envelope) = match std::mem::replace(&mut self.state, RepState::Ready) {
+            RepState::Replying(idx, envelope) => (idx, envelope),
+            RepState::Ready => return Err(SocketError::InvalidState),
+        };
+
+        for part in envelope.into_iter().rev() {
+            msg.push_front(part);
+        }
+
+        self.socket.send_to(idx, msg).await
+    }
+}
+
+impl<P: Peer> Default for Rep<P> {
+    fn default() -> Rep<P> {
+        Rep::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::ChannelPeer;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_state_machine() {
+        block_on(async {
+            let mut rep = Rep::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::Rep, SocketType::Req);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::Rep, SocketType::Dealer);
+            rep.attach(local_a).unwrap();
+            rep.attach(local_b).unwrap();
+
+            assert!(matches!(
+                rep.send(Message::from(&b"reply"[..])).await,
+                Err(SocketError::InvalidState)
+            ));
+
+            let request = vec![b"id".to_vec(), Vec::new(), b"request".to_vec()];
+            remote_b.send_message(Message::from(request)).await.unwrap();
+            remote_a
+                .send_message(Message::from(&b"no delimiter"[..]))
+                .await
+                .unwrap();
+
+            assert_eq!(rep.recv().await.unwrap(), Message::from(&b"request"[..]));
+            assert!(matches!(rep.recv().await, Err(SocketError::InvalidState)));
+
+            rep.send(Message::from(&b"reply"[..])).await.unwrap();
+            let reply = remote_b.recv_message().await.unwrap();
+            assert_eq!(
+                reply.parts(),
+                &[b"id".to_vec(), Vec::new(), b"reply".to_vec()]
+            );
+        });
+    }
+}
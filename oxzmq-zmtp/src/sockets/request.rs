@@ -0,0 +1,398 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    endpoint::Endpoint,
+    message::Message,
+    metrics::Metrics,
+    peer::{Origin, Peer},
+    socket::SocketType,
+    sockets::SocketError,
+    ZmtpSocket,
+};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+/// A REQ socket sends a request to one of its peers in turn and then waits
+/// for that peer's reply. Sends and receives must alternate, starting with a
+/// send; anything else fails with `SocketError::InvalidState`. Wrapped in a
+/// `ReqSocket`, that's checked at compile time instead.
+#[derive(Debug, Clone)]
+pub struct Req<P> {
+    socket: ZmtpSocket<P>,
+    state: ReqState,
+    correlate: bool,
+    relaxed: bool,
+    // The ID of the latest request, if requests are being correlated.
+    request_id: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReqState {
+    Ready,
+    // The index of the peer the request went to.
+    AwaitingReply(usize),
+}
+
+impl<P: Peer> Req<P> {
+    pub fn new() -> Req<P> {
+        // Like routing IDs, request IDs start at a random point so that a
+        // restarted socket is unlikely to accept replies meant for its
+        // previous life.
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u8(0);
+
+        Req {
+            socket: ZmtpSocket::new(SocketType::Req),
+            state: ReqState::Ready,
+            correlate: false,
+            relaxed: false,
+            request_id: hasher.finish() as u32,
+        }
+    }
+
+    socket_methods!(options, close, pause_recv);
+
+    pub fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        self.socket.attach(peer)
+    }
+
+    /// Closes the connections that were made to `endpoint` and drops their
+    /// peers. Returns how many there were.
+    ///
+    /// A request that went to one of them is abandoned.
+    pub async fn disconnect(&mut self, endpoint: &Endpoint) -> usize {
+        self.detach(Origin::Connected(endpoint.clone())).await
+    }
+
+    /// Closes the connections that were accepted on `endpoint` and drops
+    /// their peers. Returns how many there were. Dropping the listener stops
+    /// new ones from being accepted.
+    ///
+    /// A request that went to one of them is abandoned.
+    pub async fn unbind(&mut self, endpoint: &Endpoint) -> usize {
+        self.detach(Origin::Accepted(endpoint.clone())).await
+    }
+
+    async fn detach(&mut self, origin: Origin) -> usize {
+        let detached = self.socket.detach(&origin).await;
+        if let ReqState::AwaitingReply(idx) = self.state {
+            self.state = match detached.contains(&idx) {
+                true => ReqState::Ready,
+                false => {
+                    ReqState::AwaitingReply(idx - detached.iter().filter(|&&d| d < idx).count())
+                }
+            };
+        }
+        detached.len()
+    }
+
+    /// Prefixes each request with an ID that the reply has to echo, and
+    /// ignores replies with any other ID. Only useful along with
+    /// `set_relaxed`, and only works with peers that echo the whole envelope
+    /// (REP and ROUTER do).
+    pub fn set_correlate(&mut self, correlate: bool) {
+        self.correlate = correlate;
+    }
+
+    /// Allows sending a new request without waiting for the reply to the last
+    /// one, which is abandoned. The new request goes to the next peer in turn,
+    /// so a stuck peer can be routed around. Without `set_correlate`, a late
+    /// reply to an abandoned request can be mistaken for the current reply.
+    pub fn set_relaxed(&mut self, relaxed: bool) {
+        self.relaxed = relaxed;
+    }
+
+    pub async fn send(&mut self, mut msg: Message) -> Result<(), SocketError> {
+        if self.state != ReqState::Ready && !self.relaxed {
+            return Err(SocketError::InvalidState);
+        }
+
+        // Requests start with an empty delimiter, which separates any routing
+        // IDs added on the way to the replier from the request itself.
+        msg.push_front(Vec::new());
+        if self.correlate {
+            self.request_id = self.request_id.wrapping_add(1);
+            msg.push_front(self.request_id.to_be_bytes().to_vec());
+        }
+
+        // REQ sockets always wait for a peer, so requests are never dropped.
+        let idx = self
+            .socket
+            .send_round_robin(msg)
+            .await?
+            .ok_or(SocketError::HighWaterMark)?;
+        self.state = ReqState::AwaitingReply(idx);
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> Result<Message, SocketError> {
+        let idx = match self.state {
+            ReqState::AwaitingReply(idx) => idx,
+            ReqState::Ready => return Err(SocketError::InvalidState),
+        };
+
+        loop {
+            let mut msg = match self.socket.recv_from(idx).await {
+                Ok(msg) => msg,
+                // The reply may still come, and can be waited for again.
+                Err(SocketError::Timeout) => return Err(SocketError::Timeout),
+                Err(err) => {
+                    // The peer is gone, so there's no reply coming.
+                    self.state = ReqState::Ready;
+                    return Err(err);
+                }
+            };
+
+            // Replies to abandoned requests are stale and ignored.
+            if self.correlate
+                && msg.pop_front().as_deref() != Some(&self.request_id.to_be_bytes()[..])
+            {
+                continue;
+            }
+
+            // Replies without the delimiter are malformed and ignored.
+            if msg.pop_front().map(|delimiter| delimiter.is_empty()) == Some(true) {
+                self.state = ReqState::Ready;
+                return Ok(msg);
+            }
+        }
+    }
+}
+
+impl<P: Peer> Default for Req<P> {
+    fn default() -> Req<P> {
+        Req::new()
+    }
+}
+
+/// A REQ socket whose type says that it's ready to send a request. Sending
+/// consumes it and hands back an `AwaitingReply`, which can only receive,
+/// so sending twice in a row or receiving first doesn't compile, rather
+/// than failing with `SocketError::InvalidState`.
+#[derive(Debug)]
+pub struct ReqSocket<P> {
+    // Boxed so that handing the socket from state to state, and back in
+    // errors, only moves a pointer.
+    req: Box<Req<P>>,
+}
+
+/// A REQ socket that sent a request and is waiting for the reply.
+#[derive(Debug)]
+pub struct AwaitingReply<P> {
+    req: Box<Req<P>>,
+}
+
+/// Why `AwaitingReply::recv` failed, along with the socket in the state the
+/// failure left it in.
+#[derive(Debug)]
+pub enum ReplyError<P> {
+    /// No reply came within the receive timeout. It may still come, and can
+    /// be waited for again.
+    Timeout(AwaitingReply<P>),
+
+    /// The peer the request went to failed, or the socket did, so there's no
+    /// reply coming.
+    Failed(ReqSocket<P>, SocketError),
+}
+
+impl<P: Peer> ReqSocket<P> {
+    /// Takes over `req`, abandoning any request it was waiting on a reply
+    /// to.
+    pub fn new(mut req: Req<P>) -> ReqSocket<P> {
+        req.state = ReqState::Ready;
+        ReqSocket { req: Box::new(req) }
+    }
+
+    pub fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        self.req.attach(peer)
+    }
+
+    /// How much the socket has sent and received so far.
+    pub fn metrics(&self) -> Metrics {
+        self.req.metrics()
+    }
+
+    /// Sends a request. If that fails, the socket is handed back along with
+    /// the error, still ready to send.
+    pub async fn send(
+        mut self,
+        msg: Message,
+    ) -> Result<AwaitingReply<P>, (ReqSocket<P>, SocketError)> {
+        match self.req.send(msg).await {
+            Ok(()) => Ok(AwaitingReply { req: self.req }),
+            Err(err) => Err((self, err)),
+        }
+    }
+
+    /// Gives back the untyped socket.
+    pub fn into_inner(self) -> Req<P> {
+        *self.req
+    }
+}
+
+impl<P: Peer> AwaitingReply<P> {
+    /// Waits for the reply, and hands it back along with the socket, ready
+    /// to send the next request.
+    pub async fn recv(mut self) -> Result<(ReqSocket<P>, Message), ReplyError<P>> {
+        match self.req.recv().await {
+            Ok(msg) => Ok((ReqSocket { req: self.req }, msg)),
+            Err(SocketError::Timeout) => Err(ReplyError::Timeout(self)),
+            Err(err) => Err(ReplyError::Failed(ReqSocket { req: self.req }, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{options::SocketOption, peer::ChannelPeer};
+    use futures::executor::block_on;
+    use std::time::Duration;
+
+    #[test]
+    fn test_state_machine() {
+        block_on(async {
+            let mut req = Req::new();
+            let (local, mut remote) = ChannelPeer::pair(SocketType::Req, SocketType::Rep);
+            req.attach(local).unwrap();
+
+            assert!(matches!(req.recv().await, Err(SocketError::InvalidState)));
+            req.send(Message::from(&b"request"[..])).await.unwrap();
+            assert!(matches!(
+                req.send(Message::from(&b"again"[..])).await,
+                Err(SocketError::InvalidState)
+            ));
+
+            let request = remote.recv_message().await.unwrap();
+            assert_eq!(request.parts(), &[Vec::new(), b"request".to_vec()]);
+
+            remote
+                .send_message(Message::from(&b"no delimiter"[..]))
+                .await
+                .unwrap();
+            remote
+                .send_message(Message::from(vec![Vec::new(), b"reply".to_vec()]))
+                .await
+                .unwrap();
+            assert_eq!(req.recv().await.unwrap(), Message::from(&b"reply"[..]));
+
+            req.send(Message::from(&b"next"[..])).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_typed() {
+        block_on(async {
+            let mut req = ReqSocket::new(Req::new());
+            let (local, mut remote) = ChannelPeer::pair(SocketType::Req, SocketType::Rep);
+            req.attach(local).unwrap();
+
+            let awaiting = req.send(Message::from(&b"request"[..])).await.unwrap();
+            let request = remote.recv_message().await.unwrap();
+            remote.send_message(request).await.unwrap();
+            let (req, reply) = awaiting.recv().await.unwrap();
+            assert_eq!(reply, Message::from(&b"request"[..]));
+
+            // Once the peer is gone, the socket can send again, to nobody.
+            let awaiting = req.send(Message::from(&b"next"[..])).await.unwrap();
+            drop(remote);
+            let req = match awaiting.recv().await {
+                Err(ReplyError::Failed(req, SocketError::Peer(_))) => req,
+                other => panic!("unexpected {:?}", other.map(|(_, msg)| msg)),
+            };
+            let (req, err) = req.send(Message::from(&b"lost"[..])).await.unwrap_err();
+            assert!(matches!(err, SocketError::NoPeers));
+            assert_eq!(req.metrics().messages_sent, 2);
+        });
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        block_on(async {
+            let mut req = Req::new();
+            req.set_option(SocketOption::RecvTimeout(Some(Duration::from_millis(20))));
+            let (local, mut remote) = ChannelPeer::pair(SocketType::Req, SocketType::Rep);
+            req.attach(local).unwrap();
+            req.send(Message::from(&b"request"[..])).await.unwrap();
+
+            // The reply can still be waited for after timing out.
+            assert!(matches!(req.recv().await, Err(SocketError::Timeout)));
+            let request = remote.recv_message().await.unwrap();
+            remote.send_message(request).await.unwrap();
+            assert_eq!(req.recv().await.unwrap(), Message::from(&b"request"[..]));
+        });
+    }
+
+    #[test]
+    fn test_disconnect_other_peer() {
+        block_on(async {
+            let mut req = Req::new();
+            let mut remotes = Vec::new();
+            for endpoint in ["tcp://10.0.0.1:5555", "tcp://10.0.0.2:5555"].iter() {
+                let (mut local, remote) = ChannelPeer::pair(SocketType::Req, SocketType::Rep);
+                local.origin = Some(Origin::Connected(endpoint.parse().unwrap()));
+                req.attach(local).unwrap();
+                remotes.push(remote);
+            }
+            let reply = || Message::from(vec![Vec::new(), b"reply".to_vec()]);
+
+            req.send(Message::from(&b"first"[..])).await.unwrap();
+            remotes[0].recv_message().await.unwrap();
+            remotes[0].send_message(reply()).await.unwrap();
+            req.recv().await.unwrap();
+
+            // The reply to the second request still arrives after the peer
+            // ahead of it is gone.
+            req.send(Message::from(&b"second"[..])).await.unwrap();
+            let first = "tcp://10.0.0.1:5555".parse().unwrap();
+            assert_eq!(req.disconnect(&first).await, 1);
+            remotes[1].recv_message().await.unwrap();
+            remotes[1].send_message(reply()).await.unwrap();
+            assert_eq!(req.recv().await.unwrap(), Message::from(&b"reply"[..]));
+
+            // Losing the peer a request went to abandons it.
+            req.send(Message::from(&b"third"[..])).await.unwrap();
+            let second = "tcp://10.0.0.2:5555".parse().unwrap();
+            assert_eq!(req.disconnect(&second).await, 1);
+            assert!(matches!(req.recv().await, Err(SocketError::InvalidState)));
+        });
+    }
+
+    #[test]
+    fn test_relaxed_correlated_resend() {
+        block_on(async {
+            let mut req = Req::new();
+            req.set_relaxed(true);
+            req.set_correlate(true);
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::Req, SocketType::Rep);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::Req, SocketType::Rep);
+            req.attach(local_a).unwrap();
+            req.attach(local_b).unwrap();
+
+            // The first peer never answers, so the request is sent again and
+            // goes to the second peer.
+            req.send(Message::from(&b"request"[..])).await.unwrap();
+            let stuck = remote_a.recv_message().await.unwrap();
+            req.send(Message::from(&b"request"[..])).await.unwrap();
+            let resent = remote_b.recv_message().await.unwrap();
+            assert_eq!(stuck.len(), 3);
+            assert_ne!(stuck.parts()[0], resent.parts()[0]);
+            assert_eq!(&resent.parts()[1..], &[Vec::new(), b"request".to_vec()]);
+
+            remote_b.send_message(resent).await.unwrap();
+            assert_eq!(req.recv().await.unwrap(), Message::from(&b"request"[..]));
+
+            // The first peer eventually answers the abandoned request, but
+            // that reply is dropped when waiting on it for the next one.
+            remote_a.send_message(stuck).await.unwrap();
+            req.send(Message::from(&b"next"[..])).await.unwrap();
+            let next = remote_a.recv_message().await.unwrap();
+            remote_a.send_message(next).await.unwrap();
+            assert_eq!(req.recv().await.unwrap(), Message::from(&b"next"[..]));
+        });
+    }
+}
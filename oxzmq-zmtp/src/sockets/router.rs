@@ -0,0 +1,670 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    heartbeat::Liveness,
+    message::Message,
+    peer::{CloseReason, Origin, Peer, PeerError},
+    socket::SocketType,
+    sockets::{peer_table::PeerTable, HwmPolicy, SocketError},
+    ZmtpSocket,
+};
+use futures::future;
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    hash::{BuildHasher, Hasher},
+    task::{Context as TaskContext, Poll},
+};
+
+/// A ROUTER socket prefixes every received message with the routing ID of the
+/// peer it came from, and expects every sent message to start with the
+/// routing ID of the peer it should go to.
+#[derive(Debug, Clone)]
+pub struct Router<P> {
+    socket: ZmtpSocket<RoutedPeer<P>>,
+    peers: PeerTable,
+    next_routing_id: u32,
+    mandatory: bool,
+    conflict: RoutingIdConflict,
+    notify_connect: bool,
+    notify_disconnect: bool,
+    // Announcements of peers coming and going that haven't been received
+    // yet.
+    notifications: VecDeque<Message>,
+}
+
+/// What a ROUTER does with a peer that asks for a routing ID another peer
+/// already has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingIdConflict {
+    /// Refuses the new peer, which is the default.
+    Reject,
+
+    /// Drops the peer that has the ID, and gives it to the new one. This is
+    /// what clients that reconnect with a stable routing ID need, since the
+    /// router may not have noticed that the old connection is dead yet.
+    Handover,
+
+    /// Keeps both, giving the new peer the ID it asked for with `#2`, `#3`
+    /// and so on after it, whichever is free first.
+    Suffix,
+}
+
+impl<P: Peer> Router<P> {
+    pub fn new() -> Router<P> {
+        // Start generated routing IDs at a random point, like libzmq does, so
+        // that IDs from a restarted router are unlikely to collide with stale
+        // ones a client may still be holding on to.
+        Router {
+            socket: ZmtpSocket::new(SocketType::Router),
+            peers: PeerTable::new(),
+            next_routing_id: first_routing_id(),
+            mandatory: false,
+            conflict: RoutingIdConflict::Reject,
+            notify_connect: false,
+            notify_disconnect: false,
+            notifications: VecDeque::new(),
+        }
+    }
+
+    socket_methods!(options, close, detach, pause_recv);
+
+    /// Attaches a peer and returns its routing ID, which is the one the peer
+    /// asked for if it picked one. What happens when that ID is already
+    /// taken depends on the `RoutingIdConflict` policy.
+    pub fn attach(&mut self, peer: P) -> Result<Vec<u8>, SocketError> {
+        // IDs starting with a zero byte are reserved for generated ones.
+        let mut routing_id = match peer.routing_id() {
+            Some(routing_id) if routing_id.first().is_some_and(|&b| b != 0x00) => {
+                routing_id.to_vec()
+            }
+            _ => generate_routing_id(&mut self.next_routing_id),
+        };
+
+        let mut existing = self.find(&routing_id);
+        if existing.is_some() {
+            match self.conflict {
+                RoutingIdConflict::Reject => {
+                    return Err(SocketError::DuplicateRoutingId(routing_id))
+                }
+                RoutingIdConflict::Handover => (),
+                RoutingIdConflict::Suffix => {
+                    routing_id = self.free_suffixed(&routing_id);
+                    existing = None;
+                }
+            }
+        }
+
+        self.socket.attach(RoutedPeer {
+            routing_id: routing_id.clone(),
+            peer,
+        })?;
+        // The new peer went on the end, so this index still points at the
+        // old one.
+        if let Some(idx) = existing {
+            self.socket.remove(idx, CloseReason::Dropped);
+            self.notify_dropped(routing_id.clone());
+        }
+        if self.notify_connect {
+            self.notifications
+                .push_back(notification(routing_id.clone()));
+        }
+        let idx = self.socket.connections().len() - 1;
+        self.peers
+            .insert(self.socket.connections(), routing_id.clone(), idx);
+        Ok(routing_id)
+    }
+
+    fn find(&mut self, routing_id: &[u8]) -> Option<usize> {
+        self.peers.find(self.socket.connections(), routing_id)
+    }
+
+    // `routing_id` with the first suffix no other peer has.
+    fn free_suffixed(&mut self, routing_id: &[u8]) -> Vec<u8> {
+        (2_u32..)
+            .map(|n| {
+                let mut suffixed = routing_id.to_vec();
+                suffixed.extend_from_slice(format!("#{}", n).as_bytes());
+                suffixed
+            })
+            .find(|suffixed| self.find(suffixed).is_none())
+            .unwrap()
+    }
+
+    /// Lets a newly attached peer take over a routing ID from the peer that
+    /// has it, which is then dropped, or refuses the new peer again. This is
+    /// `set_conflict_policy` with `Handover` or `Reject`.
+    pub fn set_handover(&mut self, handover: bool) {
+        self.conflict = match handover {
+            true => RoutingIdConflict::Handover,
+            false => RoutingIdConflict::Reject,
+        };
+    }
+
+    /// Changes what happens when a peer asks for a routing ID that's already
+    /// taken.
+    pub fn set_conflict_policy(&mut self, conflict: RoutingIdConflict) {
+        self.conflict = conflict;
+    }
+
+    /// Whether a peer with this routing ID is attached.
+    pub fn is_attached(&mut self, routing_id: &[u8]) -> bool {
+        self.find(routing_id).is_some()
+    }
+
+    /// The routing IDs of the peers that are attached, in the order they
+    /// were attached.
+    pub fn routing_ids(&self) -> impl Iterator<Item = &[u8]> {
+        self.socket
+            .connections()
+            .iter()
+            .map(|routed| routed.routing_id.as_slice())
+    }
+
+    /// Announces peers being attached, and being dropped because their
+    /// connections failed or were handed over, with a message of their
+    /// routing ID and an empty part, like libzmq's `ZMQ_ROUTER_NOTIFY`. This
+    /// lets a broker clean up what it keeps for each client without watching
+    /// the socket's events. Peers dropped by `disconnect` or `unbind` aren't
+    /// announced, since the application already knows about them.
+    pub fn set_notify(&mut self, connect: bool, disconnect: bool) {
+        self.notify_connect = connect;
+        self.notify_disconnect = disconnect;
+    }
+
+    fn notify_dropped(&mut self, routing_id: Vec<u8>) {
+        if self.notify_disconnect {
+            self.notifications.push_back(notification(routing_id));
+        }
+    }
+
+    /// Makes `send` fail when a message can't be delivered, instead of
+    /// silently dropping it. This lets a broker notice that a worker is gone.
+    /// It also sets the `HwmPolicy` to `Error`, or back to `Drop`, so set a
+    /// policy of your own after this one.
+    pub fn set_mandatory(&mut self, mandatory: bool) {
+        self.mandatory = mandatory;
+        self.socket.set_hwm_policy(match mandatory {
+            true => HwmPolicy::Error,
+            false => HwmPolicy::Drop,
+        });
+    }
+
+    /// What `send` does when the peer can't take another message: drop the
+    /// message (the default), wait for the peer or fail with
+    /// `SocketError::PeerBusy`.
+    pub fn set_hwm_policy(&mut self, policy: HwmPolicy) {
+        self.socket.set_hwm_policy(policy)
+    }
+
+    /// Writes out the messages that peers set to cork them are holding back;
+    /// see `ConnectionOptions::cork`. Peers that fail are dropped, and
+    /// announced if that's turned on.
+    pub async fn flush(&mut self) {
+        for routed in self.socket.flush().await {
+            self.notify_dropped(routed.routing_id);
+        }
+    }
+
+    /// Sends the message to the peer named by its first part. Messages for
+    /// unknown peers are silently dropped unless the socket is set to be
+    /// mandatory. What happens to messages for peers that can't take another
+    /// one right now depends on the `HwmPolicy`.
+    pub async fn send(&mut self, mut msg: Message) -> Result<(), SocketError> {
+        let routing_id = msg.pop_front().ok_or(SocketError::MissingRoutingId)?;
+        let idx = match self.find(&routing_id) {
+            Some(idx) => idx,
+            None if self.mandatory => return Err(SocketError::HostUnreachable(routing_id)),
+            None => return Ok(()),
+        };
+
+        // Check whether the peer is ready without waiting for it to be.
+        let routed = &mut self.socket.connections_mut()[idx];
+        match future::poll_fn(|cx| Poll::Ready(routed.poll_ready(cx))).await {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(err)) => {
+                self.socket.remove(idx, err.close_reason());
+                return self.unreachable(routing_id);
+            }
+            Poll::Pending => match self.socket.hwm_policy() {
+                HwmPolicy::Block => (),
+                HwmPolicy::Drop => return Ok(()),
+                HwmPolicy::Error => return Err(SocketError::PeerBusy(routing_id)),
+            },
+        }
+
+        match self.socket.send_to(idx, msg).await {
+            // The peer failed while taking the message, and has been dropped
+            // for it.
+            Err(SocketError::Peer(_)) => self.unreachable(routing_id),
+            result => result,
+        }
+    }
+
+    // Announces a peer that failed while a message was being sent to it, and
+    // reports the message as undeliverable if the socket is mandatory.
+    fn unreachable(&mut self, routing_id: Vec<u8>) -> Result<(), SocketError> {
+        self.notify_dropped(routing_id.clone());
+        match self.mandatory {
+            true => Err(SocketError::HostUnreachable(routing_id)),
+            false => Ok(()),
+        }
+    }
+
+    /// Receives the next message from a peer, or the next announcement of a
+    /// peer coming or going, if those are turned on.
+    pub async fn recv(&mut self) -> Result<Message, SocketError> {
+        loop {
+            if let Some(msg) = self.notifications.pop_front() {
+                return Ok(msg);
+            }
+            match self.socket.recv_next().await? {
+                (idx, Ok(msg)) => {
+                    let mut msg = self.socket.conflated(idx, msg);
+                    msg.push_front(self.socket.connections()[idx].routing_id.clone());
+                    return Ok(msg);
+                }
+                (idx, Err(err)) => {
+                    let routed = self.socket.remove(idx, err.close_reason());
+                    self.notify_dropped(routed.routing_id);
+                }
+            }
+        }
+    }
+}
+
+// Generated IDs are five bytes long and start with a zero byte, which
+// distinguishes them from IDs chosen by peers.
+pub(crate) fn generate_routing_id(next_routing_id: &mut u32) -> Vec<u8> {
+    let mut routing_id = vec![0x00];
+    routing_id.extend_from_slice(&next_routing_id.to_be_bytes());
+    *next_routing_id = next_routing_id.wrapping_add(1);
+    routing_id
+}
+
+// Peers coming and going are announced with an empty part after their
+// routing ID.
+pub(crate) fn notification(routing_id: Vec<u8>) -> Message {
+    Message::from(vec![routing_id, Vec::new()])
+}
+
+pub(crate) fn first_routing_id() -> u32 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    hasher.finish() as u32
+}
+
+impl<P: Peer> Default for Router<P> {
+    fn default() -> Router<P> {
+        Router::new()
+    }
+}
+
+// A peer along with the routing ID it's known by.
+#[derive(Debug, Clone)]
+pub(crate) struct RoutedPeer<P> {
+    pub(crate) routing_id: Vec<u8>,
+    pub(crate) peer: P,
+}
+
+impl<P: Peer> Peer for RoutedPeer<P> {
+    fn remote_socket_type(&self) -> SocketType {
+        self.peer.remote_socket_type()
+    }
+
+    fn routing_id(&self) -> Option<&[u8]> {
+        self.peer.routing_id()
+    }
+
+    fn origin(&self) -> Option<&Origin> {
+        self.peer.origin()
+    }
+
+    fn weight(&self) -> Option<u32> {
+        self.peer.weight()
+    }
+
+    async fn send_message(&mut self, msg: Message) -> Result<(), PeerError> {
+        self.peer.send_message(msg).await
+    }
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), PeerError>> {
+        self.peer.poll_ready(cx)
+    }
+
+    async fn send_batch(&mut self, msgs: Vec<Message>) -> Result<(), PeerError> {
+        self.peer.send_batch(msgs).await
+    }
+
+    async fn recv_message(&mut self) -> Result<Message, PeerError> {
+        self.peer.recv_message().await
+    }
+
+    fn try_recv_message(&mut self) -> Option<Result<Message, PeerError>> {
+        self.peer.try_recv_message()
+    }
+
+    async fn send_heartbeat(&mut self) -> Result<(), PeerError> {
+        self.peer.send_heartbeat().await
+    }
+
+    fn liveness(&self) -> Option<&Liveness> {
+        self.peer.liveness()
+    }
+
+    async fn flush(&mut self) -> Result<(), PeerError> {
+        self.peer.flush().await
+    }
+
+    async fn close(&mut self) -> Result<(), PeerError> {
+        self.peer.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        options::{ConnectionOptions, SocketOption, SocketOptionName},
+        peer::{BrokenPipe, ChannelPeer},
+        testing, Connection, ConnectionError,
+    };
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_routing() {
+        block_on(async {
+            let mut router = Router::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::Router, SocketType::Dealer);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::Router, SocketType::Req);
+            let id_a = router.attach(local_a).unwrap();
+            let id_b = router.attach(local_b).unwrap();
+            assert_ne!(id_a, id_b);
+            assert_eq!(id_a.len(), 5);
+
+            remote_b
+                .send_message(Message::from(&b"hello"[..]))
+                .await
+                .unwrap();
+            let msg = router.recv().await.unwrap();
+            assert_eq!(msg.parts(), &[id_b.clone(), b"hello".to_vec()]);
+
+            router
+                .send(Message::from(vec![id_b, b"world".to_vec()]))
+                .await
+                .unwrap();
+            router
+                .send(Message::from(vec![id_a, b"other".to_vec()]))
+                .await
+                .unwrap();
+            assert_eq!(
+                remote_b.recv_message().await.unwrap(),
+                Message::from(&b"world"[..])
+            );
+            assert_eq!(
+                remote_a.recv_message().await.unwrap(),
+                Message::from(&b"other"[..])
+            );
+        });
+    }
+
+    #[test]
+    fn test_handover() {
+        block_on(async {
+            let mut router = Router::new();
+            let client = |routing_id: &[u8]| {
+                let (mut local, remote) = ChannelPeer::pair(SocketType::Router, SocketType::Dealer);
+                local.routing_id = Some(routing_id.to_vec());
+                (local, remote)
+            };
+
+            let (old, mut old_remote) = client(b"client");
+            assert_eq!(router.attach(old).unwrap(), b"client");
+            assert!(matches!(
+                router.attach(client(b"client").0),
+                Err(SocketError::DuplicateRoutingId(_))
+            ));
+
+            router.set_handover(true);
+            let (new, mut new_remote) = client(b"client");
+            assert_eq!(router.attach(new).unwrap(), b"client");
+            assert_eq!(router.routing_ids().count(), 1);
+
+            let msg = Message::from(vec![b"client".to_vec(), b"data".to_vec()]);
+            router.send(msg).await.unwrap();
+            assert_eq!(
+                new_remote.recv_message().await.unwrap(),
+                Message::from(&b"data"[..])
+            );
+            assert!(old_remote.recv_message().await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_notify() {
+        block_on(async {
+            let mut router = Router::new();
+            router.set_notify(true, true);
+            let (local, mut remote) = ChannelPeer::pair(SocketType::Router, SocketType::Dealer);
+            let id = router.attach(local).unwrap();
+            assert_eq!(router.recv().await.unwrap(), notification(id.clone()));
+
+            remote
+                .send_message(Message::from(&b"bye"[..]))
+                .await
+                .unwrap();
+            drop(remote);
+            let msg = router.recv().await.unwrap();
+            assert_eq!(msg.parts(), &[id.clone(), b"bye".to_vec()]);
+            assert_eq!(router.recv().await.unwrap(), notification(id));
+            assert!(router.routing_ids().next().is_none());
+        });
+    }
+
+    #[test]
+    fn test_suffixed_routing_ids() {
+        block_on(async {
+            let mut router = Router::new();
+            router.set_conflict_policy(RoutingIdConflict::Suffix);
+            let mut remotes = Vec::new();
+            for expected in [&b"worker"[..], b"worker#2", b"worker#3"] {
+                let (mut local, remote) = ChannelPeer::pair(SocketType::Router, SocketType::Dealer);
+                local.routing_id = Some(b"worker".to_vec());
+                assert_eq!(router.attach(local).unwrap(), expected);
+                remotes.push(remote);
+            }
+            assert!(router.is_attached(b"worker#2"));
+
+            let msg = Message::from(vec![b"worker#3".to_vec(), b"data".to_vec()]);
+            router.send(msg).await.unwrap();
+            assert_eq!(
+                remotes[2].recv_message().await.unwrap(),
+                Message::from(&b"data"[..])
+            );
+        });
+    }
+
+    #[test]
+    fn test_routing_id_handshake() {
+        block_on(async {
+            let connect = |options: ConnectionOptions| async move {
+                let (a, b) = testing::duplex();
+                let (router_side, dealer_side) = futures::join!(
+                    Connection::new(a, &SocketType::Router),
+                    Connection::with_options(b, &SocketType::Dealer, &options),
+                );
+                (router_side.unwrap(), dealer_side.unwrap())
+            };
+            let mut router = Router::new();
+            let (named, mut named_remote) =
+                connect(ConnectionOptions::new().routing_id(b"named".to_vec())).await;
+            let (anonymous, mut anonymous_remote) = connect(ConnectionOptions::new()).await;
+            assert_eq!(router.attach(named).unwrap(), b"named");
+            let generated = router.attach(anonymous).unwrap();
+            assert_eq!(generated.len(), 5);
+            assert_eq!(generated[0], 0x00);
+
+            named_remote
+                .send_message(Message::from(&b"hello"[..]))
+                .await
+                .unwrap();
+            let msg = router.recv().await.unwrap();
+            assert_eq!(msg.parts(), &[b"named".to_vec(), b"hello".to_vec()]);
+
+            router
+                .send(Message::from(vec![generated, b"hi".to_vec()]))
+                .await
+                .unwrap();
+            assert_eq!(
+                anonymous_remote.recv_message().await.unwrap(),
+                Message::from(&b"hi"[..])
+            );
+
+            // IDs that ROUTER sockets would mistake for generated ones, or
+            // that are longer than libzmq allows, aren't sent.
+            for routing_id in [Vec::new(), vec![0x00, 1], vec![1; 256]] {
+                let (_, b) = testing::duplex();
+                let options = ConnectionOptions::new().routing_id(routing_id);
+                assert!(matches!(
+                    Connection::with_options(b, &SocketType::Dealer, &options).await,
+                    Err(ConnectionError::InvalidRoutingId(_))
+                ));
+            }
+        });
+    }
+
+    #[test]
+    fn test_reserved_routing_id_replaced() {
+        let mut router = Router::new();
+        let (mut local, _remote) = ChannelPeer::pair(SocketType::Router, SocketType::Dealer);
+        local.routing_id = Some(b"\x00\x01\x02\x03\x04".to_vec());
+        let routing_id = router.attach(local).unwrap();
+        assert_ne!(routing_id, b"\x00\x01\x02\x03\x04");
+        assert_eq!(routing_id.len(), 5);
+    }
+
+    #[test]
+    fn test_mandatory() {
+        block_on(async {
+            let mut router = Router::new();
+            router.set_mandatory(true);
+            let (local, mut remote) =
+                ChannelPeer::pair_with_capacity(SocketType::Router, SocketType::Dealer, 1);
+            let id = router.attach(local).unwrap();
+
+            let msg = Message::from(vec![b"nobody".to_vec(), b"data".to_vec()]);
+            assert!(matches!(
+                router.send(msg).await,
+                Err(SocketError::HostUnreachable(ref id)) if id == b"nobody"
+            ));
+
+            let msg = Message::from(vec![id.clone(), b"data".to_vec()]);
+            router.send(msg.clone()).await.unwrap();
+            assert!(matches!(
+                router.send(msg.clone()).await,
+                Err(SocketError::PeerBusy(_))
+            ));
+
+            // Without the option, the message is dropped.
+            router.set_mandatory(false);
+            assert!(router.send(msg.clone()).await.is_ok());
+            remote.recv_message().await.unwrap();
+            router.send(msg).await.unwrap();
+            remote.recv_message().await.unwrap();
+
+            drop(remote);
+            router.set_mandatory(true);
+            let msg = Message::from(vec![id, b"data".to_vec()]);
+            assert!(matches!(
+                router.send(msg).await,
+                Err(SocketError::HostUnreachable(_))
+            ));
+            assert_eq!(router.routing_ids().count(), 0);
+        });
+    }
+
+    #[test]
+    fn test_send_failure() {
+        block_on(async {
+            let mut router = Router::new();
+            router.set_notify(false, true);
+            let id = router.attach(BrokenPipe(SocketType::Dealer)).unwrap();
+
+            // The peer is dropped and announced, and the message with it.
+            let msg = Message::from(vec![id.clone(), b"data".to_vec()]);
+            router.send(msg).await.unwrap();
+            assert_eq!(router.recv().await.unwrap(), notification(id));
+            assert_eq!(router.routing_ids().count(), 0);
+
+            router.set_mandatory(true);
+            let id = router.attach(BrokenPipe(SocketType::Dealer)).unwrap();
+            let msg = Message::from(vec![id.clone(), b"data".to_vec()]);
+            assert!(matches!(
+                router.send(msg).await,
+                Err(SocketError::HostUnreachable(ref unreachable)) if *unreachable == id
+            ));
+            assert_eq!(router.recv().await.unwrap(), notification(id));
+        });
+    }
+
+    #[test]
+    fn test_hwm_policy() {
+        block_on(async {
+            let mut router = Router::new();
+            let (local, mut remote) =
+                ChannelPeer::pair_with_capacity(SocketType::Router, SocketType::Dealer, 1);
+            let id = router.attach(local).unwrap();
+            let msg = Message::from(vec![id.clone(), b"data".to_vec()]);
+            router.send(msg.clone()).await.unwrap();
+
+            // Messages for a busy peer are dropped by default, and fail the
+            // send once the socket is mandatory.
+            router.send(msg.clone()).await.unwrap();
+            router.set_mandatory(true);
+            assert_eq!(
+                router.get_option(SocketOptionName::HwmPolicy),
+                SocketOption::HwmPolicy(HwmPolicy::Error)
+            );
+            assert!(matches!(
+                router.send(msg.clone()).await,
+                Err(SocketError::PeerBusy(_))
+            ));
+
+            // Or they wait for the peer to have room.
+            router.set_option(SocketOption::HwmPolicy(HwmPolicy::Block));
+            let sent = router.send(msg.clone());
+            let received = async {
+                remote.recv_message().await.unwrap();
+                remote.recv_message().await.unwrap()
+            };
+            let ((), received) = future::join(async { sent.await.unwrap() }, received).await;
+            assert_eq!(received.parts(), &[b"data".to_vec()]);
+        });
+    }
+
+    #[test]
+    fn test_flush_failure() {
+        block_on(async {
+            let mut router = Router::new();
+            router.set_notify(false, true);
+            let id = router.attach(BrokenPipe(SocketType::Dealer)).unwrap();
+            router.flush().await;
+            assert_eq!(router.recv().await.unwrap(), notification(id));
+            assert_eq!(router.routing_ids().count(), 0);
+        });
+    }
+
+    #[test]
+    fn test_unknown_routing_id_is_dropped() {
+        block_on(async {
+            let mut router = Router::<ChannelPeer>::new();
+            let msg = Message::from(vec![b"nobody".to_vec(), b"data".to_vec()]);
+            assert!(router.send(msg).await.is_ok());
+            assert!(matches!(
+                router.send(Message::new()).await,
+                Err(SocketError::MissingRoutingId)
+            ));
+        });
+    }
+}
@@ -0,0 +1,106 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::Peer,
+    socket::SocketType,
+    sockets::{shared::SharedSocket, SocketError},
+};
+
+/// A SCATTER socket is a thread-safe PUSH for single-part messages. Every
+/// method takes `&self`, so one socket can be shared between tasks without a
+/// lock around it. It never receives.
+#[derive(Debug)]
+pub struct Scatter<P> {
+    socket: SharedSocket<P>,
+}
+
+impl<P: Peer> Scatter<P> {
+    pub fn new() -> Scatter<P> {
+        Scatter {
+            socket: SharedSocket::new(SocketType::Scatter),
+        }
+    }
+
+    socket_methods!(shared: options, close, detach, flush);
+
+    pub async fn attach(&self, peer: P) -> Result<(), SocketError> {
+        self.socket.lock().await.attach(peer)
+    }
+
+    pub async fn send(&self, msg: Message) -> Result<(), SocketError> {
+        if msg.len() != 1 {
+            return Err(SocketError::MultipartNotAllowed);
+        }
+
+        self.socket
+            .send(msg, |socket, cx| socket.poll_next_ready(cx))
+            .await
+    }
+}
+
+impl<P: Peer> Default for Scatter<P> {
+    fn default() -> Scatter<P> {
+        Scatter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{peer::ChannelPeer, Gather};
+    use futures::{executor::block_on, join};
+
+    #[test]
+    fn test_pipeline() {
+        block_on(async {
+            let scatter = Scatter::new();
+            let gather = Gather::new();
+            let (scatter_end, gather_end) =
+                ChannelPeer::pair(SocketType::Scatter, SocketType::Gather);
+            scatter.attach(scatter_end).await.unwrap();
+            gather.attach(gather_end).await.unwrap();
+
+            assert!(matches!(
+                scatter
+                    .send(Message::from(vec![b"a".to_vec(), b"b".to_vec()]))
+                    .await,
+                Err(SocketError::MultipartNotAllowed)
+            ));
+
+            let (a, b, received) = join!(
+                scatter.send(Message::from(&b"a"[..])),
+                scatter.send(Message::from(&b"b"[..])),
+                async { (gather.recv().await.unwrap(), gather.recv().await.unwrap()) }
+            );
+            a.unwrap();
+            b.unwrap();
+            assert_eq!(
+                received,
+                (Message::from(&b"a"[..]), Message::from(&b"b"[..]))
+            );
+        });
+    }
+
+    #[test]
+    fn test_paused_gather() {
+        block_on(async {
+            let scatter = Scatter::new();
+            let gather = Gather::new();
+            let (scatter_end, gather_end) =
+                ChannelPeer::pair(SocketType::Scatter, SocketType::Gather);
+            scatter.attach(scatter_end).await.unwrap();
+            gather.attach(gather_end).await.unwrap();
+            gather.pause_recv().await;
+
+            // The receiving task is woken once receiving resumes.
+            let (received, _) = join!(gather.recv(), async {
+                scatter.send(Message::from(&b"a"[..])).await.unwrap();
+                gather.resume_recv().await;
+            });
+            assert_eq!(received.unwrap(), Message::from(&b"a"[..]));
+        });
+    }
+}
@@ -0,0 +1,349 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::Peer,
+    socket::SocketType,
+    sockets::{router::RoutedPeer, shared::SharedSocket, HwmPolicy, SocketError},
+};
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    convert::TryFrom,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Mutex,
+    },
+    task::Poll,
+};
+
+/// A SERVER socket is a thread-safe ROUTER for single-part messages. Instead
+/// of an envelope, the peer a message came from or is going to is given by
+/// `Message::routing_id`. Every method takes `&self`, so one socket can be
+/// shared between tasks without a lock around it.
+#[derive(Debug)]
+pub struct Server<P> {
+    socket: SharedSocket<RoutedPeer<P>>,
+    next_routing_id: AtomicU32,
+    notify_connect: AtomicBool,
+    notify_disconnect: AtomicBool,
+    // Announcements of peers coming and going that haven't been received
+    // yet.
+    notifications: Mutex<VecDeque<Message>>,
+}
+
+impl<P: Peer> Server<P> {
+    pub fn new() -> Server<P> {
+        // Start at a random point for the same reason as ROUTER does.
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u8(0);
+
+        Server {
+            socket: SharedSocket::new(SocketType::Server),
+            next_routing_id: AtomicU32::new(hasher.finish() as u32),
+            notify_connect: AtomicBool::new(false),
+            notify_disconnect: AtomicBool::new(false),
+            notifications: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    socket_methods!(shared: options, close, detach, pause_recv);
+
+    /// Attaches a peer and returns the routing ID it was assigned.
+    pub async fn attach(&self, peer: P) -> Result<u32, SocketError> {
+        // Zero isn't a valid routing ID, since libzmq uses it to mean "none".
+        let mut routing_id = 0;
+        while routing_id == 0 {
+            routing_id = self.next_routing_id.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.socket.lock().await.attach(RoutedPeer {
+            routing_id: routing_id.to_be_bytes().to_vec(),
+            peer,
+        })?;
+        if self.notify_connect.load(Ordering::Relaxed) {
+            self.notifications().push_back(notification(routing_id));
+            self.socket.wake_waiters();
+        }
+        Ok(routing_id)
+    }
+
+    /// Announces peers being attached, and being dropped because their
+    /// connections failed, with a message that has their routing ID and no
+    /// parts at all, which no peer can send. This lets a broker clean up
+    /// what it keeps for each client without watching the socket's events.
+    /// Peers dropped by `disconnect` or `unbind` aren't announced, since the
+    /// application already knows about them.
+    pub fn set_notify(&self, connect: bool, disconnect: bool) {
+        self.notify_connect.store(connect, Ordering::Relaxed);
+        self.notify_disconnect.store(disconnect, Ordering::Relaxed);
+    }
+
+    fn notifications(&self) -> std::sync::MutexGuard<'_, VecDeque<Message>> {
+        self.notifications.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // The announcement of the peer with `routing_id` being dropped, if those
+    // are turned on.
+    fn dropped(&self, routing_id: u32) -> Option<Message> {
+        match self.notify_disconnect.load(Ordering::Relaxed) {
+            true => Some(notification(routing_id)),
+            false => None,
+        }
+    }
+
+    /// Writes out the messages that peers set to cork them are holding back;
+    /// see `ConnectionOptions::cork`. Peers that fail are dropped, and
+    /// announced if that's turned on.
+    pub async fn flush(&self) {
+        let dropped = self.socket.flush().await;
+        let announced = dropped
+            .iter()
+            .filter_map(|routed| self.dropped(routing_id(routed)))
+            .collect::<Vec<_>>();
+        if !announced.is_empty() {
+            self.notifications().extend(announced);
+            self.socket.wake_waiters();
+        }
+    }
+
+    /// Sends the message to the peer named by its routing ID. If the peer
+    /// has no room for it, what happens depends on the `HwmPolicy`: by
+    /// default, this waits until it does.
+    pub async fn send(&self, msg: Message) -> Result<(), SocketError> {
+        let routing_id = msg.routing_id().ok_or(SocketError::MissingRoutingId)?;
+        if msg.len() != 1 {
+            return Err(SocketError::MultipartNotAllowed);
+        }
+
+        let policy = self.socket.lock().await.hwm_policy();
+        let id = routing_id;
+        let routing_id = routing_id.to_be_bytes().to_vec();
+        let sent = self
+            .socket
+            .send(msg, |socket, cx| {
+                let idx = socket
+                    .connections()
+                    .iter()
+                    .position(|routed| routed.routing_id == routing_id);
+                let idx = match idx {
+                    Some(idx) => idx,
+                    None => {
+                        return Poll::Ready(Err(SocketError::HostUnreachable(routing_id.clone())))
+                    }
+                };
+
+                match socket.connections_mut()[idx].poll_ready(cx) {
+                    Poll::Ready(Ok(())) => Poll::Ready(Ok(idx)),
+                    Poll::Ready(Err(err)) => {
+                        socket.remove(idx, err.close_reason());
+                        if let Some(msg) = self.dropped(id) {
+                            self.notifications().push_back(msg);
+                        }
+                        Poll::Ready(Err(SocketError::HostUnreachable(routing_id.clone())))
+                    }
+                    Poll::Pending if policy == HwmPolicy::Block => Poll::Pending,
+                    Poll::Pending => Poll::Ready(Err(SocketError::PeerBusy(routing_id.clone()))),
+                }
+            })
+            .await;
+
+        match sent {
+            Err(SocketError::PeerBusy(_)) if policy == HwmPolicy::Drop => Ok(()),
+            // The peer failed while taking the message, and has been dropped
+            // for it.
+            Err(SocketError::Peer(_)) => {
+                if let Some(msg) = self.dropped(id) {
+                    self.notifications().push_back(msg);
+                    self.socket.wake_waiters();
+                }
+                Err(SocketError::HostUnreachable(routing_id))
+            }
+            sent => sent,
+        }
+    }
+
+    /// Receives the next message from a peer, or the next announcement of a
+    /// peer coming or going, if those are turned on.
+    pub async fn recv(&self) -> Result<Message, SocketError> {
+        loop {
+            let received = self
+                .socket
+                .recv_announced(
+                    || self.notifications().pop_front().map(Some),
+                    |routed, mut msg| {
+                        // Peers aren't allowed to send multipart messages.
+                        if msg.len() != 1 {
+                            return None;
+                        }
+                        msg.set_routing_id(routing_id(routed));
+                        Some(msg)
+                    },
+                    |routed| self.dropped(routing_id(&routed)).map(Some),
+                )
+                .await?;
+            if let Some(msg) = received {
+                return Ok(msg);
+            }
+        }
+    }
+}
+
+fn routing_id<P>(routed: &RoutedPeer<P>) -> u32 {
+    // Can't fail; we generated the routing ID from a `u32`.
+    let routing_id = <[u8; 4]>::try_from(routed.routing_id.as_slice()).unwrap();
+    u32::from_be_bytes(routing_id)
+}
+
+// Peers coming and going are announced with a message of no parts.
+fn notification(routing_id: u32) -> Message {
+    let mut msg = Message::new();
+    msg.set_routing_id(routing_id);
+    msg
+}
+
+impl<P: Peer> Default for Server<P> {
+    fn default() -> Server<P> {
+        Server::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        options::SocketOption,
+        peer::{BrokenPipe, ChannelPeer},
+    };
+    use futures::{executor::block_on, FutureExt};
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[test]
+    fn test_routing() {
+        block_on(async {
+            let server = Server::new();
+            assert_send_sync(&server);
+
+            let (local_a, remote_a) = ChannelPeer::pair(SocketType::Server, SocketType::Client);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::Server, SocketType::Client);
+            let id_a = server.attach(local_a).await.unwrap();
+            let id_b = server.attach(local_b).await.unwrap();
+            assert_ne!(id_a, id_b);
+
+            remote_b
+                .send_message(Message::from(&b"hello"[..]))
+                .await
+                .unwrap();
+            let recv = server.recv();
+            assert_send(&recv);
+            let mut msg = recv.await.unwrap();
+            assert_eq!(msg.routing_id(), Some(id_b));
+            assert_eq!(msg, Message::from(&b"hello"[..]));
+
+            server.send(msg.clone()).await.unwrap();
+            assert_eq!(
+                remote_b.recv_message().await.unwrap(),
+                Message::from(&b"hello"[..])
+            );
+
+            msg.set_routing_id(id_a ^ id_b ^ 1);
+            assert!(matches!(
+                server.send(msg).await,
+                Err(SocketError::HostUnreachable(_))
+            ));
+            assert!(matches!(
+                server.send(Message::from(&b"nobody"[..])).await,
+                Err(SocketError::MissingRoutingId)
+            ));
+
+            drop(remote_a);
+            let mut msg = Message::from(&b"gone"[..]);
+            msg.set_routing_id(id_a);
+            assert!(server.send(msg).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_notify() {
+        block_on(async {
+            let server = Server::new();
+            server.set_notify(true, true);
+            let (local, mut remote) = ChannelPeer::pair(SocketType::Server, SocketType::Client);
+            let id = server.attach(local).await.unwrap();
+            assert_eq!(server.recv().await.unwrap(), notification(id));
+
+            remote
+                .send_message(Message::from(&b"bye"[..]))
+                .await
+                .unwrap();
+            drop(remote);
+            assert_eq!(server.recv().await.unwrap(), Message::from(&b"bye"[..]));
+            let announced = server.recv().await.unwrap();
+            assert_eq!((announced.routing_id(), announced.len()), (Some(id), 0));
+        });
+    }
+
+    #[test]
+    fn test_send_failure() {
+        block_on(async {
+            let server = Server::new();
+            server.set_notify(false, true);
+            let id = server.attach(BrokenPipe(SocketType::Client)).await.unwrap();
+
+            // The peer is dropped and announced, and the message with it.
+            let mut msg = Message::from(&b"data"[..]);
+            msg.set_routing_id(id);
+            assert!(matches!(
+                server.send(msg).await,
+                Err(SocketError::HostUnreachable(ref unreachable)) if *unreachable == id.to_be_bytes()
+            ));
+            let announced = server.recv().await.unwrap();
+            assert_eq!((announced.routing_id(), announced.len()), (Some(id), 0));
+        });
+    }
+
+    #[test]
+    fn test_flush_failure() {
+        block_on(async {
+            let server = Server::new();
+            server.set_notify(false, true);
+            let id = server.attach(BrokenPipe(SocketType::Client)).await.unwrap();
+            server.flush().await;
+            let announced = server.recv().await.unwrap();
+            assert_eq!((announced.routing_id(), announced.len()), (Some(id), 0));
+        });
+    }
+
+    #[test]
+    fn test_hwm_policy() {
+        block_on(async {
+            let server = Server::new();
+            let (local, mut remote) =
+                ChannelPeer::pair_with_capacity(SocketType::Server, SocketType::Client, 1);
+            let id = server.attach(local).await.unwrap();
+            let mut msg = Message::from(&b"data"[..]);
+            msg.set_routing_id(id);
+            server.send(msg.clone()).await.unwrap();
+
+            server
+                .set_option(SocketOption::HwmPolicy(HwmPolicy::Error))
+                .await;
+            assert!(matches!(
+                server.send(msg.clone()).await,
+                Err(SocketError::PeerBusy(ref busy)) if *busy == id.to_be_bytes()
+            ));
+            server
+                .set_option(SocketOption::HwmPolicy(HwmPolicy::Drop))
+                .await;
+            server.send(msg.clone()).await.unwrap();
+
+            // Only the first message got through.
+            remote.recv_message().await.unwrap();
+            assert!(remote.recv_message().now_or_never().is_none());
+        });
+    }
+}
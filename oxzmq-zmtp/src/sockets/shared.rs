@@ -0,0 +1,211 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    context::Context,
+    message::Message,
+    options::SocketOptions,
+    peer::{Origin, Peer},
+    socket::SocketType,
+    sockets::SocketError,
+    timer, ZmtpSocket,
+};
+use futures::{
+    future,
+    lock::{Mutex, MutexGuard},
+    pin_mut, poll,
+};
+use std::{
+    sync::Mutex as StdMutex,
+    task::{Context as TaskContext, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+// The core of the thread-safe socket types, which can be used through a
+// shared reference from several tasks at once.
+//
+// The peers sit behind an async lock, but nothing holds the lock while
+// waiting for a peer. Instead, each operation takes the lock, polls the peers
+// once, and lets go of it again if none of them were ready. Peers only
+// remember the last task that polled them, so every task that's waiting is
+// also remembered here, and all of them are woken to poll again whenever one
+// of them gets anywhere.
+#[derive(Debug)]
+pub(crate) struct SharedSocket<P> {
+    socket: Mutex<ZmtpSocket<P>>,
+    waiters: StdMutex<Vec<Waker>>,
+}
+
+impl<P: Peer> SharedSocket<P> {
+    pub(crate) fn new(socket_type: SocketType) -> SharedSocket<P> {
+        SharedSocket {
+            socket: Mutex::new(ZmtpSocket::new(socket_type)),
+            waiters: StdMutex::new(Vec::new()),
+        }
+    }
+
+    // Closes the peers, giving them up to the linger period to finish.
+    pub(crate) async fn close(self) {
+        self.socket.into_inner().close().await
+    }
+
+    pub(crate) fn set_context(&mut self, context: &Context) {
+        self.socket.get_mut().set_context(context)
+    }
+
+    pub(crate) fn set_options(&mut self, options: &SocketOptions) {
+        self.socket.get_mut().set_options(options)
+    }
+
+    // For operations that never wait on a peer.
+    pub(crate) async fn lock(&self) -> MutexGuard<'_, ZmtpSocket<P>> {
+        self.socket.lock().await
+    }
+
+    // Closes and drops the peers reached through `origin`, and returns how
+    // many there were. Waiting tasks are woken, in case none are left.
+    pub(crate) async fn detach(&self, origin: &Origin) -> usize {
+        let detached = self.socket.lock().await.detach(origin).await.len();
+        self.wake_waiters();
+        detached
+    }
+
+    // Writes out what the peers are holding back, and returns the ones that
+    // failed and were dropped. Waiting tasks are woken in case that drops the
+    // last peer.
+    pub(crate) async fn flush(&self) -> Vec<P> {
+        let dropped = self.socket.lock().await.flush().await;
+        self.wake_waiters();
+        dropped
+    }
+
+    // Sends to the peer that `pick` returns the index of once it's ready.
+    pub(crate) async fn send<F>(&self, msg: Message, mut pick: F) -> Result<(), SocketError>
+    where
+        F: FnMut(&mut ZmtpSocket<P>, &mut TaskContext<'_>) -> Poll<Result<usize, SocketError>>,
+    {
+        let deadline = deadline(self.socket.lock().await.send_timeout());
+        loop {
+            let mut socket = self.socket.lock().await;
+            let picked = future::poll_fn(|cx| Poll::Ready(pick(&mut socket, cx))).await;
+            match picked {
+                Poll::Ready(Ok(idx)) => {
+                    let result = socket.send_to(idx, msg).await;
+                    self.wake_waiters();
+                    return result;
+                }
+                Poll::Ready(Err(err)) => {
+                    // Other tasks may be waiting to find out the same thing,
+                    // e.g. that the context was terminated.
+                    self.wake_waiters();
+                    return Err(err);
+                }
+                Poll::Pending => {
+                    drop(socket);
+                    self.wait_until(deadline).await?;
+                }
+            }
+        }
+    }
+
+    // Receives the next message from any peer, fair-queued, and passes it to
+    // `accept` along with the peer it came from.
+    pub(crate) async fn recv<F, T>(&self, accept: F) -> Result<T, SocketError>
+    where
+        F: FnMut(&P, Message) -> T,
+    {
+        self.recv_announced(|| None, accept, |_| None).await
+    }
+
+    // Like `recv`, for sockets that announce changes to their peers among
+    // what they receive. `pending` is asked for an announcement before each
+    // attempt to receive, so that tasks woken by `wake_waiters` find the ones
+    // made in the meantime. A peer that fails is dropped and passed to
+    // `dropped`, and the announcement it returns, if any, is received.
+    pub(crate) async fn recv_announced<F, G, H, T>(
+        &self,
+        mut pending: H,
+        mut accept: F,
+        mut dropped: G,
+    ) -> Result<T, SocketError>
+    where
+        F: FnMut(&P, Message) -> T,
+        G: FnMut(P) -> Option<T>,
+        H: FnMut() -> Option<T>,
+    {
+        let deadline = deadline(self.socket.lock().await.recv_timeout());
+        loop {
+            if let Some(announced) = pending() {
+                return Ok(announced);
+            }
+            let mut socket = self.socket.lock().await;
+            let polled = {
+                let recv = socket.recv_next();
+                pin_mut!(recv);
+                poll!(recv)
+            };
+            match polled {
+                Poll::Ready(Ok((idx, Ok(msg)))) => {
+                    self.wake_waiters();
+                    let msg = socket.conflated(idx, msg);
+                    return Ok(accept(&socket.connections()[idx], msg));
+                }
+                Poll::Ready(Ok((idx, Err(err)))) => {
+                    let peer = socket.remove(idx, err.close_reason());
+                    drop(socket);
+                    self.wake_waiters();
+                    if let Some(announced) = dropped(peer) {
+                        return Ok(announced);
+                    }
+                }
+                Poll::Ready(Err(err)) => {
+                    self.wake_waiters();
+                    return Err(err);
+                }
+                Poll::Pending => {
+                    drop(socket);
+                    self.wait_until(deadline).await?;
+                }
+            }
+        }
+    }
+
+    // Returns after this task has been woken, which the peers it just polled
+    // will do when they're ready, as will other tasks that got a turn.
+    async fn wait(&self) {
+        let mut waited = false;
+        future::poll_fn(|cx| {
+            if waited {
+                return Poll::Ready(());
+            }
+            waited = true;
+
+            let mut waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+            if !waiters.iter().any(|waiter| waiter.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    // Like `wait`, but fails with `SocketError::Timeout` once `deadline` has
+    // passed.
+    async fn wait_until(&self, deadline: Option<Instant>) -> Result<(), SocketError> {
+        let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        Ok(timer::timeout(left, self.wait()).await?)
+    }
+
+    // Wakes every waiting task to poll again.
+    pub(crate) fn wake_waiters(&self) {
+        let waiters = std::mem::take(&mut *self.waiters.lock().unwrap_or_else(|e| e.into_inner()));
+        waiters.into_iter().for_each(Waker::wake);
+    }
+}
+
+// When an operation that can wait up to `timeout` has to give up, if it
+// started now. The whole operation counts, however many turns it takes.
+fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.map(|timeout| Instant::now() + timeout)
+}
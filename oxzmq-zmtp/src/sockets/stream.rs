@@ -0,0 +1,154 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::{CloseReason, Peer},
+    socket::SocketType,
+    sockets::{
+        peer_table::PeerTable,
+        router::{self, notification, RoutedPeer},
+        SocketError,
+    },
+    ZmtpSocket,
+};
+use std::collections::VecDeque;
+
+/// A STREAM socket talks to peers that don't speak ZMTP, usually through
+/// `RawPeer`, which lets it front HTTP or other plain TCP protocols.
+///
+/// Like a ROUTER socket, it prefixes each received message with the routing
+/// ID of the peer it came from, and expects each sent message to start with
+/// the routing ID of the peer it should go to. A message with nothing but a
+/// routing ID is received when a peer connects and again when it
+/// disconnects. Sending a message with nothing after the routing ID closes
+/// the connection.
+#[derive(Debug, Clone)]
+pub struct Stream<P> {
+    socket: ZmtpSocket<RoutedPeer<P>>,
+    peers: PeerTable,
+    next_routing_id: u32,
+    // Connection notifications that haven't been received yet.
+    notifications: VecDeque<Message>,
+}
+
+impl<P: Peer> Stream<P> {
+    pub fn new() -> Stream<P> {
+        Stream {
+            socket: ZmtpSocket::new(SocketType::Stream),
+            peers: PeerTable::new(),
+            next_routing_id: router::first_routing_id(),
+            notifications: VecDeque::new(),
+        }
+    }
+
+    socket_methods!(options, close, detach, pause_recv);
+
+    /// Attaches a peer and returns the routing ID it was given. Raw peers
+    /// can't pick their own.
+    pub fn attach(&mut self, peer: P) -> Result<Vec<u8>, SocketError> {
+        let routing_id = router::generate_routing_id(&mut self.next_routing_id);
+        self.socket.attach(RoutedPeer {
+            routing_id: routing_id.clone(),
+            peer,
+        })?;
+        let idx = self.socket.connections().len() - 1;
+        self.peers
+            .insert(self.socket.connections(), routing_id.clone(), idx);
+
+        self.notifications
+            .push_back(notification(routing_id.clone()));
+        Ok(routing_id)
+    }
+
+    /// Writes everything after the routing ID to the peer it names.
+    pub async fn send(&mut self, mut msg: Message) -> Result<(), SocketError> {
+        let routing_id = msg.pop_front().ok_or(SocketError::MissingRoutingId)?;
+        let idx = self
+            .peers
+            .find(self.socket.connections(), &routing_id)
+            .ok_or_else(|| SocketError::HostUnreachable(routing_id.clone()))?;
+
+        if msg.parts().iter().all(Vec::is_empty) {
+            let mut routed = self.socket.remove(idx, CloseReason::Dropped);
+            routed.close().await?;
+            return Ok(());
+        }
+
+        self.socket.send_to(idx, msg).await
+    }
+
+    /// Receives the next data from a peer, or the next notification that a
+    /// peer connected or disconnected.
+    pub async fn recv(&mut self) -> Result<Message, SocketError> {
+        if let Some(msg) = self.notifications.pop_front() {
+            return Ok(msg);
+        }
+
+        match self.socket.recv_next().await? {
+            (idx, Ok(mut msg)) => {
+                msg.push_front(self.socket.connections()[idx].routing_id.clone());
+                Ok(msg)
+            }
+            (idx, Err(err)) => {
+                let routed = self.socket.remove(idx, err.close_reason());
+                Ok(notification(routed.routing_id))
+            }
+        }
+    }
+}
+
+impl<P: Peer> Default for Stream<P> {
+    fn default() -> Stream<P> {
+        Stream::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::ChannelPeer;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_notifications_and_data() {
+        block_on(async {
+            let mut stream = Stream::new();
+            let (local, mut remote) = ChannelPeer::pair(SocketType::Stream, SocketType::Stream);
+            let routing_id = stream.attach(local).unwrap();
+
+            let connected = stream.recv().await.unwrap();
+            assert_eq!(connected, notification(routing_id.clone()));
+
+            remote
+                .send_message(Message::from(&b"hello"[..]))
+                .await
+                .unwrap();
+            assert_eq!(
+                stream.recv().await.unwrap(),
+                Message::from(vec![routing_id.clone(), b"hello".to_vec()])
+            );
+
+            stream
+                .send(Message::from(vec![routing_id.clone(), b"hi".to_vec()]))
+                .await
+                .unwrap();
+            assert_eq!(
+                remote.recv_message().await.unwrap(),
+                Message::from(&b"hi"[..])
+            );
+
+            assert!(matches!(
+                stream
+                    .send(Message::from(vec![b"nobody".to_vec(), b"hi".to_vec()]))
+                    .await,
+                Err(SocketError::HostUnreachable(_))
+            ));
+
+            drop(remote);
+            assert_eq!(stream.recv().await.unwrap(), notification(routing_id));
+            assert!(matches!(stream.recv().await, Err(SocketError::NoPeers)));
+        });
+    }
+}
@@ -0,0 +1,194 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    message::Message,
+    peer::Peer,
+    socket::SocketType,
+    sockets::{subscription::Subscription, xsubscribe::XSub, SocketError},
+};
+
+/// A SUB socket receives messages from its peers whose first part starts
+/// with one of its subscribed topics. It starts out subscribed to nothing.
+///
+/// Subscriptions are reference counted, so several consumers sharing a
+/// socket can each subscribe to the same topic. Publishers are only told
+/// about a topic when it's first subscribed to, and only told to cancel it
+/// when the last subscriber unsubscribes. Publishers speaking ZMTP 3.1 are
+/// told with SUBSCRIBE and CANCEL commands, and older ones with subscription
+/// messages.
+#[derive(Debug, Clone)]
+pub struct Sub<P> {
+    socket: XSub<P>,
+}
+
+impl<P: Peer> Sub<P> {
+    pub fn new() -> Sub<P> {
+        Sub {
+            socket: XSub::with_socket_type(SocketType::Sub),
+        }
+    }
+
+    socket_methods!(options, close, detach, pause_recv);
+
+    /// Attaches a peer and sends it all of our current subscriptions.
+    pub async fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        self.socket.attach(peer).await
+    }
+
+    pub async fn subscribe(&mut self, topic: &[u8]) -> Result<(), SocketError> {
+        let sub = Subscription::Subscribe(topic.to_vec());
+        self.socket.send(sub.to_message()).await
+    }
+
+    /// Drops one subscription to the topic. Unsubscribing from a topic that
+    /// isn't subscribed to does nothing.
+    pub async fn unsubscribe(&mut self, topic: &[u8]) -> Result<(), SocketError> {
+        let cancel = Subscription::Cancel(topic.to_vec());
+        self.socket.send(cancel.to_message()).await
+    }
+
+    /// The number of outstanding subscriptions to exactly this topic.
+    pub fn subscribers(&self, topic: &[u8]) -> usize {
+        self.socket.subscribers(topic)
+    }
+
+    /// Keeps only the newest message each peer has waiting, skipping the
+    /// older ones, for feeds where only the latest value matters. Only
+    /// messages that have arrived in full are skipped; for peers over TCP,
+    /// those are the rest of a batch.
+    pub fn set_conflate(&mut self, conflate: bool) {
+        self.socket.set_conflate(conflate)
+    }
+
+    pub async fn recv(&mut self) -> Result<Message, SocketError> {
+        self.socket.recv().await
+    }
+}
+
+impl<P: Peer> Default for Sub<P> {
+    fn default() -> Sub<P> {
+        Sub::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        frame::FrameDirection, options::ConnectionOptions, peer::ChannelPeer, testing, Connection,
+        Version,
+    };
+    use futures::{executor::block_on, FutureExt};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_subscriptions_sent_to_peers() {
+        block_on(async {
+            let mut sub = Sub::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::Sub, SocketType::Pub);
+            sub.attach(local_a).await.unwrap();
+            sub.subscribe(b"topic").await.unwrap();
+
+            // Peers attached later get the existing subscriptions.
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::Sub, SocketType::Pub);
+            sub.attach(local_b).await.unwrap();
+            sub.unsubscribe(b"topic").await.unwrap();
+
+            let expected = [
+                Subscription::Subscribe(b"topic".to_vec()),
+                Subscription::Cancel(b"topic".to_vec()),
+            ];
+            for remote in [&mut remote_a, &mut remote_b].iter_mut() {
+                for sub in expected.iter() {
+                    let msg = remote.recv_message().await.unwrap();
+                    assert_eq!(Subscription::parse(&msg).as_ref(), Some(sub));
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_shared_subscriptions() {
+        block_on(async {
+            let mut sub = Sub::new();
+            let (local, mut remote) = ChannelPeer::pair(SocketType::Sub, SocketType::Pub);
+            sub.attach(local).await.unwrap();
+
+            sub.subscribe(b"topic").await.unwrap();
+            sub.subscribe(b"topic").await.unwrap();
+            assert_eq!(sub.subscribers(b"topic"), 2);
+            sub.unsubscribe(b"topic").await.unwrap();
+            sub.unsubscribe(b"never subscribed").await.unwrap();
+            sub.unsubscribe(b"topic").await.unwrap();
+            assert_eq!(sub.subscribers(b"topic"), 0);
+
+            let expected = [
+                Subscription::Subscribe(b"topic".to_vec()),
+                Subscription::Cancel(b"topic".to_vec()),
+            ];
+            for sub in expected.iter() {
+                let msg = remote.recv_message().await.unwrap();
+                assert_eq!(Subscription::parse(&msg).as_ref(), Some(sub));
+            }
+            assert!(remote.recv_message().now_or_never().is_none());
+        });
+    }
+
+    #[test]
+    fn test_recv_filters_locally() {
+        block_on(async {
+            let mut sub = Sub::new();
+            let (local, mut remote) = ChannelPeer::pair(SocketType::Sub, SocketType::XPub);
+            sub.attach(local).await.unwrap();
+            sub.subscribe(b"yes").await.unwrap();
+
+            remote
+                .send_message(Message::from(&b"no thanks"[..]))
+                .await
+                .unwrap();
+            remote
+                .send_message(Message::from(&b"yes please"[..]))
+                .await
+                .unwrap();
+            assert_eq!(sub.recv().await.unwrap(), Message::from(&b"yes please"[..]));
+        });
+    }
+
+    #[test]
+    fn test_subscription_commands() {
+        block_on(async {
+            // ZMTP 3.1 has commands for subscriptions, which 3.0 doesn't.
+            let cases = [
+                (Version::new(3, 1), &b"\x04\x0e\x09SUBSCRIBEnews"[..]),
+                (Version::new(3, 0), &b"\x00\x05\x01news"[..]),
+            ];
+            for &(version, expected) in cases.iter() {
+                let (a, b) = testing::duplex();
+                let options = ConnectionOptions::new().max_version(version);
+                let (local, remote) = futures::join!(
+                    Connection::with_options(a, &SocketType::Sub, &options),
+                    Connection::new(b, &SocketType::Pub),
+                );
+                let mut remote = remote.unwrap();
+                let received = Arc::new(Mutex::new(Vec::new()));
+                let frames = received.clone();
+                remote.set_frame_hook(move |direction, frame| {
+                    assert_eq!(direction, FrameDirection::Received);
+                    frames.lock().unwrap().push(frame.to_vec());
+                });
+
+                let mut sub = Sub::new();
+                sub.attach(local.unwrap()).await.unwrap();
+                sub.subscribe(b"news").await.unwrap();
+
+                // Either way, the publisher sees a subscription message.
+                let msg = remote.recv_message().await.unwrap();
+                let expected_sub = Subscription::Subscribe(b"news".to_vec());
+                assert_eq!(Subscription::parse(&msg), Some(expected_sub));
+                assert_eq!(*received.lock().unwrap(), vec![expected.to_vec()]);
+            }
+        });
+    }
+}
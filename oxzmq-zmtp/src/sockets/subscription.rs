@@ -0,0 +1,186 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    command::ZmtpCommand,
+    heartbeat::Liveness,
+    message::Message,
+    peer::{Origin, Peer, PeerError},
+    socket::SocketType,
+    sockets::trie::Trie,
+};
+use std::task::{Context, Poll};
+
+const SUBSCRIBE_BYTE: u8 = 0x01;
+const CANCEL_BYTE: u8 = 0x00;
+
+// More info: https://rfc.zeromq.org/spec/29/
+//
+// Subscriptions travel from SUB to PUB as single-part messages whose first
+// byte says whether to add or remove the topic in the rest of the message.
+// ZMTP 3.1 sends them as SUBSCRIBE and CANCEL commands instead, which
+// connections turn into the same messages, so that sockets only ever see
+// those.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Subscription {
+    Subscribe(Vec<u8>),
+    Cancel(Vec<u8>),
+}
+
+impl Subscription {
+    // Returns `None` for anything that isn't a subscription message, which
+    // subscribers are allowed to send and publishers must ignore.
+    pub(crate) fn parse(msg: &Message) -> Option<Subscription> {
+        let part = match msg.parts() {
+            [part] => part,
+            _ => return None,
+        };
+
+        match part.split_first() {
+            Some((&SUBSCRIBE_BYTE, topic)) => Some(Subscription::Subscribe(topic.to_vec())),
+            Some((&CANCEL_BYTE, topic)) => Some(Subscription::Cancel(topic.to_vec())),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_message(&self) -> Message {
+        let (first_byte, topic) = match self {
+            Subscription::Subscribe(topic) => (SUBSCRIBE_BYTE, topic),
+            Subscription::Cancel(topic) => (CANCEL_BYTE, topic),
+        };
+
+        let mut part = Vec::with_capacity(1 + topic.len());
+        part.push(first_byte);
+        part.extend_from_slice(topic);
+        Message::from(part)
+    }
+
+    pub(crate) fn to_command(&self) -> ZmtpCommand {
+        match self {
+            Subscription::Subscribe(topic) => ZmtpCommand::Subscribe(topic.clone()),
+            Subscription::Cancel(topic) => ZmtpCommand::Cancel(topic.clone()),
+        }
+    }
+
+    // Applies the subscription to a set of topics. Returns whether the topic
+    // was subscribed to before a cancellation, which is always the case for
+    // subscriptions.
+    pub(crate) fn apply(self, topics: &mut Trie) -> bool {
+        match self {
+            Subscription::Subscribe(topic) => {
+                topics.add(&topic);
+                true
+            }
+            Subscription::Cancel(topic) => topics.remove(&topic),
+        }
+    }
+}
+
+// A message matches if any topic is a prefix of its first part.
+pub(crate) fn matches(topics: &Trie, msg: &Message) -> bool {
+    let first_part = msg.parts().first().map(Vec::as_slice).unwrap_or(&[]);
+    topics.matches(first_part)
+}
+
+// A peer on the publishing side, along with the topics it subscribed to.
+#[derive(Debug, Clone)]
+pub(crate) struct SubscribedPeer<P> {
+    pub(crate) topics: Trie,
+    pub(crate) peer: P,
+}
+
+impl<P: Peer> Peer for SubscribedPeer<P> {
+    fn remote_socket_type(&self) -> SocketType {
+        self.peer.remote_socket_type()
+    }
+
+    fn routing_id(&self) -> Option<&[u8]> {
+        self.peer.routing_id()
+    }
+
+    fn origin(&self) -> Option<&Origin> {
+        self.peer.origin()
+    }
+
+    fn weight(&self) -> Option<u32> {
+        self.peer.weight()
+    }
+
+    async fn send_message(&mut self, msg: Message) -> Result<(), PeerError> {
+        self.peer.send_message(msg).await
+    }
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), PeerError>> {
+        self.peer.poll_ready(cx)
+    }
+
+    async fn send_batch(&mut self, msgs: Vec<Message>) -> Result<(), PeerError> {
+        self.peer.send_batch(msgs).await
+    }
+
+    async fn recv_message(&mut self) -> Result<Message, PeerError> {
+        self.peer.recv_message().await
+    }
+
+    fn try_recv_message(&mut self) -> Option<Result<Message, PeerError>> {
+        self.peer.try_recv_message()
+    }
+
+    async fn send_heartbeat(&mut self) -> Result<(), PeerError> {
+        self.peer.send_heartbeat().await
+    }
+
+    fn liveness(&self) -> Option<&Liveness> {
+        self.peer.liveness()
+    }
+
+    async fn flush(&mut self) -> Result<(), PeerError> {
+        self.peer.flush().await
+    }
+
+    async fn close(&mut self) -> Result<(), PeerError> {
+        self.peer.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let sub = Subscription::Subscribe(b"weather".to_vec());
+        assert_eq!(sub.to_message().parts(), &[b"\x01weather".to_vec()]);
+        assert_eq!(Subscription::parse(&sub.to_message()), Some(sub));
+
+        let cancel = Subscription::Cancel(Vec::new());
+        assert_eq!(
+            Subscription::parse(&cancel.to_message()),
+            Some(cancel.clone())
+        );
+
+        assert_eq!(Subscription::parse(&Message::from(&b"\x02x"[..])), None);
+        assert_eq!(Subscription::parse(&Message::new()), None);
+
+        assert_eq!(cancel.to_command(), ZmtpCommand::Cancel(Vec::new()));
+    }
+
+    #[test]
+    fn test_prefix_matching() {
+        let mut topics = Trie::new();
+        Subscription::Subscribe(b"abc".to_vec()).apply(&mut topics);
+        Subscription::Subscribe(b"abc".to_vec()).apply(&mut topics);
+
+        assert!(matches(&topics, &Message::from(&b"abcdef"[..])));
+        assert!(!matches(&topics, &Message::from(&b"ab"[..])));
+
+        Subscription::Cancel(b"abc".to_vec()).apply(&mut topics);
+        assert!(matches(&topics, &Message::from(&b"abc"[..])));
+        Subscription::Cancel(b"abc".to_vec()).apply(&mut topics);
+        assert!(!matches(&topics, &Message::from(&b"abc"[..])));
+
+        Subscription::Subscribe(Vec::new()).apply(&mut topics);
+        assert!(matches(&topics, &Message::new()));
+    }
+}
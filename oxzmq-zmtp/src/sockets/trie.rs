@@ -0,0 +1,283 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// A set of subscribed topics, counted so that subscribing twice to a topic
+// takes two cancellations to remove it, which can tell quickly whether any
+// of them is a prefix of a message.
+//
+// Each edge is labelled with as many bytes as there are between branches,
+// so the trie is only as deep as there are topics sharing a prefix, however
+// long the topics are. Matching a message only looks at as many edges as
+// its first part has bytes, whatever the number of topics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Trie {
+    // How many times the topic ending at this node was subscribed to.
+    count: usize,
+    children: Vec<Edge>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Edge {
+    // Never empty, and no two edges out of a node start with the same byte.
+    label: Vec<u8>,
+    node: Trie,
+}
+
+impl Trie {
+    pub(crate) fn new() -> Trie {
+        Trie::default()
+    }
+
+    /// Adds one subscription to `topic`. Returns whether it's the first.
+    pub(crate) fn add(&mut self, topic: &[u8]) -> bool {
+        let mut node = self;
+        let mut rest = topic;
+        while !rest.is_empty() {
+            let idx = match node.edge_starting_with(rest[0]) {
+                Some(idx) => idx,
+                None => {
+                    node.children.push(Edge {
+                        label: rest.to_vec(),
+                        node: Trie::new(),
+                    });
+                    node.children.len() - 1
+                }
+            };
+
+            // Topics that stop or branch partway along an edge split it.
+            let edge = &mut node.children[idx];
+            let common = common_prefix_len(&edge.label, rest);
+            if common < edge.label.len() {
+                let tail = edge.label.split_off(common);
+                let below = std::mem::take(&mut edge.node);
+                edge.node.children.push(Edge {
+                    label: tail,
+                    node: below,
+                });
+            }
+
+            node = &mut node.children[idx].node;
+            rest = &rest[common..];
+        }
+
+        node.count += 1;
+        node.count == 1
+    }
+
+    /// Removes one subscription to `topic`. Returns whether there was one.
+    pub(crate) fn remove(&mut self, topic: &[u8]) -> bool {
+        let path = match self.path_to(topic) {
+            Some(path) => path,
+            None => return false,
+        };
+        let node = self.node_at_mut(&path);
+        if node.count == 0 {
+            return false;
+        }
+        node.count -= 1;
+
+        // Drop the node if nothing's left below it, and merge away whichever
+        // of it and its parent now only lead on to one other node.
+        if let Some((&last, parents)) = path.split_last() {
+            self.node_at_mut(parents).compact(last);
+            if let Some((&parent, grandparents)) = parents.split_last() {
+                self.node_at_mut(grandparents).compact(parent);
+            }
+        }
+        true
+    }
+
+    /// Whether `topic` itself is subscribed to.
+    pub(crate) fn contains(&self, topic: &[u8]) -> bool {
+        self.count(topic) > 0
+    }
+
+    /// How many times `topic` itself is subscribed to.
+    pub(crate) fn count(&self, topic: &[u8]) -> usize {
+        match self.path_to(topic) {
+            Some(path) => self.node_at(&path).count,
+            None => 0,
+        }
+    }
+
+    /// Whether any subscribed topic is a prefix of `data`.
+    pub(crate) fn matches(&self, data: &[u8]) -> bool {
+        let mut node = self;
+        let mut rest = data;
+        loop {
+            if node.count > 0 {
+                return true;
+            }
+            let edge = match rest.first().and_then(|&b| node.edge_starting_with(b)) {
+                Some(idx) => &node.children[idx],
+                None => return false,
+            };
+            if !rest.starts_with(&edge.label) {
+                return false;
+            }
+            rest = &rest[edge.label.len()..];
+            node = &edge.node;
+        }
+    }
+
+    /// Every subscribed topic, along with how many times it's subscribed.
+    pub(crate) fn topics(&self) -> Vec<(Vec<u8>, usize)> {
+        let mut topics = Vec::new();
+        let mut stack = vec![(Vec::new(), self)];
+        while let Some((prefix, node)) = stack.pop() {
+            for edge in node.children.iter() {
+                let mut topic = prefix.clone();
+                topic.extend_from_slice(&edge.label);
+                stack.push((topic, &edge.node));
+            }
+            if node.count > 0 {
+                topics.push((prefix, node.count));
+            }
+        }
+        topics
+    }
+
+    fn edge_starting_with(&self, byte: u8) -> Option<usize> {
+        self.children.iter().position(|edge| edge.label[0] == byte)
+    }
+
+    // The edges to follow to the node for exactly `topic`, if there is one.
+    fn path_to(&self, topic: &[u8]) -> Option<Vec<usize>> {
+        let mut path = Vec::new();
+        let mut node = self;
+        let mut rest = topic;
+        while !rest.is_empty() {
+            let idx = node.edge_starting_with(rest[0])?;
+            let edge = &node.children[idx];
+            if !rest.starts_with(&edge.label) {
+                return None;
+            }
+            path.push(idx);
+            rest = &rest[edge.label.len()..];
+            node = &edge.node;
+        }
+        Some(path)
+    }
+
+    fn node_at(&self, path: &[usize]) -> &Trie {
+        path.iter()
+            .fold(self, |node, &idx| &node.children[idx].node)
+    }
+
+    fn node_at_mut(&mut self, path: &[usize]) -> &mut Trie {
+        let mut node = self;
+        for &idx in path {
+            node = &mut node.children[idx].node;
+        }
+        node
+    }
+
+    // Removes the child at `idx` if it's no longer needed, or merges it with
+    // its own child if that's the only place it leads.
+    fn compact(&mut self, idx: usize) {
+        let edge = &mut self.children[idx];
+        if edge.node.count > 0 {
+            return;
+        }
+        match edge.node.children.len() {
+            0 => {
+                self.children.swap_remove(idx);
+            }
+            1 => {
+                let below = edge.node.children.pop().unwrap();
+                edge.label.extend_from_slice(&below.label);
+                edge.node = below.node;
+            }
+            _ => (),
+        }
+    }
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::{Gen, CASES};
+
+    #[test]
+    fn test_prefixes() {
+        let mut trie = Trie::new();
+        assert!(!trie.matches(b""));
+        assert!(trie.add(b"weather.london"));
+        assert!(trie.add(b"weather.paris"));
+        assert!(!trie.add(b"weather.paris"));
+        assert!(trie.add(b"weather"));
+
+        assert!(trie.matches(b"weather.rome"));
+        assert!(!trie.matches(b"weathe"));
+        assert!(trie.contains(b"weather"));
+        assert!(!trie.contains(b"weather."));
+        assert_eq!(trie.count(b"weather.paris"), 2);
+
+        assert!(trie.remove(b"weather"));
+        assert!(!trie.matches(b"weather.rome"));
+        assert!(trie.matches(b"weather.paris, 20C"));
+        assert!(!trie.remove(b"weather"));
+        assert!(!trie.remove(b"weather.par"));
+
+        // Topics stay until they're cancelled as often as they were added.
+        assert!(trie.remove(b"weather.paris"));
+        assert!(trie.matches(b"weather.paris"));
+        assert!(trie.remove(b"weather.paris"));
+        assert!(!trie.matches(b"weather.paris"));
+        assert!(trie.remove(b"weather.london"));
+        assert_eq!(trie, Trie::new());
+
+        // The empty topic matches everything.
+        trie.add(b"");
+        assert!(trie.matches(b""));
+        assert!(trie.matches(b"anything"));
+        assert_eq!(trie.topics(), vec![(Vec::new(), 1)]);
+    }
+
+    // The trie has to agree with checking every topic one by one, and go
+    // back to being empty once every topic is removed.
+    #[test]
+    fn test_against_list() {
+        const BYTES: &[u8] = b"abc";
+        for case in 0..CASES {
+            let mut gen = Gen::new(case);
+            let mut trie = Trie::new();
+            let mut list: Vec<Vec<u8>> = Vec::new();
+            for _ in 0..gen.up_to(30) {
+                let topic = gen.string(BYTES, 5).into_bytes();
+                if gen.bool() {
+                    assert_eq!(trie.add(&topic), !list.contains(&topic), "case {}", case);
+                    list.push(topic);
+                } else {
+                    let idx = list.iter().position(|t| *t == topic);
+                    assert_eq!(trie.remove(&topic), idx.is_some(), "case {}", case);
+                    if let Some(idx) = idx {
+                        list.swap_remove(idx);
+                    }
+                }
+
+                let data = gen.string(BYTES, 6).into_bytes();
+                let expected = list.iter().any(|topic| data.starts_with(topic));
+                assert_eq!(trie.matches(&data), expected, "case {}", case);
+            }
+
+            let mut topics: Vec<_> = trie
+                .topics()
+                .into_iter()
+                .flat_map(|(topic, count)| std::iter::repeat_n(topic, count))
+                .collect();
+            topics.sort();
+            list.sort();
+            assert_eq!(topics, list, "case {}", case);
+            for topic in list {
+                assert!(trie.remove(&topic), "case {}", case);
+            }
+            assert_eq!(trie, Trie::new(), "case {}", case);
+        }
+    }
+}
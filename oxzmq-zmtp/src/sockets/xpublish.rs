@@ -0,0 +1,305 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    context::Context,
+    message::Message,
+    options::SocketOptions,
+    peer::Peer,
+    socket::SocketType,
+    sockets::{
+        subscription::{self, SubscribedPeer, Subscription},
+        trie::Trie,
+        HwmPolicy, SocketError,
+    },
+    ZmtpSocket,
+};
+use futures::FutureExt;
+use std::collections::VecDeque;
+
+/// An XPUB socket is a PUB socket that also hands subscriptions to the
+/// application as messages, which is what a proxy needs to forward them
+/// upstream. Each topic is only passed on when the first peer subscribes to
+/// it and when the last peer cancels it (or disconnects).
+#[derive(Debug, Clone)]
+pub struct XPub<P> {
+    socket: ZmtpSocket<SubscribedPeer<P>>,
+    // Topics that we've told the application about, so that it can tell the
+    // publishers upstream.
+    upstream: Vec<Vec<u8>>,
+    // Messages waiting to be received. PUB sockets never receive, so they
+    // don't queue anything here.
+    incoming: VecDeque<Message>,
+    keep_incoming: bool,
+    // Whether to pass on subscriptions and cancellations that don't change
+    // the set of topics anyone is subscribed to.
+    verbose_subscribe: bool,
+    verbose_cancel: bool,
+}
+
+impl<P: Peer> XPub<P> {
+    pub fn new() -> XPub<P> {
+        XPub::with_socket_type(SocketType::XPub)
+    }
+
+    socket_methods!(options, close, detach, flush);
+
+    pub(crate) fn with_socket_type(socket_type: SocketType) -> XPub<P> {
+        XPub {
+            socket: ZmtpSocket::new(socket_type),
+            upstream: Vec::new(),
+            incoming: VecDeque::new(),
+            keep_incoming: socket_type == SocketType::XPub,
+            verbose_subscribe: false,
+            verbose_cancel: false,
+        }
+    }
+
+    pub(crate) fn set_context(&mut self, context: &Context) {
+        self.socket.set_context(context)
+    }
+
+    pub(crate) fn set_options(&mut self, options: &SocketOptions) {
+        self.socket.set_options(options)
+    }
+
+    /// Passes on every subscription, not just the first one to each topic.
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose_subscribe = verbose;
+        self.verbose_cancel = false;
+    }
+
+    /// Passes on every subscription and cancellation, which is what a broker
+    /// needs to keep track of each subscriber. Cancellations for topics the
+    /// peer wasn't subscribed to are still dropped.
+    pub fn set_verboser(&mut self, verboser: bool) {
+        self.verbose_subscribe = verboser;
+        self.verbose_cancel = verboser;
+    }
+
+    pub fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        self.socket.attach(SubscribedPeer {
+            topics: Trie::new(),
+            peer,
+        })
+    }
+
+    /// What `send` does when subscribed peers can't take another message:
+    /// drop it for those peers (the default), wait for them or fail.
+    pub fn set_hwm_policy(&mut self, policy: HwmPolicy) {
+        self.socket.set_hwm_policy(policy)
+    }
+
+    /// Sends the message to all subscribed peers. Having no subscribed peers
+    /// isn't an error; the message is simply dropped, as are peers that fail.
+    pub async fn send(&mut self, msg: Message) -> Result<(), SocketError> {
+        self.process_incoming();
+        self.socket
+            .send_filtered(&msg, |subscribed| {
+                subscription::matches(&subscribed.topics, &msg)
+            })
+            .await
+    }
+
+    /// Stops reading subscriptions and other messages from peers until
+    /// `resume_recv` is called. Messages sent in the meantime only reach
+    /// the peers that were subscribed before the pause. `recv` waits.
+    pub fn pause_recv(&mut self) {
+        self.socket.pause_recv()
+    }
+
+    pub fn resume_recv(&mut self) {
+        self.socket.resume_recv()
+    }
+
+    /// Receives the next subscription or other message sent by a peer.
+    pub async fn recv(&mut self) -> Result<Message, SocketError> {
+        loop {
+            self.process_incoming();
+            if let Some(msg) = self.incoming.pop_front() {
+                return Ok(msg);
+            }
+
+            let (idx, msg) = self.socket.recv_fair().await?;
+            self.accept(idx, msg);
+        }
+    }
+
+    // Takes in every message that peers have already sent, without waiting
+    // for more.
+    fn process_incoming(&mut self) {
+        if self.socket.recv_paused() {
+            return;
+        }
+
+        for idx in (0..self.socket.connections().len()).rev() {
+            loop {
+                let subscribed = &mut self.socket.connections_mut()[idx];
+                match subscribed.peer.recv_message().now_or_never() {
+                    Some(Ok(msg)) => self.accept(idx, msg),
+                    Some(Err(err)) => {
+                        self.socket.remove(idx, err.close_reason());
+                        break;
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        // Peers may have gone away, taking their subscriptions with them.
+        let mut idx = 0;
+        while idx < self.upstream.len() {
+            if self.is_subscribed(&self.upstream[idx]) {
+                idx += 1;
+            } else {
+                let topic = self.upstream.swap_remove(idx);
+                self.queue(Subscription::Cancel(topic).to_message());
+            }
+        }
+    }
+
+    fn accept(&mut self, idx: usize, msg: Message) {
+        let sub = match Subscription::parse(&msg) {
+            Some(sub) => sub,
+            None => {
+                self.queue(msg);
+                return;
+            }
+        };
+
+        let topics = &mut self.socket.connections_mut()[idx].topics;
+        let was_subscribed = sub.clone().apply(topics);
+
+        match sub {
+            Subscription::Subscribe(topic) => {
+                if !self.upstream.contains(&topic) {
+                    self.upstream.push(topic);
+                    self.queue(msg);
+                } else if self.verbose_subscribe {
+                    self.queue(msg);
+                }
+            }
+            Subscription::Cancel(topic) => {
+                let upstream_idx = self.upstream.iter().position(|t| *t == topic);
+                match upstream_idx {
+                    Some(upstream_idx) if !self.is_subscribed(&topic) => {
+                        self.upstream.swap_remove(upstream_idx);
+                        self.queue(msg);
+                    }
+                    _ if self.verbose_cancel && was_subscribed => self.queue(msg),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    fn is_subscribed(&self, topic: &[u8]) -> bool {
+        self.socket
+            .connections()
+            .iter()
+            .any(|subscribed| subscribed.topics.contains(topic))
+    }
+
+    fn queue(&mut self, msg: Message) {
+        if self.keep_incoming {
+            self.incoming.push_back(msg);
+        }
+    }
+}
+
+impl<P: Peer> Default for XPub<P> {
+    fn default() -> XPub<P> {
+        XPub::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::ChannelPeer;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_subscriptions_deduplicated() {
+        block_on(async {
+            let mut xpub = XPub::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::XPub, SocketType::Sub);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::XPub, SocketType::XSub);
+            xpub.attach(local_a).unwrap();
+            xpub.attach(local_b).unwrap();
+
+            let sub = Subscription::Subscribe(b"x".to_vec()).to_message();
+            let cancel = Subscription::Cancel(b"x".to_vec()).to_message();
+
+            remote_a.send_message(sub.clone()).await.unwrap();
+            assert_eq!(xpub.recv().await.unwrap(), sub);
+
+            remote_b.send_message(sub.clone()).await.unwrap();
+            remote_a.send_message(cancel.clone()).await.unwrap();
+            remote_b
+                .send_message(Message::from(&b"upstream"[..]))
+                .await
+                .unwrap();
+            assert_eq!(xpub.recv().await.unwrap(), Message::from(&b"upstream"[..]));
+
+            remote_b.send_message(cancel.clone()).await.unwrap();
+            assert_eq!(xpub.recv().await.unwrap(), cancel);
+        });
+    }
+
+    #[test]
+    fn test_verbose() {
+        block_on(async {
+            let mut xpub = XPub::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::XPub, SocketType::Sub);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::XPub, SocketType::Sub);
+            xpub.attach(local_a).unwrap();
+            xpub.attach(local_b).unwrap();
+
+            let sub = Subscription::Subscribe(b"x".to_vec()).to_message();
+            let cancel = Subscription::Cancel(b"x".to_vec()).to_message();
+            let marker = Message::from(&b"marker"[..]);
+
+            xpub.set_verbose(true);
+            remote_a.send_message(sub.clone()).await.unwrap();
+            assert_eq!(xpub.recv().await.unwrap(), sub);
+            remote_b.send_message(sub.clone()).await.unwrap();
+            remote_b.send_message(cancel.clone()).await.unwrap();
+            remote_b.send_message(marker.clone()).await.unwrap();
+            assert_eq!(xpub.recv().await.unwrap(), sub);
+            assert_eq!(xpub.recv().await.unwrap(), marker);
+
+            xpub.set_verboser(true);
+            remote_b.send_message(sub.clone()).await.unwrap();
+            remote_b.send_message(cancel.clone()).await.unwrap();
+            // Not subscribed anymore, so there's nothing to cancel.
+            remote_b.send_message(cancel.clone()).await.unwrap();
+            remote_b.send_message(marker.clone()).await.unwrap();
+            assert_eq!(xpub.recv().await.unwrap(), sub);
+            assert_eq!(xpub.recv().await.unwrap(), cancel);
+            assert_eq!(xpub.recv().await.unwrap(), marker);
+        });
+    }
+
+    #[test]
+    fn test_disconnect_cancels_subscriptions() {
+        block_on(async {
+            let mut xpub = XPub::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::XPub, SocketType::Sub);
+            let (local_b, _remote_b) = ChannelPeer::pair(SocketType::XPub, SocketType::Sub);
+            xpub.attach(local_a).unwrap();
+            xpub.attach(local_b).unwrap();
+
+            let sub = Subscription::Subscribe(b"x".to_vec()).to_message();
+            remote_a.send_message(sub.clone()).await.unwrap();
+            assert_eq!(xpub.recv().await.unwrap(), sub);
+
+            drop(remote_a);
+            assert_eq!(
+                xpub.recv().await.unwrap(),
+                Subscription::Cancel(b"x".to_vec()).to_message()
+            );
+        });
+    }
+}
@@ -0,0 +1,190 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    context::Context,
+    message::Message,
+    options::SocketOptions,
+    peer::Peer,
+    socket::SocketType,
+    sockets::{
+        subscription::{self, Subscription},
+        trie::Trie,
+        SocketError,
+    },
+    ZmtpSocket,
+};
+
+/// An XSUB socket is a SUB socket whose subscriptions are made by sending
+/// subscription messages, which is what a proxy needs to pass along the
+/// subscriptions it receives from an XPUB socket. Every other message sent
+/// is forwarded to all peers.
+///
+/// Subscriptions are reference counted, as they are in libzmq, so that a
+/// proxy passing along the same topic for several subscribers only tells
+/// the publishers about it once. Peers hear about a topic when it's first
+/// subscribed to, and are told to cancel it when the last subscription to
+/// it is cancelled.
+#[derive(Debug, Clone)]
+pub struct XSub<P> {
+    socket: ZmtpSocket<P>,
+    topics: Trie,
+}
+
+impl<P: Peer> XSub<P> {
+    pub fn new() -> XSub<P> {
+        XSub::with_socket_type(SocketType::XSub)
+    }
+
+    socket_methods!(options, close, detach, pause_recv);
+
+    pub(crate) fn with_socket_type(socket_type: SocketType) -> XSub<P> {
+        XSub {
+            socket: ZmtpSocket::new(socket_type),
+            topics: Trie::new(),
+        }
+    }
+
+    pub(crate) fn set_context(&mut self, context: &Context) {
+        self.socket.set_context(context)
+    }
+
+    pub(crate) fn set_options(&mut self, options: &SocketOptions) {
+        self.socket.set_options(options)
+    }
+
+    /// Attaches a peer and sends it all of our current subscriptions.
+    pub async fn attach(&mut self, peer: P) -> Result<(), SocketError> {
+        self.socket.attach(peer)?;
+
+        let idx = self.socket.connections().len() - 1;
+        for (topic, _) in self.topics.topics() {
+            let sub = Subscription::Subscribe(topic);
+            self.socket.send_to(idx, sub.to_message()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the message to all peers. Subscription messages also change
+    /// which messages this socket receives, and are only sent on if they
+    /// change which topics are subscribed to.
+    pub async fn send(&mut self, msg: Message) -> Result<(), SocketError> {
+        let changed = match Subscription::parse(&msg) {
+            Some(Subscription::Subscribe(topic)) => self.topics.add(&topic),
+            Some(Subscription::Cancel(topic)) => {
+                self.topics.remove(&topic) && !self.topics.contains(&topic)
+            }
+            None => true,
+        };
+        if !changed {
+            return Ok(());
+        }
+
+        self.socket.send_filtered(&msg, |_| true).await
+    }
+
+    /// The number of outstanding subscriptions to exactly this topic.
+    pub fn subscribers(&self, topic: &[u8]) -> usize {
+        self.topics.count(topic)
+    }
+
+    /// Keeps only the newest message each peer has waiting, skipping the
+    /// older ones, for feeds where only the latest value matters. Only
+    /// messages that have arrived in full are skipped; for peers over TCP,
+    /// those are the rest of a batch.
+    pub fn set_conflate(&mut self, conflate: bool) {
+        self.socket.set_conflate(conflate)
+    }
+
+    pub async fn recv(&mut self) -> Result<Message, SocketError> {
+        // Publishers filter too, but they may not have seen our latest
+        // subscriptions yet.
+        loop {
+            let (_, msg) = self.socket.recv_fair().await?;
+            if subscription::matches(&self.topics, &msg) {
+                return Ok(msg);
+            }
+        }
+    }
+}
+
+impl<P: Peer> Default for XSub<P> {
+    fn default() -> XSub<P> {
+        XSub::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::ChannelPeer;
+    use futures::{executor::block_on, FutureExt};
+
+    #[test]
+    fn test_messages_forwarded_to_all_peers() {
+        block_on(async {
+            let mut xsub = XSub::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::XSub, SocketType::Pub);
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::XSub, SocketType::XPub);
+            xsub.attach(local_a).await.unwrap();
+            xsub.attach(local_b).await.unwrap();
+
+            let sub = Subscription::Subscribe(b"x".to_vec()).to_message();
+            xsub.send(sub.clone()).await.unwrap();
+            xsub.send(Message::from(&b"other"[..])).await.unwrap();
+
+            for remote in [&mut remote_a, &mut remote_b].iter_mut() {
+                assert_eq!(remote.recv_message().await.unwrap(), sub);
+                assert_eq!(
+                    remote.recv_message().await.unwrap(),
+                    Message::from(&b"other"[..])
+                );
+            }
+
+            remote_a
+                .send_message(Message::from(&b"y"[..]))
+                .await
+                .unwrap();
+            remote_b
+                .send_message(Message::from(&b"x"[..]))
+                .await
+                .unwrap();
+            assert_eq!(xsub.recv().await.unwrap(), Message::from(&b"x"[..]));
+        });
+    }
+
+    #[test]
+    fn test_subscriptions_aggregated() {
+        block_on(async {
+            let mut xsub = XSub::new();
+            let (local_a, mut remote_a) = ChannelPeer::pair(SocketType::XSub, SocketType::Pub);
+            xsub.attach(local_a).await.unwrap();
+
+            // Two subscribers downstream want the same topic.
+            let sub = Subscription::Subscribe(b"x".to_vec()).to_message();
+            let cancel = Subscription::Cancel(b"x".to_vec()).to_message();
+            xsub.send(sub.clone()).await.unwrap();
+            xsub.send(sub.clone()).await.unwrap();
+            assert_eq!(xsub.subscribers(b"x"), 2);
+
+            // Peers attached later hear about it once too.
+            let (local_b, mut remote_b) = ChannelPeer::pair(SocketType::XSub, SocketType::Pub);
+            xsub.attach(local_b).await.unwrap();
+
+            xsub.send(cancel.clone()).await.unwrap();
+            xsub.send(Subscription::Cancel(b"y".to_vec()).to_message())
+                .await
+                .unwrap();
+            xsub.send(cancel.clone()).await.unwrap();
+            assert_eq!(xsub.subscribers(b"x"), 0);
+
+            for remote in [&mut remote_a, &mut remote_b].iter_mut() {
+                assert_eq!(remote.recv_message().await.unwrap(), sub);
+                assert_eq!(remote.recv_message().await.unwrap(), cancel);
+                assert!(remote.recv_message().now_or_never().is_none());
+            }
+        });
+    }
+}
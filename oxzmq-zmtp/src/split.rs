@@ -0,0 +1,582 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    batch,
+    command::{Command, CommandHandler, ZmtpCommand},
+    frame::{Frame, FrameDirection, FrameHook, FrameReader, MessageFrame},
+    heartbeat::{self, Liveness},
+    message::{Message, Timestamp},
+    metrics::{message_bytes, Metrics},
+    options::{ConnectionOptions, MessageLimits},
+    peer::PeerError,
+    pool::MessagePool,
+    properties::Properties,
+    socket::SocketType,
+    sockets::subscription::Subscription,
+    Version,
+};
+use futures::{
+    io::{self, AsyncBufRead, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
+    lock::Mutex,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex as StdMutex, MutexGuard as StdMutexGuard},
+    time::Instant,
+};
+
+// What a connection needs to keep track of to receive messages, apart from
+// the stream it reads them from.
+#[derive(Debug, Clone)]
+pub(crate) struct RecvHalf {
+    // Holds on to a frame that's partly read, so that receiving can stop
+    // anywhere and start again.
+    pub(crate) reader: FrameReader,
+    pub(crate) multipart_buffer: Vec<MessageFrame>,
+    // Whether both ends advertised support for batched messages, and the
+    // messages from the last batch that haven't been received yet.
+    pub(crate) batching: bool,
+    pub(crate) unbatched: VecDeque<Message>,
+    // Whether the peer told us that it's closing.
+    pub(crate) closed_by_peer: bool,
+    pub(crate) timestamps: bool,
+    // What to attach to every received message; see `Message::metadata`.
+    pub(crate) metadata: Arc<Properties>,
+    // Where to get buffers for received message frames, if anywhere.
+    pub(crate) pool: Option<MessagePool>,
+    pub(crate) handlers: HashMap<String, CommandHandler>,
+    pub(crate) frame_hook: Option<FrameHook>,
+    pub(crate) metrics: Metrics,
+    // How big a message the peer may send.
+    pub(crate) limits: MessageLimits,
+}
+
+// What came of reading from the peer. Commands that need something done on
+// the sending side are handed back, since the stream being read from can't
+// be written to.
+pub(crate) enum Received {
+    Message(Message),
+    // A frame to send back to the peer straight away.
+    Reply(Frame),
+    Pong(u64),
+}
+
+impl RecvHalf {
+    pub(crate) fn new(batching: bool, options: &ConnectionOptions) -> RecvHalf {
+        RecvHalf {
+            reader: FrameReader::default(),
+            multipart_buffer: Vec::new(),
+            batching,
+            unbatched: VecDeque::new(),
+            closed_by_peer: false,
+            timestamps: options.records_timestamps(),
+            metadata: Arc::default(),
+            pool: None,
+            handlers: HashMap::new(),
+            frame_hook: None,
+            metrics: Metrics::default(),
+            limits: options.message_limits(),
+        }
+    }
+
+    pub(crate) fn pop_unbatched(&mut self) -> Option<Message> {
+        let msg = self.unbatched.pop_front()?;
+        self.metrics.received(&msg);
+        Some(msg)
+    }
+
+    // Subscriptions sent as commands are handed on as the messages they'd
+    // otherwise have been.
+    fn subscription(&mut self, sub: Subscription) -> Received {
+        let msg = sub.to_message();
+        self.metrics.received(&msg);
+        Received::Message(msg)
+    }
+
+    pub(crate) async fn recv<R: AsyncBufRead + Unpin>(
+        &mut self,
+        stream: &mut R,
+    ) -> Result<Received, PeerError> {
+        if let Some(msg) = self.pop_unbatched() {
+            return Ok(Received::Message(msg));
+        }
+        if self.closed_by_peer {
+            return Err(PeerError::Closed);
+        }
+
+        loop {
+            // A peer that closes the stream between messages is shutting down
+            // cleanly. Closing it anywhere else cuts a message short.
+            let header = match self.reader.read_header(stream).await? {
+                Some(header) => header,
+                None if self.multipart_buffer.is_empty() => return Err(PeerError::Closed),
+                None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            };
+
+            // Frames are checked against the limits before their data is
+            // read. A command is held to the byte limit on its own, so a
+            // batch can't hold more than one message could.
+            if header.is_command() {
+                self.limits.check(1, header.len())?;
+            } else {
+                let received = self
+                    .multipart_buffer
+                    .iter()
+                    .map(|msg_frame| msg_frame.data.len() as u64)
+                    .sum::<u64>();
+                let parts = self.multipart_buffer.len() + 1;
+                self.limits
+                    .check(parts, received.saturating_add(header.len()))?;
+            }
+            let frame = self.reader.read_body(stream, self.pool.as_ref()).await?;
+            if let Some(hook) = &self.frame_hook {
+                hook.received(&frame);
+            }
+            let cmd = match frame {
+                Frame::Message(msg_frame) => {
+                    let more = msg_frame.more;
+                    self.multipart_buffer.push(msg_frame);
+                    match more {
+                        true => continue,
+                        false => break,
+                    }
+                }
+                Frame::Command(cmd) => ZmtpCommand::from_frame(cmd)?,
+            };
+
+            match cmd {
+                ZmtpCommand::Batch(data) if self.batching => {
+                    let mut msgs = batch::decode(&data)?;
+                    for msg in msgs.iter() {
+                        self.limits.check(msg.len(), message_bytes(msg))?;
+                    }
+                    if self.timestamps {
+                        // Every message in a batch arrives at once.
+                        let timestamp = Timestamp::now();
+                        msgs.iter_mut().for_each(|msg| msg.set_timestamp(timestamp));
+                    }
+                    for msg in msgs.iter_mut() {
+                        msg.set_metadata(self.metadata.clone());
+                    }
+                    self.unbatched.extend(msgs);
+                    if let Some(msg) = self.pop_unbatched() {
+                        return Ok(Received::Message(msg));
+                    }
+                }
+                ZmtpCommand::Ping { context, .. } => {
+                    let pong = ZmtpCommand::Pong { context }.into_frame();
+                    return Ok(Received::Reply(pong));
+                }
+                ZmtpCommand::Close => {
+                    // Everything sent before the CLOSE has been delivered.
+                    self.multipart_buffer.clear();
+                    self.closed_by_peer = true;
+                    return Err(PeerError::Closed);
+                }
+                ZmtpCommand::Error(reason) => return Err(PeerError::Rejected(reason)),
+                ZmtpCommand::Pong { context } => {
+                    if let Some(seq) = heartbeat::pong_seq(&context) {
+                        return Ok(Received::Pong(seq));
+                    }
+                }
+                ZmtpCommand::Subscribe(topic) => {
+                    return Ok(self.subscription(Subscription::Subscribe(topic)));
+                }
+                ZmtpCommand::Cancel(topic) => {
+                    return Ok(self.subscription(Subscription::Cancel(topic)));
+                }
+
+                // Commands nobody registered a handler for are ignored.
+                ZmtpCommand::Unknown { name, data } => {
+                    let handler = self.handlers.get(&name);
+                    if let Some(reply) = handler.and_then(|handler| (handler.0)(&data)) {
+                        return Ok(Received::Reply(command_frame(reply)));
+                    }
+                }
+
+                // Handshake commands have no place after the handshake, and
+                // batches none unless both ends agreed to them.
+                ZmtpCommand::Ready(_)
+                | ZmtpCommand::Hello(_)
+                | ZmtpCommand::Welcome
+                | ZmtpCommand::Initiate(_)
+                | ZmtpCommand::Batch(_) => (),
+            }
+        }
+        let parts = self
+            .multipart_buffer
+            .drain(..)
+            .map(|msg_frame| msg_frame.data)
+            .collect::<Vec<_>>();
+        let mut msg = Message::from(parts);
+        if self.timestamps {
+            msg.set_timestamp(Timestamp::now());
+        }
+        msg.set_metadata(self.metadata.clone());
+        self.metrics.received(&msg);
+        Ok(Received::Message(msg))
+    }
+}
+
+pub(crate) fn command_frame(cmd: Command) -> Frame {
+    let (name, data) = cmd.into_parts();
+    Frame::new_command(name, data)
+}
+
+// What a connection needs to keep track of to send messages, apart from the
+// stream it writes them to.
+#[derive(Debug, Clone)]
+pub(crate) struct SendHalf {
+    pub(crate) batching: bool,
+    // Whether the peer can be told that we're closing.
+    pub(crate) announces_close: bool,
+    // Message frames held back to be written together, once there are at
+    // least `max_corked` bytes of them. Nothing is held back if that's 0.
+    pub(crate) corked: Vec<u8>,
+    pub(crate) max_corked: usize,
+    // Whether subscription messages go out as SUBSCRIBE and CANCEL commands,
+    // which is how ZMTP 3.1 sends them.
+    pub(crate) subscription_commands: bool,
+    pub(crate) frame_hook: Option<FrameHook>,
+    pub(crate) metrics: Metrics,
+}
+
+impl SendHalf {
+    pub(crate) fn new(batching: bool, announces_close: bool, max_corked: usize) -> SendHalf {
+        SendHalf {
+            batching,
+            announces_close,
+            corked: Vec::new(),
+            max_corked,
+            subscription_commands: false,
+            frame_hook: None,
+            metrics: Metrics::default(),
+        }
+    }
+
+    pub(crate) async fn send_message<W: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut W,
+        msg: Message,
+    ) -> Result<(), PeerError> {
+        let bytes = message_bytes(&msg);
+        if let Some(sub) = self.subscription(&msg) {
+            self.write_frame(stream, &sub.to_command().into_frame())
+                .await?;
+            self.metrics.sent(bytes);
+            return Ok(());
+        }
+
+        let last_idx = msg.len().saturating_sub(1);
+        for (idx, part) in msg.into_parts().into_iter().enumerate() {
+            let frame = Frame::new_message(idx != last_idx, part);
+            self.write_frame(stream, &frame).await?;
+        }
+
+        self.metrics.sent(bytes);
+        Ok(())
+    }
+
+    pub(crate) async fn send_batch<W: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut W,
+        msgs: Vec<Message>,
+    ) -> Result<(), PeerError> {
+        let fits = msgs
+            .iter()
+            .flat_map(|msg| msg.parts())
+            .all(|part| part.len() <= u32::MAX as usize);
+        let has_commands = msgs.iter().any(|msg| self.subscription(msg).is_some());
+        if !self.batching || !fits || has_commands {
+            for msg in msgs {
+                self.send_message(stream, msg).await?;
+            }
+            return Ok(());
+        }
+
+        let frame = ZmtpCommand::Batch(batch::encode(&msgs)).into_frame();
+        self.write_frame(stream, &frame).await?;
+        msgs.iter()
+            .for_each(|msg| self.metrics.sent(message_bytes(msg)));
+        Ok(())
+    }
+
+    // The subscription `msg` holds, if it's to be sent as a command.
+    fn subscription(&self, msg: &Message) -> Option<Subscription> {
+        match self.subscription_commands {
+            true => Subscription::parse(msg),
+            false => None,
+        }
+    }
+
+    pub(crate) async fn close<W: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut W,
+    ) -> Result<(), PeerError> {
+        if self.announces_close {
+            let close = ZmtpCommand::Close.into_frame();
+            self.write_frame_now(stream, &close).await?;
+        }
+        self.flush_corked(stream).await?;
+        stream.close().await?;
+        Ok(())
+    }
+
+    // Writes a message frame, or holds it back if frames are being corked,
+    // until enough have built up.
+    pub(crate) async fn write_frame<W: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut W,
+        frame: &Frame,
+    ) -> io::Result<()> {
+        if self.max_corked == 0 && self.corked.is_empty() && self.frame_hook.is_none() {
+            return frame.write_to(stream).await;
+        }
+
+        self.cork(frame);
+        if self.corked.len() >= self.max_corked {
+            self.flush_corked(stream).await?;
+        }
+        Ok(())
+    }
+
+    // Writes a frame straight away, along with any frames held back before
+    // it. Commands go through here, since the peer may be waiting on them.
+    pub(crate) async fn write_frame_now<W: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut W,
+        frame: &Frame,
+    ) -> io::Result<()> {
+        if self.corked.is_empty() && self.frame_hook.is_none() {
+            return frame.write_to(stream).await;
+        }
+
+        self.cork(frame);
+        self.flush_corked(stream).await
+    }
+
+    // Adds a frame to the ones held back, which is also where the frame hook
+    // gets to see the frame's bytes.
+    pub(crate) fn cork(&mut self, frame: &Frame) {
+        let start = self.corked.len();
+        frame.encode(&mut self.corked);
+        if let Some(hook) = &self.frame_hook {
+            (hook.0)(FrameDirection::Sent, &self.corked[start..]);
+        }
+    }
+
+    pub(crate) async fn flush_corked<W: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut W,
+    ) -> io::Result<()> {
+        if self.corked.is_empty() {
+            return Ok(());
+        }
+
+        // What's written is taken off as it goes, so that if this is dropped
+        // part of the way through, the next flush carries on with the rest
+        // rather than writing some of it twice.
+        while !self.corked.is_empty() {
+            let written = stream.write(&self.corked).await?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.corked.drain(..written);
+        }
+        stream.flush().await
+    }
+}
+
+// The sending side of a split connection. The reader needs it too, to answer
+// PINGs and commands with registered handlers.
+#[derive(Debug)]
+struct Writing<S> {
+    send: SendHalf,
+    stream: WriteHalf<S>,
+}
+
+impl<S: AsyncWrite> Writing<S> {
+    async fn write_frame_now(&mut self, frame: &Frame) -> io::Result<()> {
+        self.send.write_frame_now(&mut self.stream, frame).await
+    }
+}
+
+// Both halves send heartbeats or hear back about them.
+pub(crate) type SharedLiveness = Arc<StdMutex<Liveness>>;
+
+pub(crate) fn lock(liveness: &SharedLiveness) -> StdMutexGuard<'_, Liveness> {
+    // Liveness is never left half updated, so it's still usable if another
+    // thread panicked while holding the lock.
+    liveness
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub(crate) fn split<S: AsyncBufRead + AsyncWrite>(
+    stream: S,
+    version: Version,
+    remote_socket_type: SocketType,
+    recv: RecvHalf,
+    send: SendHalf,
+    liveness: Liveness,
+) -> (ConnectionReader<S>, ConnectionWriter<S>) {
+    let (read_half, write_half) = io::AsyncReadExt::split(stream);
+    let metrics = send.metrics;
+    let writing = Arc::new(Mutex::new(Writing {
+        send,
+        stream: write_half,
+    }));
+    let liveness = Arc::new(StdMutex::new(liveness));
+    let reader = ConnectionReader {
+        remote_socket_type,
+        recv,
+        liveness: liveness.clone(),
+        writing: writing.clone(),
+        replies: VecDeque::new(),
+        stream: BufReader::new(read_half),
+    };
+    let writer = ConnectionWriter {
+        version,
+        liveness,
+        metrics,
+        writing,
+    };
+    (reader, writer)
+}
+
+/// The receiving half of a connection, from `Connection::split`. It answers
+/// the peer's heartbeats through the writing half, so it has to wait whenever
+/// the writing half is in the middle of sending something.
+#[derive(Debug)]
+pub struct ConnectionReader<S> {
+    remote_socket_type: SocketType,
+    recv: RecvHalf,
+    liveness: SharedLiveness,
+    writing: Arc<Mutex<Writing<S>>>,
+    // Frames to send back to the peer that haven't been handed to the
+    // writing half yet.
+    replies: VecDeque<Frame>,
+    stream: BufReader<ReadHalf<S>>,
+}
+
+impl<S: AsyncBufRead + AsyncWrite + Unpin> ConnectionReader<S> {
+    pub fn remote_socket_type(&self) -> SocketType {
+        self.remote_socket_type
+    }
+
+    /// Receives the next message. Unlike with a whole `Connection`, frames
+    /// the writing half holds back aren't sent first, so they have to be
+    /// flushed if the peer won't say anything until it gets them.
+    pub async fn recv_message(&mut self) -> Result<Message, PeerError> {
+        loop {
+            self.send_replies().await?;
+            match self.recv.recv(&mut self.stream).await? {
+                Received::Message(msg) => return Ok(msg),
+                Received::Reply(frame) => self.replies.push_back(frame),
+                Received::Pong(seq) => lock(&self.liveness).pong_received(seq, Instant::now()),
+            }
+        }
+    }
+
+    // Replies are queued rather than written as they come, so that none are
+    // lost if receiving is given up on while waiting for the writing half.
+    async fn send_replies(&mut self) -> io::Result<()> {
+        if self.replies.is_empty() {
+            return Ok(());
+        }
+        let writing = &mut *self.writing.lock().await;
+        self.replies
+            .drain(..)
+            .for_each(|frame| writing.send.cork(&frame));
+        writing.send.flush_corked(&mut writing.stream).await
+    }
+
+    /// Returns a message if one has arrived in full already.
+    pub fn try_recv_message(&mut self) -> Option<Message> {
+        self.recv.pop_unbatched()
+    }
+
+    /// How many messages have been received, and how big they were.
+    pub fn metrics(&self) -> Metrics {
+        self.recv.metrics
+    }
+
+    /// Whether the peer has been answering heartbeats.
+    pub fn liveness(&self) -> Liveness {
+        lock(&self.liveness).clone()
+    }
+}
+
+/// The sending half of a connection, from `Connection::split`.
+#[derive(Debug)]
+pub struct ConnectionWriter<S> {
+    version: Version,
+    liveness: SharedLiveness,
+    writing: Arc<Mutex<Writing<S>>>,
+    // Kept up to date after every send, so that reading them doesn't have to
+    // wait for the lock.
+    metrics: Metrics,
+}
+
+impl<S: AsyncBufRead + AsyncWrite + Unpin> ConnectionWriter<S> {
+    pub async fn send_message(&mut self, msg: Message) -> Result<(), PeerError> {
+        let writing = &mut *self.writing.lock().await;
+        let sent = writing.send.send_message(&mut writing.stream, msg).await;
+        self.metrics = writing.send.metrics;
+        sent
+    }
+
+    pub async fn send_batch(&mut self, msgs: Vec<Message>) -> Result<(), PeerError> {
+        let writing = &mut *self.writing.lock().await;
+        let sent = writing.send.send_batch(&mut writing.stream, msgs).await;
+        self.metrics = writing.send.metrics;
+        sent
+    }
+
+    pub async fn send_command(&mut self, cmd: Command) -> Result<(), PeerError> {
+        let frame = command_frame(cmd);
+        self.writing.lock().await.write_frame_now(&frame).await?;
+        Ok(())
+    }
+
+    pub async fn send_heartbeat(&mut self) -> Result<(), PeerError> {
+        // Heartbeats were added in ZMTP 3.1.
+        if self.version < Version::new(3, 1) {
+            return Ok(());
+        }
+
+        let seq = lock(&self.liveness).ping_sent(Instant::now());
+        let ping = heartbeat::ping(seq).into_frame();
+        self.writing.lock().await.write_frame_now(&ping).await?;
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<(), PeerError> {
+        let writing = &mut *self.writing.lock().await;
+        Ok(writing.send.flush_corked(&mut writing.stream).await?)
+    }
+
+    /// Tells the peer we're closing, if it understands that, and closes the
+    /// stream for writing. The reading half still gets whatever the peer
+    /// sends until it closes its end.
+    pub async fn close(&mut self) -> Result<(), PeerError> {
+        let writing = &mut *self.writing.lock().await;
+        writing.send.close(&mut writing.stream).await
+    }
+
+    /// How many messages have been sent, and how big they were.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics
+    }
+
+    /// Whether the peer has been answering heartbeats.
+    pub fn liveness(&self) -> Liveness {
+        lock(&self.liveness).clone()
+    }
+
+    pub(crate) fn shared_liveness(&self) -> SharedLiveness {
+        self.liveness.clone()
+    }
+}
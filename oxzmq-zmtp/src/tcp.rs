@@ -0,0 +1,767 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use self::sockopt::SockOpt;
+use crate::{
+    endpoint::{Endpoint, Host, Resolver},
+    event::EventKind,
+    options::ConnectionOptions,
+    peer::{CloseReason, Origin, Peer},
+    socket::SocketType,
+    timer::Sleep,
+    Connection, ConnectionError,
+};
+use futures::{
+    channel::oneshot,
+    io::{self, AsyncRead, AsyncWrite, BufReader},
+    stream::{self, Stream},
+};
+use std::{
+    convert::TryFrom,
+    future::Future,
+    io::{IoSlice, Read, Write},
+    net::{self, Shutdown, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    thread,
+    time::{Duration, Instant},
+};
+
+mod sockopt;
+
+// How long to wait for each address when connecting.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The standard library can't tell us when a socket becomes ready, so until
+// there's a reactor to register with, operations that would block are
+// retried on a timer instead. The wait starts short and doubles for as long
+// as there's nothing to do, so a quiet connection is only checked on now
+// and then.
+const MIN_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A connection over TCP, ready to be attached to a socket.
+pub type TcpConnection = Connection<BufReader<TcpStream>>;
+
+impl TcpConnection {
+    /// Connects to the endpoint and performs the greeting and handshake.
+    /// Service endpoints are looked up again on every call, so reconnecting
+    /// finds a peer that has moved, unless the options gave up on the
+    /// endpoint. The connection remembers the endpoint, so that sockets can
+    /// disconnect from it later.
+    pub async fn connect<R: Resolver>(
+        endpoint: &Endpoint,
+        resolver: &R,
+        socket_type: &SocketType,
+        options: &ConnectionOptions,
+    ) -> Result<TcpConnection, ConnectionError> {
+        if options.is_stopped(endpoint) {
+            return Err(ConnectionError::ReconnectStopped(endpoint.clone()));
+        }
+        let stream = match TcpStream::connect_with(endpoint, resolver, options.tcp_options()).await
+        {
+            Ok(stream) => stream,
+            Err(err) => {
+                let reason = CloseReason::Io(err.kind());
+                let endpoint = endpoint.clone();
+                options.emit(*socket_type, EventKind::ConnectFailed { endpoint, reason });
+                return Err(err.into());
+            }
+        };
+        let endpoint = endpoint.clone();
+        options.emit(
+            *socket_type,
+            EventKind::Connected {
+                endpoint: endpoint.clone(),
+            },
+        );
+        set_up(stream, socket_type, options, Origin::Connected(endpoint)).await
+    }
+}
+
+// Performs the greeting and handshake on a new stream, and reports how they
+// went.
+async fn set_up(
+    stream: TcpStream,
+    socket_type: &SocketType,
+    options: &ConnectionOptions,
+    origin: Origin,
+) -> Result<TcpConnection, ConnectionError> {
+    let peer_addr = stream.peer_addr();
+    match Connection::with_options(BufReader::new(stream), socket_type, options).await {
+        Ok(mut conn) => {
+            if let Ok(addr) = peer_addr {
+                conn.set_peer_address(addr.ip());
+            }
+            let kind = EventKind::HandshakeSucceeded {
+                origin: origin.clone(),
+                peer_type: conn.remote_socket_type(),
+            };
+            options.emit(*socket_type, kind);
+            conn.set_origin(origin);
+            Ok(conn)
+        }
+        Err(err) => {
+            let reason = err.close_reason();
+            options.emit(*socket_type, EventKind::HandshakeFailed { origin, reason });
+            Err(err)
+        }
+    }
+}
+
+/// A TCP stream that can be used without blocking the task.
+#[derive(Debug)]
+pub struct TcpStream {
+    stream: net::TcpStream,
+    read_retry: Retry,
+    write_retry: Retry,
+}
+
+impl TcpStream {
+    /// Connects to the first address the endpoint resolves to that accepts
+    /// the connection.
+    pub async fn connect<R: Resolver>(endpoint: &Endpoint, resolver: &R) -> io::Result<TcpStream> {
+        TcpStream::connect_with(endpoint, resolver, &TcpOptions::new()).await
+    }
+
+    /// Connects like `connect`, and tunes the stream with `options` before
+    /// anything is sent.
+    pub async fn connect_with<R: Resolver>(
+        endpoint: &Endpoint,
+        resolver: &R,
+        options: &TcpOptions,
+    ) -> io::Result<TcpStream> {
+        let addrs = endpoint.resolve(resolver).await?;
+
+        // Connecting blocks, so it's done on a thread of its own.
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(connect_any(&addrs));
+        });
+        let stream = rx
+            .await
+            .map_err(|_| io::Error::other("connecting thread panicked"))??;
+        TcpStream::new(stream, options)
+    }
+
+    fn new(stream: net::TcpStream, options: &TcpOptions) -> io::Result<TcpStream> {
+        stream.set_nonblocking(true)?;
+        let stream = TcpStream {
+            stream,
+            read_retry: Retry::new(),
+            write_retry: Retry::new(),
+        };
+        stream.tune(options)?;
+        Ok(stream)
+    }
+
+    /// Applies `options` to the stream. Options that aren't set are left as
+    /// they are.
+    pub fn tune(&self, options: &TcpOptions) -> io::Result<()> {
+        self.stream.set_nodelay(options.nodelay)?;
+        if let Some(keepalive) = &options.keepalive {
+            sockopt::set(&self.stream, SockOpt::KeepAlive, 1)?;
+            if let Some(idle) = keepalive.idle {
+                sockopt::set(&self.stream, SockOpt::KeepIdle, secs(idle))?;
+            }
+            if let Some(interval) = keepalive.interval {
+                sockopt::set(&self.stream, SockOpt::KeepInterval, secs(interval))?;
+            }
+            if let Some(count) = keepalive.count {
+                let count = i32::try_from(count).unwrap_or(i32::MAX);
+                sockopt::set(&self.stream, SockOpt::KeepCount, count)?;
+            }
+        }
+        if let Some(size) = options.send_buffer {
+            let size = i32::try_from(size).unwrap_or(i32::MAX);
+            sockopt::set(&self.stream, SockOpt::SendBuffer, size)?;
+        }
+        if let Some(size) = options.recv_buffer {
+            let size = i32::try_from(size).unwrap_or(i32::MAX);
+            sockopt::set(&self.stream, SockOpt::RecvBuffer, size)?;
+        }
+        Ok(())
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+}
+
+// Keepalive times are set in whole seconds, and can't be zero.
+fn secs(duration: Duration) -> i32 {
+    let secs = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+    i32::try_from(secs.max(1)).unwrap_or(i32::MAX)
+}
+
+/// How TCP streams are tuned, as part of `ConnectionOptions`. By default,
+/// Nagle's algorithm is turned off and everything else is left to the
+/// operating system. Keepalive and buffer sizes can only be set on Linux
+/// for now; elsewhere, connecting with them set fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpOptions {
+    nodelay: bool,
+    keepalive: Option<TcpKeepalive>,
+    send_buffer: Option<usize>,
+    recv_buffer: Option<usize>,
+}
+
+impl TcpOptions {
+    pub fn new() -> TcpOptions {
+        TcpOptions {
+            nodelay: true,
+            keepalive: None,
+            send_buffer: None,
+            recv_buffer: None,
+        }
+    }
+
+    /// Whether small writes go out straight away (`TCP_NODELAY`), rather
+    /// than waiting to be combined with the next ones. On by default, which
+    /// suits request-reply traffic; turning it off saves on packets when
+    /// many small messages are sent in a row.
+    pub fn nodelay(mut self, nodelay: bool) -> TcpOptions {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Turns on TCP keepalive (`SO_KEEPALIVE`), so that connections to
+    /// peers that vanished without closing them are found out and closed,
+    /// and so that idle connections survive NAT and firewall timeouts.
+    pub fn keepalive(mut self, keepalive: TcpKeepalive) -> TcpOptions {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// The size the kernel should give the send buffer (`SO_SNDBUF`). Links
+    /// with a high bandwidth-delay product need bigger buffers than the
+    /// default to be kept busy.
+    pub fn send_buffer(mut self, bytes: usize) -> TcpOptions {
+        self.send_buffer = Some(bytes);
+        self
+    }
+
+    /// The size the kernel should give the receive buffer (`SO_RCVBUF`).
+    pub fn recv_buffer(mut self, bytes: usize) -> TcpOptions {
+        self.recv_buffer = Some(bytes);
+        self
+    }
+}
+
+impl Default for TcpOptions {
+    fn default() -> TcpOptions {
+        TcpOptions::new()
+    }
+}
+
+/// When TCP keepalive probes are sent. Whatever isn't set is left to the
+/// operating system, which on Linux waits two hours before the first probe.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    idle: Option<Duration>,
+    interval: Option<Duration>,
+    count: Option<u32>,
+}
+
+impl TcpKeepalive {
+    pub fn new() -> TcpKeepalive {
+        TcpKeepalive::default()
+    }
+
+    /// How long the connection has to be idle before the first probe
+    /// (`TCP_KEEPIDLE`). Rounded up to whole seconds.
+    pub fn idle(mut self, idle: Duration) -> TcpKeepalive {
+        self.idle = Some(idle);
+        self
+    }
+
+    /// How long to wait between probes that go unanswered
+    /// (`TCP_KEEPINTVL`). Rounded up to whole seconds.
+    pub fn interval(mut self, interval: Duration) -> TcpKeepalive {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// How many probes can go unanswered before the connection is dropped
+    /// (`TCP_KEEPCNT`).
+    pub fn count(mut self, count: u32) -> TcpKeepalive {
+        self.count = Some(count);
+        self
+    }
+}
+
+fn connect_any(addrs: &[SocketAddr]) -> io::Result<net::TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "endpoint has no addresses");
+    for addr in addrs {
+        match net::TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let stream = &mut this.stream;
+        this.read_retry.poll(cx, || stream.read(buf))
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let stream = &mut this.stream;
+        this.write_retry.poll(cx, || stream.write(buf))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let stream = &mut this.stream;
+        this.write_retry.poll(cx, || stream.write_vectored(bufs))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let stream = &mut this.stream;
+        this.write_retry.poll(cx, || stream.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.stream.shutdown(Shutdown::Write))
+    }
+}
+
+/// Listens for TCP connections on a `tcp://host:port` endpoint.
+#[derive(Debug)]
+pub struct TcpListener {
+    listener: net::TcpListener,
+    endpoint: Endpoint,
+}
+
+impl TcpListener {
+    /// Starts listening, on the address of a `tcp://` or `ws://` endpoint.
+    /// Port 0 (or `*`) picks a free port, which `last_endpoint` tells.
+    pub async fn bind(endpoint: &Endpoint) -> io::Result<TcpListener> {
+        let addrs = match endpoint {
+            Endpoint::Tcp { .. } | Endpoint::Ws { .. } => endpoint.resolve(&NoServices).await?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "can only listen on tcp://host:port and ws:// endpoints",
+                ))
+            }
+        };
+
+        let listener = net::TcpListener::bind(addrs.as_slice())?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpListener {
+            listener,
+            endpoint: endpoint.clone(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The endpoint the listener was bound to, with the address and port it
+    /// actually got, e.g. `tcp://127.0.0.1:40123` after binding
+    /// `tcp://127.0.0.1:*`. Peers can connect to it, unless the host is a
+    /// wildcard.
+    pub fn last_endpoint(&self) -> io::Result<Endpoint> {
+        let addr = self.listener.local_addr()?;
+        let host = Host::Ip(addr.ip());
+        let port = addr.port();
+        Ok(match &self.endpoint {
+            Endpoint::Ws { path, .. } => Endpoint::Ws {
+                host,
+                port,
+                path: path.clone(),
+            },
+            _ => Endpoint::Tcp { host, port },
+        })
+    }
+
+    pub async fn accept(&self) -> io::Result<TcpStream> {
+        self.accept_with(&TcpOptions::new()).await
+    }
+
+    /// Accepts like `accept`, and tunes the stream with `options` before
+    /// anything is sent.
+    pub async fn accept_with(&self, options: &TcpOptions) -> io::Result<TcpStream> {
+        let mut retry = Retry::new();
+        let (stream, _) =
+            futures::future::poll_fn(|cx| retry.poll(cx, || self.listener.accept())).await?;
+        TcpStream::new(stream, options)
+    }
+
+    /// Accepts connections forever, performing the greeting and handshake on
+    /// each one, so that they can be attached to a socket as they come. A
+    /// peer that fails its handshake only yields an error; the listener
+    /// carries on with the next one. Connections remember that they were
+    /// accepted on `last_endpoint`, so that sockets can unbind from it later.
+    pub fn incoming(
+        &self,
+        socket_type: SocketType,
+        options: ConnectionOptions,
+    ) -> impl Stream<Item = Result<TcpConnection, ConnectionError>> + '_ {
+        let endpoint = self
+            .last_endpoint()
+            .unwrap_or_else(|_| self.endpoint.clone());
+        stream::unfold(options, move |options| {
+            let endpoint = endpoint.clone();
+            async move {
+                let result = match self.accept_with(options.tcp_options()).await {
+                    Ok(stream) => {
+                        let kind = EventKind::Accepted {
+                            endpoint: endpoint.clone(),
+                        };
+                        options.emit(socket_type, kind);
+                        let origin = Origin::Accepted(endpoint);
+                        set_up(stream, &socket_type, &options, origin).await
+                    }
+                    Err(err) => Err(err.into()),
+                };
+                Some((result, options))
+            }
+        })
+    }
+}
+
+// Listening endpoints are never services, so there's nothing to resolve.
+struct NoServices;
+
+impl Resolver for NoServices {
+    async fn resolve(&self, service: &str) -> io::Result<Vec<SocketAddr>> {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown service: {}", service),
+        ))
+    }
+}
+
+// When to try again an operation that would have blocked, which backs off
+// for as long as the operation keeps finding nothing to do.
+#[derive(Debug)]
+struct Retry {
+    interval: Duration,
+    sleep: Option<Sleep>,
+}
+
+impl Retry {
+    fn new() -> Retry {
+        Retry {
+            interval: MIN_RETRY_INTERVAL,
+            sleep: None,
+        }
+    }
+
+    fn poll<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut op: impl FnMut() -> io::Result<T>,
+    ) -> Poll<io::Result<T>> {
+        loop {
+            match op() {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    let interval = self.interval;
+                    let sleep = self
+                        .sleep
+                        .get_or_insert_with(|| Sleep::until(Instant::now() + interval));
+                    if Pin::new(sleep).poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    // The wait went by with nothing to do, so the next one
+                    // is longer.
+                    self.sleep = None;
+                    self.interval = (interval * 2).min(MAX_RETRY_INTERVAL);
+                }
+                result => {
+                    self.sleep = None;
+                    self.interval = MIN_RETRY_INTERVAL;
+                    return Poll::Ready(result);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        endpoint::StaticResolver,
+        event::EventSink,
+        message::Message,
+        options::ReconnectStop,
+        peer::{Peer, PeerError},
+        socket::SocketType,
+        Connection,
+    };
+    use futures::{
+        executor::block_on, io::BufReader, join, AsyncReadExt, AsyncWriteExt, StreamExt,
+    };
+
+    #[test]
+    fn test_connect_and_accept() {
+        block_on(async {
+            let listener = TcpListener::bind(&"tcp://127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let port = listener.local_addr().unwrap().port();
+
+            // The client finds the listener through a service name.
+            let mut resolver = StaticResolver::new();
+            resolver.insert("echo", vec![SocketAddr::from(([127, 0, 0, 1], port))]);
+            let endpoint = "tcp://service:echo".parse().unwrap();
+
+            let (client, server) =
+                join!(TcpStream::connect(&endpoint, &resolver), listener.accept());
+            let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+            // Reading waits for data rather than failing.
+            let mut buf = [0; 5];
+            let (read, written) = join!(server.read_exact(&mut buf), async {
+                client.write_all(b"hello").await?;
+                client.flush().await
+            });
+            read.unwrap();
+            written.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            client.close().await.unwrap();
+            let mut rest = Vec::new();
+            server.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_handshake() {
+        block_on(async {
+            let listener = TcpListener::bind(&"tcp://127.0.0.1:*".parse().unwrap())
+                .await
+                .unwrap();
+            let endpoint = listener.last_endpoint().unwrap();
+            let resolver = StaticResolver::new();
+            let (client, server) =
+                join!(TcpStream::connect(&endpoint, &resolver), listener.accept());
+            let (client, server) = join!(
+                Connection::new(BufReader::new(client.unwrap()), &SocketType::Req),
+                Connection::new(BufReader::new(server.unwrap()), &SocketType::Rep),
+            );
+            let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+            let request = Message::from(vec![Vec::new(), b"hello".to_vec()]);
+            client.send_message(request.clone()).await.unwrap();
+            client.close().await.unwrap();
+            assert_eq!(server.recv_message().await.unwrap(), request);
+            assert!(matches!(
+                server.recv_message().await,
+                Err(PeerError::Closed)
+            ));
+        });
+    }
+
+    #[test]
+    fn test_idle_backoff() {
+        block_on(async {
+            let listener = TcpListener::bind(&"tcp://127.0.0.1:*".parse().unwrap())
+                .await
+                .unwrap();
+            let endpoint = listener.last_endpoint().unwrap();
+            let resolver = StaticResolver::new();
+            let (client, server) =
+                join!(TcpStream::connect(&endpoint, &resolver), listener.accept());
+            let (client, server) = join!(
+                Connection::new(BufReader::new(client.unwrap()), &SocketType::Pair),
+                Connection::new(BufReader::new(server.unwrap()), &SocketType::Pair),
+            );
+            let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+            let sender = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                block_on(client.send_message(Message::from(&b"late"[..]))).unwrap();
+                client
+            });
+
+            // Waiting on a quiet connection backs off instead of checking it
+            // every millisecond, but still notices the message.
+            let mut polls = 0;
+            let mut recv = Box::pin(server.recv_message());
+            let msg = futures::future::poll_fn(|cx| {
+                polls += 1;
+                recv.as_mut().poll(cx)
+            })
+            .await;
+            assert_eq!(msg.unwrap(), Message::from(&b"late"[..]));
+            assert!(polls < 30, "polled {} times", polls);
+            drop(sender.join().unwrap());
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_tune() {
+        block_on(async {
+            let listener = TcpListener::bind(&"tcp://127.0.0.1:*".parse().unwrap())
+                .await
+                .unwrap();
+            let endpoint = listener.last_endpoint().unwrap();
+            let options = TcpOptions::new()
+                .nodelay(false)
+                .keepalive(
+                    TcpKeepalive::new()
+                        .idle(Duration::from_secs(30))
+                        .interval(Duration::from_millis(1500))
+                        .count(4),
+                )
+                .send_buffer(64 * 1024)
+                .recv_buffer(128 * 1024);
+            let resolver = StaticResolver::new();
+            let (client, server) = join!(
+                TcpStream::connect_with(&endpoint, &resolver, &options),
+                listener.accept(),
+            );
+            let (client, server) = (client.unwrap(), server.unwrap());
+
+            let get = |stream: &TcpStream, opt| sockopt::get(&stream.stream, opt).unwrap();
+            assert!(!client.stream.nodelay().unwrap());
+            assert_eq!(get(&client, SockOpt::KeepAlive), 1);
+            assert_eq!(get(&client, SockOpt::KeepIdle), 30);
+            assert_eq!(get(&client, SockOpt::KeepInterval), 2);
+            assert_eq!(get(&client, SockOpt::KeepCount), 4);
+            // Linux doubles buffer sizes to leave room for its bookkeeping.
+            assert!(get(&client, SockOpt::SendBuffer) >= 64 * 1024);
+            assert!(get(&client, SockOpt::RecvBuffer) >= 128 * 1024);
+
+            // Accepted streams get the defaults.
+            assert!(server.stream.nodelay().unwrap());
+            assert_eq!(get(&server, SockOpt::KeepAlive), 0);
+        });
+    }
+
+    #[test]
+    fn test_last_endpoint() {
+        block_on(async {
+            let listener = TcpListener::bind(&"tcp://127.0.0.1:*".parse().unwrap())
+                .await
+                .unwrap();
+            let endpoint = listener.last_endpoint().unwrap();
+            let port = listener.local_addr().unwrap().port();
+            assert_ne!(port, 0);
+            assert_eq!(endpoint.to_string(), format!("tcp://127.0.0.1:{}", port));
+
+            // The endpoint it reports can be connected to as it is.
+            let resolver = StaticResolver::new();
+            let (client, server) =
+                join!(TcpStream::connect(&endpoint, &resolver), listener.accept());
+            client.unwrap();
+            server.unwrap();
+
+            let listener = TcpListener::bind(&"ws://*:*/zmq".parse().unwrap())
+                .await
+                .unwrap();
+            let endpoint = listener.last_endpoint().unwrap();
+            let port = listener.local_addr().unwrap().port();
+            assert_eq!(endpoint.to_string(), format!("ws://0.0.0.0:{}/zmq", port));
+        });
+    }
+
+    #[test]
+    fn test_peer_address() {
+        block_on(async {
+            let listener = TcpListener::bind(&"tcp://127.0.0.1:*".parse().unwrap())
+                .await
+                .unwrap();
+            let endpoint = listener.last_endpoint().unwrap();
+            let resolver = StaticResolver::new();
+            let options = ConnectionOptions::new();
+            let (client, server) = join!(
+                TcpConnection::connect(&endpoint, &resolver, &SocketType::Dealer, &options),
+                async {
+                    let incoming = listener.incoming(SocketType::Router, options.clone());
+                    futures::pin_mut!(incoming);
+                    incoming.next().await.unwrap()
+                }
+            );
+            let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+            client
+                .send_message(Message::from(b"hello".to_vec()))
+                .await
+                .unwrap();
+            let received = server.recv_message().await.unwrap();
+            let metadata = received.metadata().unwrap();
+            assert_eq!(metadata.get("Peer-Address"), Some(&b"127.0.0.1"[..]));
+        });
+    }
+
+    #[test]
+    fn test_reconnect_stop() {
+        block_on(async {
+            let listener = TcpListener::bind(&"tcp://127.0.0.1:*".parse().unwrap())
+                .await
+                .unwrap();
+            let endpoint = listener.last_endpoint().unwrap();
+            let resolver = StaticResolver::new();
+            let (sink, events) = EventSink::channel();
+            let options = ConnectionOptions::new()
+                .event_sink(sink)
+                .reconnect_stop(ReconnectStop::HandshakeFailed);
+
+            // REQ can't talk to PUB, and trying again won't change that.
+            let (client, server) = join!(
+                TcpConnection::connect(&endpoint, &resolver, &SocketType::Req, &options),
+                async {
+                    let stream = listener.accept().await.unwrap();
+                    Connection::new(BufReader::new(stream), &SocketType::Pub).await
+                }
+            );
+            assert!(matches!(
+                client.map(drop),
+                Err(ConnectionError::InvalidSocketCombination(..))
+            ));
+            assert!(server.is_err());
+            assert!(options.is_stopped(&endpoint));
+
+            // Nothing is listening for this one, and it isn't tried.
+            let again = TcpConnection::connect(&endpoint, &resolver, &SocketType::Req, &options);
+            assert!(matches!(
+                again.await.map(drop),
+                Err(ConnectionError::ReconnectStopped(stopped)) if stopped == endpoint
+            ));
+            drop(options);
+
+            let kinds: Vec<_> = events.map(|event| event.kind().clone()).collect().await;
+            assert_eq!(
+                kinds.last(),
+                Some(&EventKind::ReconnectStopped {
+                    endpoint: endpoint.clone(),
+                    reason: CloseReason::InvalidSocketCombination,
+                })
+            );
+            assert_eq!(kinds.len(), 3);
+        });
+    }
+}
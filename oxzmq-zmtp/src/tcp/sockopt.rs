@@ -0,0 +1,131 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// The socket options the standard library doesn't have setters for. Only
+// Linux is supported for now; elsewhere setting them fails with
+// `ErrorKind::Unsupported`.
+
+use std::{io, net};
+
+// More info: https://man7.org/linux/man-pages/man7/socket.7.html
+//            https://man7.org/linux/man-pages/man7/tcp.7.html
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::os::raw::{c_int, c_void};
+
+    pub(super) const SOL_SOCKET: c_int = 1;
+    pub(super) const SO_SNDBUF: c_int = 7;
+    pub(super) const SO_RCVBUF: c_int = 8;
+    pub(super) const SO_KEEPALIVE: c_int = 9;
+    pub(super) const IPPROTO_TCP: c_int = 6;
+    pub(super) const TCP_KEEPIDLE: c_int = 4;
+    pub(super) const TCP_KEEPINTVL: c_int = 5;
+    pub(super) const TCP_KEEPCNT: c_int = 6;
+
+    extern "C" {
+        pub(super) fn setsockopt(
+            fd: c_int,
+            level: c_int,
+            name: c_int,
+            value: *const c_void,
+            len: u32,
+        ) -> c_int;
+
+        #[cfg(test)]
+        pub(super) fn getsockopt(
+            fd: c_int,
+            level: c_int,
+            name: c_int,
+            value: *mut c_void,
+            len: *mut u32,
+        ) -> c_int;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum SockOpt {
+    SendBuffer,
+    RecvBuffer,
+    KeepAlive,
+    KeepIdle,
+    KeepInterval,
+    KeepCount,
+}
+
+#[cfg(target_os = "linux")]
+impl SockOpt {
+    fn level_and_name(self) -> (i32, i32) {
+        match self {
+            SockOpt::SendBuffer => (sys::SOL_SOCKET, sys::SO_SNDBUF),
+            SockOpt::RecvBuffer => (sys::SOL_SOCKET, sys::SO_RCVBUF),
+            SockOpt::KeepAlive => (sys::SOL_SOCKET, sys::SO_KEEPALIVE),
+            SockOpt::KeepIdle => (sys::IPPROTO_TCP, sys::TCP_KEEPIDLE),
+            SockOpt::KeepInterval => (sys::IPPROTO_TCP, sys::TCP_KEEPINTVL),
+            SockOpt::KeepCount => (sys::IPPROTO_TCP, sys::TCP_KEEPCNT),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(super) fn set(stream: &net::TcpStream, opt: SockOpt, value: i32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = opt.level_and_name();
+    // The value is a plain int that outlives the call, and its size is the
+    // length we pass.
+    let ret = unsafe {
+        sys::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &value as *const i32 as *const _,
+            std::mem::size_of::<i32>() as u32,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+pub(super) fn get(stream: &net::TcpStream, opt: SockOpt) -> io::Result<i32> {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = opt.level_and_name();
+    let mut value: i32 = 0;
+    let mut len = std::mem::size_of::<i32>() as u32;
+    // As with `set`, the kernel writes at most `len` bytes into the int.
+    let ret = unsafe {
+        sys::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut i32 as *mut _,
+            &mut len,
+        )
+    };
+    match ret {
+        0 => Ok(value),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn set(_stream: &net::TcpStream, opt: SockOpt, _value: i32) -> io::Result<()> {
+    Err(unsupported(opt))
+}
+
+#[cfg(all(test, not(target_os = "linux")))]
+pub(super) fn get(_stream: &net::TcpStream, opt: SockOpt) -> io::Result<i32> {
+    Err(unsupported(opt))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported(opt: SockOpt) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{:?} can only be set on Linux", opt),
+    )
+}
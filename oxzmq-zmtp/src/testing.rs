@@ -0,0 +1,272 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Helpers for testing code that runs over a `Connection` without touching
+//! the network.
+
+use futures::io::{self, AsyncBufRead, AsyncRead, AsyncWrite};
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How a `duplex` pair delivers what's written to it.
+#[derive(Debug, Clone, Default)]
+pub struct DuplexOptions {
+    latency: Duration,
+    max_read: Option<usize>,
+}
+
+impl DuplexOptions {
+    pub fn new() -> DuplexOptions {
+        DuplexOptions::default()
+    }
+
+    /// Holds back every write for `latency` before the other end can read
+    /// it.
+    pub fn latency(mut self, latency: Duration) -> DuplexOptions {
+        self.latency = latency;
+        self
+    }
+
+    /// Hands out at most `max_read` bytes per read, so that readers see
+    /// frames split at awkward places. Values below 1 count as 1.
+    pub fn max_read(mut self, max_read: usize) -> DuplexOptions {
+        self.max_read = Some(max_read.max(1));
+        self
+    }
+}
+
+/// Two connected in-memory streams: whatever is written to one can be read
+/// from the other. Writes never block, since nothing bounds the bytes in
+/// flight. Closing or dropping one end makes the other read EOF once it has
+/// read everything before that.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    duplex_with(&DuplexOptions::new())
+}
+
+/// Like `duplex`, with latency or chunking injected in both directions.
+pub fn duplex_with(options: &DuplexOptions) -> (DuplexStream, DuplexStream) {
+    let a_to_b = Arc::new(Mutex::new(Pipe::default()));
+    let b_to_a = Arc::new(Mutex::new(Pipe::default()));
+    let a = DuplexStream::new(b_to_a.clone(), a_to_b.clone(), options);
+    let b = DuplexStream::new(a_to_b, b_to_a, options);
+    (a, b)
+}
+
+/// One end of a `duplex` pair.
+#[derive(Debug)]
+pub struct DuplexStream {
+    inbound: Arc<Mutex<Pipe>>,
+    outbound: Arc<Mutex<Pipe>>,
+    // Bytes taken from `inbound` that haven't been read yet.
+    buf: Vec<u8>,
+    pos: usize,
+    latency: Duration,
+    max_read: usize,
+}
+
+// The bytes going one way, each write kept apart along with when it can be
+// read.
+#[derive(Debug, Default)]
+struct Pipe {
+    writes: VecDeque<(Instant, Vec<u8>)>,
+    reader: Option<Waker>,
+    write_closed: bool,
+    read_closed: bool,
+}
+
+impl DuplexStream {
+    fn new(
+        inbound: Arc<Mutex<Pipe>>,
+        outbound: Arc<Mutex<Pipe>>,
+        options: &DuplexOptions,
+    ) -> DuplexStream {
+        DuplexStream {
+            inbound,
+            outbound,
+            buf: Vec::new(),
+            pos: 0,
+            latency: options.latency,
+            max_read: options.max_read.unwrap_or(usize::MAX),
+        }
+    }
+}
+
+fn lock(pipe: &Mutex<Pipe>) -> MutexGuard<'_, Pipe> {
+    pipe.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl AsyncBufRead for DuplexStream {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.pos == this.buf.len() {
+            this.buf.clear();
+            this.pos = 0;
+
+            let mut pipe = lock(&this.inbound);
+            let now = Instant::now();
+            while this.buf.len() < this.max_read {
+                let data = match pipe.writes.front_mut() {
+                    Some((ready_at, data)) if *ready_at <= now => data,
+                    _ => break,
+                };
+                let take = data.len().min(this.max_read - this.buf.len());
+                this.buf.extend(data.drain(..take));
+                if data.is_empty() {
+                    pipe.writes.pop_front();
+                }
+            }
+
+            if this.buf.is_empty() {
+                match pipe.writes.front() {
+                    // Wake up when the next write is due.
+                    Some(&(ready_at, _)) => {
+                        let waker = cx.waker().clone();
+                        thread::spawn(move || {
+                            thread::sleep(ready_at.saturating_duration_since(Instant::now()));
+                            waker.wake();
+                        });
+                    }
+                    None if pipe.write_closed => return Poll::Ready(Ok(&[])),
+                    None => pipe.reader = Some(cx.waker().clone()),
+                }
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(Ok(&this.buf[this.pos..]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.pos = (this.pos + amt).min(this.buf.len());
+    }
+}
+
+impl AsyncRead for DuplexStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let available = match self.as_mut().poll_fill_buf(cx) {
+            Poll::Ready(Ok(available)) => available,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        };
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for DuplexStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = lock(&self.outbound);
+        if pipe.write_closed || pipe.read_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if !buf.is_empty() {
+            pipe.writes
+                .push_back((Instant::now() + self.latency, buf.to_vec()));
+            if let Some(reader) = pipe.reader.take() {
+                reader.wake();
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        close_write(&self.outbound);
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn close_write(pipe: &Mutex<Pipe>) {
+    let mut pipe = lock(pipe);
+    pipe.write_closed = true;
+    if let Some(reader) = pipe.reader.take() {
+        reader.wake();
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        close_write(&self.outbound);
+        lock(&self.inbound).read_closed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, join, AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_duplex() {
+        block_on(async {
+            let (mut a, mut b) = duplex();
+            a.write_all(b"ping").await.unwrap();
+            b.write_all(b"pong").await.unwrap();
+            let mut buf = [0; 4];
+            b.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            a.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+
+            // Reads wait for the other end to write.
+            let mut buf = [0; 5];
+            let (read, ()) = join!(b.read_exact(&mut buf), async {
+                a.write_all(b"hel").await.unwrap();
+                a.write_all(b"lo").await.unwrap();
+            });
+            read.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // Closing one end ends the other's reads, after what was
+            // already sent.
+            a.write_all(b"bye").await.unwrap();
+            a.close().await.unwrap();
+            let mut rest = Vec::new();
+            b.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, b"bye");
+
+            drop(b);
+            assert!(a.write_all(b"anyone?").await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_injection() {
+        block_on(async {
+            let options = DuplexOptions::new()
+                .latency(Duration::from_millis(20))
+                .max_read(3);
+            let (mut a, mut b) = duplex_with(&options);
+
+            let start = Instant::now();
+            a.write_all(b"abcdefg").await.unwrap();
+            let mut buf = [0; 16];
+            assert_eq!(b.read(&mut buf).await.unwrap(), 3);
+            assert!(start.elapsed() >= Duration::from_millis(20));
+            assert_eq!(&buf[..3], b"abc");
+            assert_eq!(b.read(&mut buf).await.unwrap(), 3);
+            assert_eq!(b.read(&mut buf).await.unwrap(), 1);
+            assert_eq!(&buf[..1], b"g");
+        });
+    }
+}
@@ -0,0 +1,195 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::runtime::Elapsed;
+use futures::{
+    future::{self, Either},
+    pin_mut,
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Condvar, Mutex, MutexGuard, OnceLock},
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Timers for socket operations that give up after a while. Sockets don't
+// have a runtime to sleep on, and a thread per operation would be far too
+// many, so a single thread wakes every timer, sleeping until the earliest
+// one is due.
+#[derive(Debug)]
+pub(crate) struct Sleep {
+    deadline: Instant,
+    // Set once the timer is waiting to be woken.
+    id: Option<u64>,
+}
+
+impl Sleep {
+    pub(crate) fn until(deadline: Instant) -> Sleep {
+        Sleep { deadline, id: None }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let (timers, cvar) = timers();
+        let mut timers = lock(timers);
+        match self.id {
+            Some(id) => timers.update(id, cx.waker()),
+            None => {
+                self.id = Some(timers.insert(self.deadline, cx.waker().clone()));
+                cvar.notify_one();
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            lock(&timers().0).remove(id);
+        }
+    }
+}
+
+/// Runs `fut`, giving up on it once `duration` has passed. Without a duration,
+/// it's given as long as it takes. This is the timer the sockets' own timeouts
+/// use, which needs no runtime.
+pub async fn timeout<F: Future>(duration: Option<Duration>, fut: F) -> Result<F::Output, Elapsed> {
+    let duration = match duration {
+        Some(duration) => duration,
+        None => return Ok(fut.await),
+    };
+    let sleep = Sleep::until(Instant::now() + duration);
+    pin_mut!(fut, sleep);
+    match future::select(fut, sleep).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(((), _)) => Err(Elapsed),
+    }
+}
+
+#[derive(Debug, Default)]
+struct Timers {
+    next_id: u64,
+    waiting: Vec<Timer>,
+}
+
+#[derive(Debug)]
+struct Timer {
+    id: u64,
+    deadline: Instant,
+    waker: Waker,
+}
+
+impl Timers {
+    fn insert(&mut self, deadline: Instant, waker: Waker) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiting.push(Timer {
+            id,
+            deadline,
+            waker,
+        });
+        id
+    }
+
+    // Timers that were already woken are gone, and aren't added back; the
+    // future they belong to is ready the next time it's polled.
+    fn update(&mut self, id: u64, waker: &Waker) {
+        if let Some(timer) = self.waiting.iter_mut().find(|timer| timer.id == id) {
+            if !timer.waker.will_wake(waker) {
+                timer.waker = waker.clone();
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some(idx) = self.waiting.iter().position(|timer| timer.id == id) {
+            self.waiting.swap_remove(idx);
+        }
+    }
+
+    // Takes out the timers that are due.
+    fn due(&mut self, now: Instant) -> Vec<Waker> {
+        let mut due = Vec::new();
+        let mut idx = 0;
+        while idx < self.waiting.len() {
+            if self.waiting[idx].deadline <= now {
+                due.push(self.waiting.swap_remove(idx).waker);
+            } else {
+                idx += 1;
+            }
+        }
+        due
+    }
+
+    fn earliest(&self) -> Option<Instant> {
+        self.waiting.iter().map(|timer| timer.deadline).min()
+    }
+}
+
+fn timers() -> &'static (Mutex<Timers>, Condvar) {
+    static TIMERS: OnceLock<&'static (Mutex<Timers>, Condvar)> = OnceLock::new();
+    TIMERS.get_or_init(|| {
+        let timers: &'static _ = Box::leak(Box::new((Mutex::default(), Condvar::new())));
+        thread::spawn(move || run(timers));
+        timers
+    })
+}
+
+fn run((timers, cvar): &(Mutex<Timers>, Condvar)) {
+    let mut guard = lock(timers);
+    loop {
+        let now = Instant::now();
+        let due = guard.due(now);
+        if !due.is_empty() {
+            drop(guard);
+            due.into_iter().for_each(Waker::wake);
+            guard = lock(timers);
+            continue;
+        }
+
+        guard = match guard.earliest() {
+            Some(deadline) => {
+                cvar.wait_timeout(guard, deadline - now)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0
+            }
+            None => cvar.wait(guard).unwrap_or_else(|e| e.into_inner()),
+        };
+    }
+}
+
+fn lock(timers: &Mutex<Timers>) -> MutexGuard<'_, Timers> {
+    timers.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_timeout() {
+        block_on(async {
+            let start = Instant::now();
+            let pending = timeout(Some(Duration::from_millis(20)), future::pending::<()>());
+            assert_eq!(pending.await, Err(Elapsed));
+            assert!(start.elapsed() >= Duration::from_millis(20));
+
+            let ready = timeout(Some(Duration::from_secs(10)), async { 1 });
+            assert_eq!(ready.await, Ok(1));
+            assert_eq!(timeout(None, async { 2 }).await, Ok(2));
+        });
+    }
+}
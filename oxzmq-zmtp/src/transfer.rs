@@ -0,0 +1,213 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// A chunked file transfer, based on the credit-based design from chapter 7 of
+// the ZeroMQ guide. The receiving DEALER asks for chunks by offset and keeps
+// a fixed number of requests in flight, so the sender never has more than
+// that many chunks queued for it. Since the receiver names the offsets, a
+// transfer that died part of the way through can be resumed from wherever
+// the receiver got to.
+//
+// The receiver sends `FETCH` messages made of the command, an 8-byte offset,
+// and a 4-byte chunk size, all big-endian. The sender answers each one with
+// the offset and the data at it. A chunk shorter than requested means the end
+// of the file. Once every request has been answered, the receiver sends
+// `DONE`.
+
+use crate::{
+    message::Message,
+    peer::Peer,
+    sockets::{Dealer, Router, SocketError},
+};
+use futures::io::{
+    self, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, SeekFrom,
+};
+use std::convert::TryFrom;
+
+const FETCH_COMMAND: &[u8] = b"FETCH";
+const DONE_COMMAND: &[u8] = b"DONE";
+
+const CHUNK_SIZE: u32 = 64 * 1024;
+// How many chunks the receiver asks for before waiting for one to arrive.
+const WINDOW: usize = 8;
+// The biggest chunk the sender will read for a single request, so that a
+// misbehaving receiver can't make it allocate arbitrary amounts of memory.
+const MAX_CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// Serves the file to the peers of `router` that ask for it with
+/// `recv_file`, until one of them says it has the whole thing.
+pub async fn send_file<P, R>(router: &mut Router<P>, file: &mut R) -> Result<(), TransferError>
+where
+    P: Peer,
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    loop {
+        let mut msg = router.recv().await?;
+        let routing_id = msg.pop_front().unwrap_or_default();
+
+        // Anything that isn't a valid request is ignored.
+        let (offset, size) = match msg.parts() {
+            [command] if command == DONE_COMMAND => return Ok(()),
+            [command, offset, size] if command == FETCH_COMMAND => {
+                match (
+                    <[u8; 8]>::try_from(&offset[..]),
+                    <[u8; 4]>::try_from(&size[..]),
+                ) {
+                    (Ok(offset), Ok(size)) => {
+                        (u64::from_be_bytes(offset), u32::from_be_bytes(size))
+                    }
+                    _ => continue,
+                }
+            }
+            _ => continue,
+        };
+
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut chunk = Vec::new();
+        (&mut *file)
+            .take(u64::from(size.min(MAX_CHUNK_SIZE)))
+            .read_to_end(&mut chunk)
+            .await?;
+
+        let reply = Message::from(vec![routing_id, offset.to_be_bytes().to_vec(), chunk]);
+        router.send(reply).await?;
+    }
+}
+
+/// Fetches a file from a peer running `send_file`, writing it to `file`
+/// starting at `offset`. Pass the number of bytes already received to resume
+/// an earlier transfer. Returns the size of the whole file.
+pub async fn recv_file<P, W>(
+    dealer: &mut Dealer<P>,
+    file: &mut W,
+    offset: u64,
+) -> Result<u64, TransferError>
+where
+    P: Peer,
+    W: AsyncWrite + Unpin,
+{
+    let mut next_fetch = offset;
+    let mut received = offset;
+    let mut in_flight = 0;
+    let mut finished = false;
+
+    loop {
+        while !finished && in_flight < WINDOW {
+            let fetch = Message::from(vec![
+                FETCH_COMMAND.to_vec(),
+                next_fetch.to_be_bytes().to_vec(),
+                CHUNK_SIZE.to_be_bytes().to_vec(),
+            ]);
+            dealer.send(fetch).await?;
+            next_fetch += u64::from(CHUNK_SIZE);
+            in_flight += 1;
+        }
+
+        // Wait for the answers to requests past the end of the file, so that
+        // they don't turn up in the middle of whatever comes next.
+        if in_flight == 0 {
+            break;
+        }
+
+        let msg = dealer.recv().await?;
+        in_flight -= 1;
+        let (chunk_offset, chunk) = match msg.parts() {
+            [chunk_offset, chunk] => match <[u8; 8]>::try_from(&chunk_offset[..]) {
+                Ok(chunk_offset) => (u64::from_be_bytes(chunk_offset), chunk),
+                Err(_) => return Err(TransferError::MalformedChunk),
+            },
+            _ => return Err(TransferError::MalformedChunk),
+        };
+        if finished || chunk_offset != received {
+            continue;
+        }
+
+        file.write_all(chunk).await?;
+        received += chunk.len() as u64;
+        finished = chunk.len() < CHUNK_SIZE as usize;
+    }
+
+    file.flush().await?;
+    dealer.send(Message::from(DONE_COMMAND)).await?;
+    Ok(received)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TransferError {
+    #[error("error communicating with peer")]
+    Socket(#[from] SocketError),
+
+    #[error("error accessing file")]
+    Io(#[from] io::Error),
+
+    #[error("peer sent a malformed chunk")]
+    MalformedChunk,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{peer::ChannelPeer, socket::SocketType};
+    use futures::{executor::block_on, io::Cursor};
+
+    fn connected() -> (Dealer<ChannelPeer>, Router<ChannelPeer>) {
+        let mut dealer = Dealer::new();
+        let mut router = Router::new();
+        let (local, remote) = ChannelPeer::pair(SocketType::Dealer, SocketType::Router);
+        dealer.attach(local).unwrap();
+        router.attach(remote).unwrap();
+        (dealer, router)
+    }
+
+    #[test]
+    fn test_transfer() {
+        block_on(async {
+            let (mut dealer, mut router) = connected();
+            let data = (0..3 * CHUNK_SIZE + 100)
+                .map(|i| i as u8)
+                .collect::<Vec<_>>();
+            let mut src = Cursor::new(data.clone());
+            let mut dst = Vec::new();
+
+            let (sent, received) = futures::join!(
+                send_file(&mut router, &mut src),
+                recv_file(&mut dealer, &mut dst, 0)
+            );
+            sent.unwrap();
+            assert_eq!(received.unwrap(), data.len() as u64);
+            assert_eq!(dst, data);
+
+            // A file that's an exact number of chunks long ends with an empty
+            // chunk.
+            let data = vec![7; CHUNK_SIZE as usize];
+            let mut src = Cursor::new(data.clone());
+            let mut dst = Vec::new();
+            let (sent, received) = futures::join!(
+                send_file(&mut router, &mut src),
+                recv_file(&mut dealer, &mut dst, 0)
+            );
+            sent.unwrap();
+            assert_eq!(received.unwrap(), data.len() as u64);
+            assert_eq!(dst, data);
+        });
+    }
+
+    #[test]
+    fn test_resume() {
+        block_on(async {
+            let (mut dealer, mut router) = connected();
+            let data = (0..2 * CHUNK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+            let mut src = Cursor::new(data.clone());
+            let mut dst = data[..1000].to_vec();
+
+            let (sent, received) = futures::join!(
+                send_file(&mut router, &mut src),
+                recv_file(&mut dealer, &mut dst, 1000)
+            );
+            sent.unwrap();
+            assert_eq!(received.unwrap(), data.len() as u64);
+            assert_eq!(dst, data);
+        });
+    }
+}
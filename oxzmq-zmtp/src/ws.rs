@@ -0,0 +1,830 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    endpoint::{Endpoint, Resolver},
+    event::EventKind,
+    handshake::MAX_COMMAND_LEN,
+    message::Message,
+    options::{ConnectionOptions, MessageLimits},
+    peer::{CloseReason, Origin, Peer, PeerError},
+    properties::{Properties, PropertiesEncodeError, PropertiesParseError},
+    socket::{SocketType, SocketTypeFromBytesError},
+    tcp::{TcpListener, TcpStream},
+};
+use futures::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use std::convert::TryFrom;
+
+pub use self::{message::WsMessagePeer, upgrade::UpgradeError};
+
+mod message;
+mod upgrade;
+
+// More info: https://www.rfc-editor.org/rfc/rfc6455#section-5.2
+const FIN_BIT: u8 = 0x80;
+const MASK_BIT: u8 = 0x80;
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+const CLOSE_NORMAL: u16 = 1000;
+
+// More info: https://rfc.zeromq.org/spec/45/
+//
+// Every ZMTP frame travels as a WebSocket message of its own, behind a byte
+// of flags.
+const FLAG_MORE: u8 = 0x01;
+const FLAG_COMMAND: u8 = 0x02;
+
+/// A connection over WebSocket, ready to be attached to a socket.
+pub type WsConnection = WsPeer<BufReader<TcpStream>>;
+
+impl WsConnection {
+    /// Connects to a `ws://` endpoint, upgrades the connection and performs
+    /// the handshake.
+    pub async fn connect<R: Resolver>(
+        endpoint: &Endpoint,
+        resolver: &R,
+        socket_type: &SocketType,
+        options: &ConnectionOptions,
+    ) -> Result<WsConnection, WsError> {
+        let (host, port, path) = match endpoint {
+            Endpoint::Ws { host, port, path } => (host, port, path),
+            _ => return Err(WsError::NotWs(endpoint.to_string())),
+        };
+        if options.is_stopped(endpoint) {
+            return Err(WsError::ReconnectStopped(endpoint.clone()));
+        }
+        let stream = match TcpStream::connect_with(endpoint, resolver, options.tcp_options()).await
+        {
+            Ok(stream) => stream,
+            Err(err) => {
+                let reason = CloseReason::Io(err.kind());
+                let endpoint = endpoint.clone();
+                options.emit(*socket_type, EventKind::ConnectFailed { endpoint, reason });
+                return Err(err.into());
+            }
+        };
+        options.emit(
+            *socket_type,
+            EventKind::Connected {
+                endpoint: endpoint.clone(),
+            },
+        );
+
+        let host = format!("{}:{}", host, port);
+        let result =
+            WsPeer::client(BufReader::new(stream), &host, path, socket_type, options).await;
+        let origin = Origin::Connected(endpoint.clone());
+        report_handshake(result, socket_type, options, origin)
+    }
+
+    /// Accepts the next connection to a listener bound to a `ws://`
+    /// endpoint, and answers its upgrade request and handshake. Requests for
+    /// any path are accepted.
+    pub async fn accept(
+        listener: &TcpListener,
+        socket_type: &SocketType,
+        options: &ConnectionOptions,
+    ) -> Result<WsConnection, WsError> {
+        let stream = listener.accept_with(options.tcp_options()).await?;
+        let endpoint = listener.last_endpoint()?;
+        options.emit(
+            *socket_type,
+            EventKind::Accepted {
+                endpoint: endpoint.clone(),
+            },
+        );
+
+        let result = WsPeer::server(BufReader::new(stream), socket_type, options).await;
+        report_handshake(result, socket_type, options, Origin::Accepted(endpoint))
+    }
+}
+
+// Reports how the upgrade and handshake with a new peer went, and has the
+// peer remember how it was reached.
+fn report_handshake(
+    result: Result<WsConnection, WsError>,
+    socket_type: &SocketType,
+    options: &ConnectionOptions,
+    origin: Origin,
+) -> Result<WsConnection, WsError> {
+    match result {
+        Ok(mut peer) => {
+            let kind = EventKind::HandshakeSucceeded {
+                origin: origin.clone(),
+                peer_type: peer.remote_socket_type,
+            };
+            options.emit(*socket_type, kind);
+            peer.origin = Some(origin);
+            Ok(peer)
+        }
+        Err(err) => {
+            let reason = err.close_reason();
+            options.emit(*socket_type, EventKind::HandshakeFailed { origin, reason });
+            Err(err)
+        }
+    }
+}
+
+/// A peer reached over ZMTP-over-WebSocket (ZWS 2.0), as spoken by libzmq's
+/// `ws://` transport and browser clients. Only the NULL mechanism is
+/// supported.
+#[derive(Debug)]
+pub struct WsPeer<S> {
+    stream: S,
+    // Clients have to mask what they send, and servers mustn't.
+    masks: bool,
+    remote_socket_type: SocketType,
+    remote_routing_id: Option<Vec<u8>>,
+    remote_weight: Option<u32>,
+    origin: Option<Origin>,
+    // Whatever has been read of the WebSocket frame, data message and ZWS
+    // message coming in, kept here so that receiving can stop anywhere and
+    // start again.
+    incoming: Vec<u8>,
+    fragments: Vec<u8>,
+    parts: Parts,
+    // WebSocket frames that haven't all been written yet.
+    outgoing: Vec<u8>,
+}
+
+impl<S: AsyncBufRead + AsyncWrite + Unpin> WsPeer<S> {
+    /// Asks the server at the other end of the stream to upgrade it, then
+    /// performs the handshake. `host` is sent in the `Host` header.
+    pub async fn client(
+        mut stream: S,
+        host: &str,
+        path: &str,
+        socket_type: &SocketType,
+        options: &ConnectionOptions,
+    ) -> Result<WsPeer<S>, WsError> {
+        upgrade::request(&mut stream, host, path).await?;
+        WsPeer::handshake(stream, true, socket_type, options).await
+    }
+
+    /// Answers the upgrade request the client at the other end of the stream
+    /// sends, then performs the handshake.
+    pub async fn server(
+        mut stream: S,
+        socket_type: &SocketType,
+        options: &ConnectionOptions,
+    ) -> Result<WsPeer<S>, WsError> {
+        upgrade::respond(&mut stream).await?;
+        WsPeer::handshake(stream, false, socket_type, options).await
+    }
+
+    // Both ends send READY straight away, and then read the other's.
+    async fn handshake(
+        stream: S,
+        masks: bool,
+        socket_type: &SocketType,
+        options: &ConnectionOptions,
+    ) -> Result<WsPeer<S>, WsError> {
+        let ready = ready_command(socket_type, options)?;
+        let mut peer = WsPeer {
+            stream,
+            masks,
+            remote_socket_type: *socket_type,
+            remote_routing_id: None,
+            remote_weight: None,
+            origin: None,
+            incoming: Vec::new(),
+            fragments: Vec::new(),
+            parts: Parts::new(options),
+            outgoing: Vec::new(),
+        };
+        peer.write_message(OPCODE_BINARY, &ready).await?;
+
+        let received = peer
+            .read_message(MAX_COMMAND_LEN)
+            .await
+            .map_err(|err| match err {
+                PeerError::Io(err) => WsError::Io(err),
+                _ => WsError::NoReadyCommand,
+            })?;
+        let remote = Ready::parse(&received, socket_type)?;
+        peer.remote_socket_type = remote.socket_type;
+        peer.remote_routing_id = remote.routing_id;
+        peer.remote_weight = remote.weight;
+
+        Ok(peer)
+    }
+
+    // Writes one unfragmented WebSocket frame.
+    async fn write_message(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        self.queue_message(opcode, payload);
+        self.flush_outgoing().await
+    }
+
+    // Adds one unfragmented WebSocket frame to what's to be written.
+    fn queue_message(&mut self, opcode: u8, payload: &[u8]) {
+        let frame = &mut self.outgoing;
+        frame.reserve(14 + payload.len());
+        frame.push(FIN_BIT | opcode);
+
+        let mask_bit = if self.masks { MASK_BIT } else { 0 };
+        match payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        if self.masks {
+            let mask = (upgrade::random_u64() as u32).to_be_bytes();
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+    }
+
+    // Writes whatever is queued. What's written is taken off as it goes, so
+    // nothing is written twice if this is dropped part of the way through.
+    async fn flush_outgoing(&mut self) -> io::Result<()> {
+        if self.outgoing.is_empty() {
+            return Ok(());
+        }
+        while !self.outgoing.is_empty() {
+            let written = self.stream.write(&self.outgoing).await?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.outgoing.drain(..written);
+        }
+        self.stream.flush().await
+    }
+
+    // Reads a whole data message, putting fragments back together and
+    // answering control frames along the way. A message longer than
+    // `max_len` is refused before more of it is read than fits.
+    async fn read_message(&mut self, max_len: u64) -> Result<Vec<u8>, PeerError> {
+        loop {
+            // Answers to control frames go out before anything more is read.
+            self.flush_outgoing().await?;
+            self.read_frame(max_len).await?;
+            let header_len = ws_header_len(&self.incoming);
+            let fin = self.incoming[0] & FIN_BIT != 0;
+            let opcode = self.incoming[0] & 0x0F;
+
+            let mut payload = self.incoming.split_off(header_len);
+            if self.incoming[1] & MASK_BIT != 0 {
+                let mask = &self.incoming[header_len - 4..];
+                payload
+                    .iter_mut()
+                    .zip(mask.iter().cycle())
+                    .for_each(|(b, m)| *b ^= m);
+            }
+            self.incoming.clear();
+
+            match opcode {
+                OPCODE_PING => self.queue_message(OPCODE_PONG, &payload),
+                OPCODE_PONG => (),
+                OPCODE_CLOSE => {
+                    // Echo the status code back, as the close handshake asks.
+                    self.queue_message(OPCODE_CLOSE, payload.get(..2).unwrap_or(&[]));
+                    let _ = self.flush_outgoing().await;
+                    return Err(PeerError::Closed);
+                }
+                OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    self.fragments.extend_from_slice(&payload);
+                    if fin {
+                        return Ok(std::mem::take(&mut self.fragments));
+                    }
+                }
+                _ => return Err(invalid_data("expected a binary WebSocket message").into()),
+            }
+        }
+    }
+
+    // Reads until `incoming` holds one whole WebSocket frame. Only as much is
+    // taken from the stream as the frame still needs, which its header gives
+    // once enough of it is in. A data frame that would make the message
+    // longer than `max_len` is refused as soon as its header is in.
+    async fn read_frame(&mut self, max_len: u64) -> Result<(), PeerError> {
+        loop {
+            let header_len = ws_header_len(&self.incoming);
+            let wanted = match self.incoming.len() < header_len {
+                true => header_len,
+                false => ws_frame_len(&self.incoming, header_len)?,
+            };
+            let is_data = matches!(
+                self.incoming.first().map(|&first| first & 0x0F),
+                Some(OPCODE_BINARY | OPCODE_CONTINUATION)
+            );
+            let len = (self.fragments.len() + wanted - header_len) as u64;
+            if self.incoming.len() >= header_len && is_data && len > max_len {
+                return Err(PeerError::MessageTooLarge);
+            }
+            if self.incoming.len() >= header_len && self.incoming.len() == wanted {
+                return Ok(());
+            }
+
+            let buf = self.stream.fill_buf().await?;
+            if buf.is_empty() {
+                return match self.incoming.is_empty() {
+                    true => Err(PeerError::Disconnected),
+                    false => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                };
+            }
+            let take = buf.len().min(wanted - self.incoming.len());
+            self.incoming.extend_from_slice(&buf[..take]);
+            self.stream.consume_unpin(take);
+        }
+    }
+}
+
+impl<S: AsyncBufRead + AsyncWrite + Unpin> Peer for WsPeer<S> {
+    fn remote_socket_type(&self) -> SocketType {
+        self.remote_socket_type
+    }
+
+    fn routing_id(&self) -> Option<&[u8]> {
+        self.remote_routing_id.as_deref()
+    }
+
+    fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+
+    fn weight(&self) -> Option<u32> {
+        self.remote_weight
+    }
+
+    async fn send_message(&mut self, msg: Message) -> Result<(), PeerError> {
+        for frame in zws_frames(msg) {
+            self.write_message(OPCODE_BINARY, &frame).await?;
+        }
+        Ok(())
+    }
+
+    async fn recv_message(&mut self) -> Result<Message, PeerError> {
+        loop {
+            let frame = self.read_message(self.parts.max_frame_len()).await?;
+            match receive_zws(&frame, &mut self.parts)? {
+                Received::Message(msg) => return Ok(msg),
+                // It's written before the next frame is read.
+                Received::Reply(reply) => self.queue_message(OPCODE_BINARY, &reply),
+                Received::Nothing => (),
+            }
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), PeerError> {
+        self.write_message(OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes())
+            .await?;
+        Ok(())
+    }
+}
+
+// The READY command that starts the handshake. There's no greeting in ZWS:
+// the subprotocol already picked the mechanism, and only NULL is supported.
+fn ready_command(
+    socket_type: &SocketType,
+    options: &ConnectionOptions,
+) -> Result<Vec<u8>, WsError> {
+    if options.plain_role().is_some() {
+        return Err(WsError::UnsupportedMechanism);
+    }
+
+    let mut properties = Properties::new();
+    properties.set_socket_type(*socket_type);
+    if let Some(routing_id) = options.identity() {
+        properties.set_identity(routing_id.to_vec())?;
+    }
+    if let Some(weight) = options.advertised_weight() {
+        properties.set_weight(weight);
+    }
+    let mut ready = vec![FLAG_COMMAND, 5];
+    ready.extend_from_slice(b"READY");
+    properties.write_to(&mut ready);
+    Ok(ready)
+}
+
+// What a peer said about itself in its READY command.
+struct Ready {
+    socket_type: SocketType,
+    routing_id: Option<Vec<u8>>,
+    weight: Option<u32>,
+}
+
+impl Ready {
+    // Reads the READY command a peer answered ours with, and checks that a
+    // socket of type `socket_type` can talk to it.
+    fn parse(received: &[u8], socket_type: &SocketType) -> Result<Ready, WsError> {
+        let data = match split_command(received) {
+            Some(("READY", data)) => data,
+            _ => return Err(WsError::NoReadyCommand),
+        };
+        let properties = Properties::decode(data)?;
+
+        let remote_type = properties
+            .socket_type()?
+            .ok_or(WsError::MissingRemoteSocketType)?;
+        if !socket_type.valid_socket_combo(&remote_type) {
+            return Err(WsError::InvalidSocketCombination(*socket_type, remote_type));
+        }
+        Ok(Ready {
+            socket_type: remote_type,
+            routing_id: properties.identity().map(<[u8]>::to_vec),
+            weight: properties.weight(),
+        })
+    }
+}
+
+// Each part of `msg` as a ZWS frame, which is the part behind a byte of flags.
+fn zws_frames(msg: Message) -> impl Iterator<Item = Vec<u8>> {
+    let parts = msg.into_parts();
+    let last = parts.len().saturating_sub(1);
+    parts.into_iter().enumerate().map(move |(i, part)| {
+        let mut frame = Vec::with_capacity(1 + part.len());
+        frame.push(if i < last { FLAG_MORE } else { 0 });
+        frame.extend_from_slice(&part);
+        frame
+    })
+}
+
+// What came of a ZWS frame that was received.
+enum Received {
+    // The frame was the last part of a message.
+    Message(Message),
+
+    // The frame was a command that has to be answered with this frame.
+    Reply(Vec<u8>),
+
+    // The frame was a part with more to follow, which went into `parts`, or
+    // a command that needs no answer.
+    Nothing,
+}
+
+// The parts of the ZWS message coming in, kept so that receiving can stop
+// between them and start again, and held to the connection's limits.
+#[derive(Debug, Default)]
+struct Parts {
+    parts: Message,
+    bytes: u64,
+    limits: MessageLimits,
+}
+
+impl Parts {
+    fn new(options: &ConnectionOptions) -> Parts {
+        Parts {
+            limits: options.message_limits(),
+            ..Parts::default()
+        }
+    }
+
+    // How long the next ZWS frame can be, flags included, without taking
+    // the message over the byte limit.
+    fn max_frame_len(&self) -> u64 {
+        match self.limits.max_size {
+            Some(max) => max.saturating_sub(self.bytes).saturating_add(1),
+            None => u64::MAX,
+        }
+    }
+}
+
+// Handles one ZWS frame, adding it to `parts` if it's part of a message.
+fn receive_zws(frame: &[u8], parts: &mut Parts) -> Result<Received, PeerError> {
+    let (&flags, data) = frame
+        .split_first()
+        .ok_or_else(|| invalid_data("ZWS frame without flags"))?;
+
+    if flags & FLAG_COMMAND != 0 {
+        return match split_command(frame) {
+            Some(("ERROR", data)) => {
+                let reason = data.get(1..).unwrap_or(&[]);
+                Err(PeerError::Rejected(
+                    String::from_utf8_lossy(reason).into_owned(),
+                ))
+            }
+            Some(("PING", ping)) => {
+                // The PONG echoes the context after the PING's TTL.
+                let mut pong = vec![FLAG_COMMAND, 4];
+                pong.extend_from_slice(b"PONG");
+                pong.extend_from_slice(ping.get(2..).unwrap_or(&[]));
+                Ok(Received::Reply(pong))
+            }
+            // Peers ignore commands they don't know.
+            _ => Ok(Received::Nothing),
+        };
+    }
+
+    parts.bytes += data.len() as u64;
+    parts.limits.check(parts.parts.len() + 1, parts.bytes)?;
+    parts.parts.push_back(data.to_vec());
+    Ok(match flags & FLAG_MORE {
+        0 => {
+            parts.bytes = 0;
+            Received::Message(std::mem::take(&mut parts.parts))
+        }
+        _ => Received::Nothing,
+    })
+}
+
+// How long the header is of the WebSocket frame that `frame` starts, as far
+// as can be told: the first two bytes give the rest.
+fn ws_header_len(frame: &[u8]) -> usize {
+    let second = match frame.get(1) {
+        Some(&second) => second,
+        None => return 2,
+    };
+    let len_len = match second & !MASK_BIT {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask_len = if second & MASK_BIT != 0 { 4 } else { 0 };
+    2 + len_len + mask_len
+}
+
+// How long the WebSocket frame is whose header, `header_len` bytes long, is
+// at the start of `frame`.
+fn ws_frame_len(frame: &[u8], header_len: usize) -> io::Result<usize> {
+    let len = match frame[1] & !MASK_BIT {
+        126 => u64::from(u16::from_be_bytes([frame[2], frame[3]])),
+        127 => u64::from_be_bytes(<[u8; 8]>::try_from(&frame[2..10]).unwrap()),
+        len => u64::from(len),
+    };
+    usize::try_from(len)
+        .ok()
+        .and_then(|len| len.checked_add(header_len))
+        .ok_or_else(|| invalid_data("frame too long"))
+}
+
+// Splits a command frame, flags byte included, into its name and data.
+fn split_command(frame: &[u8]) -> Option<(&str, &[u8])> {
+    match frame {
+        [flags, name_len, rest @ ..] if flags & FLAG_COMMAND != 0 => {
+            let name_len = usize::from(*name_len);
+            let name = std::str::from_utf8(rest.get(..name_len)?).ok()?;
+            Some((name, &rest[name_len..]))
+        }
+        _ => None,
+    }
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WsError {
+    #[error("error reading data stream")]
+    Io(#[from] io::Error),
+
+    #[error("not a ws endpoint: {0}")]
+    NotWs(String),
+
+    #[error("could not upgrade to WebSocket")]
+    Upgrade(#[from] UpgradeError),
+
+    #[error("only the NULL mechanism is supported over WebSocket")]
+    UnsupportedMechanism,
+
+    #[error("peer did not send READY command")]
+    NoReadyCommand,
+
+    #[error("could not parse properties")]
+    PropertiesParse(#[from] PropertiesParseError),
+
+    #[error("could not encode properties")]
+    PropertiesEncode(#[from] PropertiesEncodeError),
+
+    #[error("invalid remote socket type")]
+    UnsupportedRemoteSocketType(#[from] SocketTypeFromBytesError),
+
+    #[error("invalid socket combination: {:?} with {:?}", .0, .1)]
+    InvalidSocketCombination(SocketType, SocketType),
+
+    #[error("remote peer must provide socket type")]
+    MissingRemoteSocketType,
+
+    #[error("gave up connecting to {0} after its handshake failed")]
+    ReconnectStopped(Endpoint),
+}
+
+impl WsError {
+    /// Why the connection ended before it was set up.
+    pub fn close_reason(&self) -> CloseReason {
+        match self {
+            WsError::Io(err) => CloseReason::Io(err.kind()),
+            WsError::InvalidSocketCombination(..) => CloseReason::InvalidSocketCombination,
+            _ => CloseReason::Protocol,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        endpoint::StaticResolver,
+        event::EventSink,
+        sockets::{Dealer, Router},
+        testing,
+    };
+    use futures::{executor::block_on, io::AsyncReadExt, join, FutureExt, StreamExt};
+
+    #[test]
+    fn test_connect_and_accept() {
+        block_on(async {
+            let listener = TcpListener::bind(&"ws://127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let endpoint = format!("ws://127.0.0.1:{}/zmq", port).parse().unwrap();
+
+            let resolver = StaticResolver::new();
+            let client_options = ConnectionOptions::new().routing_id(b"browser".to_vec());
+            let server_options = ConnectionOptions::new();
+            let (client, server) = join!(
+                WsConnection::connect(&endpoint, &resolver, &SocketType::Dealer, &client_options),
+                WsConnection::accept(&listener, &SocketType::Router, &server_options)
+            );
+
+            let mut dealer = Dealer::new();
+            dealer.attach(client.unwrap()).unwrap();
+            let mut router = Router::new();
+            let routing_id = router.attach(server.unwrap()).unwrap();
+            assert_eq!(routing_id, b"browser");
+
+            // Big enough to need the longest length field.
+            let big = vec![7; 70_000];
+            dealer
+                .send(Message::from(vec![b"hello".to_vec(), big.clone()]))
+                .await
+                .unwrap();
+            assert_eq!(
+                router.recv().await.unwrap(),
+                Message::from(vec![b"browser".to_vec(), b"hello".to_vec(), big])
+            );
+
+            router
+                .send(Message::from(vec![b"browser".to_vec(), Vec::new()]))
+                .await
+                .unwrap();
+            assert_eq!(
+                dealer.recv().await.unwrap(),
+                Message::from(vec![Vec::new()])
+            );
+        });
+    }
+
+    #[test]
+    fn test_connection_events() {
+        block_on(async {
+            let listener = TcpListener::bind(&"ws://127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let endpoint: Endpoint = listener.last_endpoint().unwrap();
+            let (sink, events) = EventSink::channel();
+            let options = ConnectionOptions::new().event_sink(sink);
+
+            let resolver = StaticResolver::new();
+            let (client, server) = join!(
+                WsConnection::connect(&endpoint, &resolver, &SocketType::Dealer, &options),
+                WsConnection::accept(&listener, &SocketType::Router, &options)
+            );
+            client.unwrap();
+            server.unwrap();
+            drop(options);
+
+            let mut kinds: Vec<_> = events.map(|event| event.kind().clone()).collect().await;
+            kinds.sort_by_key(|kind| format!("{:?}", kind));
+            assert_eq!(
+                kinds,
+                vec![
+                    EventKind::Accepted {
+                        endpoint: endpoint.clone()
+                    },
+                    EventKind::Connected {
+                        endpoint: endpoint.clone()
+                    },
+                    EventKind::HandshakeSucceeded {
+                        origin: Origin::Accepted(endpoint.clone()),
+                        peer_type: SocketType::Dealer,
+                    },
+                    EventKind::HandshakeSucceeded {
+                        origin: Origin::Connected(endpoint.clone()),
+                        peer_type: SocketType::Router,
+                    },
+                ]
+            );
+
+            // Nothing is listening any more.
+            drop(listener);
+            let (sink, mut events) = EventSink::channel();
+            let options = ConnectionOptions::new().event_sink(sink);
+            assert!(
+                WsConnection::connect(&endpoint, &resolver, &SocketType::Dealer, &options)
+                    .await
+                    .is_err()
+            );
+            assert!(matches!(
+                events.next().await.unwrap().kind(),
+                EventKind::ConnectFailed { .. }
+            ));
+        });
+    }
+
+    // A peer that skipped the upgrade and handshake.
+    fn peer<S>(stream: S, masks: bool) -> WsPeer<S> {
+        WsPeer {
+            stream,
+            masks,
+            remote_socket_type: SocketType::Dealer,
+            remote_routing_id: None,
+            remote_weight: None,
+            origin: None,
+            incoming: Vec::new(),
+            fragments: Vec::new(),
+            parts: Parts::default(),
+            outgoing: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_message_limits() {
+        block_on(async {
+            let options = ConnectionOptions::new()
+                .max_message_size(8)
+                .max_message_parts(2);
+            let receiver = |input: Vec<u8>| {
+                let mut receiver = peer(io::Cursor::new(input), false);
+                receiver.parts = Parts::new(&options);
+                receiver
+            };
+
+            // A frame's length is checked before its data arrives.
+            let mut input = vec![FIN_BIT | OPCODE_BINARY, 127];
+            input.extend_from_slice(&(1_u64 << 30).to_be_bytes());
+            assert!(matches!(
+                receiver(input).recv_message().await,
+                Err(PeerError::MessageTooLarge)
+            ));
+
+            // The parts received so far count too.
+            let mut sender = peer(io::Cursor::new(Vec::new()), true);
+            sender.queue_message(OPCODE_BINARY, &[FLAG_MORE, 1, 2, 3, 4, 5]);
+            sender.queue_message(OPCODE_BINARY, &[0, 6, 7, 8, 9]);
+            assert!(matches!(
+                receiver(sender.outgoing).recv_message().await,
+                Err(PeerError::MessageTooLarge)
+            ));
+
+            let mut sender = peer(io::Cursor::new(Vec::new()), true);
+            for _ in 0..2 {
+                sender.queue_message(OPCODE_BINARY, &[FLAG_MORE, 1]);
+            }
+            sender.queue_message(OPCODE_BINARY, &[0, 1]);
+            assert!(matches!(
+                receiver(sender.outgoing).recv_message().await,
+                Err(PeerError::MessageTooLarge)
+            ));
+        });
+    }
+
+    // Receives that are given up on part of the way through a frame lose
+    // nothing, and the PING among the frames is still answered once.
+    #[test]
+    fn test_cancelled_recv() {
+        block_on(async {
+            // Masked, as a client sends them, with the first part split into
+            // fragments long enough to need the 16-bit length.
+            let mut sender = peer(io::Cursor::new(Vec::new()), true);
+            let first = [&[FLAG_MORE][..], &[3; 200]].concat();
+            sender.queue_message(OPCODE_BINARY, &first[..150]);
+            sender.outgoing[0] &= !FIN_BIT;
+            sender.queue_message(OPCODE_PING, b"ping");
+            sender.queue_message(OPCODE_CONTINUATION, &first[150..]);
+            sender.queue_message(OPCODE_BINARY, b"\x00last");
+            let input = sender.outgoing;
+
+            let (mut a, b) = testing::duplex();
+            let mut receiver = peer(b, false);
+            let mut received = Vec::new();
+            for chunk in input.chunks(5) {
+                a.write_all(chunk).await.unwrap();
+                if let Some(msg) = receiver.recv_message().now_or_never() {
+                    received.push(msg.unwrap());
+                }
+            }
+            let expected = Message::from(vec![vec![3; 200], b"last".to_vec()]);
+            assert_eq!(received, vec![expected]);
+
+            let mut pong = [0; 6];
+            a.read_exact(&mut pong).await.unwrap();
+            assert_eq!(pong, [FIN_BIT | OPCODE_PONG, 4, b'p', b'i', b'n', b'g']);
+            assert!(a.fill_buf().now_or_never().is_none());
+        });
+    }
+}
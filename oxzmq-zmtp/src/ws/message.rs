@@ -0,0 +1,204 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use super::{ready_command, receive_zws, zws_frames, Parts, Ready, Received, WsError};
+use crate::{
+    message::Message,
+    options::ConnectionOptions,
+    peer::{Peer, PeerError},
+    socket::SocketType,
+};
+use futures::{io, Sink, SinkExt, Stream, StreamExt};
+
+/// A peer reached over ZWS 2.0 through a WebSocket that's already open and
+/// deals in whole binary messages, as a browser's does. The WebSocket takes
+/// care of framing, masking, pings and closing, so this only handles the
+/// ZWS frames inside the messages, one per message.
+///
+/// Nothing here needs threads or TCP, so this is what a browser client is
+/// built on: wrap the browser's WebSocket in a `Stream` of the messages it
+/// receives and a `Sink` of the ones to send, opened with the `ZWS2.0`
+/// subprotocol, and attach the peer to a socket.
+#[derive(Debug)]
+pub struct WsMessagePeer<T> {
+    messages: T,
+    remote_socket_type: SocketType,
+    remote_routing_id: Option<Vec<u8>>,
+    remote_weight: Option<u32>,
+    parts: Parts,
+}
+
+impl<T> WsMessagePeer<T>
+where
+    T: Stream<Item = io::Result<Vec<u8>>> + Sink<Vec<u8>, Error = io::Error> + Unpin,
+{
+    /// Performs the handshake over the open WebSocket. Only the NULL
+    /// mechanism is supported.
+    pub async fn new(
+        mut messages: T,
+        socket_type: &SocketType,
+        options: &ConnectionOptions,
+    ) -> Result<WsMessagePeer<T>, WsError> {
+        messages.send(ready_command(socket_type, options)?).await?;
+        let received = messages.next().await.ok_or(WsError::NoReadyCommand)??;
+        let remote = Ready::parse(&received, socket_type)?;
+        Ok(WsMessagePeer {
+            messages,
+            remote_socket_type: remote.socket_type,
+            remote_routing_id: remote.routing_id,
+            remote_weight: remote.weight,
+            parts: Parts::new(options),
+        })
+    }
+}
+
+impl<T> Peer for WsMessagePeer<T>
+where
+    T: Stream<Item = io::Result<Vec<u8>>> + Sink<Vec<u8>, Error = io::Error> + Unpin,
+{
+    fn remote_socket_type(&self) -> SocketType {
+        self.remote_socket_type
+    }
+
+    fn routing_id(&self) -> Option<&[u8]> {
+        self.remote_routing_id.as_deref()
+    }
+
+    fn weight(&self) -> Option<u32> {
+        self.remote_weight
+    }
+
+    async fn send_message(&mut self, msg: Message) -> Result<(), PeerError> {
+        for frame in zws_frames(msg) {
+            self.messages.feed(frame).await?;
+        }
+        self.messages.flush().await?;
+        Ok(())
+    }
+
+    async fn recv_message(&mut self) -> Result<Message, PeerError> {
+        loop {
+            let frame = match self.messages.next().await {
+                Some(frame) => frame?,
+                None => return Err(PeerError::Disconnected),
+            };
+            match receive_zws(&frame, &mut self.parts)? {
+                Received::Message(msg) => return Ok(msg),
+                Received::Reply(reply) => self.messages.send(reply).await?,
+                Received::Nothing => (),
+            }
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), PeerError> {
+        self.messages.close().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sockets::{Dealer, Router};
+    use futures::{channel::mpsc, executor::block_on, join};
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    // One end of an in-memory WebSocket that carries whole messages.
+    struct Channel {
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+        rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    }
+
+    fn websocket() -> (Channel, Channel) {
+        let (a_tx, a_rx) = mpsc::unbounded();
+        let (b_tx, b_rx) = mpsc::unbounded();
+        let a = Channel { tx: a_tx, rx: b_rx };
+        let b = Channel { tx: b_tx, rx: a_rx };
+        (a, b)
+    }
+
+    impl Stream for Channel {
+        type Item = io::Result<Vec<u8>>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.rx.poll_next_unpin(cx).map(|msg| msg.map(Ok))
+        }
+    }
+
+    impl Sink<Vec<u8>> for Channel {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, msg: Vec<u8>) -> io::Result<()> {
+            self.tx
+                .unbounded_send(msg)
+                .map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.tx.close_channel();
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        block_on(async {
+            let (client, server) = websocket();
+            let options = ConnectionOptions::new();
+            let (client, server) = join!(
+                WsMessagePeer::new(client, &SocketType::Dealer, &options),
+                WsMessagePeer::new(server, &SocketType::Router, &options),
+            );
+
+            let mut dealer = Dealer::new();
+            let mut router = Router::new();
+            dealer.attach(client.unwrap()).unwrap();
+            let id = router.attach(server.unwrap()).unwrap();
+
+            let mut msg = Message::new();
+            msg.push_back(b"hello".to_vec());
+            msg.push_back(b"world".to_vec());
+            dealer.send(msg.clone()).await.unwrap();
+            let mut received = router.recv().await.unwrap();
+            assert_eq!(received.pop_front(), Some(id));
+            assert_eq!(received, msg);
+        });
+    }
+
+    #[test]
+    fn test_ping_answered() {
+        block_on(async {
+            let (local, mut remote) = websocket();
+            let options = ConnectionOptions::new();
+            let ready = ready_command(&SocketType::Pair, &options).unwrap();
+            remote.send(ready).await.unwrap();
+            let mut peer = WsMessagePeer::new(local, &SocketType::Pair, &options)
+                .await
+                .unwrap();
+            assert_eq!(peer.remote_socket_type(), SocketType::Pair);
+            remote.next().await.unwrap().unwrap();
+
+            let mut ping = vec![0x02, 4];
+            ping.extend_from_slice(b"PING\x00\x0actx");
+            remote.send(ping).await.unwrap();
+            remote.send(b"\x00data".to_vec()).await.unwrap();
+            assert_eq!(
+                peer.recv_message().await.unwrap(),
+                Message::from(b"data".to_vec())
+            );
+            assert_eq!(remote.next().await.unwrap().unwrap(), b"\x02\x04PONGctx");
+        });
+    }
+}
@@ -0,0 +1,302 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use futures::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+// More info: https://rfc.zeromq.org/spec/45/
+//
+// The subprotocols we speak, most preferred first. Plain `ZWS2.0` means the
+// NULL mechanism too.
+const PROTOCOLS: [&str; 2] = ["ZWS2.0/NULL", "ZWS2.0"];
+
+// Appended to the client's key before hashing it, as RFC 6455 says.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Requests and responses with longer heads than this are refused, so that a
+// peer can't make us buffer without end.
+const MAX_HEAD_LEN: usize = 8192;
+
+// More info: https://www.rfc-editor.org/rfc/rfc6455#section-4.1
+pub(crate) async fn request<S>(stream: &mut S, host: &str, path: &str) -> Result<(), UpgradeError>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let mut nonce = [0; 16];
+    nonce[..8].copy_from_slice(&random_u64().to_be_bytes());
+    nonce[8..].copy_from_slice(&random_u64().to_be_bytes());
+    let key = base64(&nonce);
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\r\n",
+        path,
+        host,
+        key,
+        PROTOCOLS.join(", ")
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    let head = Head::read(stream).await?;
+    if head.start.split_whitespace().nth(1) != Some("101") {
+        return Err(UpgradeError::Refused(head.start));
+    }
+    if head.header("sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+        return Err(UpgradeError::WrongAccept);
+    }
+    match head.header("sec-websocket-protocol") {
+        Some(protocol) if PROTOCOLS.contains(&protocol) => Ok(()),
+        _ => Err(UpgradeError::NoCommonProtocol),
+    }
+}
+
+// More info: https://www.rfc-editor.org/rfc/rfc6455#section-4.2
+pub(crate) async fn respond<S>(stream: &mut S) -> Result<(), UpgradeError>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let head = Head::read(stream).await?;
+    let accepted = match check_request(&head) {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")
+                .await?;
+            stream.flush().await?;
+            return Err(err);
+        }
+    };
+
+    let (key, protocol) = accepted;
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\r\n",
+        accept_key(&key),
+        protocol
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+// Returns the client's key and the subprotocol to answer with.
+fn check_request(head: &Head) -> Result<(String, &'static str), UpgradeError> {
+    if !head.start.starts_with("GET ") {
+        return Err(UpgradeError::Malformed);
+    }
+    let upgrade = head.header("upgrade").unwrap_or("");
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return Err(UpgradeError::MissingHeader("Upgrade"));
+    }
+    if head.header("sec-websocket-version") != Some("13") {
+        return Err(UpgradeError::MissingHeader("Sec-WebSocket-Version"));
+    }
+    let key = head
+        .header("sec-websocket-key")
+        .ok_or(UpgradeError::MissingHeader("Sec-WebSocket-Key"))?;
+
+    let offered: Vec<&str> = head
+        .header("sec-websocket-protocol")
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .collect();
+    let protocol = PROTOCOLS
+        .iter()
+        .find(|protocol| offered.contains(protocol))
+        .ok_or(UpgradeError::NoCommonProtocol)?;
+
+    Ok((key.to_string(), protocol))
+}
+
+// The start line and headers of an HTTP request or response.
+struct Head {
+    start: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    async fn read<S: AsyncBufRead + Unpin>(stream: &mut S) -> Result<Head, UpgradeError> {
+        let mut lines = Vec::new();
+        let mut len = 0;
+        loop {
+            let mut line = Vec::new();
+            let read = stream.read_until(b'\n', &mut line).await?;
+            if read == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            len += read;
+            if len > MAX_HEAD_LEN {
+                return Err(UpgradeError::Malformed);
+            }
+
+            let line = String::from_utf8(line).map_err(|_| UpgradeError::Malformed)?;
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                break;
+            }
+            lines.push(line.to_string());
+        }
+
+        let mut lines = lines.into_iter();
+        let start = lines.next().ok_or(UpgradeError::Malformed)?;
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_once(':').ok_or(UpgradeError::Malformed)?;
+                Ok((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            })
+            .collect::<Result<_, UpgradeError>>()?;
+        Ok(Head { start, headers })
+    }
+
+    // Takes the name in lowercase.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+// Good enough for nonces and masking keys, which only have to be hard to
+// predict from outside, not secret.
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// More info: https://www.rfc-editor.org/rfc/rfc3174
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in padded.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (chunk, h) in digest.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum UpgradeError {
+    #[error("error reading data stream")]
+    Io(#[from] io::Error),
+
+    #[error("malformed HTTP upgrade")]
+    Malformed,
+
+    #[error("upgrade request is missing the {0} header")]
+    MissingHeader(&'static str),
+
+    #[error("server refused the upgrade: {0}")]
+    Refused(String),
+
+    #[error("server answered with the wrong Sec-WebSocket-Accept")]
+    WrongAccept,
+
+    #[error("peers have no ZWS subprotocol in common")]
+    NoCommonProtocol,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(
+            sha1(b"abc"),
+            [
+                0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+                0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d
+            ]
+        );
+
+        // The example from RFC 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}
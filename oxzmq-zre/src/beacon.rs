@@ -0,0 +1,156 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{PeerId, ZreError};
+use futures::{channel::mpsc, StreamExt};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+/// The UDP port ZRE beacons are broadcast on, unless the application picks
+/// another.
+pub const BEACON_PORT: u16 = 5670;
+
+// "ZRE", the version, the sender's UUID, and the port its ROUTER listens on.
+const PREFIX: &[u8] = b"ZRE\x01";
+const BEACON_LEN: usize = 22;
+
+// How often the receiving thread checks whether it's still wanted.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a node broadcasts so that others on the LAN can connect to it. A port
+/// of zero means the node is going away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Beacon {
+    uuid: PeerId,
+    port: u16,
+}
+
+impl Beacon {
+    pub fn new(uuid: PeerId, port: u16) -> Beacon {
+        Beacon { uuid, port }
+    }
+
+    pub fn uuid(&self) -> PeerId {
+        self.uuid
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn to_bytes(&self) -> [u8; BEACON_LEN] {
+        let mut bytes = [0; BEACON_LEN];
+        bytes[..4].copy_from_slice(PREFIX);
+        bytes[4..20].copy_from_slice(self.uuid.as_bytes());
+        bytes[20..].copy_from_slice(&self.port.to_be_bytes());
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Beacon, ZreError> {
+        if bytes.len() != BEACON_LEN || !bytes.starts_with(PREFIX) {
+            return Err(ZreError::Protocol("not a ZRE beacon"));
+        }
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(&bytes[4..20]);
+        let port = u16::from_be_bytes([bytes[20], bytes[21]]);
+        Ok(Beacon::new(PeerId::from_bytes(uuid), port))
+    }
+}
+
+/// Broadcasts beacons on a UDP port and hears other nodes' beacons on it.
+///
+/// The standard library can't share a UDP port between sockets, so only one
+/// `UdpBeacon` per host can listen on a given port.
+#[derive(Debug)]
+pub struct UdpBeacon {
+    socket: Arc<UdpSocket>,
+    port: u16,
+    received: mpsc::UnboundedReceiver<(Beacon, IpAddr)>,
+}
+
+impl UdpBeacon {
+    /// Listens for beacons on `port` on every interface. Broadcasts go to
+    /// the same port.
+    pub fn bind(port: u16) -> io::Result<UdpBeacon> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        socket.set_broadcast(true)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let socket = Arc::new(socket);
+
+        // Receiving blocks, so it's done on a thread of its own, which stops
+        // once the beacon has been dropped.
+        let (tx, received) = mpsc::unbounded();
+        let receiving = socket.clone();
+        thread::spawn(move || {
+            let mut buf = [0; BEACON_LEN + 1];
+            while !tx.is_closed() {
+                let (len, from) = match receiving.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(err)
+                        if err.kind() == io::ErrorKind::WouldBlock
+                            || err.kind() == io::ErrorKind::TimedOut
+                            || err.kind() == io::ErrorKind::Interrupted =>
+                    {
+                        continue
+                    }
+                    Err(_) => return,
+                };
+                // Anything else on the port is ignored.
+                if let Ok(beacon) = Beacon::parse(&buf[..len]) {
+                    let _ = tx.unbounded_send((beacon, from.ip()));
+                }
+            }
+        });
+
+        Ok(UdpBeacon {
+            socket,
+            port,
+            received,
+        })
+    }
+
+    /// Broadcasts `beacon` to the LAN. This should be done about once a
+    /// second for as long as the node is up.
+    pub fn send(&self, beacon: &Beacon) -> io::Result<()> {
+        let broadcast = SocketAddr::from((Ipv4Addr::BROADCAST, self.port));
+        self.send_to(beacon, broadcast)
+    }
+
+    /// Sends `beacon` to a single address, for networks that don't pass
+    /// broadcasts on.
+    pub fn send_to(&self, beacon: &Beacon, addr: SocketAddr) -> io::Result<()> {
+        self.socket.send_to(&beacon.to_bytes(), addr)?;
+        Ok(())
+    }
+
+    /// Waits for the next beacon, including our own, and returns it along
+    /// with the address it came from.
+    pub async fn recv(&mut self) -> Option<(Beacon, IpAddr)> {
+        self.received.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let beacon = Beacon::new(PeerId::random(), 49152);
+        let bytes = beacon.to_bytes();
+        assert_eq!(&bytes[..4], b"ZRE\x01");
+        assert_eq!(&bytes[20..], &[0xC0, 0x00]);
+        assert_eq!(Beacon::parse(&bytes).unwrap(), beacon);
+
+        assert!(Beacon::parse(&bytes[..21]).is_err());
+        let mut wrong_version = bytes;
+        wrong_version[3] = 0x02;
+        assert!(Beacon::parse(&wrong_version).is_err());
+    }
+}
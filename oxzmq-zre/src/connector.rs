@@ -0,0 +1,60 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::ZreError;
+use oxzmq_zmtp::{
+    ConnectionOptions, Endpoint, InprocContext, InprocPeer, Peer, Resolver, SocketType,
+    TcpConnection,
+};
+use std::future::Future;
+
+/// Opens the connections a node uses to send to the peers it finds.
+pub trait Connector {
+    type Peer: Peer;
+
+    /// Connects a DEALER to the peer's ROUTER at `endpoint`.
+    fn connect(
+        &self,
+        endpoint: &Endpoint,
+        options: &ConnectionOptions,
+    ) -> impl Future<Output = Result<Self::Peer, ZreError>>;
+}
+
+impl Connector for InprocContext {
+    type Peer = InprocPeer;
+
+    fn connect(
+        &self,
+        endpoint: &Endpoint,
+        options: &ConnectionOptions,
+    ) -> impl Future<Output = Result<InprocPeer, ZreError>> {
+        let peer = InprocContext::connect(self, endpoint, SocketType::Dealer, options);
+        async move { Ok(peer?) }
+    }
+}
+
+/// Connects over TCP, looking up service endpoints with `resolver`.
+#[derive(Debug, Clone, Default)]
+pub struct TcpConnector<R> {
+    resolver: R,
+}
+
+impl<R: Resolver> TcpConnector<R> {
+    pub fn new(resolver: R) -> TcpConnector<R> {
+        TcpConnector { resolver }
+    }
+}
+
+impl<R: Resolver> Connector for TcpConnector<R> {
+    type Peer = TcpConnection;
+
+    async fn connect(
+        &self,
+        endpoint: &Endpoint,
+        options: &ConnectionOptions,
+    ) -> Result<TcpConnection, ZreError> {
+        let socket_type = SocketType::Dealer;
+        Ok(TcpConnection::connect(endpoint, &self.resolver, &socket_type, options).await?)
+    }
+}
@@ -0,0 +1,54 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+/// The 16-byte UUID a node is known by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId([u8; 16]);
+
+impl PeerId {
+    /// A new random ID. It isn't a proper version 4 UUID, but nothing in ZRE
+    /// cares, as long as no two nodes pick the same one.
+    pub fn random() -> PeerId {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        // Every `RandomState` is seeded differently, which is all the
+        // randomness the standard library offers.
+        let mut bytes = [0; 16];
+        for half in bytes.chunks_mut(8) {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+            if let Ok(since_epoch) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+                hasher.write_u128(since_epoch.as_nanos());
+            }
+            half.copy_from_slice(&hasher.finish().to_be_bytes());
+        }
+        PeerId(bytes)
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> PeerId {
+        PeerId(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+// Uppercase hex, the way Zyre prints them.
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
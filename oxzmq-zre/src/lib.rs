@@ -0,0 +1,147 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The ZeroMQ Realtime Exchange Protocol (ZRE), which Zyre implements: nodes
+//! on a LAN find each other through UDP beacons, connect to every node they
+//! find, and talk to each other one-to-one (WHISPER) or through named groups
+//! (SHOUT). Applications see nodes come and go as ENTER and EXIT events.
+//!
+//! More info: https://rfc.zeromq.org/spec/36/
+//!
+//! Each node receives on a ROUTER socket and sends to each of its peers on a
+//! DEALER socket of their own, so the mesh works over any transport those do.
+//! Beacons only make sense for TCP, though; nodes on other transports have to
+//! be told about each other with `Node::connect`.
+
+use oxzmq_zmtp::{ConnectionError, InprocError, SocketError};
+use std::io;
+
+pub use crate::{
+    beacon::{Beacon, UdpBeacon, BEACON_PORT},
+    connector::{Connector, TcpConnector},
+    id::PeerId,
+    node::{Event, Node},
+};
+
+mod beacon;
+mod connector;
+mod id;
+mod node;
+mod protocol;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ZreError {
+    #[error("{0}")]
+    Socket(#[from] SocketError),
+
+    #[error("{0}")]
+    Connection(#[from] ConnectionError),
+
+    #[error("{0}")]
+    Inproc(#[from] InprocError),
+
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("protocol error: {0}")]
+    Protocol(&'static str),
+
+    #[error("no peer {0}")]
+    UnknownPeer(PeerId),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use oxzmq_zmtp::{InprocContext, InprocListener, InprocPeer, Message, SocketType};
+    use std::time::Duration;
+
+    fn node(context: &InprocContext, name: &str) -> (Node<InprocContext>, InprocListener) {
+        let endpoint = format!("inproc://{}", name).parse().unwrap();
+        let listener = context.bind(&endpoint, SocketType::Router).unwrap();
+        let mut node = Node::new(context.clone(), endpoint);
+        node.set_name(name);
+        (node, listener)
+    }
+
+    async fn accept(node: &mut Node<InprocContext>, listener: &mut InprocListener) {
+        let peer: InprocPeer = listener.accept().await.unwrap();
+        node.attach(peer).unwrap();
+    }
+
+    #[test]
+    fn test_mesh() {
+        block_on(async {
+            let context = InprocContext::new();
+            let (mut a, mut a_listener) = node(&context, "a");
+            let (mut b, mut b_listener) = node(&context, "b");
+
+            // A finds B, and B connects back when it hears A's HELLO.
+            a.connect(b.uuid(), &"inproc://b".parse().unwrap())
+                .await
+                .unwrap();
+            accept(&mut b, &mut b_listener).await;
+            let entered = b.recv().await.unwrap();
+            assert!(
+                matches!(entered, Event::Enter { peer, name, .. } if peer == a.uuid() && name == "a")
+            );
+            accept(&mut a, &mut a_listener).await;
+            let entered = a.recv().await.unwrap();
+            assert!(
+                matches!(entered, Event::Enter { peer, name, .. } if peer == b.uuid() && name == "b")
+            );
+
+            // Groups.
+            b.join("chat").await.unwrap();
+            let joined = a.recv().await.unwrap();
+            assert_eq!(
+                joined,
+                Event::Join {
+                    peer: b.uuid(),
+                    name: "b".to_string(),
+                    group: "chat".to_string()
+                }
+            );
+            a.shout("chat", Message::from(&b"hi all"[..]))
+                .await
+                .unwrap();
+            assert_eq!(
+                b.recv().await.unwrap(),
+                Event::Shout {
+                    peer: a.uuid(),
+                    name: "a".to_string(),
+                    group: "chat".to_string(),
+                    content: Message::from(&b"hi all"[..])
+                }
+            );
+
+            // One-to-one.
+            a.whisper(b.uuid(), Message::from(&b"hi b"[..]))
+                .await
+                .unwrap();
+            assert_eq!(
+                b.recv().await.unwrap(),
+                Event::Whisper {
+                    peer: a.uuid(),
+                    name: "a".to_string(),
+                    content: Message::from(&b"hi b"[..])
+                }
+            );
+
+            // Peers that go quiet leave.
+            a.heartbeat(Duration::from_secs(60)).await.unwrap();
+            assert_eq!(a.peers().count(), 1);
+            a.heartbeat(Duration::ZERO).await.unwrap();
+            assert_eq!(a.peers().count(), 0);
+            assert_eq!(
+                a.recv().await.unwrap(),
+                Event::Exit {
+                    peer: b.uuid(),
+                    name: "b".to_string()
+                }
+            );
+        });
+    }
+}
@@ -0,0 +1,447 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{protocol::ZreMessage, Beacon, Connector, PeerId, ZreError};
+use oxzmq_zmtp::{ConnectionOptions, Dealer, Endpoint, Message, Router};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+/// Something that happened to one of a node's peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A peer said HELLO.
+    Enter {
+        peer: PeerId,
+        name: String,
+        endpoint: String,
+        headers: HashMap<String, String>,
+    },
+    /// A peer went away, or stopped making sense.
+    Exit { peer: PeerId, name: String },
+    Join {
+        peer: PeerId,
+        name: String,
+        group: String,
+    },
+    Leave {
+        peer: PeerId,
+        name: String,
+        group: String,
+    },
+    /// A message sent to us alone.
+    Whisper {
+        peer: PeerId,
+        name: String,
+        content: Message,
+    },
+    /// A message sent to a group we're in.
+    Shout {
+        peer: PeerId,
+        name: String,
+        group: String,
+        content: Message,
+    },
+}
+
+/// One member of a ZRE mesh.
+///
+/// Peers connect to the node's ROUTER, which the application binds to the
+/// endpoint given to `new` and attaches every connection it accepts to. The
+/// node connects to each peer it finds through `connector`, either when told
+/// to with `connect` or `handle_beacon`, or when a peer it doesn't know yet
+/// says HELLO.
+///
+/// The node only does something when the application calls `recv`, and
+/// `heartbeat`, which should be called regularly to check on the peers.
+#[derive(Debug)]
+pub struct Node<C: Connector> {
+    uuid: PeerId,
+    name: String,
+    endpoint: Endpoint,
+    headers: HashMap<String, String>,
+    groups: HashSet<String>,
+    // Bumped every time we join or leave a group.
+    status: u8,
+    connector: C,
+    server: Router<C::Peer>,
+    peers: HashMap<PeerId, PeerState<C::Peer>>,
+    events: VecDeque<Event>,
+}
+
+#[derive(Debug)]
+struct PeerState<P> {
+    socket: Dealer<P>,
+    // Set once the peer has said HELLO.
+    name: Option<String>,
+    groups: HashSet<String>,
+    // The sequence numbers of the last messages sent and received.
+    sent: u16,
+    received: u16,
+    last_heard: Instant,
+}
+
+impl<C: Connector> Node<C> {
+    /// A node with a random UUID, which peers reach at `endpoint`. Its name
+    /// is the start of its UUID until `set_name` is called.
+    pub fn new(connector: C, endpoint: Endpoint) -> Node<C> {
+        let uuid = PeerId::random();
+        Node {
+            uuid,
+            name: uuid.to_string()[..6].to_string(),
+            endpoint,
+            headers: HashMap::new(),
+            groups: HashSet::new(),
+            status: 0,
+            connector,
+            server: Router::new(),
+            peers: HashMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn uuid(&self) -> PeerId {
+        self.uuid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sets the name peers know us by. Only peers we say HELLO to after this
+    /// see it.
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    /// Sets a header that's sent to peers with HELLO. Only peers we say
+    /// HELLO to after this see it.
+    pub fn set_header(&mut self, key: &str, value: &str) {
+        self.headers.insert(key.to_string(), value.to_string());
+    }
+
+    /// The beacon to broadcast for this node, if it's reachable over TCP.
+    pub fn beacon(&self) -> Option<Beacon> {
+        match self.endpoint {
+            Endpoint::Tcp { port, .. } => Some(Beacon::new(self.uuid, port)),
+            _ => None,
+        }
+    }
+
+    /// Attaches a peer that connected to us.
+    pub fn attach(&mut self, peer: C::Peer) -> Result<(), ZreError> {
+        self.server.attach(peer)?;
+        Ok(())
+    }
+
+    /// Connects to the node `uuid` at `endpoint` and says HELLO, unless
+    /// we're already connected to it.
+    pub async fn connect(&mut self, uuid: PeerId, endpoint: &Endpoint) -> Result<(), ZreError> {
+        if uuid == self.uuid || self.peers.contains_key(&uuid) {
+            return Ok(());
+        }
+
+        // The routing ID tells the peer's ROUTER who we are.
+        let mut routing_id = vec![1];
+        routing_id.extend_from_slice(self.uuid.as_bytes());
+        let options = ConnectionOptions::new().routing_id(routing_id);
+        let mut socket = Dealer::new();
+        socket.attach(self.connector.connect(endpoint, &options).await?)?;
+        self.peers.insert(
+            uuid,
+            PeerState {
+                socket,
+                name: None,
+                groups: HashSet::new(),
+                sent: 0,
+                received: 0,
+                last_heard: Instant::now(),
+            },
+        );
+
+        let hello = ZreMessage::Hello {
+            endpoint: self.endpoint.to_string(),
+            groups: self.groups.iter().cloned().collect(),
+            status: self.status,
+            name: self.name.clone(),
+            headers: self.headers.clone(),
+        };
+        self.send_to(uuid, &hello).await
+    }
+
+    /// Connects to the node that sent `beacon` from `addr`, or forgets it if
+    /// the beacon says it's going away. Our own beacons are ignored.
+    pub async fn handle_beacon(&mut self, beacon: &Beacon, addr: IpAddr) -> Result<(), ZreError> {
+        if beacon.port() == 0 {
+            self.remove_peer(beacon.uuid());
+            return Ok(());
+        }
+        let endpoint = format!("tcp://{}", SocketAddr::new(addr, beacon.port()))
+            .parse()
+            .map_err(|_| ZreError::Protocol("beacon address isn't an endpoint"))?;
+        self.connect(beacon.uuid(), &endpoint).await
+    }
+
+    /// The peers that have said HELLO.
+    pub fn peers(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.peers
+            .iter()
+            .filter(|(_, state)| state.name.is_some())
+            .map(|(&uuid, _)| uuid)
+    }
+
+    pub fn peer_name(&self, peer: PeerId) -> Option<&str> {
+        self.peers.get(&peer)?.name.as_deref()
+    }
+
+    /// The groups `peer` is in.
+    pub fn peer_groups(&self, peer: PeerId) -> impl Iterator<Item = &str> {
+        self.peers
+            .get(&peer)
+            .into_iter()
+            .flat_map(|state| state.groups.iter().map(String::as_str))
+    }
+
+    /// The groups we're in.
+    pub fn groups(&self) -> impl Iterator<Item = &str> {
+        self.groups.iter().map(String::as_str)
+    }
+
+    pub async fn join(&mut self, group: &str) -> Result<(), ZreError> {
+        if !self.groups.insert(group.to_string()) {
+            return Ok(());
+        }
+        self.status = self.status.wrapping_add(1);
+        let join = ZreMessage::Join {
+            group: group.to_string(),
+            status: self.status,
+        };
+        self.send_to_all(&join).await
+    }
+
+    pub async fn leave(&mut self, group: &str) -> Result<(), ZreError> {
+        if !self.groups.remove(group) {
+            return Ok(());
+        }
+        self.status = self.status.wrapping_add(1);
+        let leave = ZreMessage::Leave {
+            group: group.to_string(),
+            status: self.status,
+        };
+        self.send_to_all(&leave).await
+    }
+
+    /// Sends `content` to `peer` alone.
+    pub async fn whisper(&mut self, peer: PeerId, content: Message) -> Result<(), ZreError> {
+        if self.peer_name(peer).is_none() {
+            return Err(ZreError::UnknownPeer(peer));
+        }
+        self.send_to(peer, &ZreMessage::Whisper { content }).await
+    }
+
+    /// Sends `content` to every peer in `group`. We don't have to be in it.
+    pub async fn shout(&mut self, group: &str, content: Message) -> Result<(), ZreError> {
+        let members: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| state.groups.contains(group))
+            .map(|(&uuid, _)| uuid)
+            .collect();
+        let shout = ZreMessage::Shout {
+            group: group.to_string(),
+            content,
+        };
+        for uuid in members {
+            self.send_to(uuid, &shout).await?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the next event.
+    pub async fn recv(&mut self) -> Result<Event, ZreError> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            self.step().await?;
+        }
+    }
+
+    /// Pings every peer, and says goodbye to the ones that haven't been
+    /// heard from within `expiry`.
+    pub async fn heartbeat(&mut self, expiry: Duration) -> Result<(), ZreError> {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| now.duration_since(state.last_heard) > expiry)
+            .map(|(&uuid, _)| uuid)
+            .collect();
+        for uuid in expired {
+            self.remove_peer(uuid);
+        }
+        self.send_to_all(&ZreMessage::Ping).await
+    }
+
+    // Handles one message from a peer.
+    async fn step(&mut self) -> Result<(), ZreError> {
+        let mut msg = self.server.recv().await?;
+        let uuid = match msg.pop_front().as_deref() {
+            Some([1, uuid @ ..]) if uuid.len() == 16 => {
+                let mut bytes = [0; 16];
+                bytes.copy_from_slice(uuid);
+                PeerId::from_bytes(bytes)
+            }
+            // Not a ZRE peer.
+            _ => return Ok(()),
+        };
+        let (sequence, parsed) = match ZreMessage::parse(msg) {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                self.remove_peer(uuid);
+                return Ok(());
+            }
+        };
+
+        if let ZreMessage::Hello {
+            endpoint,
+            groups,
+            name,
+            headers,
+            ..
+        } = parsed
+        {
+            return self
+                .hello(uuid, sequence, endpoint, groups, name, headers)
+                .await;
+        }
+
+        // Everything else has to come after HELLO, in order.
+        let state = match self.peers.get_mut(&uuid) {
+            Some(state) if state.name.is_some() => state,
+            _ => return Ok(()),
+        };
+        if sequence != state.received.wrapping_add(1) {
+            self.remove_peer(uuid);
+            return Ok(());
+        }
+        state.received = sequence;
+        state.last_heard = Instant::now();
+        let name = state.name.clone().unwrap_or_default();
+
+        let event = match parsed {
+            ZreMessage::Whisper { content } => Event::Whisper {
+                peer: uuid,
+                name,
+                content,
+            },
+            ZreMessage::Shout { group, content } if self.groups.contains(&group) => Event::Shout {
+                peer: uuid,
+                name,
+                group,
+                content,
+            },
+            ZreMessage::Join { group, .. } => {
+                state.groups.insert(group.clone());
+                Event::Join {
+                    peer: uuid,
+                    name,
+                    group,
+                }
+            }
+            ZreMessage::Leave { group, .. } => {
+                state.groups.remove(&group);
+                Event::Leave {
+                    peer: uuid,
+                    name,
+                    group,
+                }
+            }
+            ZreMessage::Ping => return self.send_to(uuid, &ZreMessage::PingOk).await,
+            _ => return Ok(()),
+        };
+        self.events.push_back(event);
+        Ok(())
+    }
+
+    async fn hello(
+        &mut self,
+        uuid: PeerId,
+        sequence: u16,
+        endpoint: String,
+        groups: Vec<String>,
+        name: String,
+        headers: HashMap<String, String>,
+    ) -> Result<(), ZreError> {
+        // A second HELLO means the peer restarted without us noticing.
+        if self.peer_name(uuid).is_some() {
+            self.remove_peer(uuid);
+        }
+        if sequence != 1 {
+            return Ok(());
+        }
+        let parsed_endpoint = endpoint
+            .parse()
+            .map_err(|_| ZreError::Protocol("HELLO with a bad endpoint"))?;
+        self.connect(uuid, &parsed_endpoint).await?;
+
+        let state = match self.peers.get_mut(&uuid) {
+            Some(state) => state,
+            // Sending our HELLO failed.
+            None => return Ok(()),
+        };
+        state.name = Some(name.clone());
+        state.groups = groups.iter().cloned().collect();
+        state.received = sequence;
+        state.last_heard = Instant::now();
+
+        self.events.push_back(Event::Enter {
+            peer: uuid,
+            name: name.clone(),
+            endpoint,
+            headers,
+        });
+        for group in groups {
+            self.events.push_back(Event::Join {
+                peer: uuid,
+                name: name.clone(),
+                group,
+            });
+        }
+        Ok(())
+    }
+
+    fn remove_peer(&mut self, uuid: PeerId) {
+        if let Some(PeerState {
+            name: Some(name), ..
+        }) = self.peers.remove(&uuid)
+        {
+            self.events.push_back(Event::Exit { peer: uuid, name });
+        }
+    }
+
+    async fn send_to_all(&mut self, msg: &ZreMessage) -> Result<(), ZreError> {
+        let uuids: Vec<_> = self.peers.keys().copied().collect();
+        for uuid in uuids {
+            self.send_to(uuid, msg).await?;
+        }
+        Ok(())
+    }
+
+    // Peers we can't send to are taken to have gone away.
+    async fn send_to(&mut self, uuid: PeerId, msg: &ZreMessage) -> Result<(), ZreError> {
+        let state = match self.peers.get_mut(&uuid) {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        state.sent = state.sent.wrapping_add(1);
+        let msg = msg.to_message(state.sent)?;
+        if state.socket.send(msg).await.is_err() {
+            self.remove_peer(uuid);
+        }
+        Ok(())
+    }
+}
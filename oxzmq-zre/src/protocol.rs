@@ -0,0 +1,279 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::ZreError;
+use oxzmq_zmtp::Message;
+use std::{collections::HashMap, convert::TryFrom};
+
+// More info: https://rfc.zeromq.org/spec/36/
+//
+// The first frame holds a two-byte signature, the command, the protocol
+// version, a sequence number, and then the command's fields. WHISPER and
+// SHOUT carry their content in the frames after it. Numbers are big-endian,
+// strings have a one-byte length, and long strings a four-byte one.
+const SIGNATURE: [u8; 2] = [0xAA, 0xA1];
+const VERSION: u8 = 2;
+
+const HELLO: u8 = 1;
+const WHISPER: u8 = 2;
+const SHOUT: u8 = 3;
+const JOIN: u8 = 4;
+const LEAVE: u8 = 5;
+const PING: u8 = 6;
+const PING_OK: u8 = 7;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ZreMessage {
+    Hello {
+        endpoint: String,
+        groups: Vec<String>,
+        status: u8,
+        name: String,
+        headers: HashMap<String, String>,
+    },
+    Whisper {
+        content: Message,
+    },
+    Shout {
+        group: String,
+        content: Message,
+    },
+    Join {
+        group: String,
+        status: u8,
+    },
+    Leave {
+        group: String,
+        status: u8,
+    },
+    Ping,
+    PingOk,
+}
+
+impl ZreMessage {
+    // Returns the message along with its sequence number.
+    pub(crate) fn parse(mut msg: Message) -> Result<(u16, ZreMessage), ZreError> {
+        let header = msg.pop_front().ok_or(ZreError::Protocol("empty message"))?;
+        let mut reader = Reader(&header);
+        if reader.take(2)? != SIGNATURE {
+            return Err(ZreError::Protocol("wrong signature"));
+        }
+        let command = reader.number1()?;
+        if reader.number1()? != VERSION {
+            return Err(ZreError::Protocol("unsupported version"));
+        }
+        let sequence = reader.number2()?;
+
+        let parsed = match command {
+            HELLO => {
+                let endpoint = reader.string()?;
+                let groups = (0..reader.number4()?)
+                    .map(|_| reader.longstr())
+                    .collect::<Result<_, _>>()?;
+                let status = reader.number1()?;
+                let name = reader.string()?;
+                let headers = (0..reader.number4()?)
+                    .map(|_| Ok((reader.string()?, reader.longstr()?)))
+                    .collect::<Result<_, ZreError>>()?;
+                ZreMessage::Hello {
+                    endpoint,
+                    groups,
+                    status,
+                    name,
+                    headers,
+                }
+            }
+            WHISPER => ZreMessage::Whisper { content: msg },
+            SHOUT => ZreMessage::Shout {
+                group: reader.string()?,
+                content: msg,
+            },
+            JOIN | LEAVE => {
+                let group = reader.string()?;
+                let status = reader.number1()?;
+                if command == JOIN {
+                    ZreMessage::Join { group, status }
+                } else {
+                    ZreMessage::Leave { group, status }
+                }
+            }
+            PING => ZreMessage::Ping,
+            PING_OK => ZreMessage::PingOk,
+            _ => return Err(ZreError::Protocol("unknown command")),
+        };
+        if !reader.0.is_empty() {
+            return Err(ZreError::Protocol("trailing bytes"));
+        }
+        Ok((sequence, parsed))
+    }
+
+    pub(crate) fn to_message(&self, sequence: u16) -> Result<Message, ZreError> {
+        let command = match self {
+            ZreMessage::Hello { .. } => HELLO,
+            ZreMessage::Whisper { .. } => WHISPER,
+            ZreMessage::Shout { .. } => SHOUT,
+            ZreMessage::Join { .. } => JOIN,
+            ZreMessage::Leave { .. } => LEAVE,
+            ZreMessage::Ping => PING,
+            ZreMessage::PingOk => PING_OK,
+        };
+        let mut header = SIGNATURE.to_vec();
+        header.push(command);
+        header.push(VERSION);
+        header.extend_from_slice(&sequence.to_be_bytes());
+
+        let mut content = None;
+        match self {
+            ZreMessage::Hello {
+                endpoint,
+                groups,
+                status,
+                name,
+                headers,
+            } => {
+                put_string(&mut header, endpoint)?;
+                put_number4(&mut header, groups.len())?;
+                for group in groups {
+                    put_longstr(&mut header, group)?;
+                }
+                header.push(*status);
+                put_string(&mut header, name)?;
+                put_number4(&mut header, headers.len())?;
+                for (key, value) in headers {
+                    put_string(&mut header, key)?;
+                    put_longstr(&mut header, value)?;
+                }
+            }
+            ZreMessage::Whisper { content: body } => content = Some(body),
+            ZreMessage::Shout {
+                group,
+                content: body,
+            } => {
+                put_string(&mut header, group)?;
+                content = Some(body);
+            }
+            ZreMessage::Join { group, status } | ZreMessage::Leave { group, status } => {
+                put_string(&mut header, group)?;
+                header.push(*status);
+            }
+            ZreMessage::Ping | ZreMessage::PingOk => (),
+        }
+
+        let mut msg = Message::new();
+        msg.push_back(header);
+        if let Some(content) = content {
+            msg.extend(content.iter().map(<[u8]>::to_vec));
+        }
+        Ok(msg)
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, string: &str) -> Result<(), ZreError> {
+    let len = u8::try_from(string.len())
+        .map_err(|_| ZreError::Protocol("string longer than 255 bytes"))?;
+    buf.push(len);
+    buf.extend_from_slice(string.as_bytes());
+    Ok(())
+}
+
+fn put_longstr(buf: &mut Vec<u8>, string: &str) -> Result<(), ZreError> {
+    put_number4(buf, string.len())?;
+    buf.extend_from_slice(string.as_bytes());
+    Ok(())
+}
+
+fn put_number4(buf: &mut Vec<u8>, number: usize) -> Result<(), ZreError> {
+    let number = u32::try_from(number).map_err(|_| ZreError::Protocol("number too large"))?;
+    buf.extend_from_slice(&number.to_be_bytes());
+    Ok(())
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ZreError> {
+        if self.0.len() < len {
+            return Err(ZreError::Protocol("message too short"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn number1(&mut self) -> Result<u8, ZreError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn number2(&mut self) -> Result<u16, ZreError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn number4(&mut self) -> Result<u32, ZreError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<String, ZreError> {
+        let len = self.number1()?.into();
+        self.utf8(len)
+    }
+
+    fn longstr(&mut self) -> Result<String, ZreError> {
+        let len = self.number4()? as usize;
+        self.utf8(len)
+    }
+
+    fn utf8(&mut self, len: usize) -> Result<String, ZreError> {
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| ZreError::Protocol("string isn't UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut headers = HashMap::new();
+        headers.insert("X-ROLE".to_string(), "sensor".to_string());
+        let msgs = vec![
+            ZreMessage::Hello {
+                endpoint: "tcp://10.0.0.1:49152".to_string(),
+                groups: vec!["chat".to_string(), "logs".to_string()],
+                status: 2,
+                name: "node".to_string(),
+                headers,
+            },
+            ZreMessage::Whisper {
+                content: Message::from(vec![b"a".to_vec(), b"b".to_vec()]),
+            },
+            ZreMessage::Shout {
+                group: "chat".to_string(),
+                content: Message::from(&b"hi"[..]),
+            },
+            ZreMessage::Join {
+                group: "chat".to_string(),
+                status: 3,
+            },
+            ZreMessage::Leave {
+                group: "chat".to_string(),
+                status: 4,
+            },
+            ZreMessage::Ping,
+            ZreMessage::PingOk,
+        ];
+        for (sequence, msg) in (1..).zip(msgs) {
+            let parsed = ZreMessage::parse(msg.to_message(sequence).unwrap()).unwrap();
+            assert_eq!(parsed, (sequence, msg));
+        }
+
+        assert_eq!(
+            ZreMessage::Ping.to_message(0x0102).unwrap(),
+            Message::from(vec![0xAA, 0xA1, PING, VERSION, 0x01, 0x02])
+        );
+    }
+}
@@ -0,0 +1,67 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! ZeroMQ sockets that bind and connect by themselves, for applications that
+//! just want to send and receive messages. Each socket type has a type of its
+//! own, such as `PubSocket` or `ReqSocket`, with only the operations that
+//! make sense for it.
+//!
+//! ```no_run
+//! # futures::executor::block_on(async {
+//! use oxzmq::{Message, RepSocket};
+//!
+//! let mut rep = RepSocket::new();
+//! rep.bind("tcp://*:5555").await?;
+//! loop {
+//!     let request = rep.recv().await?;
+//!     rep.send(request).await?;
+//! }
+//! # Ok::<(), oxzmq::Error>(())
+//! # });
+//! ```
+//!
+//! Sockets can bind and connect to `tcp://`, `ws://` and `inproc://`
+//! endpoints, several at once. Bound sockets accept peers on background
+//! threads. Everything else, from the greeting and handshake to routing
+//! messages between peers, is done by `oxzmq-zmtp`, which can be used
+//! directly for more control: custom transports, runtimes and peers.
+
+use oxzmq_zmtp::{ConnectionError, EndpointError, InprocError, SocketError, WsError};
+use std::io;
+
+pub use crate::sockets::{
+    DealerSocket, PairSocket, PubSocket, PullSocket, PushSocket, RepSocket, ReqSocket,
+    RouterSocket, SubSocket,
+};
+pub use oxzmq_zmtp::{
+    Context, Endpoint, HwmPolicy, Message, Metrics, SocketOption, SocketOptionName,
+};
+
+mod socket;
+mod sockets;
+mod transport;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Endpoint(#[from] EndpointError),
+
+    #[error("{0}")]
+    Socket(#[from] SocketError),
+
+    #[error("{0}")]
+    Connection(#[from] ConnectionError),
+
+    #[error("{0}")]
+    Ws(#[from] WsError),
+
+    #[error("{0}")]
+    Inproc(#[from] InprocError),
+
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("socket isn't bound to {0}")]
+    NotBound(Endpoint),
+}
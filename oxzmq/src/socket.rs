@@ -0,0 +1,319 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    transport::{self, TransportPeer},
+    Error,
+};
+use futures::{
+    channel::mpsc,
+    future::{self, AbortHandle, Either},
+    pin_mut, StreamExt,
+};
+use oxzmq_zmtp::{
+    Context, Dealer, Endpoint, Message, Metrics, Pair, Pub, Pull, Push, Rep, Req, Router,
+    SocketError, SocketOption, SocketOptionName, SocketType, Sub,
+};
+use std::{future::Future, time::Duration};
+
+/// The socket types from `oxzmq-zmtp` that the sockets here are built on,
+/// over peers of any transport.
+pub(crate) trait Inner: Sized {
+    const SOCKET_TYPE: SocketType;
+
+    /// Whether sending has to wait for a peer. Sockets that drop messages
+    /// nobody is listening for, like PUB, send straight away.
+    const SEND_WAITS: bool = true;
+
+    fn with_context(context: &Context) -> Self;
+
+    fn attach(&mut self, peer: TransportPeer) -> impl Future<Output = Result<(), SocketError>>;
+
+    fn set_option(&mut self, option: SocketOption);
+
+    fn get_option(&self, name: SocketOptionName) -> SocketOption;
+
+    fn metrics(&self) -> Metrics;
+
+    fn close(self) -> impl Future<Output = ()>;
+}
+
+pub(crate) trait InnerRecv {
+    fn recv(&mut self) -> impl Future<Output = Result<Message, SocketError>>;
+}
+
+pub(crate) trait InnerSend {
+    fn send(&mut self, msg: Message) -> impl Future<Output = Result<(), SocketError>>;
+}
+
+macro_rules! impl_inner {
+    (
+        $socket:ident,
+        $socket_type:ident,
+        send_waits = $send_waits:expr,
+        attach = |$s:ident, $peer:ident| $attach:expr
+    ) => {
+        impl Inner for $socket<TransportPeer> {
+            const SOCKET_TYPE: SocketType = SocketType::$socket_type;
+            const SEND_WAITS: bool = $send_waits;
+
+            fn with_context(context: &Context) -> Self {
+                $socket::with_context(context)
+            }
+
+            async fn attach(&mut self, $peer: TransportPeer) -> Result<(), SocketError> {
+                let $s = self;
+                $attach
+            }
+
+            fn set_option(&mut self, option: SocketOption) {
+                $socket::set_option(self, option)
+            }
+
+            fn get_option(&self, name: SocketOptionName) -> SocketOption {
+                $socket::get_option(self, name)
+            }
+
+            fn metrics(&self) -> Metrics {
+                $socket::metrics(self)
+            }
+
+            async fn close(self) {
+                $socket::close(self).await
+            }
+        }
+    };
+    ($socket:ident, $socket_type:ident) => {
+        impl_inner!(
+            $socket,
+            $socket_type,
+            send_waits = true,
+            attach = |s, peer| s.attach(peer).map(drop)
+        );
+    };
+}
+
+macro_rules! impl_recv {
+    ($($socket:ident),*) => {
+        $(
+            impl InnerRecv for $socket<TransportPeer> {
+                async fn recv(&mut self) -> Result<Message, SocketError> {
+                    $socket::recv(self).await
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_send {
+    ($($socket:ident),*) => {
+        $(
+            impl InnerSend for $socket<TransportPeer> {
+                async fn send(&mut self, msg: Message) -> Result<(), SocketError> {
+                    $socket::send(self, msg).await
+                }
+            }
+        )*
+    };
+}
+
+impl_inner!(Dealer, Dealer);
+impl_inner!(Pair, Pair);
+impl_inner!(
+    Pub,
+    Pub,
+    send_waits = false,
+    attach = |s, peer| s.attach(peer)
+);
+impl_inner!(Pull, Pull);
+impl_inner!(Push, Push);
+impl_inner!(Rep, Rep);
+impl_inner!(Req, Req);
+impl_inner!(Router, Router);
+impl_inner!(
+    Sub,
+    Sub,
+    send_waits = true,
+    attach = |s, peer| s.attach(peer).await
+);
+
+impl_recv!(Dealer, Pair, Pull, Rep, Req, Router, Sub);
+impl_send!(Dealer, Pair, Pub, Push, Rep, Req, Router);
+
+/// What every socket does the same way: binding and connecting, and
+/// attaching the peers that connect to the endpoints it's bound to.
+pub(crate) struct Core<S> {
+    pub(crate) socket: S,
+    context: Context,
+    peers_tx: mpsc::UnboundedSender<TransportPeer>,
+    peers: mpsc::UnboundedReceiver<TransportPeer>,
+    listeners: Listeners,
+    has_peer: bool,
+}
+
+// The background tasks accepting peers on the endpoints the socket is bound
+// to, which stop once it's dropped or closed.
+#[derive(Default)]
+struct Listeners(Vec<(Endpoint, AbortHandle)>);
+
+impl Drop for Listeners {
+    fn drop(&mut self) {
+        for (_, handle) in &self.0 {
+            handle.abort();
+        }
+    }
+}
+
+impl<S: Inner> Core<S> {
+    pub(crate) fn new(context: &Context) -> Core<S> {
+        let (peers_tx, peers) = mpsc::unbounded();
+        Core {
+            socket: S::with_context(context),
+            context: context.clone(),
+            peers_tx,
+            peers,
+            listeners: Listeners::default(),
+            has_peer: false,
+        }
+    }
+
+    pub(crate) async fn bind(&mut self, endpoint: &str) -> Result<Endpoint, Error> {
+        let endpoint = endpoint.parse()?;
+        let peers = self.peers_tx.clone();
+        let (bound, handle) =
+            transport::bind(&self.context, &endpoint, S::SOCKET_TYPE, peers).await?;
+        self.listeners.0.push((bound.clone(), handle));
+        Ok(bound)
+    }
+
+    pub(crate) async fn connect(&mut self, endpoint: &str) -> Result<(), Error> {
+        let endpoint = endpoint.parse()?;
+        let peer = transport::connect(&self.context, &endpoint, S::SOCKET_TYPE).await?;
+        self.attach(peer).await
+    }
+
+    // Stops accepting peers on `endpoint`, which has to be the one `bind`
+    // returned. The peers that were already accepted stay attached.
+    pub(crate) fn unbind(&mut self, endpoint: &str) -> Result<(), Error> {
+        let endpoint: Endpoint = endpoint.parse()?;
+        let idx = self
+            .listeners
+            .0
+            .iter()
+            .position(|(bound, _)| *bound == endpoint)
+            .ok_or(Error::NotBound(endpoint))?;
+        let (_, handle) = self.listeners.0.remove(idx);
+        handle.abort();
+        Ok(())
+    }
+
+    pub(crate) fn set_option(&mut self, option: SocketOption) {
+        self.socket.set_option(option)
+    }
+
+    pub(crate) fn get_option(&self, name: SocketOptionName) -> SocketOption {
+        self.socket.get_option(name)
+    }
+
+    pub(crate) fn metrics(&self) -> Metrics {
+        self.socket.metrics()
+    }
+
+    // Stops accepting peers, then closes the ones that are attached. Peers
+    // that were accepted but never attached have nothing to send, and are
+    // dropped.
+    pub(crate) async fn close(self) {
+        let Core {
+            socket, listeners, ..
+        } = self;
+        drop(listeners);
+        socket.close().await
+    }
+
+    fn timeout(&self, name: SocketOptionName) -> Option<Duration> {
+        match self.get_option(name) {
+            SocketOption::SendTimeout(timeout) | SocketOption::RecvTimeout(timeout) => timeout,
+            _ => None,
+        }
+    }
+
+    async fn attach(&mut self, peer: TransportPeer) -> Result<(), Error> {
+        self.socket.attach(peer).await?;
+        self.has_peer = true;
+        Ok(())
+    }
+
+    // Attaches the peers that have been accepted since last time.
+    async fn attach_accepted(&mut self) -> Result<(), Error> {
+        while let Ok(peer) = self.peers.try_recv() {
+            self.attach(peer).await?;
+        }
+        Ok(())
+    }
+
+    // Waits for a peer to be accepted, if the socket is bound anywhere.
+    async fn accept(&mut self) -> Result<(), Error> {
+        if self.listeners.0.is_empty() {
+            return Err(SocketError::NoPeers.into());
+        }
+        if let Some(peer) = self.peers.next().await {
+            self.attach(peer).await?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Inner + InnerRecv> Core<S> {
+    // Receives the next message, attaching the peers that are accepted while
+    // waiting for it, since any of them may be the one that sends it. The
+    // receive timeout covers waiting for the first peer too.
+    pub(crate) async fn recv(&mut self) -> Result<Message, Error> {
+        let timeout = self.timeout(SocketOptionName::RecvTimeout);
+        oxzmq_zmtp::timeout(timeout, self.recv_attaching())
+            .await
+            .map_err(SocketError::from)?
+    }
+
+    async fn recv_attaching(&mut self) -> Result<Message, Error> {
+        loop {
+            self.attach_accepted().await?;
+            let accepted = {
+                let recv = self.socket.recv();
+                let accept = self.peers.next();
+                pin_mut!(recv);
+                match future::select(recv, accept).await {
+                    Either::Left((Err(SocketError::NoPeers), _)) => None,
+                    Either::Left((result, _)) => return Ok(result?),
+                    // Accepted peers never run out, since the core keeps a
+                    // sender of its own.
+                    Either::Right((peer, _)) => peer,
+                }
+            };
+            match accepted {
+                Some(peer) => self.attach(peer).await?,
+                None => self.accept().await?,
+            }
+        }
+    }
+}
+
+impl<S: Inner + InnerSend> Core<S> {
+    // Sends a message, first waiting for a peer to connect if the socket
+    // type needs one and hasn't had any yet. The send timeout covers that
+    // wait too.
+    pub(crate) async fn send(&mut self, msg: Message) -> Result<(), Error> {
+        let timeout = self.timeout(SocketOptionName::SendTimeout);
+        oxzmq_zmtp::timeout(timeout, self.send_attaching(msg))
+            .await
+            .map_err(SocketError::from)?
+    }
+
+    async fn send_attaching(&mut self, msg: Message) -> Result<(), Error> {
+        self.attach_accepted().await?;
+        if S::SEND_WAITS && !self.has_peer {
+            self.accept().await?;
+        }
+        Ok(self.socket.send(msg).await?)
+    }
+}
@@ -0,0 +1,290 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{socket::Core, transport::TransportPeer, Error};
+use oxzmq_zmtp::{
+    Context, Dealer, Endpoint, Message, Metrics, Pair, Pub, Pull, Push, Rep, Req, Router,
+    SocketOption, SocketOptionName, Sub,
+};
+
+macro_rules! socket {
+    ($(#[$doc:meta])* $name:ident($inner:ident)) => {
+        $(#[$doc])*
+        pub struct $name {
+            core: Core<$inner<TransportPeer>>,
+        }
+
+        impl $name {
+            /// A socket with a context of its own, so it can't reach
+            /// `inproc://` endpoints bound by other sockets.
+            pub fn new() -> $name {
+                $name::with_context(&Context::new())
+            }
+
+            /// A socket that belongs to `context`: it connects with the
+            /// context's options, shares its `inproc://` endpoints, and stops
+            /// working once the context is terminated.
+            pub fn with_context(context: &Context) -> $name {
+                $name {
+                    core: Core::new(context),
+                }
+            }
+
+            /// Starts accepting peers on `endpoint`, such as
+            /// `tcp://127.0.0.1:5555`, `tcp://*:*`, `ws://*:8080/zmq` or
+            /// `inproc://name`. Peers are accepted in the background and
+            /// attached the next time the socket is used. Returns the endpoint
+            /// that was bound, with the port picked when `endpoint` asked for
+            /// any.
+            pub async fn bind(&mut self, endpoint: &str) -> Result<Endpoint, Error> {
+                self.core.bind(endpoint).await
+            }
+
+            /// Stops accepting peers on an endpoint that `bind` returned.
+            pub fn unbind(&mut self, endpoint: &str) -> Result<(), Error> {
+                self.core.unbind(endpoint)
+            }
+
+            /// Connects to `endpoint` and performs the handshake. Unlike in
+            /// libzmq, the peer has to be there already, and the connection
+            /// isn't remade if it drops.
+            pub async fn connect(&mut self, endpoint: &str) -> Result<(), Error> {
+                self.core.connect(endpoint).await
+            }
+
+            /// Changes one of the socket's settings, such as its linger
+            /// period or receive timeout. The send and receive timeouts cover
+            /// waiting for a first peer as well.
+            pub fn set_option(&mut self, option: SocketOption) {
+                self.core.set_option(option)
+            }
+
+            /// The current value of one of the socket's settings.
+            pub fn get_option(&self, name: SocketOptionName) -> SocketOption {
+                self.core.get_option(name)
+            }
+
+            /// How much the socket has sent and received so far.
+            pub fn metrics(&self) -> Metrics {
+                self.core.metrics()
+            }
+
+            /// Stops accepting peers and closes the ones it has, waiting up
+            /// to the linger period (`SocketOption::Linger`) for them to
+            /// finish sending. Dropping the socket instead drops whatever
+            /// they're holding back.
+            pub async fn close(self) {
+                self.core.close().await
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> $name {
+                $name::new()
+            }
+        }
+    };
+}
+
+macro_rules! recv {
+    ($($name:ident),*) => {
+        $(
+            impl $name {
+                pub async fn recv(&mut self) -> Result<Message, Error> {
+                    self.core.recv().await
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! send {
+    ($($name:ident),*) => {
+        $(
+            impl $name {
+                pub async fn send(&mut self, msg: Message) -> Result<(), Error> {
+                    self.core.send(msg).await
+                }
+            }
+        )*
+    };
+}
+
+socket!(
+    /// Sends every message to each subscriber whose subscriptions match it.
+    /// Messages are dropped while there are no subscribers.
+    PubSocket(Pub)
+);
+
+socket!(
+    /// Receives the messages from publishers that match its subscriptions.
+    /// It receives nothing until it subscribes to something.
+    SubSocket(Sub)
+);
+
+socket!(
+    /// Sends requests and receives their replies, strictly in turn.
+    ReqSocket(Req)
+);
+
+socket!(
+    /// Receives requests and sends their replies, strictly in turn.
+    RepSocket(Rep)
+);
+
+socket!(
+    /// Sends messages to its peers in turn, to spread work between them.
+    PushSocket(Push)
+);
+
+socket!(
+    /// Receives messages from all its peers, fairly.
+    PullSocket(Pull)
+);
+
+socket!(
+    /// Sends to its peers in turn and receives from all of them, without the
+    /// lockstep of REQ and REP.
+    DealerSocket(Dealer)
+);
+
+socket!(
+    /// Receives messages with the routing ID of the peer they came from in
+    /// front, and sends each message to the peer whose routing ID is in front
+    /// of it.
+    RouterSocket(Router)
+);
+
+socket!(
+    /// Exchanges messages with exactly one peer.
+    PairSocket(Pair)
+);
+
+recv!(
+    SubSocket,
+    ReqSocket,
+    RepSocket,
+    PullSocket,
+    DealerSocket,
+    RouterSocket,
+    PairSocket
+);
+send!(
+    PubSocket,
+    ReqSocket,
+    RepSocket,
+    PushSocket,
+    DealerSocket,
+    RouterSocket,
+    PairSocket
+);
+
+impl SubSocket {
+    /// Receives the messages that start with `topic`, from now on. An empty
+    /// topic matches every message.
+    pub async fn subscribe(&mut self, topic: &[u8]) -> Result<(), Error> {
+        Ok(self.core.socket.subscribe(topic).await?)
+    }
+
+    pub async fn unsubscribe(&mut self, topic: &[u8]) -> Result<(), Error> {
+        Ok(self.core.socket.unsubscribe(topic).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, join};
+    use oxzmq_zmtp::SocketError;
+    use std::time::Duration;
+
+    #[test]
+    fn test_req_rep_over_tcp() {
+        block_on(async {
+            let mut rep = RepSocket::new();
+            let endpoint = rep.bind("tcp://127.0.0.1:*").await.unwrap();
+
+            let mut req = ReqSocket::new();
+            req.connect(&endpoint.to_string()).await.unwrap();
+            req.send(Message::from(b"ping".to_vec())).await.unwrap();
+
+            let request = rep.recv().await.unwrap();
+            assert_eq!(request, Message::from(b"ping".to_vec()));
+            rep.send(Message::from(b"pong".to_vec())).await.unwrap();
+            let reply = req.recv().await.unwrap();
+            assert_eq!(reply, Message::from(b"pong".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_pull_accepts_while_waiting() {
+        block_on(async {
+            let context = Context::new();
+            let mut pull = PullSocket::with_context(&context);
+            pull.bind("inproc://work").await.unwrap();
+
+            // The first pusher never sends anything, so the message only
+            // arrives if the second one is attached while already receiving.
+            let mut idle = PushSocket::with_context(&context);
+            idle.connect("inproc://work").await.unwrap();
+            let mut busy = PushSocket::with_context(&context);
+            let (received, ()) = join!(pull.recv(), async {
+                busy.connect("inproc://work").await.unwrap();
+                busy.send(Message::from(b"job".to_vec())).await.unwrap();
+            });
+            assert_eq!(received.unwrap(), Message::from(b"job".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_unbind() {
+        block_on(async {
+            let context = Context::new();
+            let mut pull = PullSocket::with_context(&context);
+            let endpoint = pull.bind("inproc://work").await.unwrap();
+            pull.unbind(&endpoint.to_string()).unwrap();
+            assert!(matches!(
+                pull.unbind(&endpoint.to_string()),
+                Err(Error::NotBound(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_recv_timeout_without_peers() {
+        block_on(async {
+            let context = Context::new();
+            let mut pull = PullSocket::with_context(&context);
+            pull.bind("inproc://work").await.unwrap();
+            let timeout = Some(Duration::from_millis(20));
+            pull.set_option(SocketOption::RecvTimeout(timeout));
+            assert_eq!(
+                pull.get_option(SocketOptionName::RecvTimeout),
+                SocketOption::RecvTimeout(timeout)
+            );
+            assert!(matches!(
+                pull.recv().await,
+                Err(Error::Socket(SocketError::Timeout))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_close() {
+        block_on(async {
+            let context = Context::new();
+            let mut pull = PullSocket::with_context(&context);
+            pull.bind("inproc://work").await.unwrap();
+
+            let mut push = PushSocket::with_context(&context);
+            push.connect("inproc://work").await.unwrap();
+            push.set_option(SocketOption::Linger(Some(Duration::from_secs(5))));
+            push.send(Message::from(b"job".to_vec())).await.unwrap();
+            assert_eq!(push.metrics().messages_sent, 1);
+            push.close().await;
+
+            assert_eq!(pull.recv().await.unwrap(), Message::from(b"job".to_vec()));
+        });
+    }
+}
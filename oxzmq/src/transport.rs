@@ -0,0 +1,182 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::Error;
+use futures::{channel::mpsc, future::AbortHandle, pin_mut, StreamExt};
+use oxzmq_zmtp::{
+    Context, Endpoint, InprocPeer, Liveness, Message, Origin, Peer, PeerError, SocketType,
+    StaticResolver, TcpConnection, TcpListener, ThreadRuntime, WsConnection,
+};
+use std::{
+    future::Future,
+    task::{Context as TaskContext, Poll},
+};
+
+/// A peer on any of the transports that sockets can bind and connect with, so
+/// that one socket can have peers on several of them at once. Connections
+/// carry their buffers, so they're boxed to keep in-process peers small.
+#[derive(Debug)]
+pub(crate) enum TransportPeer {
+    Tcp(Box<TcpConnection>),
+    Ws(Box<WsConnection>),
+    Inproc(InprocPeer),
+}
+
+// Calls the same method on whichever peer this is.
+macro_rules! on_peer {
+    ($peer:expr, $p:ident => $call:expr) => {
+        match $peer {
+            TransportPeer::Tcp($p) => $call,
+            TransportPeer::Ws($p) => $call,
+            TransportPeer::Inproc($p) => $call,
+        }
+    };
+}
+
+impl Peer for TransportPeer {
+    fn remote_socket_type(&self) -> SocketType {
+        on_peer!(self, p => p.remote_socket_type())
+    }
+
+    fn routing_id(&self) -> Option<&[u8]> {
+        on_peer!(self, p => p.routing_id())
+    }
+
+    fn origin(&self) -> Option<&Origin> {
+        on_peer!(self, p => p.origin())
+    }
+
+    fn weight(&self) -> Option<u32> {
+        on_peer!(self, p => p.weight())
+    }
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), PeerError>> {
+        on_peer!(self, p => p.poll_ready(cx))
+    }
+
+    async fn send_message(&mut self, msg: Message) -> Result<(), PeerError> {
+        on_peer!(self, p => p.send_message(msg).await)
+    }
+
+    async fn send_batch(&mut self, msgs: Vec<Message>) -> Result<(), PeerError> {
+        on_peer!(self, p => p.send_batch(msgs).await)
+    }
+
+    async fn recv_message(&mut self) -> Result<Message, PeerError> {
+        on_peer!(self, p => p.recv_message().await)
+    }
+
+    fn try_recv_message(&mut self) -> Option<Result<Message, PeerError>> {
+        on_peer!(self, p => p.try_recv_message())
+    }
+
+    async fn send_heartbeat(&mut self) -> Result<(), PeerError> {
+        on_peer!(self, p => p.send_heartbeat().await)
+    }
+
+    fn liveness(&self) -> Option<&Liveness> {
+        on_peer!(self, p => p.liveness())
+    }
+
+    async fn flush(&mut self) -> Result<(), PeerError> {
+        on_peer!(self, p => p.flush().await)
+    }
+
+    async fn close(&mut self) -> Result<(), PeerError> {
+        on_peer!(self, p => p.close().await)
+    }
+}
+
+// Connects to `endpoint` and performs the handshake, with the context's
+// options. `inproc://` endpoints have to have been bound in the same context.
+pub(crate) async fn connect(
+    context: &Context,
+    endpoint: &Endpoint,
+    socket_type: SocketType,
+) -> Result<TransportPeer, Error> {
+    let options = context.options();
+    let resolver = StaticResolver::new();
+    Ok(match endpoint {
+        Endpoint::Inproc(_) => {
+            TransportPeer::Inproc(context.inproc().connect(endpoint, socket_type, options)?)
+        }
+        Endpoint::Ws { .. } => TransportPeer::Ws(Box::new(
+            WsConnection::connect(endpoint, &resolver, &socket_type, options).await?,
+        )),
+        _ => TransportPeer::Tcp(Box::new(
+            TcpConnection::connect(endpoint, &resolver, &socket_type, options).await?,
+        )),
+    })
+}
+
+// Starts listening on `endpoint`, and accepts peers in the background,
+// sending each one that completes its handshake to `peers`. Returns the
+// endpoint that was actually bound, which differs from `endpoint` when it
+// asked for any free port, and a handle that stops accepting.
+pub(crate) async fn bind(
+    context: &Context,
+    endpoint: &Endpoint,
+    socket_type: SocketType,
+    peers: mpsc::UnboundedSender<TransportPeer>,
+) -> Result<(Endpoint, AbortHandle), Error> {
+    let options = context.options().clone();
+    match endpoint {
+        Endpoint::Inproc(_) => {
+            let listener = context.inproc().bind(endpoint, socket_type)?;
+            let accept = listener.map(|peer| Ok(TransportPeer::Inproc(peer)));
+            Ok((endpoint.clone(), spawn(context, accept.forward(peers))))
+        }
+        Endpoint::Ws { .. } => {
+            let listener = TcpListener::bind(endpoint).await?;
+            let bound = listener.last_endpoint()?;
+            let accept = async move {
+                loop {
+                    // Peers that fail their handshake are simply left out.
+                    let peer = WsConnection::accept(&listener, &socket_type, &options).await;
+                    if let Ok(peer) = peer {
+                        if peers
+                            .unbounded_send(TransportPeer::Ws(Box::new(peer)))
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            };
+            Ok((bound, spawn(context, accept)))
+        }
+        _ => {
+            let listener = TcpListener::bind(endpoint).await?;
+            let bound = listener.last_endpoint()?;
+            let accept = async move {
+                let incoming = listener.incoming(socket_type, options);
+                pin_mut!(incoming);
+                while let Some(conn) = incoming.next().await {
+                    if let Ok(conn) = conn {
+                        if peers
+                            .unbounded_send(TransportPeer::Tcp(Box::new(conn)))
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            };
+            Ok((bound, spawn(context, accept)))
+        }
+    }
+}
+
+// Runs `task` in the background until it ends, the context is terminated, or
+// the returned handle is aborted.
+fn spawn<F>(context: &Context, task: F) -> AbortHandle
+where
+    F: Future + Send + 'static,
+{
+    let (handle, registration) = AbortHandle::new_pair();
+    context.spawn(&ThreadRuntime, async move {
+        let _ = futures::future::Abortable::new(task, registration).await;
+    });
+    handle
+}